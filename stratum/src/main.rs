@@ -14,9 +14,11 @@
 
 //! Mining Stratum Pool
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate bincode;
 extern crate bufstream;
 #[macro_use]
 extern crate log;
@@ -24,7 +26,13 @@ extern crate time;
 extern crate log4rs;
 extern crate toml;
 extern crate reqwest;
+extern crate hyper;
+extern crate notify;
+extern crate rayon;
+extern crate tungstenite;
+extern crate mio;
 extern crate redis;
+extern crate rusqlite;
 extern crate blake2_rfc as blake2;
 extern crate byteorder;
 extern crate rand;
@@ -33,10 +41,16 @@ extern crate grin_core;
 extern crate grin_util;
 extern crate failure;
 extern crate backtrace;
+extern crate ctrlc;
+#[cfg(test)]
+extern crate mockito;
+#[cfg(test)]
+extern crate testcontainers;
 
 use std::io::BufRead;
 use std::io::{ErrorKind, Write};
 use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
@@ -44,19 +58,30 @@ use std::time::SystemTime;
 
 mod pool;
 use pool::config;
+use pool::db;
 use pool::pool::Pool;
 use pool::logger::init_logger;
 
 fn main() {
 
-    init_logger();
+    let log_levels = init_logger();
 
     warn!("Startng Grin-Pool");
 
     let config = config::read_config();
 
-    println!("{:?}", config);
+    info!("{:?}", config);
 
-    let mut my_pool = Pool::new(config);
-    my_pool.run();
+    let db = db::open(&config.grin_pool.db_file).expect("Failed to open database");
+
+    let mut my_pool = Pool::new(config, Arc::new(Mutex::new(db)));
+    my_pool.set_log_levels(log_levels);
+    let shutdown = my_pool.shutdown_handle();
+    if let Err(e) = ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst)) {
+        error!("Failed to install the shutdown handler: {}", e);
+    }
+    if let Err(e) = my_pool.run() {
+        error!("Grin-Pool failed to start: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,233 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool Admin Control
+//!
+//! A line based TCP listener for operators.  Each line is
+//! `<secret> <command> [args]` and gets a single line JSON reply:
+//!   list_workers
+//!   kick <full_id>
+//!   ban <ip> <duration_secs>
+//!
+
+use serde_json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use pool::worker::Worker;
+
+#[derive(Serialize, Debug)]
+struct AdminResponse {
+    workers: usize, // Connected workers after the command ran
+    ok: bool,
+    result: Value,
+}
+
+/// Shared pool state the admin commands act on
+// Locks are only ever taken one at a time here, never nested, so the
+// admin thread can not deadlock against the main loop
+#[derive(Clone)]
+pub struct AdminState {
+    pub workers: Arc<Mutex<HashMap<String, Worker>>>,
+    pub banned: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+}
+
+/// Run the admin listener - blocks, so run it in its own thread
+pub fn start(address: String, secret: String, state: AdminState) {
+    if secret.is_empty() {
+        error!("Admin - Refusing to start without an admin_secret");
+        return;
+    }
+    let listener = match TcpListener::bind(address.clone()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Admin - Failed to bind to {}: {}", address, e);
+            return;
+        }
+    };
+    warn!("Admin - Listening on {}", address);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // One operator at a time is plenty
+                if let Err(e) = handle_connection(stream, &secret, &state) {
+                    debug!("Admin - Connection closed: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("Admin - Error accepting connection: {:?}", e);
+            }
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, secret: &str, state: &AdminState) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .map_err(|e| e.to_string())?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let response = run_command(&line, secret, state);
+        let mut response_str = serde_json::to_string(&response).unwrap();
+        response_str += "\n";
+        writer
+            .write_all(response_str.as_bytes())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn run_command(line: &str, secret: &str, state: &AdminState) -> AdminResponse {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let result = if args.len() < 2 || !secret_matches(args[0], secret) {
+        warn!("Admin - Rejected unauthenticated command");
+        Err("Not authorized".to_string())
+    } else {
+        match (args[1], &args[2..]) {
+            ("list_workers", &[]) => Ok(list_workers(state)),
+            ("kick", &[full_id]) => kick(state, full_id),
+            ("ban", &[ip, duration]) => ban(state, ip, duration),
+            _ => Err(format!("Unknown command: {}", args[1..].join(" "))),
+        }
+    };
+    let workers = state.workers.lock().unwrap().len();
+    match result {
+        Ok(result) => AdminResponse {
+            workers: workers,
+            ok: true,
+            result: result,
+        },
+        Err(e) => AdminResponse {
+            workers: workers,
+            ok: false,
+            result: Value::from(e),
+        },
+    }
+}
+
+fn list_workers(state: &AdminState) -> Value {
+    let w_m = state.workers.lock().unwrap();
+    let workers: Vec<Value> = w_m
+        .values()
+        .map(|w| {
+            let mut status = serde_json::to_value(&w.status).unwrap();
+            status["ip"] = match w.ip() {
+                Some(ip) => Value::from(ip.to_string()),
+                None => Value::Null,
+            };
+            status["login"] = Value::from(w.login());
+            status
+        })
+        .collect();
+    Value::from(workers)
+}
+
+fn kick(state: &AdminState, full_id: &str) -> Result<Value, String> {
+    // Workers are keyed by uuid, full ids are kept unique among them
+    let mut w_m = state.workers.lock().unwrap();
+    match w_m.values_mut().find(|w| w.full_id() == full_id) {
+        Some(worker) => {
+            warn!("Admin - Kicking worker {}", full_id);
+            worker.disconnect();
+            Ok(Value::from(format!("Kicked {}", full_id)))
+        }
+        None => Err(format!("No such worker: {}", full_id)),
+    }
+}
+
+fn ban(state: &AdminState, ip: &str, duration: &str) -> Result<Value, String> {
+    let ip: IpAddr = ip.parse().map_err(|_| format!("Invalid ip address: {}", ip))?;
+    let duration: u64 = duration
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", duration))?;
+    let expires = Instant::now()
+        .checked_add(Duration::from_secs(duration))
+        .ok_or_else(|| format!("Duration too long: {}", duration))?;
+    warn!("Admin - Banning {} for {} seconds", ip, duration);
+    state.banned.lock().unwrap().insert(ip, expires);
+    // Drop anyone already connected from there
+    let mut kicked = 0;
+    let mut w_m = state.workers.lock().unwrap();
+    for worker in w_m.values_mut() {
+        if worker.ip() == Some(ip) {
+            worker.disconnect();
+            kicked += 1;
+        }
+    }
+    Ok(Value::from(format!("Banned {} for {} seconds, kicked {} workers", ip, duration, kicked)))
+}
+
+/// Compare without bailing out at the first difference
+pub fn secret_matches(given: &str, secret: &str) -> bool {
+    if given.len() != secret.len() {
+        return false;
+    }
+    given
+        .bytes()
+        .zip(secret.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pool::pool::tests::{test_config, test_worker};
+
+    #[test]
+    fn admin_commands() {
+        let config = test_config();
+        let state = AdminState {
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            banned: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let (worker, _miner) = test_worker(&config);
+        let worker_id = worker.uuid();
+        let full_id = worker.full_id();
+        state.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        let response = run_command("wrong list_workers", "secret", &state);
+        assert!(!response.ok);
+
+        let response = run_command("secret list_workers", "secret", &state);
+        assert!(response.ok);
+        assert_eq!(response.workers, 1);
+        assert_eq!(response.result[0]["ip"], "127.0.0.1");
+
+        let response = run_command(&format!("secret kick {}", worker_id), "secret", &state);
+        assert!(!response.ok);
+        assert!(!state.workers.lock().unwrap()[&worker_id].error());
+        let response = run_command(&format!("secret kick {}", full_id), "secret", &state);
+        assert!(response.ok);
+        assert!(state.workers.lock().unwrap()[&worker_id].error());
+
+        let response = run_command("secret ban 10.0.0.1 60", "secret", &state);
+        assert!(response.ok);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(state.banned.lock().unwrap().contains_key(&ip));
+
+        let response = run_command("secret ban nonsense 60", "secret", &state);
+        assert!(!response.ok);
+
+        // Too long to add to the clock, refused without poisoning the lock
+        let response = run_command(&format!("secret ban 10.0.0.2 {}", u64::max_value()), "secret", &state);
+        assert!(!response.ok);
+        assert_eq!(state.banned.lock().unwrap().len(), 1);
+    }
+}
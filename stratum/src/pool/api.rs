@@ -0,0 +1,595 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool Stats API
+//!
+//! An HTTP JSON api for dashboards:
+//!   GET /api/v1/stats
+//!   GET /api/v1/status - every worker and the pool totals in one snapshot
+//!   GET /api/v1/workers
+//!   GET /api/v1/workers/{id}
+//!   GET /api/v1/blocks
+//!   GET /api/v1/payouts - what a block found now would pay, and the pool fee
+//!
+//! And for operators, with an `Authorization: Bearer <api_admin_token>` header:
+//!   POST /api/v1/workers/{id}/reset - zero a workers share counts
+//!   POST /api/v1/log-level - change a modules log level, with a JSON body
+//!     like `{"module": "pool::worker", "level": "debug"}`
+//!
+
+use grin_core::consensus::REWARD;
+use hyper::rt::{self, Future, Stream};
+use hyper::service::service_fn;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Chunk, Method, Request, Response, Server, StatusCode};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use pool::admin::secret_matches;
+use pool::db;
+use pool::hashrate;
+use pool::histogram::{BucketCount, DifficultyHistogram};
+use pool::logger::ModuleLevels;
+use pool::metrics::{ConnectionCounters, ConnectionCounts};
+use pool::pplns::PplnsWindow;
+use pool::sampler::HashrateSampler;
+use pool::netdiff::NetworkDifficulty;
+use pool::proto::WorkerStatus;
+use pool::server::ServerState;
+use pool::worker::{Worker, WorkerShares};
+
+const RECENT_BLOCKS: u32 = 50;
+// Bumped when a field of the status snapshot is removed or changes meaning
+const STATUS_VERSION: u32 = 1;
+
+/// Shared pool state the api reads from
+#[derive(Clone)]
+pub struct ApiState {
+    pub workers: Arc<Mutex<HashMap<String, Worker>>>,
+    pub pplns: Arc<Mutex<PplnsWindow>>,
+    pub db: Arc<Mutex<Connection>>,
+    pub sampler: Arc<Mutex<HashrateSampler>>,
+    pub histogram: Arc<Mutex<DifficultyHistogram>>,
+    pub network: Arc<Mutex<NetworkDifficulty>>,
+    pub upstream_state: Arc<Mutex<ServerState>>,
+    pub header_errors: Arc<AtomicUsize>, // Shares whose block header could not be built
+    pub connections: Arc<ConnectionCounters>,
+    pub admin_token: String, // Required by POST endpoints, empty refuses them
+    pub log_levels: ModuleLevels,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub shares_per_minute: u64,
+    pub hashrate: f64, // Accepted share difficulty per second over the last minute
+    pub graphs_per_second: f64, // Estimated C31 graphs per second of all workers
+    pub hashrate_1m: f64, // Average C31 graphs per second over the last minute
+    pub hashrate_15m: f64,
+    pub hashrate_24h: f64,
+    pub network_difficulty: u64, // Scaled difficulty of the block being mined, 0 until known
+    pub network_difficulty_c31: u64, // Unscaled difficulty a C31 share needs to be a block
+    pub share_difficulty: HashMap<u32, Vec<BucketCount>>, // Accepted shares by their difficulty, for each edge_bits
+    pub header_errors: usize, // Shares rejected because their block header could not be built, not because of the miner
+    pub connections: ConnectionCounts, // Worker connections since the pool started
+    pub upstream_state: ServerState, // connected, syncing, disconnected, or error
+}
+
+/// The pool and all its workers, for dashboards
+#[derive(Serialize, Debug)]
+pub struct PoolStatus {
+    pub version: u32, // STATUS_VERSION
+    pub height: u64, // Of the block being mined, 0 until known
+    pub network_difficulty: u64,
+    pub workers: usize,
+    pub accepted: u64, // Totals of the connected workers
+    pub rejected: u64,
+    pub stale: u64,
+    pub graphs_per_second: f64, // Estimated C31 graphs per second of all workers
+    pub worker_status: Vec<WorkerStatusView>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WorkerStatusView {
+    pub id: String,
+    pub full_id: String,
+    pub difficulty: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub stale: u64,
+    pub last_seen: u64, // Unix time a message was last received from the worker
+    pub last_accepted_share: Option<u64>, // Unix time a share was last accepted, None if none was
+    pub silent: bool, // No share accepted in many times the expected share interval
+    pub graphs_per_second: f64, // Estimated C31 graphs per second
+}
+
+#[derive(Serialize)]
+struct WorkerDetail<'a> {
+    status: &'a WorkerStatus,
+    shares: &'a WorkerShares,
+    graphs_per_second: HashMap<u32, f64>, // Estimated for each edge_bits mined
+    nonce_range_violations: u64, // Shares outside the nonce range sent with their job
+}
+
+#[derive(Serialize)]
+struct ResetResponse<'a> {
+    ok: bool,
+    worker: &'a WorkerStatus,
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    module: String,
+    level: String,
+}
+
+#[derive(Serialize)]
+struct LogLevelResponse {
+    ok: bool,
+    levels: HashMap<String, String>, // Every module with its own level
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+/// Run the api server - blocks, so run it in its own thread
+pub fn start(address: String, state: ApiState) {
+    let addr: SocketAddr = match address.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("API - Invalid listen address {}: {}", address, e);
+            return;
+        }
+    };
+    let new_service = move || {
+        let state = state.clone();
+        service_fn(move |req: Request<Body>| {
+            let state = state.clone();
+            // Routed once the whole body has arrived
+            let (head, body) = req.into_parts();
+            body.concat2().map(move |body| route(&Request::from_parts(head, body), &state))
+        })
+    };
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(new_service),
+        Err(e) => {
+            error!("API - Failed to bind to {}: {}", addr, e);
+            return;
+        }
+    };
+    warn!("API - Listening on {}", addr);
+    rt::run(server.map_err(|e| error!("API - Server error: {}", e)));
+}
+
+fn route(req: &Request<Chunk>, state: &ApiState) -> Response<Body> {
+    let parts: Vec<&str> = req.uri().path().trim_end_matches('/').split('/').collect();
+    if req.method() == &Method::POST {
+        return route_post(req, &parts, state);
+    }
+    if req.method() != &Method::GET {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+    match &parts[..] {
+        &["", "api", "v1", "stats"] => json_response(StatusCode::OK, &stats(state)),
+        &["", "api", "v1", "status"] => json_response(StatusCode::OK, &status(state)),
+        &["", "api", "v1", "workers"] => {
+            let w_m = state.workers.lock().unwrap();
+            let workers: Vec<&WorkerStatus> = w_m.values().map(|w| &w.status).collect();
+            json_response(StatusCode::OK, &workers)
+        }
+        &["", "api", "v1", "workers", id] => {
+            let w_m = state.workers.lock().unwrap();
+            match w_m.get(id) {
+                Some(worker) => {
+                    let detail = WorkerDetail {
+                        status: &worker.status,
+                        shares: &worker.worker_shares,
+                        graphs_per_second: worker.hashrate.graphs_per_second(),
+                        nonce_range_violations: worker.nonce_range_violations,
+                    };
+                    json_response(StatusCode::OK, &detail)
+                }
+                None => error_response(StatusCode::NOT_FOUND, "Worker not found"),
+            }
+        }
+        &["", "api", "v1", "blocks"] => {
+            match db::recent_blocks(&state.db.lock().unwrap(), RECENT_BLOCKS) {
+                Ok(blocks) => json_response(StatusCode::OK, &blocks),
+                Err(e) => {
+                    error!("API - Failed to read blocks: {:?}", e);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read blocks")
+                }
+            }
+        }
+        &["", "api", "v1", "payouts"] => {
+            let snapshot = state.pplns.lock().unwrap().payout_snapshot(REWARD);
+            json_response(StatusCode::OK, &snapshot)
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn route_post(req: &Request<Chunk>, parts: &[&str], state: &ApiState) -> Response<Body> {
+    let token = match req.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+        Some(value) if value.starts_with("Bearer ") => value["Bearer ".len()..].trim(),
+        _ => "",
+    };
+    if state.admin_token.is_empty() || !secret_matches(token, &state.admin_token) {
+        return error_response(StatusCode::FORBIDDEN, "Forbidden");
+    }
+    match parts {
+        &["", "api", "v1", "workers", id, "reset"] => {
+            let mut w_m = state.workers.lock().unwrap();
+            match w_m.get_mut(id) {
+                Some(worker) => {
+                    worker.reset_stats();
+                    warn!("API - Reset the stats of worker {}", worker.full_id());
+                    let response = ResetResponse {
+                        ok: true,
+                        worker: &worker.status,
+                    };
+                    json_response(StatusCode::OK, &response)
+                }
+                None => error_response(StatusCode::NOT_FOUND, "Worker not found"),
+            }
+        }
+        &["", "api", "v1", "log-level"] => {
+            let (module, level) = match serde_json::from_slice::<LogLevelRequest>(req.body()) {
+                Ok(request) => (request.module, request.level),
+                Err(_) => return error_response(StatusCode::BAD_REQUEST, "module and level are required"),
+            };
+            if let Err(e) = state.log_levels.set(&module, &level) {
+                return error_response(StatusCode::BAD_REQUEST, &e);
+            }
+            warn!("API - Set the log level of {} to {}", module, level);
+            let response = LogLevelResponse {
+                ok: true,
+                levels: state.log_levels.levels(),
+            };
+            json_response(StatusCode::OK, &response)
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn stats(state: &ApiState) -> PoolStats {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    let (shares, difficulty) = state.pplns.lock().unwrap().shares_since(now.saturating_sub(60));
+    let w_m = state.workers.lock().unwrap();
+    let sampler = state.sampler.lock().unwrap();
+    let network = state.network.lock().unwrap();
+    PoolStats {
+        workers: w_m.len(),
+        shares_per_minute: shares,
+        hashrate: difficulty as f64 / 60.0,
+        graphs_per_second: hashrate::pool_graphs_per_second(&w_m).1,
+        hashrate_1m: sampler.hashrate_gps(Duration::from_secs(60)),
+        hashrate_15m: sampler.hashrate_gps(Duration::from_secs(15 * 60)),
+        hashrate_24h: sampler.hashrate_gps(Duration::from_secs(24 * 60 * 60)),
+        network_difficulty: network.difficulty,
+        network_difficulty_c31: network.unscaled(31),
+        share_difficulty: state.histogram.lock().unwrap().counts(),
+        header_errors: state.header_errors.load(Ordering::Relaxed),
+        connections: state.connections.counts(),
+        upstream_state: *state.upstream_state.lock().unwrap(),
+    }
+}
+
+// Copies what it needs from the workers so the lock is not held while
+// the snapshot is serialized
+fn status(state: &ApiState) -> PoolStatus {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    let worker_status: Vec<WorkerStatusView> = state
+        .workers
+        .lock()
+        .unwrap()
+        .values()
+        .map(|worker| WorkerStatusView {
+            id: worker.uuid(),
+            full_id: worker.full_id(),
+            difficulty: worker.status.difficulty,
+            accepted: worker.status.accepted,
+            rejected: worker.status.rejected,
+            stale: worker.status.stale,
+            last_seen: now.saturating_sub(worker.status.idle_seconds()),
+            last_accepted_share: worker.last_accepted_share_age().map(|age| now.saturating_sub(age)),
+            silent: worker.is_silent(),
+            graphs_per_second: worker.hashrate.c31_graphs_per_second(),
+        })
+        .collect();
+    let (height, network_difficulty) = {
+        let network = state.network.lock().unwrap();
+        (network.height, network.difficulty)
+    };
+    PoolStatus {
+        version: STATUS_VERSION,
+        height: height,
+        network_difficulty: network_difficulty,
+        workers: worker_status.len(),
+        accepted: worker_status.iter().map(|w| w.accepted).sum(),
+        rejected: worker_status.iter().map(|w| w.rejected).sum(),
+        stale: worker_status.iter().map(|w| w.stale).sum(),
+        graphs_per_second: worker_status.iter().map(|w| w.graphs_per_second).sum(),
+        worker_status: worker_status,
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = match serde_json::to_string(value) {
+        Ok(body) => body,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    let body = serde_json::to_string(&ApiError {
+        error: message.to_string(),
+    }).unwrap();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pool::pool::tests::{test_config, test_worker};
+    use serde_json::Value;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::{thread, time};
+
+    // Start the api on a free local port, returns the port
+    fn start_test_api(state: ApiState) -> u16 {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        thread::spawn(move || start(format!("127.0.0.1:{}", port), state));
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        port
+    }
+
+    // Make a request, returns the status code and the parsed json body
+    fn request(port: u16, method: &str, path: &str, headers: &str) -> (u16, Value) {
+        request_with_body(port, method, path, headers, "")
+    }
+
+    fn request_with_body(port: u16, method: &str, path: &str, headers: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            method, path, body.len(), headers, body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status: u16 = response[9..12].parse().unwrap();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    fn get(port: u16, path: &str) -> (u16, Value) {
+        request(port, "GET", path, "")
+    }
+
+    fn test_state(admin_token: &str) -> ApiState {
+        ApiState {
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            pplns: Arc::new(Mutex::new(PplnsWindow::new(10))),
+            db: Arc::new(Mutex::new(db::open_in_memory().unwrap())),
+            sampler: Arc::new(Mutex::new(HashrateSampler::new())),
+            histogram: Arc::new(Mutex::new(DifficultyHistogram::new(vec![4, 64], Duration::from_secs(600)))),
+            network: Arc::new(Mutex::new(NetworkDifficulty::new())),
+            upstream_state: Arc::new(Mutex::new(ServerState::Syncing)),
+            header_errors: Arc::new(AtomicUsize::new(0)),
+            connections: Arc::new(ConnectionCounters::default()),
+            admin_token: admin_token.to_string(),
+            log_levels: ModuleLevels::default(),
+        }
+    }
+
+    #[test]
+    fn api_endpoints() {
+        let config = test_config();
+        let state = test_state("");
+        state.sampler.lock().unwrap().push(60, 600.0);
+        state.sampler.lock().unwrap().push(120, 1200.0);
+        state.network.lock().unwrap().update(1, 1_000_000, 100);
+        state.network.lock().unwrap().update(2, 1_000_000 + 7936 * 50, 100);
+        let (mut worker, _miner) = test_worker(&config);
+        worker.hashrate.add_share(31, 300);
+        let worker_id = worker.uuid();
+        state.workers.lock().unwrap().insert(worker.uuid(), worker);
+        state.pplns.lock().unwrap().add_share(worker_id.clone(), 31, 120);
+        state.histogram.lock().unwrap().add_share(31, 130);
+        state.histogram.lock().unwrap().add_share(32, 2);
+        state.header_errors.store(3, Ordering::Relaxed);
+        state.connections.accepted.store(5, Ordering::Relaxed);
+        state.connections.dropped_idle.store(2, Ordering::Relaxed);
+        db::upsert_block(&state.db.lock().unwrap(), 100, "aaaa", &worker_id).unwrap();
+        let port = start_test_api(state);
+
+        let (status, stats) = get(port, "/api/v1/stats");
+        assert_eq!(status, 200);
+        assert_eq!(stats["workers"], 1);
+        assert_eq!(stats["shares_per_minute"], 1);
+        assert_eq!(stats["hashrate"], 2.0);
+        assert_eq!(stats["graphs_per_second"], 42.0);
+        assert_eq!(stats["hashrate_1m"], 20.0);
+        assert_eq!(stats["hashrate_15m"], 15.0);
+        assert_eq!(stats["network_difficulty"], 7936 * 50);
+        assert_eq!(stats["network_difficulty_c31"], 50);
+        assert_eq!(stats["share_difficulty"]["31"][2]["up_to"], Value::Null);
+        assert_eq!(stats["share_difficulty"]["31"][2]["shares"], 1);
+        assert_eq!(stats["share_difficulty"]["32"][0]["up_to"], 4);
+        assert_eq!(stats["share_difficulty"]["32"][0]["shares"], 1);
+        assert_eq!(stats["header_errors"], 3);
+        assert_eq!(stats["upstream_state"], "syncing");
+        assert_eq!(stats["connections"]["accepted"], 5);
+        assert_eq!(stats["connections"]["dropped_idle"], 2);
+        assert_eq!(stats["connections"]["rejected_banned"], 0);
+
+        let (status, workers) = get(port, "/api/v1/workers");
+        assert_eq!(status, 200);
+        assert_eq!(workers[0]["id"], Value::from(worker_id.clone()));
+        assert!(workers[0]["accepted"].is_u64());
+
+        let (status, detail) = get(port, &format!("/api/v1/workers/{}", worker_id));
+        assert_eq!(status, 200);
+        assert!(detail["status"]["difficulty"].is_u64());
+        assert!(detail["shares"]["shares"].is_object());
+        assert_eq!(detail["graphs_per_second"]["31"], 42.0);
+
+        let (status, missing) = get(port, "/api/v1/workers/nobody");
+        assert_eq!(status, 404);
+        assert!(missing["error"].is_string());
+
+        let (status, blocks) = get(port, "/api/v1/blocks");
+        assert_eq!(status, 200);
+        assert_eq!(blocks[0]["height"], 100);
+        assert_eq!(blocks[0]["found_by"], Value::from(worker_id.clone()));
+
+        let (status, payouts) = get(port, "/api/v1/payouts");
+        assert_eq!(status, 200);
+        assert_eq!(payouts["fee"], 0);
+        assert_eq!(payouts["payouts"][&worker_id], payouts["reward"]);
+    }
+
+    #[test]
+    fn status_snapshot() {
+        let config = test_config();
+        let state = test_state("");
+        state.network.lock().unwrap().update(1, 1_000_000, 100);
+        state.network.lock().unwrap().update(2, 1_000_000 + 7936 * 50, 100);
+        let mut worker_ids = Vec::new();
+        for accepted in 1..3 {
+            let (mut worker, _miner) = test_worker(&config);
+            worker.hashrate.add_share(31, 300);
+            worker.status.difficulty = 4;
+            worker.status.accepted = accepted;
+            worker.status.stale = 1;
+            worker_ids.push(worker.uuid());
+            state.workers.lock().unwrap().insert(worker.uuid(), worker);
+        }
+        let port = start_test_api(state);
+
+        let (status, snapshot) = get(port, "/api/v1/status");
+        assert_eq!(status, 200);
+        assert_eq!(snapshot["version"], STATUS_VERSION);
+        assert_eq!(snapshot["height"], 2);
+        assert_eq!(snapshot["network_difficulty"], 7936 * 50);
+        assert_eq!(snapshot["workers"], 2);
+        assert_eq!((snapshot["accepted"].as_u64(), snapshot["stale"].as_u64()), (Some(3), Some(2)));
+        assert_eq!(snapshot["graphs_per_second"], 84.0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for view in snapshot["worker_status"].as_array().unwrap() {
+            assert!(worker_ids.iter().any(|id| view["id"] == Value::from(id.clone())));
+            assert!(view["full_id"].is_string());
+            assert_eq!(view["difficulty"], 4);
+            assert!(now - view["last_seen"].as_u64().unwrap() < 60);
+            assert!(view["last_accepted_share"].is_null());
+            assert_eq!(view["silent"], false);
+            assert_eq!(view["graphs_per_second"], 42.0);
+        }
+    }
+
+    #[test]
+    fn worker_stats_reset() {
+        let config = test_config();
+        let state = test_state("s3cret");
+        let (mut worker, _miner) = test_worker(&config);
+        worker.status.difficulty = 8;
+        worker.add_shares(31, 5, 2, 1);
+        worker.status.accepted = 5;
+        worker.status.rejected = 2;
+        worker.status.stale = 1;
+        let worker_id = worker.uuid();
+        state.workers.lock().unwrap().insert(worker.uuid(), worker);
+        let workers = state.workers.clone();
+        let port = start_test_api(state);
+        let path = format!("/api/v1/workers/{}/reset", worker_id);
+
+        let (status, _) = request(port, "POST", &path, "");
+        assert_eq!(status, 403);
+        let (status, _) = request(port, "POST", &path, "Authorization: Bearer wrong\r\n");
+        assert_eq!(status, 403);
+        assert_eq!(workers.lock().unwrap()[&worker_id].status.accepted, 5);
+
+        let auth = "Authorization: Bearer s3cret\r\n";
+        let (status, _) = request(port, "POST", "/api/v1/workers/nobody/reset", auth);
+        assert_eq!(status, 404);
+
+        let (status, reset) = request(port, "POST", &path, auth);
+        assert_eq!(status, 200);
+        assert_eq!(reset["ok"], true);
+        assert_eq!(reset["worker"]["accepted"], 0);
+        assert_eq!(reset["worker"]["difficulty"], 8);
+        let w_m = workers.lock().unwrap();
+        let worker = &w_m[&worker_id];
+        assert_eq!((worker.status.accepted, worker.status.rejected, worker.status.stale), (0, 0, 0));
+        assert!(worker.worker_shares.shares.is_empty());
+        assert_eq!(worker.worker_shares.difficulty, 8);
+    }
+
+    #[test]
+    fn log_level_changed() {
+        let state = test_state("s3cret");
+        let log_levels = state.log_levels.clone();
+        let port = start_test_api(state);
+        let auth = "Authorization: Bearer s3cret\r\n";
+
+        let set = |headers: &str, body: &str| request_with_body(port, "POST", "/api/v1/log-level", headers, body);
+        let (status, _) = set("", r#"{"module":"pool::worker","level":"debug"}"#);
+        assert_eq!(status, 403);
+        let (status, _) = set(auth, r#"{"module":"pool::worker","level":"loud"}"#);
+        assert_eq!(status, 400);
+        let (status, _) = set(auth, r#"{"level":"debug"}"#);
+        assert_eq!(status, 400);
+        let (status, _) = set(auth, "level=debug");
+        assert_eq!(status, 400);
+        // Not from the query string
+        let (status, _) = request(port, "POST", "/api/v1/log-level?module=pool::worker&level=debug", auth);
+        assert_eq!(status, 400);
+        assert!(log_levels.levels().is_empty());
+
+        let (status, changed) = set(auth, r#"{"module":"pool::worker","level":"debug"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(changed["ok"], true);
+        assert_eq!(changed["levels"]["pool::worker"], "debug");
+        assert_eq!(log_levels.levels()["pool::worker"], "debug");
+    }
+}
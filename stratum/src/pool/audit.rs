@@ -0,0 +1,300 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Share Audit Log
+//!
+//! One JSON object per line for every share decision, appended to its own
+//! file so shares can be audited or replayed apart from the pool log.  The
+//! pool fee taken for each found block is recorded here as well.
+//!
+//! Login attempts are written the same way to the auth audit log, which
+//! also counts the failed ones from each ip address so an address guessing
+//! at logins can be banned.
+//!
+
+use serde::Serialize;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pool::proto::SubmitParams;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub ts: u64, // Milliseconds since the unix epoch
+    pub worker: String,
+    pub height: u64,
+    pub nonce: u64,
+    pub edge_bits: u32,
+    pub difficulty: u64, // 0 if rejected before the difficulty was computed
+    pub result: String, // accepted, rejected, stale, or duplicate
+    pub reason: String, // Why it was not accepted, empty if it was
+}
+
+impl AuditEntry {
+    pub fn new(worker: String, share: &SubmitParams, difficulty: u64, result: &str, reason: &str) -> AuditEntry {
+        AuditEntry {
+            ts: now_millis(),
+            worker: worker,
+            height: share.height,
+            nonce: share.nonce,
+            edge_bits: share.edge_bits,
+            difficulty: difficulty,
+            result: result.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeEntry {
+    pub ts: u64, // Milliseconds since the unix epoch
+    pub height: u64,
+    pub hash: String,
+    pub result: String, // Always "fee"
+    pub fee_address: String,
+    pub fee: u64,    // Taken from the workers payouts
+    pub reward: u64, // The block reward the payouts were split from
+}
+
+impl FeeEntry {
+    pub fn new(height: u64, hash: String, fee_address: String, fee: u64, reward: u64) -> FeeEntry {
+        FeeEntry {
+            ts: now_millis(),
+            height: height,
+            hash: hash,
+            result: "fee".to_string(),
+            fee_address: fee_address,
+            fee: fee,
+            reward: reward,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthEntry {
+    pub ts: u64, // Milliseconds since the unix epoch
+    pub ip: String, // Empty for workers on the unix socket
+    pub login: String, // As the miner sent it
+    pub rig_id: String, // Empty if the login was not parsed
+    pub result: String, // success or failure
+    pub reason: String, // Why it failed, empty if it did not
+}
+
+impl AuthEntry {
+    pub fn new(ip: Option<IpAddr>, login: &str, rig_id: &str, result: &str, reason: &str) -> AuthEntry {
+        AuthEntry {
+            ts: now_millis(),
+            ip: ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            login: login.to_string(),
+            rig_id: rig_id.to_string(),
+            result: result.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+pub struct ShareAuditLog {
+    file: File,
+}
+
+impl ShareAuditLog {
+    /// Open the audit log for appending, creating it if needed
+    pub fn open(path: &str) -> Result<ShareAuditLog, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        Ok(ShareAuditLog { file: file })
+    }
+
+    /// Append an entry, flushed before returning
+    pub fn write<T: Serialize>(&mut self, entry: &T) -> Result<(), String> {
+        let mut line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        line += "\n";
+        self.file.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        self.file.flush().map_err(|e| e.to_string())
+    }
+}
+
+// Failed logins are counted per ip address over this long
+const AUTH_FAILURE_WINDOW_SECS: u64 = 60 * 60;
+// Expired failure counts are purged once this many addresses are tracked
+const AUTH_PURGE_THRESHOLD: usize = 10000;
+
+pub struct AuthAuditLog {
+    log: Option<ShareAuditLog>, // Where the entries are written, None only counts failures
+    max_failures: u32, // Failed logins allowed from an ip address per hour, 0 never bans
+    failures: HashMap<IpAddr, (u32, Instant)>, // Failed logins from each ip address, when its hour started
+}
+
+impl AuthAuditLog {
+    pub fn new(log: Option<ShareAuditLog>, max_failures: u32) -> AuthAuditLog {
+        AuthAuditLog {
+            log: log,
+            max_failures: max_failures,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Write a login attempt from ip, returns true if it was a failure that
+    /// takes the address over max_failures in the hour and should be banned
+    pub fn record(&mut self, ip: Option<IpAddr>, entry: &AuthEntry) -> bool {
+        if let Some(ref mut log) = self.log {
+            if let Err(e) = log.write(entry) {
+                error!("Failed to write to the auth audit log: {}", e);
+            }
+        }
+        let ip = match ip {
+            Some(ip) if entry.result == "failure" && self.max_failures > 0 => ip,
+            _ => return false,
+        };
+        if self.failures.len() >= AUTH_PURGE_THRESHOLD {
+            let window = Duration::from_secs(AUTH_FAILURE_WINDOW_SECS);
+            self.failures.retain(|_, &mut (_, start)| start.elapsed() < window);
+        }
+        let now = Instant::now();
+        let count = self.failures.entry(ip).or_insert((0, now));
+        if now.duration_since(count.1) >= Duration::from_secs(AUTH_FAILURE_WINDOW_SECS) {
+            *count = (0, now);
+        }
+        count.0 += 1;
+        if count.0 <= self.max_failures {
+            return false;
+        }
+        // Counted again from zero once the ban is over
+        self.failures.remove(&ip);
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader};
+    use std::{env, fs, process};
+
+    #[test]
+    fn entries_written_as_json_lines() {
+        let path = env::temp_dir().join(format!("grin-pool-audit-{}.log", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        let share = SubmitParams {
+            height: 100,
+            job_id: 1,
+            nonce: 42,
+            edge_bits: 31,
+            pow: vec![],
+            stale: false,
+        };
+        let results = [
+            ("accepted", "", 20),
+            ("rejected", "Below required difficulty", 2),
+            ("stale", "Solution submitted too late", 0),
+            ("duplicate", "Duplicate share", 0),
+        ];
+        {
+            let mut audit_log = ShareAuditLog::open(&path).unwrap();
+            for &(result, reason, difficulty) in results.iter() {
+                let entry = AuditEntry::new("7-abc".to_string(), &share, difficulty, result, reason);
+                audit_log.write(&entry).unwrap();
+            }
+        }
+        // Reopening appends
+        ShareAuditLog::open(&path)
+            .unwrap()
+            .write(&AuditEntry::new("7-abc".to_string(), &share, 20, "accepted", ""))
+            .unwrap();
+
+        let lines: Vec<Value> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[4]["result"], "accepted");
+        for (line, &(result, reason, difficulty)) in lines.iter().zip(results.iter()) {
+            assert!(line["ts"].as_u64().unwrap() > 0);
+            assert_eq!(line["worker"], "7-abc");
+            assert_eq!(line["height"], 100);
+            assert_eq!(line["nonce"], 42);
+            assert_eq!(line["edge_bits"], 31);
+            assert_eq!(line["difficulty"], difficulty);
+            assert_eq!(line["result"], result);
+            assert_eq!(line["reason"], reason);
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn sixth_failed_login_bans() {
+        let path = env::temp_dir().join(format!("grin-pool-audit-auth-{}.log", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        let mut auth_log = AuthAuditLog::new(Some(ShareAuditLog::open(&path).unwrap()), 5);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let failure = AuthEntry::new(Some(ip), "alice.rig1", "rig1", "failure", "Unknown user");
+        let success = AuthEntry::new(Some(ip), "alice.rig1", "rig1", "success", "");
+        assert!(!auth_log.record(Some(ip), &success));
+        let banned: Vec<bool> = (0..6).map(|_| auth_log.record(Some(ip), &failure)).collect();
+        assert_eq!(banned, vec![false, false, false, false, false, true]);
+        // Other addresses and the unix socket are counted apart
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(!auth_log.record(Some(other), &failure));
+        assert!((0..10).all(|_| !auth_log.record(None, &failure)));
+
+        let lines: Vec<Value> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 18);
+        assert_eq!(lines[0]["result"], "success");
+        assert_eq!(lines[1]["ip"], "10.0.0.1");
+        assert_eq!(lines[1]["login"], "alice.rig1");
+        assert_eq!(lines[1]["rig_id"], "rig1");
+        assert_eq!(lines[1]["result"], "failure");
+        assert_eq!(lines[1]["reason"], "Unknown user");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn fee_entry_written() {
+        let path = env::temp_dir().join(format!("grin-pool-audit-fee-{}.log", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        ShareAuditLog::open(&path)
+            .unwrap()
+            .write(&FeeEntry::new(100, "aaaa".to_string(), "pool".to_string(), 9, 900))
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(File::open(&path).unwrap()).read_line(&mut line).unwrap();
+        let entry: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["result"], "fee");
+        assert_eq!(entry["height"], 100);
+        assert_eq!(entry["fee_address"], "pool");
+        assert_eq!(entry["fee"], 9);
+        assert_eq!(entry["reward"], 900);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -13,12 +13,21 @@
 
 #[macro_use]
 use serde_derive;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-use std::env;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::{env, process};
+use log::LevelFilter;
 use toml;
 
-const CONFIG_FILE_NAME: &'static str = "grin-pool.toml";
+use pool::job::JobIdCodec;
+use pool::validator::PowAlgorithm;
+
+pub const CONFIG_FILE_NAME: &'static str = "grin-pool.toml";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -26,32 +35,584 @@ pub struct Config {
     pub grin_node: NodeConfig,
     pub workers: WorkerConfig,
     pub redis: RedisConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+/// A config value that must not end up in the logs, its Debug output is redacted
+#[derive(Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"<redacted>\"")
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PortDifficulty {
     pub port: u64,
     pub difficulty: u64,
+    #[serde(default)]
+    pub min_difficulty: Option<u64>, // Overrides workers.min_difficulty on this port
+    #[serde(default)]
+    pub max_difficulty: Option<u64>, // Overrides workers.max_difficulty on this port
+}
+
+// A single port_difficulty entry (the old config shape) or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortDifficultyList {
+    One(PortDifficulty),
+    Many(Vec<PortDifficulty>),
+}
+
+fn deserialize_port_difficulty<'de, D>(deserializer: D) -> Result<Vec<PortDifficulty>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match PortDifficultyList::deserialize(deserializer)? {
+        PortDifficultyList::One(port_difficulty) => Ok(vec![port_difficulty]),
+        PortDifficultyList::Many(port_difficulty) => Ok(port_difficulty),
+    }
+}
+
+// TOML table keys are always strings, parse them into edge_bits
+fn deserialize_edge_bits_table<'de, D>(deserializer: D) -> Result<HashMap<u8, u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let table: HashMap<String, u64> = HashMap::deserialize(deserializer)?;
+    let mut edge_bits_table = HashMap::new();
+    for (edge_bits, value) in table {
+        let edge_bits: u8 = edge_bits
+            .parse()
+            .map_err(|_| D::Error::custom(format!("Invalid edge_bits: {}", edge_bits)))?;
+        edge_bits_table.insert(edge_bits, value);
+    }
+    Ok(edge_bits_table)
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PoolConfig {
     pub log_dir: String,
+    #[serde(default = "default_log_format")]
+    pub log_format: String, // "text" or "json", the GRIN_POOL_LOG_FORMAT environment variable overrides it
+    #[serde(default)]
+    pub log_levels: HashMap<String, String>, // Log level of a module and its submodules, ex: "pool::worker" = "debug"
+    #[serde(default = "default_log_sample_rate")]
+    pub log_sample_rate: u64, // Log one in this many of the per job and per share diagnostics, 1 logs them all
+    #[serde(default = "default_max_tracked_duplicates")]
+    pub max_tracked_duplicates: usize, // Exactly tracked pows, older ones go to a bloom filter
+    #[serde(default = "default_duplicates_file")]
+    pub duplicates_file: String, // Where the submitted pows are saved between restarts
+    #[serde(default = "default_duplicate_reset_after_blocks")]
+    pub duplicate_reset_after_blocks: u64, // Forget submitted pows once the chain is this many blocks past them
+    #[serde(default)]
+    pub share_duplicates: bool, // Detect duplicate shares across all pools using the same redis
+    #[serde(default = "default_verify_shares")]
+    pub verify_shares: bool, // Verify the cuckoo cycle of every share - UNSAFE to turn off outside testnets and trusted miners
+    #[serde(default = "default_stale_grace_period_ms")]
+    pub stale_grace_period_ms: u64, // Shares for the previous height are still accepted this long after the height changes, 0 disables
+    #[serde(default = "default_difficulty_histogram_buckets")]
+    pub difficulty_histogram_buckets: Vec<u64>, // Upper bounds of the accepted share difficulty buckets, one more bucket holds the rest
+    #[serde(default = "default_difficulty_histogram_window_secs")]
+    pub difficulty_histogram_window_secs: u64, // Shares counted in the histogram, rounded down to whole minutes
+    #[serde(default = "default_pplns_window")]
+    pub pplns_window: usize, // Number of most recent shares used for PPLNS payouts
+    #[serde(default)]
+    pub pplns_window_secs: u64, // Also limit PPLNS to shares this recent, 0 disables
+    #[serde(default = "default_pplns_file")]
+    pub pplns_file: String, // Where the PPLNS window is saved between restarts
+    #[serde(default = "default_db_file")]
+    pub db_file: String, // SQLite database of share history, workers and found blocks
+    #[serde(default = "default_api_port")]
+    pub api_port: u64, // HTTP stats api port, 0 disables
+    #[serde(default = "default_admin_address")]
+    pub admin_address: String,
+    #[serde(default)]
+    pub admin_port: u64, // Admin control port, 0 disables
+    #[serde(default)]
+    pub admin_secret: Secret, // Shared secret admin commands must start with
+    #[serde(default)]
+    pub api_admin_token: Secret, // Bearer token for the apis POST endpoints, empty refuses them all
+    #[serde(default)]
+    pub block_found_webhook_url: Option<String>, // POSTed to when we submit a block
+    #[serde(default)]
+    pub allowed_edge_bits: Vec<u8>, // Accepted proof sizes, empty accepts C29 and C31 and up
+    #[serde(default, deserialize_with = "deserialize_edge_bits_table")]
+    pub allowed_edge_bits_after_height: HashMap<u8, u64>, // Proof sizes only accepted above a block height
+    #[serde(default)]
+    pub algorithm_policy: AlgorithmPolicy, // Which cuckoo cycle variants are accepted: any, cuckatoo_only, or cuckaroo_only
+    #[serde(default)]
+    pub share_audit_file: Option<String>, // Append a JSON line per share decision here
+    #[serde(default)]
+    pub auth_audit_log_path: Option<String>, // Append a JSON line per login attempt here
+    #[serde(default, alias = "pool_fee_percent", deserialize_with = "deserialize_fee_percent")]
+    pub fee_percent: f64, // Pool fee taken from each payout, 0 up to but not including 100
+    #[serde(default)]
+    pub fee_address: String, // Where the pool fee is paid
+    #[serde(default)]
+    pub job_refresh_interval_secs: u64, // Ask upstream for a newer template at the same height this often, 0 disables
+    #[serde(default = "default_min_job_broadcast_interval_ms")]
+    pub min_job_broadcast_interval_ms: u64, // Newer jobs at the same height are sent to workers at most this often, 0 sends them all
+    #[serde(default)]
+    pub upstream_min_difficulty: u64, // Unscaled difficulty a share needs to be submitted upstream, 0 uses the jobs difficulty
+    #[serde(default)]
+    pub target_submit_rate: f64, // Without vardiff, move every workers difficulty to submit this many shares a second upstream, 0 disables
+    #[serde(default = "default_difficulty_adjustment_interval_secs")]
+    pub difficulty_adjustment_interval_secs: u64, // How often the pool difficulty is moved towards target_submit_rate
+    #[serde(default = "default_job_version_ttl_secs")]
+    pub job_version_ttl_secs: u64, // Shares for a job version sent longer ago than this are rejected
+    #[serde(default = "default_max_job_versions")]
+    pub max_job_versions: usize, // Job versions kept per height, shares for evicted versions are rejected
+    #[serde(default)]
+    pub persist_job_versions: bool, // Keep job versions in redis so they survive a restart
+    #[serde(default)]
+    pub share_log_file: Option<String>, // Write a JSON line per share outcome here for accounting
+    #[serde(default = "default_share_log_max_bytes")]
+    pub share_log_max_bytes: u64, // Rotate the share log when it would grow past this
+    #[serde(default = "default_share_log_max_files")]
+    pub share_log_max_files: usize, // Rotated share logs kept
+    #[serde(default)]
+    pub stats_snapshot_path: Option<String>, // Write the stats of every worker to this JSON file now and then
+    #[serde(default = "default_stats_snapshot_interval_secs")]
+    pub stats_snapshot_interval_secs: u64, // How often the stats snapshot is written
+    #[serde(default = "default_stats_snapshot_keep_count")]
+    pub stats_snapshot_keep_count: usize, // Old stats snapshots kept
+    #[serde(default = "default_server_poll_interval_ms")]
+    pub server_poll_interval_ms: u64, // Longest the main loop waits for a socket event while jobs are arriving
+    #[serde(default = "default_server_poll_max_interval_ms")]
+    pub server_poll_max_interval_ms: u64, // The wait doubles up to this while no new job arrives
+    #[serde(default = "default_job_id_encoding")]
+    pub job_id_encoding: String, // How workers job_ids are built: height_prefix, raw, or uuid
+}
+
+fn deserialize_fee_percent<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fee_percent = f64::deserialize(deserializer)?;
+    if !(fee_percent >= 0.0 && fee_percent < 100.0) {
+        return Err(D::Error::custom(format!("fee_percent must be at least 0 and below 100, not {}", fee_percent)));
+    }
+    Ok(fee_percent)
+}
+
+/// The proof of work algorithms the pool accepts shares of
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlgorithmPolicy {
+    Any,
+    CuckatooOnly,
+    CuckarooOnly,
+}
+
+impl Default for AlgorithmPolicy {
+    fn default() -> AlgorithmPolicy {
+        AlgorithmPolicy::Any
+    }
+}
+
+impl AlgorithmPolicy {
+    pub fn allows(&self, algorithm: PowAlgorithm) -> bool {
+        match *self {
+            AlgorithmPolicy::Any => true,
+            AlgorithmPolicy::CuckatooOnly => algorithm == PowAlgorithm::Cuckatoo,
+            AlgorithmPolicy::CuckarooOnly => algorithm == PowAlgorithm::Cuckaroo,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Does the pool accept shares of this proof size at this height?
+    pub fn is_valid_edge_bits(&self, edge_bits: u32, height: u64) -> bool {
+        if edge_bits > u8::max_value() as u32 {
+            return false;
+        }
+        match PowAlgorithm::from_edge_bits(edge_bits) {
+            Some(algorithm) if self.algorithm_policy.allows(algorithm) => {}
+            _ => return false,
+        }
+        if let Some(after) = self.allowed_edge_bits_after_height.get(&(edge_bits as u8)) {
+            if height <= *after {
+                return false;
+            }
+        }
+        if self.allowed_edge_bits.is_empty() {
+            return edge_bits == 29 || edge_bits >= 31;
+        }
+        self.allowed_edge_bits.iter().any(|e| *e as u32 == edge_bits)
+    }
+}
+
+fn default_max_tracked_duplicates() -> usize {
+    100000
+}
+
+fn default_verify_shares() -> bool {
+    true
+}
+
+fn default_stale_grace_period_ms() -> u64 {
+    500
+}
+
+// From a single GPU at the minimum up to ASICs
+fn default_difficulty_histogram_buckets() -> Vec<u64> {
+    vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 1024, 4096, 16384, 65536]
+}
+
+fn default_difficulty_histogram_window_secs() -> u64 {
+    3600
+}
+
+fn default_duplicates_file() -> String {
+    "duplicates.bin".to_string()
+}
+
+fn default_duplicate_reset_after_blocks() -> u64 {
+    2
+}
+
+fn default_pplns_window() -> usize {
+    100000
+}
+
+fn default_pplns_file() -> String {
+    "pplns.bin".to_string()
+}
+
+fn default_db_file() -> String {
+    "grin-pool.db".to_string()
+}
+
+fn default_api_port() -> u64 {
+    3300
+}
+
+fn default_admin_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_job_version_ttl_secs() -> u64 {
+    300
+}
+
+fn default_min_job_broadcast_interval_ms() -> u64 {
+    100
+}
+
+fn default_difficulty_adjustment_interval_secs() -> u64 {
+    60
+}
+
+fn default_server_poll_interval_ms() -> u64 {
+    50
+}
+
+fn default_server_poll_max_interval_ms() -> u64 {
+    500
+}
+
+fn default_job_id_encoding() -> String {
+    "height_prefix".to_string()
+}
+
+fn default_log_sample_rate() -> u64 {
+    100
+}
+
+fn default_log_format() -> String {
+    "text".to_string()
+}
+
+fn default_max_job_versions() -> usize {
+    1000
+}
+
+fn default_share_log_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_share_log_max_files() -> usize {
+    10
+}
+
+fn default_stats_snapshot_interval_secs() -> u64 {
+    60
+}
+
+fn default_stats_snapshot_keep_count() -> usize {
+    5
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct WorkerConfig {
     pub listen_address: String,
-    pub port_difficulty: PortDifficulty,
+    #[serde(deserialize_with = "deserialize_port_difficulty")]
+    pub port_difficulty: Vec<PortDifficulty>, // One listener per port, each with its own starting difficulty
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64, // Drop workers silent for this long, 0 disables
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: u64, // Ping workers quiet for this long, 0 disables
+    #[serde(default = "default_keepalive_timeout_secs")]
+    pub keepalive_timeout_secs: u64, // Drop a pinged worker that sends nothing for this long
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize, // Total connected workers allowed
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize, // Connected workers allowed from a single ip address
+    #[serde(default = "default_max_conns_per_ip_per_min")]
+    pub max_conns_per_ip_per_min: u32, // New connections accepted from a single ip address per minute, 0 disables
+    #[serde(default)]
+    pub ban_connection_floods: bool, // Ban ip addresses that go over max_conns_per_ip_per_min
+    #[serde(default = "default_max_auth_failures_per_hour")]
+    pub max_auth_failures_per_hour: u32, // Failed logins from an ip address before it is banned, 0 disables
+    #[serde(default = "default_max_invalid_per_minute")]
+    pub max_invalid_per_minute: usize, // Invalid shares before a worker is banned, 0 disables
+    #[serde(default = "default_max_benign_stale_depth")]
+    pub max_benign_stale_depth: u64, // Shares this many heights back are honestly stale, older ones count as invalid
+    #[serde(default = "default_ban_duration_secs")]
+    pub ban_duration_secs: u64, // How long a banned ip address is refused
+    #[serde(default = "default_max_login_attempts_per_minute")]
+    pub max_login_attempts_per_minute: u32, // Login attempts allowed from a single ip address
+    #[serde(default = "default_max_shares_per_sec")]
+    pub max_shares_per_sec: u32, // Shares a worker may submit per second, past the burst
+    #[serde(default = "default_share_burst")]
+    pub share_burst: u32, // Shares a worker may submit at once before max_shares_per_sec applies
+    #[serde(default = "default_buffer_size")]
+    pub read_buffer_size: usize, // Per-worker stream read buffer in bytes
+    #[serde(default = "default_buffer_size")]
+    pub write_buffer_size: usize, // Per-worker stream write buffer in bytes
+    #[serde(default, deserialize_with = "deserialize_edge_bits_table")]
+    pub edge_bits_difficulty: HashMap<u8, u64>, // Minimum share difficulty per edge_bits
+    #[serde(default = "default_reconnect_grace_secs")]
+    pub reconnect_grace_secs: u64, // Keep a disconnected workers stats this long for its next login, 0 disables
+    #[serde(default = "default_outbound_queue_depth")]
+    pub outbound_queue_depth: usize, // Messages queued for a slow worker before the oldest is dropped
+    #[serde(default = "default_min_share_difficulty")]
+    pub min_share_difficulty: u64, // Lowest difficulty a miner may suggest
+    #[serde(default = "default_max_share_difficulty")]
+    pub max_share_difficulty: u64, // Highest difficulty a miner may suggest
+    #[serde(default = "default_login_delimiter")]
+    pub login_delimiter: String, // Separates the account, rig and worker names in a login
+    #[serde(default = "default_max_login_part_len")]
+    pub max_login_part_len: usize, // Longest account, rig or worker name accepted
+    #[serde(default = "default_login_tag_delimiter")]
+    pub login_tag_delimiter: String, // Anything after it in a login is a free-form tag for the stats, empty disables
+    #[serde(default)]
+    pub enable_websocket: bool, // Also accept WebSocket connections on the worker ports
+    #[serde(default)]
+    pub proxy_protocol: bool, // Connections come through a load balancer and start with a PROXY header giving the miners address
+    #[serde(default = "default_min_difficulty")]
+    pub min_difficulty: u64, // Lowest difficulty a worker is ever set to, shares below it are rejected unverified
+    #[serde(default = "default_max_difficulty")]
+    pub max_difficulty: u64, // Highest difficulty vardiff sets
+    #[serde(default)]
+    pub vardiff_target_share_secs: u64, // Retarget worker difficulty for a share this often, 0 disables vardiff
+    #[serde(default = "default_vardiff_retarget_secs")]
+    pub vardiff_retarget_secs: u64, // How often worker difficulty is retargeted
+    #[serde(default)]
+    pub unix_socket_path: Option<String>, // Also accept workers, like a local proxy, on this unix socket
+    #[serde(default = "default_unix_socket_difficulty")]
+    pub unix_socket_difficulty: u64, // Starting difficulty for workers on the unix socket
+    #[serde(default)]
+    pub nonce_range_bits: u32, // Give each worker a range of 2^bits nonces per job and count shares outside it, 0 disables
+    #[serde(default)]
+    pub enforce_nonce_range: bool, // Reject shares outside the range too, instead of only counting them
+    #[serde(default)]
+    pub extranonce1_bytes: usize, // Top bytes of the nonce given to each subscribed worker as its extranonce1, 0 disables
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64, // Longest a single read from a worker socket may block
+    #[serde(default = "default_max_consecutive_timeouts")]
+    pub max_consecutive_timeouts: u32, // Reads in a row that time out before a worker is dropped, 0 disables
+}
+
+impl WorkerConfig {
+    /// Lowest and highest difficulty for workers on this port
+    pub fn difficulty_bounds(&self, port: u64) -> (u64, u64) {
+        let mut bounds = (self.min_difficulty, self.max_difficulty);
+        if let Some(pd) = self.port_difficulty.iter().find(|pd| pd.port == port) {
+            bounds.0 = pd.min_difficulty.unwrap_or(bounds.0);
+            bounds.1 = pd.max_difficulty.unwrap_or(bounds.1);
+        }
+        bounds
+    }
+
+    /// Minimum difficulty a share with these edge_bits must meet,
+    /// falling back to the worker difficulty for unlisted edge_bits
+    pub fn min_difficulty(&self, edge_bits: u32, worker_difficulty: u64) -> u64 {
+        if edge_bits > u8::max_value() as u32 {
+            return worker_difficulty;
+        }
+        match self.edge_bits_difficulty.get(&(edge_bits as u8)) {
+            Some(difficulty) => *difficulty,
+            None => worker_difficulty,
+        }
+    }
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    60
+}
+
+fn default_keepalive_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_connections() -> usize {
+    1000
+}
+
+fn default_max_connections_per_ip() -> usize {
+    10
+}
+
+fn default_max_conns_per_ip_per_min() -> u32 {
+    60
+}
+
+fn default_max_invalid_per_minute() -> usize {
+    100
+}
+
+fn default_max_benign_stale_depth() -> u64 {
+    2
+}
+
+fn default_max_auth_failures_per_hour() -> u32 {
+    5
+}
+
+fn default_ban_duration_secs() -> u64 {
+    3600
+}
+
+fn default_max_shares_per_sec() -> u32 {
+    10
+}
+
+fn default_share_burst() -> u32 {
+    100
+}
+
+fn default_max_login_attempts_per_minute() -> u32 {
+    5
+}
+
+fn default_buffer_size() -> usize {
+    8 * 1024
+}
+
+fn default_reconnect_grace_secs() -> u64 {
+    60
+}
+
+fn default_outbound_queue_depth() -> usize {
+    16
+}
+
+fn default_min_share_difficulty() -> u64 {
+    1
+}
+
+fn default_max_share_difficulty() -> u64 {
+    u64::max_value()
+}
+
+fn default_min_difficulty() -> u64 {
+    1
+}
+
+fn default_max_difficulty() -> u64 {
+    1 << 32
+}
+
+fn default_vardiff_retarget_secs() -> u64 {
+    60
+}
+
+fn default_unix_socket_difficulty() -> u64 {
+    1
+}
+
+fn default_read_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_consecutive_timeouts() -> u32 {
+    5
+}
+
+fn default_login_delimiter() -> String {
+    ".".to_string()
+}
+
+fn default_max_login_part_len() -> usize {
+    64
+}
+
+fn default_login_tag_delimiter() -> String {
+    "#".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct NodeConfig {
     pub address: String,
+    #[serde(default)]
+    pub failover_addresses: Vec<String>, // Tried in order when the primary node is unusable
+    #[serde(default = "default_reconnect_backoff_max_secs")]
+    pub reconnect_backoff_max_secs: u64, // Longest wait between upstream reconnect attempts
+    #[serde(default = "default_upstream_timeout_secs")]
+    pub upstream_timeout_secs: u64, // Reconnect when the node sends nothing for this long, 0 disables
+    #[serde(default = "default_batch_submits")]
+    pub batch_submits: bool, // Send the shares found in one main loop pass together, false submits each right away
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize, // Longest message accepted from the node, the connection is dropped on a longer one
     pub api_port: u64,
     pub stratum_port: u64,
     pub login: String,
-    pub password: String,
+    pub password: Secret,
+}
+
+fn default_reconnect_backoff_max_secs() -> u64 {
+    60
+}
+
+fn default_upstream_timeout_secs() -> u64 {
+    120
+}
+
+fn default_batch_submits() -> bool {
+    true
+}
+
+fn default_max_message_bytes() -> usize {
+    65536
+}
+
+impl NodeConfig {
+    /// All upstream node addresses in priority order
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addresses = vec![self.address.clone()];
+        addresses.extend(self.failover_addresses.iter().cloned());
+        addresses
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -60,20 +621,222 @@ pub struct RedisConfig {
     pub port: u64,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub influxdb_address: Option<String>, // host:port to send worker stats to over UDP, in InfluxDB line protocol
+    #[serde(default = "default_influxdb_batch_size")]
+    pub influxdb_batch_size: usize, // Lines sent together in one datagram
+}
+
+fn default_influxdb_batch_size() -> usize {
+    50
+}
+
+impl Default for MetricsConfig {
+    fn default() -> MetricsConfig {
+        MetricsConfig {
+            influxdb_address: None,
+            influxdb_batch_size: default_influxdb_batch_size(),
+        }
+    }
+}
+
+/// Every problem found in a config, each naming the field it is about
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.problems.join("; "))
+    }
+}
+
+// Ports are u16, 0 is only allowed where it means disabled or any port
+fn check_port(problems: &mut Vec<String>, field: &str, port: u64, allow_zero: bool) {
+    if port > u16::max_value() as u64 || (port == 0 && !allow_zero) {
+        problems.push(format!("{} must be between 1 and 65535, not {}", field, port));
+    }
+}
+
+fn check_address(problems: &mut Vec<String>, field: &str, address: &str) {
+    if address.trim().is_empty() {
+        problems.push(format!("{} can not be empty", field));
+    } else if address.parse::<IpAddr>().is_err() && (address, 0).to_socket_addrs().is_err() {
+        problems.push(format!("{} is not an ip address or a host name that resolves: {}", field, address));
+    }
+}
+
+impl Config {
+    /// Check values serde can not, reporting every problem found
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        check_address(&mut problems, "workers.listen_address", &self.workers.listen_address);
+        if self.workers.port_difficulty.is_empty() {
+            problems.push("workers.port_difficulty must list at least one port".to_string());
+        }
+        let mut ports = HashSet::new();
+        for pd in self.workers.port_difficulty.iter() {
+            // Port 0 binds any free port
+            check_port(&mut problems, "workers.port_difficulty port", pd.port, true);
+            if !ports.insert(pd.port) {
+                problems.push(format!("workers.port_difficulty lists port {} more than once", pd.port));
+            }
+            let (min, max) = self.workers.difficulty_bounds(pd.port);
+            if min == 0 || min > max {
+                problems.push(format!(
+                    "workers min_difficulty must be at least 1 and at most max_difficulty on port {}",
+                    pd.port
+                ));
+            } else if pd.difficulty < min || pd.difficulty > max {
+                problems.push(format!(
+                    "workers.port_difficulty difficulty {} on port {} must be between {} and {}",
+                    pd.difficulty, pd.port, min, max
+                ));
+            }
+        }
+        if self.workers.min_share_difficulty > self.workers.max_share_difficulty {
+            problems.push("workers.min_share_difficulty is above workers.max_share_difficulty".to_string());
+        }
+        if self.workers.edge_bits_difficulty.values().any(|d| *d == 0) {
+            problems.push("workers.edge_bits_difficulty difficulties must be at least 1".to_string());
+        }
+        if self.workers.max_shares_per_sec == 0 || self.workers.share_burst == 0 {
+            problems.push("workers.max_shares_per_sec and workers.share_burst must be at least 1".to_string());
+        }
+        if self.workers.keepalive_interval_secs > 0 && self.workers.keepalive_timeout_secs == 0 {
+            problems.push("workers.keepalive_timeout_secs must be at least 1 with keepalives on".to_string());
+        }
+        if self.workers.max_connections == 0 {
+            problems.push("workers.max_connections must be at least 1".to_string());
+        }
+        if self.workers.unix_socket_difficulty == 0 {
+            problems.push("workers.unix_socket_difficulty must be at least 1".to_string());
+        }
+        if self.workers.nonce_range_bits >= 64 {
+            problems.push("workers.nonce_range_bits must be below 64".to_string());
+        }
+        if self.workers.extranonce1_bytes > 4 {
+            problems.push("workers.extranonce1_bytes must be 4 or less".to_string());
+        } else if self.workers.extranonce1_bytes > 0
+            && self.workers.nonce_range_bits as usize > 64 - 8 * self.workers.extranonce1_bytes
+        {
+            problems.push("workers.nonce_range_bits must fit in the nonce bytes left by workers.extranonce1_bytes".to_string());
+        }
+        if self.workers.login_delimiter.is_empty() {
+            problems.push("workers.login_delimiter can not be empty".to_string());
+        }
+        if !self.workers.login_tag_delimiter.is_empty()
+            && self.workers.login_tag_delimiter == self.workers.login_delimiter
+        {
+            problems.push("workers.login_tag_delimiter must differ from workers.login_delimiter".to_string());
+        }
+
+        if self.grin_pool.fee_percent > 0.0 && self.grin_pool.fee_address.is_empty() {
+            problems.push("grin_pool.fee_address is needed to take a fee".to_string());
+        }
+        if !(self.grin_pool.target_submit_rate >= 0.0 && self.grin_pool.target_submit_rate.is_finite()) {
+            problems.push("grin_pool.target_submit_rate must be 0 or more".to_string());
+        } else if self.grin_pool.target_submit_rate > 0.0 {
+            if self.workers.vardiff_target_share_secs > 0 {
+                problems.push("grin_pool.target_submit_rate needs vardiff off (workers.vardiff_target_share_secs = 0)".to_string());
+            }
+            if self.grin_pool.difficulty_adjustment_interval_secs == 0 {
+                problems.push("grin_pool.difficulty_adjustment_interval_secs must be at least 1".to_string());
+            }
+        }
+        if self.grin_pool.log_format != "text" && self.grin_pool.log_format != "json" {
+            problems.push(format!("grin_pool.log_format must be text or json, not {}", self.grin_pool.log_format));
+        }
+        for (module, level) in self.grin_pool.log_levels.iter() {
+            if level.parse::<LevelFilter>().is_err() {
+                problems.push(format!("grin_pool.log_levels has an invalid level {} for {}", level, module));
+            }
+        }
+        if self.grin_pool.difficulty_histogram_buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            problems.push("grin_pool.difficulty_histogram_buckets must be in ascending order".to_string());
+        }
+        if let Err(e) = JobIdCodec::from_name(&self.grin_pool.job_id_encoding) {
+            problems.push(e);
+        }
+        if self.grin_pool.server_poll_interval_ms == 0
+            || self.grin_pool.server_poll_interval_ms > self.grin_pool.server_poll_max_interval_ms
+        {
+            problems.push(
+                "grin_pool.server_poll_interval_ms must be at least 1 and at most server_poll_max_interval_ms".to_string(),
+            );
+        }
+        check_port(&mut problems, "grin_pool.api_port", self.grin_pool.api_port, true);
+        check_port(&mut problems, "grin_pool.admin_port", self.grin_pool.admin_port, true);
+
+        if self.grin_node.address.trim().is_empty() {
+            problems.push("grin_node.address can not be empty".to_string());
+        }
+        if self.grin_node.failover_addresses.iter().any(|a| a.trim().is_empty()) {
+            problems.push("grin_node.failover_addresses can not contain an empty address".to_string());
+        }
+        check_port(&mut problems, "grin_node.api_port", self.grin_node.api_port, false);
+        check_port(&mut problems, "grin_node.stratum_port", self.grin_node.stratum_port, false);
+        if self.grin_node.max_message_bytes == 0 {
+            problems.push("grin_node.max_message_bytes must be at least 1".to_string());
+        }
+        if self.grin_node.login.is_empty() {
+            problems.push("grin_node.login can not be empty".to_string());
+        }
+
+        if self.redis.address.trim().is_empty() {
+            problems.push("redis.address can not be empty".to_string());
+        }
+        check_port(&mut problems, "redis.port", self.redis.port, false);
+
+        if let Some(ref address) = self.metrics.influxdb_address {
+            if address.to_socket_addrs().is_err() {
+                problems.push(format!("metrics.influxdb_address is not a host:port that resolves: {}", address));
+            }
+        }
+        if self.metrics.influxdb_batch_size == 0 {
+            problems.push("metrics.influxdb_batch_size must be at least 1".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError { problems: problems })
+        }
+    }
+}
 
+/// Load the config file, exiting with the problems found if it is unusable
 pub fn read_config() -> Config {
-    let mut config_file = File::open(CONFIG_FILE_NAME).expect("Config file not found");
+    match load_config(CONFIG_FILE_NAME) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Read and parse a config file, apply environment variable overrides, then validate it
+pub fn load_config(path: &str) -> Result<Config, String> {
+    let mut config_file = File::open(path).map_err(|e| format!("Config file {} not found: {}", path, e))?;
     let mut toml_str = String::new();
     config_file
         .read_to_string(&mut toml_str)
-        .expect("Failure while reading config file");
-    let mut config: Config = toml::from_str(&toml_str).unwrap();
+        .map_err(|e| format!("Failure while reading config file {}: {}", path, e))?;
+    let mut config: Config = toml::from_str(&toml_str).map_err(|e| format!("Invalid config file {}: {}", path, e))?;
 
     // Environment Variable Overrides
     match env::var("DIFFICULTY") {
         Ok(difficulty) => {
-            config.workers.port_difficulty.difficulty = difficulty.parse().unwrap() ;
-            println!("env difficulty: {:?}", config);
+            // Overrides the first (primary) port only, validate reports a config without one
+            if let Some(first) = config.workers.port_difficulty.first_mut() {
+                first.difficulty = difficulty.parse().unwrap() ;
+                info!("env difficulty: {:?}", config);
+            }
 
         }
         Err(e) => {}
@@ -82,7 +845,7 @@ pub fn read_config() -> Config {
     match env::var("GRIN_ADDRESS") {
         Ok(address) => {
             config.grin_node.address = address;
-            println!("env address: {:?}", config);
+            info!("env address: {:?}", config);
         }
         Err(e) => {}
     }
@@ -90,10 +853,203 @@ pub fn read_config() -> Config {
     match env::var("REDIS_PORT") {
         Ok(port) => {
             config.redis.port = port.parse::<u64>().unwrap();
-            println!("env redis: {:?}", config.redis);
+            info!("env redis: {:?}", config.redis);
         }
         Err(e) => {}
     }
 
-    return config.clone();
+    // After the overrides, they can be wrong too
+    config
+        .validate()
+        .map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORKERS: &'static str = r#"
+        listen_address = "0.0.0.0"
+    "#;
+
+    #[test]
+    fn single_port_difficulty() {
+        let toml_str = format!("{}port_difficulty = [3333, 8]", WORKERS);
+        let workers: WorkerConfig = toml::from_str(&toml_str).unwrap();
+        assert_eq!(workers.port_difficulty.len(), 1);
+        assert_eq!(workers.port_difficulty[0].port, 3333);
+        assert_eq!(workers.port_difficulty[0].difficulty, 8);
+    }
+
+    #[test]
+    fn multiple_port_difficulty() {
+        let toml_str = format!("{}port_difficulty = [[3333, 1], [4444, 5]]", WORKERS);
+        let workers: WorkerConfig = toml::from_str(&toml_str).unwrap();
+        assert_eq!(workers.port_difficulty.len(), 2);
+        assert_eq!(workers.port_difficulty[1].port, 4444);
+        assert_eq!(workers.port_difficulty[1].difficulty, 5);
+    }
+
+    #[test]
+    fn allowed_edge_bits() {
+        let pool: PoolConfig = toml::from_str("log_dir = \"/tmp\"").unwrap();
+        assert!(!pool.is_valid_edge_bits(28, 1));
+        assert!(pool.is_valid_edge_bits(29, 1));
+        assert!(!pool.is_valid_edge_bits(30, 1));
+        assert!(pool.is_valid_edge_bits(31, 1));
+        assert!(pool.is_valid_edge_bits(32, 1));
+
+        let pool: PoolConfig = toml::from_str("log_dir = \"/tmp\"\nallowed_edge_bits = [32, 33]").unwrap();
+        assert!(!pool.is_valid_edge_bits(29, 1));
+        assert!(!pool.is_valid_edge_bits(31, 1));
+        assert!(pool.is_valid_edge_bits(32, 1));
+        assert!(pool.is_valid_edge_bits(33, 1));
+        assert!(!pool.is_valid_edge_bits(32 + 256, 1));
+    }
+
+    #[test]
+    fn algorithm_policy() {
+        for (policy, c29, c31) in [("any", true, true), ("cuckatoo_only", false, true), ("cuckaroo_only", true, false)].iter() {
+            let pool: PoolConfig =
+                toml::from_str(&format!("log_dir = \"/tmp\"\nalgorithm_policy = \"{}\"", policy)).unwrap();
+            assert_eq!(pool.is_valid_edge_bits(29, 1), *c29, "{}", policy);
+            assert_eq!(pool.is_valid_edge_bits(31, 1), *c31, "{}", policy);
+            assert_eq!(pool.is_valid_edge_bits(32, 1), *c31, "{}", policy);
+        }
+        assert!(toml::from_str::<PoolConfig>("log_dir = \"/tmp\"\nalgorithm_policy = \"cuckoo\"").is_err());
+    }
+
+    #[test]
+    fn edge_bits_after_height() {
+        let pool: PoolConfig = toml::from_str(
+            "log_dir = \"/tmp\"\nallowed_edge_bits = [29, 31]\nallowed_edge_bits_after_height = { 31 = 500000 }",
+        ).unwrap();
+        assert!(pool.is_valid_edge_bits(29, 500000));
+        assert!(!pool.is_valid_edge_bits(31, 499999));
+        assert!(!pool.is_valid_edge_bits(31, 500000));
+        assert!(pool.is_valid_edge_bits(31, 500001));
+        // Still has to be in the allowed list
+        assert!(!pool.is_valid_edge_bits(32, 500001));
+    }
+
+    #[test]
+    fn fee_percent_range() {
+        let pool: PoolConfig = toml::from_str("log_dir = \"/tmp\"").unwrap();
+        assert_eq!(pool.fee_percent, 0.0);
+        let pool: PoolConfig = toml::from_str("log_dir = \"/tmp\"\nfee_percent = 1.5").unwrap();
+        assert_eq!(pool.fee_percent, 1.5);
+        assert!(toml::from_str::<PoolConfig>("log_dir = \"/tmp\"\nfee_percent = 100.0").is_err());
+        assert!(toml::from_str::<PoolConfig>("log_dir = \"/tmp\"\nfee_percent = -1.0").is_err());
+        let pool: PoolConfig = toml::from_str("log_dir = \"/tmp\"\npool_fee_percent = 2.0").unwrap();
+        assert_eq!(pool.fee_percent, 2.0);
+    }
+
+    #[test]
+    fn edge_bits_difficulty_table() {
+        let toml_str = format!(
+            "{}port_difficulty = [3333, 8]\n[edge_bits_difficulty]\n29 = 4\n31 = 64\n",
+            WORKERS
+        );
+        let workers: WorkerConfig = toml::from_str(&toml_str).unwrap();
+        assert_eq!(workers.min_difficulty(29, 8), 4);
+        assert_eq!(workers.min_difficulty(31, 8), 64);
+        assert_eq!(workers.min_difficulty(30, 8), 8);
+        // A C29 share meeting the C29 minimum is good even though it is
+        // below the C31 minimum
+        let share_difficulty = 10;
+        assert!(share_difficulty >= workers.min_difficulty(29, 8));
+        assert!(share_difficulty < workers.min_difficulty(31, 8));
+
+        let inline = format!(
+            "{}port_difficulty = [3333, 8]\nedge_bits_difficulty = {{ 29 = 4, 31 = 64 }}\n",
+            WORKERS
+        );
+        let workers: WorkerConfig = toml::from_str(&inline).unwrap();
+        assert_eq!(workers.edge_bits_difficulty.len(), 2);
+
+        let bad = format!("{}port_difficulty = [3333, 8]\n[edge_bits_difficulty]\nc29 = 4\n", WORKERS);
+        assert!(toml::from_str::<WorkerConfig>(&bad).is_err());
+    }
+
+    fn valid_config() -> Config {
+        toml::from_str(
+            r#"
+            [grin_pool]
+            log_dir = "/tmp"
+            [workers]
+            listen_address = "0.0.0.0"
+            port_difficulty = [3333, 8]
+            [redis]
+            address = "127.0.0.1"
+            port = 6379
+            [grin_node]
+            address = "127.0.0.1"
+            api_port = 13413
+            stratum_port = 13416
+            login = "GrinPool"
+            password = ""
+            "#,
+        ).unwrap()
+    }
+
+    #[test]
+    fn valid_config_passes() {
+        assert_eq!(valid_config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn secrets_redacted() {
+        let mut config = valid_config();
+        assert_eq!(format!("{:?}", config.grin_pool.admin_secret), "\"\"");
+        config.grin_pool.admin_secret = Secret("admin-hunter2".to_string());
+        config.grin_pool.api_admin_token = Secret("token-hunter2".to_string());
+        config.grin_node.password = Secret("node-hunter2".to_string());
+        let logged = format!("{:?}", config);
+        assert!(!logged.contains("hunter2"));
+        assert!(logged.contains("<redacted>"));
+        assert_eq!(config.grin_pool.admin_secret.expose(), "admin-hunter2");
+    }
+
+    #[test]
+    fn every_problem_reported() {
+        let mut config = valid_config();
+        config.workers.listen_address = "".to_string();
+        config.grin_node.stratum_port = 0;
+        config.grin_node.address = " ".to_string();
+        config.redis.port = 70000;
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("workers.listen_address"));
+        assert!(problems.iter().any(|p| p.starts_with("grin_node.stratum_port")));
+        assert!(problems.iter().any(|p| p.starts_with("grin_node.address")));
+        assert!(problems.iter().any(|p| p.starts_with("redis.port")));
+    }
+
+    #[test]
+    fn invalid_worker_ports_and_difficulties() {
+        let mut config = valid_config();
+        config.workers.port_difficulty.push(PortDifficulty {
+            port: 3333,
+            difficulty: 8,
+            min_difficulty: None,
+            max_difficulty: None,
+        });
+        assert!(config.validate().unwrap_err().problems[0].contains("port 3333 more than once"));
+
+        let mut config = valid_config();
+        config.workers.port_difficulty[0].port = 65536;
+        assert_eq!(config.validate().unwrap_err().problems.len(), 1);
+
+        // Below the lowest difficulty vardiff enforces on the port
+        let mut config = valid_config();
+        config.workers.port_difficulty[0].min_difficulty = Some(16);
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(problems, vec!["workers.port_difficulty difficulty 8 on port 3333 must be between 16 and 4294967296"]);
+
+        let mut config = valid_config();
+        config.workers.listen_address = "not an address".to_string();
+        config.grin_pool.job_id_encoding = "height*1000".to_string();
+        assert_eq!(config.validate().unwrap_err().problems.len(), 2);
+    }
 }
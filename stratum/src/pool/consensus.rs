@@ -143,7 +143,7 @@ impl Proof {
 	}
 
 	/// Hash, as in Grin
-	fn hash(&self) -> Hash {
+	pub fn hash(&self) -> Hash {
 		let nonce_bits = self.edge_bits as usize;
 		let mut bitvec = BitVec::new(nonce_bits * PROOF_SIZE);
 		for (n, nonce) in self.nonces.iter().enumerate() {
@@ -0,0 +1,210 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool Database
+//!
+//! SQLite persistence for share history, worker logins and found blocks.
+//!
+
+use rusqlite::types::ToSql;
+use rusqlite::{Connection, Error, NO_PARAMS};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Schema migrations, applied in order.  Never edit one that has shipped,
+// add a new one to the end instead.
+const MIGRATIONS: &'static [&'static str] = &[
+    "CREATE TABLE shares (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        worker_id TEXT NOT NULL,
+        height INTEGER NOT NULL,
+        nonce INTEGER NOT NULL,
+        edge_bits INTEGER NOT NULL,
+        pow_hash TEXT NOT NULL,
+        accepted_at INTEGER NOT NULL,
+        result TEXT NOT NULL
+    );
+    CREATE INDEX shares_height ON shares (height);
+    CREATE TABLE workers (
+        id TEXT PRIMARY KEY,
+        login TEXT NOT NULL,
+        rig_id TEXT NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE blocks (
+        height INTEGER PRIMARY KEY,
+        hash TEXT NOT NULL,
+        found_by TEXT NOT NULL,
+        found_at INTEGER NOT NULL
+    );",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockRecord {
+    pub height: u64,
+    pub hash: String,
+    pub found_by: String,
+    pub found_at: u64,
+}
+
+/// Open (creating if needed) the pool database and bring its schema up to date
+pub fn open(path: &str) -> Result<Connection, Error> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+/// Open a migrated database that only lives in memory
+pub fn open_in_memory() -> Result<Connection, Error> {
+    let conn = Connection::open_in_memory()?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+/// Apply any migrations newer than the recorded schema_version
+pub fn migrate(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);")?;
+    let version: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        debug!("Applying database migration {}", i + 1);
+        conn.execute_batch(&format!("BEGIN; {} COMMIT;", migration))?;
+        conn.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            &[&((i + 1) as i64) as &ToSql],
+        )?;
+    }
+    Ok(())
+}
+
+/// Record a share submission and its result
+pub fn insert_share(
+    conn: &Connection,
+    worker_id: &str,
+    height: u64,
+    nonce: u64,
+    edge_bits: u32,
+    pow_hash: &str,
+    result: &str,
+) -> Result<(), Error> {
+    let now = now();
+    conn.execute(
+        "INSERT INTO shares (worker_id, height, nonce, edge_bits, pow_hash, accepted_at, result)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        &[
+            &worker_id as &ToSql,
+            &(height as i64),
+            &(nonce as i64), // stored as the same 64 bits, sqlite has no unsigned type
+            &(edge_bits as i64),
+            &pow_hash,
+            &now,
+            &result,
+        ],
+    )?;
+    conn.execute(
+        "UPDATE workers SET last_seen = ?2 WHERE id = ?1",
+        &[&worker_id as &ToSql, &now],
+    )?;
+    Ok(())
+}
+
+/// Record a worker login
+pub fn upsert_worker(conn: &Connection, id: &str, login: &str, rig_id: &str) -> Result<(), Error> {
+    let now = now();
+    conn.execute(
+        "INSERT INTO workers (id, login, rig_id, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?4)
+         ON CONFLICT(id) DO UPDATE SET login = ?2, rig_id = ?3, last_seen = ?4",
+        &[&id as &ToSql, &login, &rig_id, &now],
+    )?;
+    Ok(())
+}
+
+/// Record a block found by the pool
+pub fn upsert_block(conn: &Connection, height: u64, hash: &str, found_by: &str) -> Result<(), Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO blocks (height, hash, found_by, found_at) VALUES (?1, ?2, ?3, ?4)",
+        &[&(height as i64) as &ToSql, &hash, &found_by, &now()],
+    )?;
+    Ok(())
+}
+
+/// The most recently found blocks, newest first
+pub fn recent_blocks(conn: &Connection, limit: u32) -> Result<Vec<BlockRecord>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT height, hash, found_by, found_at FROM blocks ORDER BY height DESC LIMIT ?1",
+    )?;
+    let rows = stmt.query_map(&[&(limit as i64) as &ToSql], |row| {
+        let height: i64 = row.get(0);
+        let found_at: i64 = row.get(3);
+        BlockRecord {
+            height: height as u64,
+            hash: row.get(1),
+            found_by: row.get(2),
+            found_at: found_at as u64,
+        }
+    })?;
+    rows.collect()
+}
+
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let conn = open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        let version: i64 = conn
+            .query_row("SELECT MAX(version) FROM schema_version", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn shares_workers_and_blocks() {
+        let conn = open_in_memory().unwrap();
+        upsert_worker(&conn, "7-abc", "user.rig1", "rig1").unwrap();
+        upsert_worker(&conn, "7-abc", "user.rig2", "rig2").unwrap();
+        insert_share(&conn, "7-abc", 100, std::u64::MAX, 31, "5fa5af8a4c86", "accepted").unwrap();
+        let (count, nonce): (i64, i64) = conn
+            .query_row("SELECT COUNT(*), MAX(nonce) FROM shares", NO_PARAMS, |row| {
+                (row.get(0), row.get(1))
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(nonce as u64, std::u64::MAX);
+        let rig_id: String = conn
+            .query_row("SELECT rig_id FROM workers WHERE id = '7-abc'", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(rig_id, "rig2");
+
+        upsert_block(&conn, 100, "aaaa", "7-abc").unwrap();
+        upsert_block(&conn, 100, "bbbb", "7-abc").unwrap();
+        upsert_block(&conn, 101, "cccc", "8-def").unwrap();
+        let blocks = recent_blocks(&conn, 10).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].height, 101);
+        assert_eq!(blocks[1].hash, "bbbb");
+    }
+}
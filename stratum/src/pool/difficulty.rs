@@ -0,0 +1,144 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool Difficulty Controller
+//!
+//! Without vardiff every worker mines at the same difficulty.  The controller
+//! counts the shares the pool submitted upstream over the last minute and
+//! moves that difficulty so the pool submits about the target rate: twice
+//! the target rate doubles the difficulty, half of it halves the difficulty.
+//!
+//! One adjustment changes the difficulty by at most MAX_STEP times, so a
+//! quiet minute does not drop it to the minimum.
+//!
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const WINDOW_SECS: u64 = 60;
+const MAX_STEP: f64 = 4.0;
+
+pub struct PoolDifficultyController {
+    target_rate: f64, // Upstream submissions per second
+    difficulty: u64, // Given to every worker
+    submissions: VecDeque<Instant>, // When each share in the window was submitted
+    started: Instant, // Until a window has passed the rate is over the time so far
+}
+
+impl PoolDifficultyController {
+    pub fn new(target_rate: f64, difficulty: u64) -> PoolDifficultyController {
+        PoolDifficultyController {
+            target_rate: target_rate,
+            difficulty: difficulty,
+            submissions: VecDeque::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn difficulty(&self) -> u64 {
+        self.difficulty
+    }
+
+    /// Count a share submitted upstream
+    pub fn record_submission(&mut self) {
+        self.submissions.push_back(Instant::now());
+        self.expire();
+    }
+
+    /// Upstream submissions per second over the window
+    pub fn rate(&mut self) -> f64 {
+        self.expire();
+        let window = self.started.elapsed().min(Duration::from_secs(WINDOW_SECS));
+        let secs = window.as_secs() as f64 + window.subsec_nanos() as f64 / 1e9;
+        if secs < 1.0 {
+            return self.target_rate;
+        }
+        self.submissions.len() as f64 / secs
+    }
+
+    /// Move the difficulty towards the target rate from the rate over the
+    /// window, keeping it between min and max.  Returns the new difficulty
+    pub fn adjust(&mut self, min: u64, max: u64) -> u64 {
+        let rate = self.rate();
+        self.adjust_for_rate(rate, min, max)
+    }
+
+    /// Move the difficulty towards the target rate from a measured rate
+    pub fn adjust_for_rate(&mut self, rate: f64, min: u64, max: u64) -> u64 {
+        if self.target_rate <= 0.0 {
+            return self.difficulty;
+        }
+        let step = (rate / self.target_rate).max(1.0 / MAX_STEP).min(MAX_STEP);
+        let difficulty = (self.difficulty as f64 * step).round() as u64;
+        self.difficulty = difficulty.max(min).min(max);
+        self.difficulty
+    }
+
+    fn expire(&mut self) {
+        let window = Duration::from_secs(WINDOW_SECS);
+        while self.submissions.front().map_or(false, |submitted| submitted.elapsed() >= window) {
+            self.submissions.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shares a second a pool with this hashrate submits at a difficulty
+    fn rate_at(hashrate: f64, difficulty: u64) -> f64 {
+        hashrate / difficulty as f64
+    }
+
+    #[test]
+    fn converges_within_five_steps() {
+        // Starting far too low, far too high, and near the target
+        for &(hashrate, start) in [(64000.0, 1000), (50.0, 4096), (1000.0, 80)].iter() {
+            let mut controller = PoolDifficultyController::new(1.0, start);
+            for _ in 0..5 {
+                let rate = rate_at(hashrate, controller.difficulty());
+                controller.adjust_for_rate(rate, 1, 1 << 32);
+            }
+            let rate = rate_at(hashrate, controller.difficulty());
+            assert!((rate - 1.0).abs() < 0.05, "rate {} from difficulty {}", rate, start);
+        }
+    }
+
+    #[test]
+    fn difficulty_kept_in_bounds() {
+        let mut controller = PoolDifficultyController::new(1.0, 10);
+        assert_eq!(controller.adjust_for_rate(0.0, 4, 100), 4);
+        assert_eq!(controller.adjust_for_rate(0.0, 4, 100), 4);
+        assert_eq!(controller.adjust_for_rate(1000.0, 4, 100), 16);
+        assert_eq!(controller.adjust_for_rate(1000.0, 4, 100), 64);
+        assert_eq!(controller.adjust_for_rate(1000.0, 4, 100), 100);
+    }
+
+    #[test]
+    fn submissions_counted_over_the_window() {
+        let mut controller = PoolDifficultyController::new(0.5, 10);
+        // As if the pool started two minutes ago
+        controller.started = Instant::now() - Duration::from_secs(2 * WINDOW_SECS);
+        for _ in 0..60 {
+            controller.record_submission();
+        }
+        assert!((controller.rate() - 1.0).abs() < 0.01);
+        // Submissions older than the window no longer count
+        for submitted in controller.submissions.iter_mut().take(30) {
+            *submitted = Instant::now() - Duration::from_secs(WINDOW_SECS + 1);
+        }
+        assert!((controller.rate() - 0.5).abs() < 0.01);
+        assert_eq!(controller.adjust(1, 100), 10);
+    }
+}
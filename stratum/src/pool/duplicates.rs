@@ -0,0 +1,593 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Duplicate Share Detection
+//!
+//! Remembers every (pow, height, job_id) submitted recently.  A pow is only
+//! valid against the pre_pow of the job version it was mined on, so the same
+//! pow sent for another job version is not a duplicate - it will fail
+//! validation instead.
+//!
+//! The most recent shares are tracked exactly by fingerprint with the worker
+//! who sent them.  Once they are too old to be tracked exactly, only the ones
+//! that were accepted are worth remembering - any other share sent again is
+//! rejected again when it is checked.  Those spill over into a bloom filter
+//! and a set of their fingerprints.  The filter answers most lookups, a hit
+//! in it is confirmed in the set before the share is rejected, so a false
+//! positive never rejects a valid share.  The set only grows with accepted
+//! shares, and only until the next generation.
+//!
+//! Every few blocks the tracker starts a new generation.  The one before is
+//! kept until the grace period for shares of the previous height is over,
+//! so such a share can not be accepted twice when the generations turn over.
+//!
+//! The tracker is saved to disk now and then and on shutdown, and loaded on
+//! startup, so a restart does not let a miner send the shares of the current
+//! block again.  It is written to a temporary file first and renamed over the
+//! old one, a crash while saving leaves the last save whole.
+//! Fingerprints are blake2b hashes, they stay the same across builds.
+//!
+//! Pools running side by side behind a load balancer can share what they
+//! have seen through Redis.  Each new pow is claimed there with SET NX, the
+//! pool that loses the race rejects it.  Claims expire after a couple of
+//! minutes, by then the share is stale anyway.  The claims of a batch of
+//! shares go out in one pipeline, after the workers are unlocked.  While
+//! Redis is down the pool goes on with only what it has seen itself, and
+//! reconnects now and then, backing off while it stays down.
+//!
+
+use bincode;
+use blake2::blake2b::Blake2b;
+use byteorder::{ByteOrder, LittleEndian};
+use redis::{self, Connection};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::mem;
+use std::time::{Duration, Instant};
+
+const BLOOM_BITS: u64 = 1 << 23; // 1MB of filter
+const BLOOM_HASHES: u64 = 4;
+const REDIS_KEY_PREFIX: &str = "duplicate";
+const REDIS_TTL_SECS: u64 = 120;
+const REDIS_TIMEOUT_MS: u64 = 500; // For each claim, a slow Redis must not stall the pool
+const REDIS_MIN_BACKOFF_SECS: u64 = 1;
+const REDIS_MAX_BACKOFF_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize)]
+pub struct Duplicates {
+    #[serde(skip)]
+    max_tracked: usize,
+    since_height: u64,              // Height of the oldest shares the current generation may hold
+    current: Generation,            // pows seen since since_height
+    previous: Option<Generation>,   // pows seen before, kept until the grace period for them ends
+    #[serde(skip)]
+    redis_url: Option<String>,      // Shared with the other pools, None when only this one counts
+    #[serde(skip)]
+    redis: Option<Connection>,      // None while disconnected
+    #[serde(skip)]
+    redis_retry_at: Option<Instant>, // When to try connecting again
+    #[serde(skip)]
+    redis_backoff: Duration,        // Doubles each time connecting fails
+    #[serde(skip)]
+    unclaimed: Vec<(u64, usize)>,   // New fingerprints and who sent them, to claim in Redis
+    #[serde(skip)]
+    lost: HashSet<u64>,             // Fingerprints another pool claimed first in the last batch
+}
+
+// The pows seen over a few blocks
+#[derive(Serialize, Deserialize, Default)]
+struct Generation {
+    recent: HashMap<u64, usize>, // (pow, height, job_id) fingerprint, worker id who first submitted it
+    order: VecDeque<u64>,        // fingerprints in insertion order, oldest first
+    accepted: HashSet<u64>,      // fingerprints in recent of accepted shares
+    evicted: Vec<u64>,           // bloom filter of accepted fingerprints evicted from recent
+    spilled: HashSet<u64>,       // the same fingerprints, to confirm a bloom filter hit
+}
+
+impl Duplicates {
+    /// Create a duplicate tracker holding at most max_tracked exact entries
+    pub fn new(max_tracked: usize) -> Duplicates {
+        Duplicates {
+            max_tracked: max_tracked,
+            since_height: 0,
+            current: Generation::default(),
+            previous: None,
+            redis_url: None,
+            redis: None,
+            redis_retry_at: None,
+            redis_backoff: Duration::from_secs(REDIS_MIN_BACKOFF_SECS),
+            unclaimed: vec![],
+            lost: HashSet::new(),
+        }
+    }
+
+    /// Share the pows seen with other pools using the same Redis.
+    /// If it can not be reached now, claim will try again later.
+    pub fn connect_redis(&mut self, address: &str, port: u64) -> Result<(), String> {
+        self.redis_url = Some(format!("redis://{}:{}/", address, port));
+        self.reconnect()
+    }
+
+    // Connect to Redis, or schedule the next try
+    fn reconnect(&mut self) -> Result<(), String> {
+        let redis_url = match self.redis_url {
+            Some(ref redis_url) => redis_url.clone(),
+            None => return Ok(()),
+        };
+        let timeout = Some(Duration::from_millis(REDIS_TIMEOUT_MS));
+        let connected = redis::Client::open(redis_url.as_str())
+            .and_then(|client| client.get_connection())
+            .and_then(|con| {
+                con.set_read_timeout(timeout)?;
+                con.set_write_timeout(timeout)?;
+                Ok(con)
+            });
+        match connected {
+            Ok(con) => {
+                self.redis = Some(con);
+                self.redis_retry_at = None;
+                self.redis_backoff = Duration::from_secs(REDIS_MIN_BACKOFF_SECS);
+                Ok(())
+            }
+            Err(e) => {
+                self.disconnect();
+                Err(format!("Failed to connect to REDIS at {}: {:?}", redis_url, e))
+            }
+        }
+    }
+
+    // Go on without Redis until the backoff is over
+    fn disconnect(&mut self) {
+        self.redis = None;
+        self.redis_retry_at = Some(Instant::now() + self.redis_backoff);
+        self.redis_backoff = (self.redis_backoff * 2).min(Duration::from_secs(REDIS_MAX_BACKOFF_SECS));
+    }
+
+    /// Load a saved tracker, or start an empty one if there is none
+    pub fn load(path: &str, max_tracked: usize) -> Duplicates {
+        let mut duplicates: Duplicates = match File::open(path) {
+            Ok(file) => match bincode::deserialize_from(BufReader::new(file)) {
+                Ok(duplicates) => duplicates,
+                Err(e) => {
+                    error!("Failed to read duplicate shares from {}: {:?}", path, e);
+                    Duplicates::new(max_tracked)
+                }
+            },
+            Err(e) => {
+                warn!("No saved duplicate shares at {}: {}", path, e);
+                Duplicates::new(max_tracked)
+            }
+        };
+        duplicates.max_tracked = max_tracked;
+        duplicates.trim();
+        duplicates
+    }
+
+    /// Save the tracker so it survives a restart
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+            let mut writer = BufWriter::new(file);
+            bincode::serialize_into(&mut writer, self).map_err(|e| e.to_string())?;
+            let file = writer.into_inner().map_err(|e| e.to_string())?;
+            file.sync_all().map_err(|e| e.to_string())?;
+        }
+        fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+    }
+
+    /// Has this pow been seen for this job?
+    pub fn contains(&self, pow: &Vec<u64>, height: u64, job_id: u64) -> bool {
+        let fp = fingerprint(pow, height, job_id);
+        self.current.contains(fp) || self.previous.as_ref().map_or(false, |previous| previous.contains(fp))
+    }
+
+    /// Remember a pow for a job and the user who first submitted it
+    pub fn insert(&mut self, pow: &Vec<u64>, height: u64, job_id: u64, user_id: usize) {
+        let fp = fingerprint(pow, height, job_id);
+        self.current.insert(fp, user_id);
+        self.trim();
+    }
+
+    /// Remember a pow for a job, returns false if this pool has seen it
+    /// already.  Pools sharing its Redis are asked by the next claim.
+    pub fn insert_new(&mut self, pow: &Vec<u64>, height: u64, job_id: u64, user_id: usize) -> bool {
+        if self.contains(pow, height, job_id) {
+            return false;
+        }
+        self.insert(pow, height, job_id, user_id);
+        if self.redis_url.is_some() {
+            self.unclaimed.push((fingerprint(pow, height, job_id), user_id));
+        }
+        true
+    }
+
+    /// Mark a pow as accepted, it is remembered after it is too old to be
+    /// tracked exactly
+    pub fn accepted(&mut self, pow: &Vec<u64>, height: u64, job_id: u64) {
+        let fp = fingerprint(pow, height, job_id);
+        self.current.accept(fp);
+    }
+
+    /// Claim the pows inserted since the last claim in Redis, in one round
+    /// trip.  Those another pool claimed first are claimed_elsewhere until
+    /// the next claim.
+    pub fn claim(&mut self) {
+        self.lost.clear();
+        let unclaimed = mem::replace(&mut self.unclaimed, vec![]);
+        if unclaimed.is_empty() {
+            return;
+        }
+        if self.redis.is_none() {
+            match self.redis_retry_at {
+                Some(retry_at) if Instant::now() >= retry_at => {
+                    if let Err(e) = self.reconnect() {
+                        warn!("{} - retrying in {:?}", e, self.redis_backoff);
+                        return;
+                    }
+                    warn!("Reconnected to REDIS, duplicates are detected across pools again");
+                }
+                _ => return,
+            }
+        }
+        let claimed = match self.redis {
+            Some(ref mut redis) => {
+                let mut pipe = redis::pipe();
+                for &(fp, user_id) in unclaimed.iter() {
+                    pipe.cmd("SET")
+                        .arg(format!("{}:{:016x}", REDIS_KEY_PREFIX, fp))
+                        .arg(user_id)
+                        .arg("NX")
+                        .arg("EX")
+                        .arg(REDIS_TTL_SECS);
+                }
+                pipe.query::<Vec<Option<String>>>(redis)
+            }
+            None => return,
+        };
+        match claimed {
+            Ok(claimed) => {
+                for (&(fp, _), claimed) in unclaimed.iter().zip(claimed.iter()) {
+                    if claimed.is_none() {
+                        self.lost.insert(fp);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Failed to check shares in REDIS, duplicates are only detected by this pool until it reconnects: {:?}", e);
+                self.disconnect();
+            }
+        }
+    }
+
+    /// Did another pool claim this pow first in the last claim?
+    pub fn claimed_elsewhere(&self, pow: &Vec<u64>, height: u64, job_id: u64) -> bool {
+        !self.lost.is_empty() && self.lost.contains(&fingerprint(pow, height, job_id))
+    }
+
+    /// Start a new generation once the chain is more than reset_after_blocks
+    /// past the oldest height of the current one.  The previous generation
+    /// is kept until drop_previous, shares for the height before may still
+    /// be accepted for a moment after the chain moved on.
+    pub fn new_height(&mut self, height: u64, reset_after_blocks: u64) {
+        if height > self.since_height.saturating_add(reset_after_blocks) {
+            self.previous = Some(mem::replace(&mut self.current, Generation::default()));
+            self.since_height = height;
+        }
+    }
+
+    /// Forget the previous generation, once no share it holds can be accepted
+    pub fn drop_previous(&mut self) {
+        self.previous = None;
+    }
+
+    /// Forget everything
+    pub fn clear(&mut self) {
+        self.current = Generation::default();
+        self.previous = None;
+    }
+
+    /// Number of exactly tracked pows
+    pub fn len(&self) -> usize {
+        self.current.recent.len() + self.previous.as_ref().map_or(0, |previous| previous.recent.len())
+    }
+
+    /// Number of accepted pows only remembered in the bloom filter
+    pub fn spilled(&self) -> usize {
+        self.current.spilled.len() + self.previous.as_ref().map_or(0, |previous| previous.spilled.len())
+    }
+
+    // Move the oldest exact entries over to the bloom filter
+    fn trim(&mut self) {
+        self.current.trim(self.max_tracked);
+    }
+}
+
+impl Generation {
+    fn contains(&self, fp: u64) -> bool {
+        if self.recent.contains_key(&fp) {
+            return true;
+        }
+        if self.evicted.is_empty() {
+            return false;
+        }
+        let in_filter = bloom_indexes(fp).iter().all(|i| self.evicted[(i / 64) as usize] & (1 << (i % 64)) != 0);
+        return in_filter && self.spilled.contains(&fp);
+    }
+
+    fn insert(&mut self, fp: u64, user_id: usize) {
+        if self.recent.insert(fp, user_id).is_none() {
+            self.order.push_back(fp);
+        }
+    }
+
+    fn accept(&mut self, fp: u64) {
+        if self.recent.contains_key(&fp) {
+            self.accepted.insert(fp);
+        } else {
+            // Evicted while it was validated
+            self.evict(fp);
+        }
+    }
+
+    fn trim(&mut self, max_tracked: usize) {
+        while self.recent.len() > max_tracked {
+            match self.order.pop_front() {
+                None => break,
+                Some(old_fp) => {
+                    self.recent.remove(&old_fp);
+                    if self.accepted.remove(&old_fp) {
+                        self.evict(old_fp);
+                    }
+                }
+            }
+        }
+    }
+
+    fn evict(&mut self, fp: u64) {
+        if self.evicted.is_empty() {
+            self.evicted = vec![0; (BLOOM_BITS / 64) as usize];
+        }
+        for i in bloom_indexes(fp).iter() {
+            self.evicted[(i / 64) as usize] |= 1 << (i % 64);
+        }
+        self.spilled.insert(fp);
+    }
+}
+
+// Nonces are sorted so a reordered copy of a pow is still a duplicate
+fn fingerprint(pow: &Vec<u64>, height: u64, job_id: u64) -> u64 {
+    let mut nonces = pow.clone();
+    nonces.sort();
+    nonces.push(height);
+    nonces.push(job_id);
+    let mut bytes = vec![0; nonces.len() * 8];
+    LittleEndian::write_u64_into(&nonces, &mut bytes);
+    let mut blake2b = Blake2b::new(8);
+    blake2b.update(&bytes);
+    LittleEndian::read_u64(blake2b.finalize().as_bytes())
+}
+
+// Double hashing: derive all bloom bit positions from the one fingerprint
+fn bloom_indexes(fp: u64) -> Vec<u64> {
+    let h1 = fp & 0xffffffff;
+    let h2 = (fp >> 32) | 1;
+    (0..BLOOM_HASHES)
+        .map(|i| h1.wrapping_add(i.wrapping_mul(h2)) % BLOOM_BITS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::{env, fs, process};
+    use testcontainers::clients::Cli;
+    use testcontainers::core::WaitFor;
+    use testcontainers::GenericImage;
+
+    #[test]
+    fn evicted_pow_is_still_duplicate() {
+        let mut duplicates = Duplicates::new(2);
+        let pows: Vec<Vec<u64>> = (0..5u64).map(|n| vec![n; 42]).collect();
+        for pow in pows.iter() {
+            assert!(!duplicates.contains(pow, 7, 1000));
+            duplicates.insert(pow, 7, 1000, 1);
+            duplicates.accepted(pow, 7, 1000);
+        }
+        assert_eq!(duplicates.len(), 2);
+        for pow in pows.iter() {
+            assert!(duplicates.contains(pow, 7, 1000));
+        }
+        duplicates.clear();
+        assert!(!duplicates.contains(&pows[0], 7, 1000));
+    }
+
+    #[test]
+    fn reordered_pow_is_duplicate() {
+        let mut duplicates = Duplicates::new(10);
+        let pow: Vec<u64> = (0..42u64).collect();
+        duplicates.insert(&pow, 7, 1000, 1);
+        let mut reordered = pow.clone();
+        reordered.reverse();
+        assert!(duplicates.contains(&reordered, 7, 1000));
+    }
+
+    #[test]
+    fn same_pow_other_job_is_not_duplicate() {
+        let mut duplicates = Duplicates::new(10);
+        let pow: Vec<u64> = (0..42u64).collect();
+        duplicates.insert(&pow, 7, 1000, 1);
+        assert!(duplicates.contains(&pow, 7, 1000));
+        assert!(!duplicates.contains(&pow, 7, 1001));
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let path = env::temp_dir().join(format!("grin-pool-duplicates-{}.bin", process::id()));
+        let path = path.to_str().unwrap();
+        let mut duplicates = Duplicates::new(2);
+        duplicates.new_height(7, 2);
+        let pows: Vec<Vec<u64>> = (0..5u64).map(|n| vec![n; 42]).collect();
+        for pow in pows.iter() {
+            duplicates.insert(pow, 7, 1000, 1);
+            duplicates.accepted(pow, 7, 1000);
+        }
+        duplicates.save(path).unwrap();
+        // Written in full, nothing left over next to it
+        assert!(!fs::metadata(format!("{}.tmp", path)).is_ok());
+
+        let mut loaded = Duplicates::load(path, 2);
+        let _ = fs::remove_file(path);
+        assert_eq!(loaded.len(), 2);
+        for pow in pows.iter() {
+            assert!(loaded.contains(pow, 7, 1000));
+        }
+        // Still remembered a couple of blocks later, then forgotten
+        loaded.new_height(9, 2);
+        assert!(loaded.contains(&pows[0], 7, 1000));
+        loaded.new_height(10, 2);
+        loaded.drop_previous();
+        assert!(!loaded.contains(&pows[0], 7, 1000));
+        assert!(!loaded.contains(&pows[4], 7, 1000));
+    }
+
+    #[test]
+    fn previous_height_share_rejected_after_new_generation() {
+        let mut duplicates = Duplicates::new(10);
+        duplicates.new_height(7, 2);
+        let pow: Vec<u64> = (0..42u64).collect();
+        assert!(duplicates.insert_new(&pow, 9, 1000, 1));
+        // Height 10 starts a new generation, shares for 9 are still in their grace period
+        duplicates.new_height(10, 2);
+        assert!(!duplicates.insert_new(&pow, 9, 1000, 1));
+        assert!(!duplicates.insert_new(&pow, 9, 1000, 2));
+        // The grace period is over, the pow can only be stale now
+        duplicates.drop_previous();
+        assert!(!duplicates.contains(&pow, 9, 1000));
+    }
+
+    #[test]
+    fn bloom_false_positives_are_not_rejected() {
+        let mut duplicates = Duplicates::new(0);
+        for n in 0..100000u64 {
+            duplicates.insert(&vec![n, n + 1], 7, 1000, 1);
+            duplicates.accepted(&vec![n, n + 1], 7, 1000);
+        }
+        let in_filter = |duplicates: &Duplicates, fp: u64| {
+            bloom_indexes(fp).iter().all(|i| duplicates.current.evicted[(i / 64) as usize] & (1 << (i % 64)) != 0)
+        };
+        let filter_hits: Vec<u64> = (100000..200000u64)
+            .filter(|n| in_filter(&duplicates, fingerprint(&vec![*n, n + 1], 7, 1000)))
+            .collect();
+        // Below 0.01% in the filter alone
+        assert!(filter_hits.len() < 10, "{} false positives", filter_hits.len());
+        for n in filter_hits.iter() {
+            assert!(!duplicates.contains(&vec![*n, n + 1], 7, 1000));
+        }
+        // Fill the filter so that it matches everything
+        for word in duplicates.current.evicted.iter_mut() {
+            *word = !0;
+        }
+        assert!(!duplicates.contains(&vec![100000, 100001], 7, 1000));
+        assert!(duplicates.insert_new(&vec![100000, 100001], 7, 1000, 1));
+        assert!(duplicates.contains(&vec![1, 2], 7, 1000));
+    }
+
+    #[test]
+    fn only_accepted_pows_are_remembered_once_evicted() {
+        let mut duplicates = Duplicates::new(2);
+        let pows: Vec<Vec<u64>> = (0..1000u64).map(|n| vec![n; 42]).collect();
+        for pow in pows.iter() {
+            assert!(duplicates.insert_new(pow, 7, 1000, 1));
+        }
+        duplicates.accepted(&pows[998], 7, 1000);
+        duplicates.insert(&vec![1000; 42], 7, 1000, 1);
+        duplicates.insert(&vec![1001; 42], 7, 1000, 1);
+        // Nothing but the accepted pow is kept past the exact entries
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates.spilled(), 1);
+        assert!(duplicates.contains(&pows[998], 7, 1000));
+        assert!(!duplicates.contains(&pows[997], 7, 1000));
+        assert!(!duplicates.contains(&pows[999], 7, 1000));
+    }
+
+    #[test]
+    fn redis_unavailable_is_local_only() {
+        let mut duplicates = Duplicates::new(10);
+        assert!(duplicates.connect_redis("127.0.0.1", 1).is_err());
+        let pow: Vec<u64> = (0..42u64).collect();
+        assert!(duplicates.insert_new(&pow, 7, 1000, 1));
+        duplicates.claim();
+        assert!(!duplicates.claimed_elsewhere(&pow, 7, 1000));
+        assert!(!duplicates.insert_new(&pow, 7, 1000, 2));
+    }
+
+    #[test]
+    fn redis_reconnect_backs_off() {
+        let mut duplicates = Duplicates::new(10);
+        assert!(duplicates.connect_redis("127.0.0.1", 1).is_err());
+        assert_eq!(duplicates.redis_backoff, Duration::from_secs(2));
+        // Not tried again before the backoff is over
+        assert!(duplicates.insert_new(&vec![1; 42], 7, 1000, 1));
+        duplicates.claim();
+        assert_eq!(duplicates.redis_backoff, Duration::from_secs(2));
+        assert!(duplicates.unclaimed.is_empty());
+        // Tried again and failed, waiting longer next time
+        duplicates.redis_retry_at = Some(Instant::now());
+        assert!(duplicates.insert_new(&vec![2; 42], 7, 1000, 1));
+        duplicates.claim();
+        assert_eq!(duplicates.redis_backoff, Duration::from_secs(4));
+        for _ in 0..10 {
+            duplicates.disconnect();
+        }
+        assert_eq!(duplicates.redis_backoff, Duration::from_secs(REDIS_MAX_BACKOFF_SECS));
+    }
+
+    // Runs Redis in a container, there is nothing to test against without docker
+    #[test]
+    fn pools_sharing_redis_reject_each_others_shares() {
+        let has_docker = Command::new("docker")
+            .arg("info")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false);
+        if !has_docker {
+            return;
+        }
+        let docker = Cli::default();
+        let image = GenericImage::new("redis", "5.0")
+            .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"))
+            .with_exposed_port(6379);
+        let redis = docker.run(image);
+        let port = redis.get_host_port_ipv4(6379) as u64;
+        let mut first = Duplicates::new(10);
+        let mut second = Duplicates::new(10);
+        first.connect_redis("127.0.0.1", port).unwrap();
+        second.connect_redis("127.0.0.1", port).unwrap();
+        let pow: Vec<u64> = (0..42u64).collect();
+        assert!(first.insert_new(&pow, 7, 1000, 1));
+        first.claim();
+        assert!(!first.claimed_elsewhere(&pow, 7, 1000));
+        // New to the second pool, but the first claimed it
+        assert!(second.insert_new(&pow, 7, 1000, 2));
+        second.claim();
+        assert!(second.claimed_elsewhere(&pow, 7, 1000));
+        // Each pool still detects its own
+        assert!(!first.insert_new(&pow, 7, 1000, 1));
+        assert!(second.insert_new(&pow, 7, 1001, 2));
+        second.claim();
+        assert!(!second.claimed_elsewhere(&pow, 7, 1001));
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hashrate Estimation
+//!
+//! A miner searches about 42 graphs for each solution at difficulty 1, so
+//! a share accepted at difficulty d stands for about 42 * d graphs.  Summing
+//! that over the accepted shares in a rolling window gives the graphs per
+//! second a worker is searching, for each edge_bits it mines.
+//!
+//! A C32 graph is twice the size of a C31 graph and takes about twice the
+//! work to search, so rates for different edge_bits are only added together
+//! after converting them to C31 graphs.
+//!
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use pool::worker::Worker;
+
+// Graphs searched per solution at difficulty 1, one per cycle length
+const GRAPHS_PER_SOLUTION: f64 = 42.0;
+// Rates are reported for graphs of this size
+const REFERENCE_EDGE_BITS: u32 = 31;
+const WINDOW_SECS: u64 = 300;
+
+pub struct HashrateEstimator {
+    window: Duration,
+    shares: VecDeque<(Instant, u32, u64)>, // When it was accepted, edge_bits, difficulty credited
+}
+
+impl HashrateEstimator {
+    pub fn new() -> HashrateEstimator {
+        HashrateEstimator {
+            window: Duration::from_secs(WINDOW_SECS),
+            shares: VecDeque::new(),
+        }
+    }
+
+    /// Record an accepted share at the difficulty it was credited at
+    pub fn add_share(&mut self, edge_bits: u32, difficulty: u64) {
+        let now = Instant::now();
+        while let Some(&(accepted, _, _)) = self.shares.front() {
+            if now.duration_since(accepted) < self.window {
+                break;
+            }
+            self.shares.pop_front();
+        }
+        self.shares.push_back((now, edge_bits, difficulty));
+    }
+
+    /// Graphs per second searched for each edge_bits over the window
+    pub fn graphs_per_second(&self) -> HashMap<u32, f64> {
+        let window = self.window;
+        let window_secs = window.as_secs() as f64;
+        let mut rates: HashMap<u32, f64> = HashMap::new();
+        for &(accepted, edge_bits, difficulty) in self.shares.iter() {
+            if accepted.elapsed() < window {
+                *rates.entry(edge_bits).or_insert(0.0) += GRAPHS_PER_SOLUTION * difficulty as f64 / window_secs;
+            }
+        }
+        rates
+    }
+
+    /// Graphs per second over the window, all edge_bits counted as C31 graphs
+    pub fn c31_graphs_per_second(&self) -> f64 {
+        self.graphs_per_second()
+            .iter()
+            .map(|(edge_bits, gps)| gps * graph_work(*edge_bits) / graph_work(REFERENCE_EDGE_BITS))
+            .sum()
+    }
+}
+
+/// C31 graphs searched to find a share of this size and difficulty
+pub fn c31_graphs(edge_bits: u32, difficulty: u64) -> f64 {
+    GRAPHS_PER_SOLUTION * difficulty as f64 * graph_work(edge_bits) / graph_work(REFERENCE_EDGE_BITS)
+}
+
+/// C31 graphs per second of each worker, by uuid, and of the whole pool
+pub fn pool_graphs_per_second(workers: &HashMap<String, Worker>) -> (HashMap<String, f64>, f64) {
+    let rates: HashMap<String, f64> = workers
+        .iter()
+        .map(|(uuid, worker)| (uuid.clone(), worker.hashrate.c31_graphs_per_second()))
+        .collect();
+    let total = rates.values().sum();
+    (rates, total)
+}
+
+// Relative work to search one graph: it has 2^edge_bits edges of edge_bits each
+fn graph_work(edge_bits: u32) -> f64 {
+    2f64.powi(edge_bits as i32) * edge_bits as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edge_bits_scaled_to_c31() {
+        let mut estimator = HashrateEstimator::new();
+        for _ in 0..10 {
+            estimator.add_share(31, 30);
+        }
+        estimator.add_share(32, 300);
+        let rates = estimator.graphs_per_second();
+        assert!((rates[&31] - 42.0).abs() < 1e-9);
+        assert!((rates[&32] - 42.0).abs() < 1e-9);
+        // A C32 graph is a little over twice the work of a C31 graph
+        let c32_as_c31 = 42.0 * 2.0 * 32.0 / 31.0;
+        assert!((estimator.c31_graphs_per_second() - (42.0 + c32_as_c31)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn old_shares_leave_the_window() {
+        let mut estimator = HashrateEstimator::new();
+        estimator.window = Duration::from_millis(50);
+        estimator.add_share(31, 1);
+        ::std::thread::sleep(Duration::from_millis(60));
+        assert!(estimator.graphs_per_second().is_empty());
+        estimator.add_share(31, 1);
+        assert_eq!(estimator.shares.len(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Share Difficulty Histogram
+//!
+//! Counts the accepted shares by the difficulty they actually had, for each
+//! edge_bits, to show operators what kind of hardware mines at the pool.  A
+//! GPU finds many low difficulty shares, an ASIC far fewer high ones.
+//!
+//! Counts are kept in one minute slots so old shares leave the window
+//! without remembering each one.
+//!
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const SLOT_SECS: u64 = 60;
+
+/// Shares in a bucket, up_to is its inclusive upper bound and None for the last
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BucketCount {
+    pub up_to: Option<u64>,
+    pub shares: u64,
+}
+
+pub struct DifficultyHistogram {
+    boundaries: Vec<u64>, // Upper bounds of all but the last bucket, ascending
+    slots: usize, // One minute slots in the window
+    counts: VecDeque<(Instant, HashMap<u32, Vec<u64>>)>, // When the slot started, counts per bucket for each edge_bits
+}
+
+impl DifficultyHistogram {
+    pub fn new(boundaries: Vec<u64>, window: Duration) -> DifficultyHistogram {
+        DifficultyHistogram {
+            boundaries: boundaries,
+            slots: (window.as_secs() / SLOT_SECS).max(1) as usize,
+            counts: VecDeque::new(),
+        }
+    }
+
+    /// Count an accepted share at the difficulty its proof has
+    pub fn add_share(&mut self, edge_bits: u32, difficulty: u64) {
+        let new_slot = match self.counts.back() {
+            Some(&(started, _)) => started.elapsed() >= Duration::from_secs(SLOT_SECS),
+            None => true,
+        };
+        if new_slot {
+            self.counts.push_back((Instant::now(), HashMap::new()));
+            while self.counts.len() > self.slots {
+                self.counts.pop_front();
+            }
+        }
+        let bucket = self
+            .boundaries
+            .iter()
+            .position(|boundary| difficulty <= *boundary)
+            .unwrap_or(self.boundaries.len());
+        let buckets = self.boundaries.len() + 1;
+        let slot = &mut self.counts.back_mut().unwrap().1;
+        slot.entry(edge_bits).or_insert_with(|| vec![0; buckets])[bucket] += 1;
+    }
+
+    /// Shares in each bucket over the window, for each edge_bits mined
+    pub fn counts(&self) -> HashMap<u32, Vec<BucketCount>> {
+        let window = Duration::from_secs(SLOT_SECS * self.slots as u64);
+        let mut totals: HashMap<u32, Vec<u64>> = HashMap::new();
+        for &(started, ref slot) in self.counts.iter() {
+            if started.elapsed() >= window {
+                continue;
+            }
+            for (edge_bits, counts) in slot.iter() {
+                let total = totals.entry(*edge_bits).or_insert_with(|| vec![0; counts.len()]);
+                for (sum, count) in total.iter_mut().zip(counts.iter()) {
+                    *sum += *count;
+                }
+            }
+        }
+        totals
+            .into_iter()
+            .map(|(edge_bits, counts)| {
+                let buckets = counts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, shares)| BucketCount {
+                        up_to: self.boundaries.get(i).cloned(),
+                        shares: shares,
+                    })
+                    .collect();
+                (edge_bits, buckets)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_bucketed_by_edge_bits() {
+        let mut histogram = DifficultyHistogram::new(vec![4, 64], Duration::from_secs(600));
+        for difficulty in [1, 4, 5, 64, 65, 100000].iter() {
+            histogram.add_share(31, *difficulty);
+        }
+        histogram.add_share(29, 2);
+        let counts = histogram.counts();
+        assert_eq!(
+            counts[&31],
+            vec![
+                BucketCount { up_to: Some(4), shares: 2 },
+                BucketCount { up_to: Some(64), shares: 2 },
+                BucketCount { up_to: None, shares: 2 },
+            ]
+        );
+        assert_eq!(counts[&29].iter().map(|b| b.shares).collect::<Vec<u64>>(), vec![1, 0, 0]);
+        assert!(!counts.contains_key(&32));
+    }
+
+    #[test]
+    fn old_slots_leave_the_window() {
+        let mut histogram = DifficultyHistogram::new(vec![4], Duration::from_secs(60));
+        histogram.add_share(31, 1);
+        // As if the slot started over a minute ago
+        histogram.counts[0].0 = Instant::now() - Duration::from_secs(SLOT_SECS + 1);
+        assert!(histogram.counts().is_empty());
+        histogram.add_share(31, 8);
+        assert_eq!(histogram.counts.len(), 1);
+        assert_eq!(histogram.counts()[&31][1].shares, 1);
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker job_id Encoding
+//!
+//! The job_id sent to workers is built from the upstream job_id by one of:
+//!
+//! - height_prefix: the block height and the upstream job_id packed into
+//!   one number, unique across heights (bminer wants this)
+//! - raw: the upstream job_id unchanged, it repeats at every height
+//! - uuid: a random tag above the upstream job_id, so workers can not guess
+//!   job_ids or tell the height from one.  The tag is kept small enough that
+//!   the job_id stays exact as a JSON double.
+//!
+//! Every scheme keeps the upstream job_id recoverable from the worker job_id
+//! alone, so shares can be submitted upstream without a lookup.
+//!
+
+use rand::{thread_rng, Rng};
+
+use pool::proto::{JobId, JOB_VERSION_BITS, MAX_JOB_VERSION};
+
+// Worker job_ids stay below 2^53
+const UUID_TAG_BITS: u32 = 53 - JOB_VERSION_BITS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobIdCodec {
+    HeightPrefix,
+    Raw,
+    Uuid,
+}
+
+impl JobIdCodec {
+    /// The codec for a grin_pool.job_id_encoding setting
+    pub fn from_name(name: &str) -> Result<JobIdCodec, String> {
+        match name {
+            "height_prefix" => Ok(JobIdCodec::HeightPrefix),
+            "raw" => Ok(JobIdCodec::Raw),
+            "uuid" => Ok(JobIdCodec::Uuid),
+            _ => Err(format!(
+                "grin_pool.job_id_encoding must be height_prefix, raw or uuid, not {}",
+                name
+            )),
+        }
+    }
+
+    /// The job_id workers see for an upstream job, fails if it does not fit
+    pub fn encode(&self, height: u64, raw_id: u64) -> Result<u64, String> {
+        match *self {
+            JobIdCodec::HeightPrefix => JobId::new(height, raw_id).encode(),
+            JobIdCodec::Raw => Ok(raw_id),
+            JobIdCodec::Uuid => {
+                if raw_id > MAX_JOB_VERSION {
+                    return Err(format!("Job version {} does not fit in a job_id", raw_id));
+                }
+                let tag = thread_rng().gen::<u64>() & ((1 << UUID_TAG_BITS) - 1);
+                Ok((tag << JOB_VERSION_BITS) | raw_id)
+            }
+        }
+    }
+
+    /// The upstream job_id of a worker job_id for a share at height
+    pub fn decode(&self, encoded_id: u64, _height: u64) -> u64 {
+        match *self {
+            JobIdCodec::HeightPrefix => JobId::decode(encoded_id).version,
+            JobIdCodec::Raw => encoded_id,
+            JobIdCodec::Uuid => encoded_id & MAX_JOB_VERSION,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn round_trip() {
+        for name in ["height_prefix", "raw", "uuid"].iter() {
+            let codec = JobIdCodec::from_name(name).unwrap();
+            for &(height, raw_id) in [(0, 0), (1, 0), (1, 999), (1, 1000), (1_000_000, 998)].iter() {
+                let job_id = codec.encode(height, raw_id).unwrap();
+                assert_eq!(codec.decode(job_id, height), raw_id, "{} at height {}", name, height);
+            }
+        }
+        assert!(JobIdCodec::from_name("height*1000").is_err());
+        assert!(JobIdCodec::Uuid.encode(1, MAX_JOB_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn height_prefix_never_collides() {
+        // Job_ids only grow with the job_id within a height, so if the last
+        // job_id of every height is below the first of the next none repeat
+        let codec = JobIdCodec::HeightPrefix;
+        let mut previous = None;
+        for height in 0..1_000_001 {
+            let first = codec.encode(height, 0).unwrap();
+            let last = codec.encode(height, 998).unwrap();
+            assert!(first < last);
+            if let Some(previous) = previous {
+                assert!(previous < first, "height {} collides with the one below", height);
+            }
+            previous = Some(last);
+        }
+    }
+
+    #[test]
+    fn uuid_job_ids_differ() {
+        let codec = JobIdCodec::Uuid;
+        let job_ids: HashSet<u64> = (0..999).map(|raw_id| codec.encode(1_000_000, raw_id).unwrap()).collect();
+        assert_eq!(job_ids.len(), 999);
+        assert!(job_ids.iter().all(|job_id| *job_id < 1 << 53));
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Job Versions
+//!
+//! A share is checked against the pre_pow of the job version it was mined
+//! on, so every version sent to workers at the current height is kept here.
+//! Memory is bounded: versions sent longer ago than the ttl expire, and past
+//! max_versions the least recently used version is evicted.
+//!
+//! Evicting a version is a trade-off.  A share mined on an evicted version
+//! can no longer be validated and is rejected as an unknown job, so the cap
+//! should stay well above the number of templates a single height sees.
+//!
+//! Optionally every version is also written to a Redis hash, so a restarted
+//! pool still accepts shares for the jobs it sent before the restart.
+//! Reloaded versions start a new ttl.  The height is saved with the pre_pow
+//! as "height:pre_pow" since not every job_id encoding includes it.
+//!
+
+use redis::{self, Commands, Connection};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use pool::proto::JobId;
+
+const REDIS_KEY: &str = "job_versions";
+
+struct JobVersion {
+    height: u64,
+    pre_pow: String,
+    sent: Instant,  // When it was sent to workers
+    last_used: u64, // Value of the use counter when it was last looked up
+}
+
+pub struct JobVersions {
+    max_versions: usize,
+    ttl: Duration,
+    versions: HashMap<u64, JobVersion>, // worker job_id, its version
+    uses: u64,                          // Counts inserts and lookups, orders versions for eviction
+    redis: Option<Connection>,
+}
+
+impl JobVersions {
+    /// Keep at most max_versions job versions, each for at most ttl_secs
+    pub fn new(max_versions: usize, ttl_secs: u64) -> JobVersions {
+        JobVersions {
+            max_versions: max_versions,
+            ttl: Duration::from_secs(ttl_secs),
+            versions: HashMap::new(),
+            uses: 0,
+            redis: None,
+        }
+    }
+
+    /// Keep a copy of every version in Redis, and reload the versions saved
+    /// there by a previous run
+    pub fn connect_redis(&mut self, address: &str, port: u64) -> Result<usize, String> {
+        let redis_url = format!("redis://{}:{}/", address, port);
+        let mut con = redis::Client::open(redis_url.as_str())
+            .and_then(|client| client.get_connection())
+            .map_err(|e| format!("Failed to connect to REDIS at {}: {:?}", redis_url, e))?;
+        let saved: HashMap<u64, String> = con
+            .hgetall(REDIS_KEY)
+            .map_err(|e| format!("Failed to load job versions from REDIS: {:?}", e))?;
+        self.redis = Some(con);
+        let count = saved.len();
+        for (job_id, saved) in saved {
+            // Versions saved before the height was, their job_id holds it
+            let (height, pre_pow) = match saved.find(':') {
+                Some(i) => match saved[..i].parse() {
+                    Ok(height) => (height, saved[i + 1..].to_string()),
+                    Err(_) => continue,
+                },
+                None => (JobId::decode(job_id).height, saved),
+            };
+            self.insert(job_id, height, pre_pow);
+        }
+        Ok(count)
+    }
+
+    /// The pre_pow of a job version, unless it expired or was evicted
+    pub fn get(&mut self, job_id: u64) -> Option<&String> {
+        self.uses += 1;
+        let (ttl, uses) = (self.ttl, self.uses);
+        match self.versions.get_mut(&job_id) {
+            Some(version) if version.sent.elapsed() < ttl => {
+                version.last_used = uses;
+                Some(&version.pre_pow)
+            }
+            _ => None,
+        }
+    }
+
+    /// Remember a job version at a height, forgetting expired ones and the
+    /// least recently used past the cap
+    pub fn insert(&mut self, job_id: u64, height: u64, pre_pow: String) {
+        let ttl = self.ttl;
+        let expired: Vec<u64> = self
+            .versions
+            .iter()
+            .filter(|&(_, version)| version.sent.elapsed() >= ttl)
+            .map(|(job_id, _)| *job_id)
+            .collect();
+        self.remove_all(expired);
+        while !self.versions.contains_key(&job_id) && self.versions.len() >= self.max_versions {
+            let lru = match self.versions.iter().min_by_key(|&(_, version)| version.last_used) {
+                Some((job_id, _)) => *job_id,
+                None => break,
+            };
+            self.remove_all(vec![lru]);
+        }
+        if let Some(ref mut redis) = self.redis {
+            let saved: redis::RedisResult<()> = redis.hset(REDIS_KEY, job_id, format!("{}:{}", height, pre_pow));
+            if let Err(e) = saved {
+                warn!("Failed to save job version {} to REDIS: {:?}", job_id, e);
+            }
+        }
+        self.uses += 1;
+        self.versions.insert(
+            job_id,
+            JobVersion {
+                height: height,
+                pre_pow: pre_pow,
+                sent: Instant::now(),
+                last_used: self.uses,
+            },
+        );
+    }
+
+    /// Forget the versions of heights outside oldest..=newest - called when the height changes
+    pub fn retain_heights(&mut self, oldest: u64, newest: u64) {
+        let other_heights: Vec<u64> = self
+            .versions
+            .iter()
+            .filter(|&(_, version)| version.height < oldest || version.height > newest)
+            .map(|(job_id, _)| *job_id)
+            .collect();
+        self.remove_all(other_heights);
+    }
+
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    fn remove_all(&mut self, job_ids: Vec<u64>) {
+        if job_ids.is_empty() {
+            return;
+        }
+        for job_id in job_ids.iter() {
+            self.versions.remove(job_id);
+        }
+        if let Some(ref mut redis) = self.redis {
+            let removed: redis::RedisResult<()> = redis.hdel(REDIS_KEY, job_ids);
+            if let Err(e) = removed {
+                warn!("Failed to remove job versions from REDIS: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn job_id(height: u64, version: u64) -> u64 {
+        JobId::new(height, version).encode().unwrap()
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let mut versions = JobVersions::new(3, 300);
+        for version in 0..3 {
+            versions.insert(job_id(1, version), 1, format!("{:x}", version));
+        }
+        // Miners are still working on the first version
+        assert!(versions.get(job_id(1, 0)).is_some());
+        versions.insert(job_id(1, 3), 1, "3".to_string());
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions.get(job_id(1, 0)).unwrap(), "0");
+        assert!(versions.get(job_id(1, 1)).is_none());
+        assert!(versions.get(job_id(1, 3)).is_some());
+    }
+
+    #[test]
+    fn expired_versions_forgotten() {
+        let mut versions = JobVersions::new(10, 1);
+        versions.insert(job_id(1, 0), 1, "aa".to_string());
+        thread::sleep(Duration::from_millis(1100));
+        assert!(versions.get(job_id(1, 0)).is_none());
+        versions.insert(job_id(1, 1), 1, "bb".to_string());
+        assert_eq!(versions.len(), 1);
+    }
+
+    #[test]
+    fn other_heights_forgotten() {
+        let mut versions = JobVersions::new(10, 300);
+        versions.insert(job_id(1, 0), 1, "aa".to_string());
+        versions.insert(job_id(2, 0), 2, "bb".to_string());
+        versions.insert(job_id(2, 1), 2, "cc".to_string());
+        versions.insert(job_id(3, 0), 3, "dd".to_string());
+        versions.retain_heights(2, 3);
+        assert_eq!(versions.len(), 3);
+        versions.retain_heights(2, 2);
+        assert_eq!(versions.len(), 2);
+        assert!(versions.get(job_id(1, 0)).is_none());
+        assert!(versions.get(job_id(3, 0)).is_none());
+        assert_eq!(versions.get(job_id(2, 1)).unwrap(), "cc");
+    }
+}
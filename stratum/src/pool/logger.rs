@@ -27,18 +27,104 @@ use log4rs::append::rolling_file::{
 };
 use log4rs::append::Append;
 use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, RwLock};
 use log4rs::filter::{threshold::ThresholdFilter, Filter, Response};
 
 
 
 use pool::config;
 
+// Overrides grin_pool.log_format when set
+const LOG_FORMAT_ENV: &str = "GRIN_POOL_LOG_FORMAT";
+
+// The log format to use - "text" or "json"
+fn log_format(configured: &str) -> String {
+    match env::var(LOG_FORMAT_ENV) {
+        Ok(format) => format,
+        Err(_) => configured.to_string(),
+    }
+}
+
+// One JSON object per line for log aggregation, anything else is text
+fn encoder(format: &str, pattern: &str) -> Box<Encode> {
+    match format {
+        "json" => Box::new(JsonEncoder::new()),
+        _ => Box::new(PatternEncoder::new(pattern)),
+    }
+}
+
+/// Log levels of single modules, they can be changed while running.
+/// A module's level also covers its submodules, the most specific one wins.
+/// Records from modules without a level go on to the appender thresholds.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleLevels {
+	levels: Arc<RwLock<HashMap<String, LevelFilter>>>,
+}
+
+impl ModuleLevels {
+	/// Levels from the config, module name to level name
+	pub fn new(levels: &HashMap<String, String>) -> Result<ModuleLevels, String> {
+		let module_levels = ModuleLevels::default();
+		for (module, level) in levels.iter() {
+			module_levels.set(module, level)?;
+		}
+		Ok(module_levels)
+	}
+
+	/// Set the level of a module
+	pub fn set(&self, module: &str, level: &str) -> Result<(), String> {
+		let level = level
+			.parse::<LevelFilter>()
+			.map_err(|_| format!("Invalid log level {}", level))?;
+		self.levels.write().unwrap().insert(module.to_string(), level);
+		Ok(())
+	}
+
+	/// The level of every module that has one
+	pub fn levels(&self) -> HashMap<String, String> {
+		self.levels
+			.read()
+			.unwrap()
+			.iter()
+			.map(|(module, level)| (module.clone(), level.to_string().to_lowercase()))
+			.collect()
+	}
+
+	// The level of the most specific module a log target is in
+	fn level(&self, target: &str) -> Option<LevelFilter> {
+		self.levels
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|&(module, _)| target == module.as_str() || target.starts_with(&format!("{}::", module)))
+			.max_by_key(|&(module, _)| module.len())
+			.map(|(_, level)| *level)
+	}
+}
+
+impl Filter for ModuleLevels {
+	fn filter(&self, record: &Record) -> Response {
+		match self.level(record.target()) {
+			None => Response::Neutral,
+			Some(level) if record.level() <= level => Response::Accept,
+			Some(_) => Response::Reject,
+		}
+	}
+}
+
 //lazy_static! {
 
-pub fn init_logger() {
+/// Start logging, returns the module levels so they can be changed later
+pub fn init_logger() -> ModuleLevels {
 
     let config = config::read_config();
+    let module_levels = ModuleLevels::new(&config.grin_pool.log_levels).unwrap_or_default();
+    let format = log_format(&config.grin_pool.log_format);
     let log_file_path = config.grin_pool.log_dir + "/" + "grin-pool.log";
     let level_stdout = LevelFilter::Trace;
     let level_file = LevelFilter::Warn;
@@ -46,7 +132,7 @@ pub fn init_logger() {
     let size = 16777216; // XXX TODO GET FROM CONFIG
 
     let stdout = ConsoleAppender::builder()
-			.encoder(Box::new(PatternEncoder::default()))
+			.encoder(encoder(&format, "{d} {l} {t} - {m}{n}"))
 			.build();
 
 		let mut root = Root::builder();
@@ -56,6 +142,7 @@ pub fn init_logger() {
 		let filter = Box::new(ThresholdFilter::new(level_stdout));
 		appenders.push(
 			Appender::builder()
+				.filter(Box::new(module_levels.clone()))
 				.filter(filter)
 				.build("stdout", Box::new(stdout)),
 		);
@@ -76,7 +163,7 @@ pub fn init_logger() {
 			Box::new(
 				RollingFileAppender::builder()
 					.append(true)
-					.encoder(Box::new(PatternEncoder::new("{d} {l} {M} - {m}{n}")))
+					.encoder(encoder(&format, "{d} {l} {M} - {m}{n}"))
 					.build(log_file_path, Box::new(policy))
 					.unwrap(),
 			)
@@ -84,6 +171,7 @@ pub fn init_logger() {
 
 		appenders.push(
 			Appender::builder()
+				.filter(Box::new(module_levels.clone()))
 				.filter(filter)
 				.build("file", file),
 		);
@@ -97,9 +185,12 @@ pub fn init_logger() {
 		let _ = log4rs::init_config(config).unwrap();
 
 		info!(
-			"log4rs is initialized, file level: {:?}, stdout level: {:?}, min. level: {:?}",
-			level_file, level_stdout, level_minimum
+			"log4rs is initialized, file level: {:?}, stdout level: {:?}, min. level: {:?}, format: {}",
+			level_file, level_stdout, level_minimum, format
         );
+		if format != "text" && format != "json" {
+			warn!("Unknown log format {}, logging as text", format);
+		}
 
         panic::set_hook(Box::new(|info| {
     		let backtrace = Backtrace::new();
@@ -129,4 +220,79 @@ pub fn init_logger() {
     			None => error!("thread '{}' panicked at '{}'{:?}", thread, msg, backtrace),
     		}
         }));
+
+	module_levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use log4rs::encode::writer::simple::SimpleWriter;
+    use serde_json::{self, Value};
+
+    fn passes(levels: &ModuleLevels, target: &str, level: Level) -> Option<bool> {
+        let response = levels.filter(
+            &Record::builder()
+                .args(format_args!("Worker {} logged in", "7-abc"))
+                .level(level)
+                .target(target)
+                .build(),
+        );
+        match response {
+            Response::Accept => Some(true),
+            Response::Reject => Some(false),
+            Response::Neutral => None,
+        }
+    }
+
+    #[test]
+    fn module_log_levels() {
+        let mut configured = HashMap::new();
+        configured.insert("pool::worker".to_string(), "warn".to_string());
+        configured.insert("pool::server".to_string(), "debug".to_string());
+        let levels = ModuleLevels::new(&configured).unwrap();
+        assert_eq!(passes(&levels, "pool::worker", Level::Debug), Some(false));
+        assert_eq!(passes(&levels, "pool::worker", Level::Warn), Some(true));
+        assert_eq!(passes(&levels, "pool::server", Level::Debug), Some(true));
+        assert_eq!(passes(&levels, "pool::server", Level::Trace), Some(false));
+        // Other modules are left to the appender thresholds
+        assert_eq!(passes(&levels, "pool::pool", Level::Debug), None);
+        assert_eq!(passes(&levels, "pool::workers", Level::Debug), None);
+
+        // Changed at runtime, the most specific module wins
+        levels.set("pool::worker", "debug").unwrap();
+        levels.set("pool", "error").unwrap();
+        assert_eq!(passes(&levels, "pool::worker", Level::Debug), Some(true));
+        assert_eq!(passes(&levels, "pool::pool", Level::Warn), Some(false));
+        assert!(levels.set("pool::worker", "loud").is_err());
+        assert_eq!(levels.levels()["pool::worker"], "debug");
+    }
+
+    fn encode(format: &str) -> String {
+        let mut w = SimpleWriter(Vec::new());
+        encoder(format, "{l} - {m}{n}")
+            .encode(
+                &mut w,
+                &Record::builder()
+                    .args(format_args!("Worker {} dropped", "7-abc"))
+                    .level(Level::Warn)
+                    .target("pool::pool")
+                    .build(),
+            )
+            .unwrap();
+        String::from_utf8(w.0).unwrap()
+    }
+
+    #[test]
+    fn json_log_lines() {
+        let line = encode("json");
+        assert!(line.ends_with("\n"));
+        let entry: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["message"], "Worker 7-abc dropped");
+        assert_eq!(entry["level"], "WARN");
+        assert_eq!(entry["target"], "pool::pool");
+
+        assert_eq!(encode("text"), "WARN - Worker 7-abc dropped\n");
+    }
 }
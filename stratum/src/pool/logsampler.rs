@@ -0,0 +1,84 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log Sampling
+//!
+//! Some diagnostics are logged for every job sent and every share, with a
+//! thousand workers that is thousands of near identical lines a second.  The
+//! sampler lets the first and then one in every N lines of each kind through,
+//! the line that is logged says how many of its kind were skipped.
+//!
+//! Only for lines meant for people - accounting output is never sampled.
+//!
+
+use std::collections::HashMap;
+
+pub struct LogSampler {
+    every: u64, // Log one in this many lines of a kind, 0 or 1 logs them all
+    skipped: HashMap<&'static str, u64>, // Lines of each kind skipped since one was logged
+}
+
+impl LogSampler {
+    pub fn new(every: u64) -> LogSampler {
+        LogSampler {
+            every: every,
+            skipped: HashMap::new(),
+        }
+    }
+
+    /// Should this line of a kind be logged?  If so, returns the number
+    /// of lines of that kind skipped since the last one logged
+    pub fn sample(&mut self, kind: &'static str) -> Option<u64> {
+        if self.every <= 1 {
+            return Some(0);
+        }
+        let every = self.every;
+        match self.skipped.get_mut(kind) {
+            Some(skipped) => {
+                if *skipped + 1 < every {
+                    *skipped += 1;
+                    return None;
+                }
+                let count = *skipped;
+                *skipped = 0;
+                return Some(count);
+            }
+            None => {}
+        }
+        self.skipped.insert(kind, 0);
+        return Some(0);
+    }
+
+    /// Change the sampling rate, from a reloaded config
+    pub fn set_rate(&mut self, every: u64) {
+        self.every = every;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_in_n_logged() {
+        let mut sampler = LogSampler::new(3);
+        let logged: Vec<Option<u64>> = (0..7).map(|_| sampler.sample("job")).collect();
+        assert_eq!(logged, vec![Some(0), None, None, Some(2), None, None, Some(2)]);
+        // Each kind is counted on its own
+        assert_eq!(sampler.sample("share"), Some(0));
+        assert_eq!(sampler.sample("job"), None);
+
+        let mut sampler = LogSampler::new(1);
+        assert!((0..5).all(|_| sampler.sample("job") == Some(0)));
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! InfluxDB Export
+//!
+//! Worker share counts and hashrates are sent to InfluxDB as line protocol
+//! over UDP, for Grafana dashboards.  Lines are queued and sent together in
+//! one datagram, each with a line of pool wide share totals.  Sending never
+//! blocks and a failed send only loses that batch.
+//!
+//! Also counts worker connections coming and going, shown by the stats api,
+//! so churn like a reconnect storm after a new block can be seen.
+//!
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pool::worker::Worker;
+
+// Send a batch that is not full after this long, so quiet pools still report
+const MAX_BATCH_AGE_SECS: u64 = 10;
+
+/// Worker connections since the pool started, by how they began and ended
+#[derive(Default)]
+pub struct ConnectionCounters {
+    pub accepted: AtomicUsize,
+    pub rejected_banned: AtomicUsize, // From a banned ip
+    pub rejected_flood: AtomicUsize, // From an ip opening connections too fast
+    pub rejected_full: AtomicUsize, // Over the pool or per ip connection limit
+    pub disconnected: AtomicUsize, // Closed by the miner
+    pub dropped_error: AtomicUsize, // Dropped by the pool after an error
+    pub dropped_idle: AtomicUsize, // Dropped by the pool for not answering a ping
+}
+
+/// A copy of the connection counters, for the api
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConnectionCounts {
+    pub accepted: usize,
+    pub rejected_banned: usize,
+    pub rejected_flood: usize,
+    pub rejected_full: usize,
+    pub disconnected: usize,
+    pub dropped_error: usize,
+    pub dropped_idle: usize,
+}
+
+impl ConnectionCounters {
+    pub fn count(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> ConnectionCounts {
+        ConnectionCounts {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected_banned: self.rejected_banned.load(Ordering::Relaxed),
+            rejected_flood: self.rejected_flood.load(Ordering::Relaxed),
+            rejected_full: self.rejected_full.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+            dropped_error: self.dropped_error.load(Ordering::Relaxed),
+            dropped_idle: self.dropped_idle.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct InfluxDbExporter {
+    socket: UdpSocket,
+    address: SocketAddr,
+    batch_size: usize, // Worker lines sent in one datagram
+    lines: Vec<String>,
+    last_flush: Instant,
+    shares_total: BTreeMap<String, u64>, // Share outcome, count across the pool
+}
+
+impl InfluxDbExporter {
+    /// Resolve the InfluxDB UDP listener address and bind a socket to send from
+    pub fn new(address: &str, batch_size: usize) -> Result<InfluxDbExporter, String> {
+        let address = address
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve {}: {}", address, e))?
+            .next()
+            .ok_or(format!("{} did not resolve to an address", address))?;
+        let bind = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).map_err(|e| format!("Failed to bind {}: {}", bind, e))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|e| format!("Failed to make the metrics socket non-blocking: {}", e))?;
+        Ok(InfluxDbExporter {
+            socket: socket,
+            address: address,
+            batch_size: batch_size,
+            lines: Vec::new(),
+            last_flush: Instant::now(),
+            shares_total: BTreeMap::new(),
+        })
+    }
+
+    /// Count a share decision toward the pool totals
+    pub fn count_share(&mut self, outcome: &str) {
+        *self.shares_total.entry(outcome.to_string()).or_insert(0) += 1;
+    }
+
+    /// Queue a line with a workers share counts and hashrate, the batch
+    /// is sent once it is full or old enough
+    pub fn worker_stats(&mut self, worker: &Worker) {
+        let line = format!(
+            "worker_stats,worker={},rig={} accepted={}i,rejected={}i,stale={}i,hashrate={} {}",
+            escape_tag(&worker.full_id()),
+            escape_tag(&worker.worker_shares.rigid),
+            worker.status.accepted,
+            worker.status.rejected,
+            worker.status.stale,
+            worker.hashrate.c31_graphs_per_second(),
+            timestamp_ns(),
+        );
+        self.lines.push(line);
+        if self.lines.len() >= self.batch_size
+            || self.last_flush.elapsed() >= Duration::from_secs(MAX_BATCH_AGE_SECS)
+        {
+            self.flush();
+        }
+    }
+
+    /// Send the queued lines and the pool totals
+    pub fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if self.lines.is_empty() {
+            return;
+        }
+        if !self.shares_total.is_empty() {
+            let fields: Vec<String> = self
+                .shares_total
+                .iter()
+                .map(|(outcome, count)| format!("{}={}i", escape_tag(outcome), count))
+                .collect();
+            self.lines.push(format!("pool_shares_total {} {}", fields.join(","), timestamp_ns()));
+        }
+        let batch = self.lines.join("\n");
+        self.lines.clear();
+        if let Err(e) = self.socket.send_to(batch.as_bytes(), self.address) {
+            warn!("Failed to send {} bytes of metrics to InfluxDB at {}: {}", batch.len(), self.address, e);
+        }
+    }
+}
+
+// Commas, spaces, and equals signs end tag keys and values unless escaped
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == ' ' || c == '=' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn timestamp_ns() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pool::pool::tests::{test_config, test_worker};
+
+    #[test]
+    fn worker_stats_sent_as_line_protocol() {
+        let influxdb = UdpSocket::bind("127.0.0.1:0").unwrap();
+        influxdb.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = influxdb.local_addr().unwrap().to_string();
+        let mut exporter = InfluxDbExporter::new(&address, 2).unwrap();
+        let (mut worker, _miner) = test_worker(&test_config());
+        worker.worker_shares.rigid = "rig 1".to_string();
+        worker.status.accepted = 5;
+        worker.status.rejected = 2;
+        worker.status.stale = 1;
+        exporter.count_share("accepted");
+        exporter.count_share("stale");
+        exporter.count_share("accepted");
+
+        // Not sent until the batch is full
+        exporter.worker_stats(&worker);
+        let mut buf = [0u8; 4096];
+        influxdb.set_nonblocking(true).unwrap();
+        assert!(influxdb.recv(&mut buf).is_err());
+        influxdb.set_nonblocking(false).unwrap();
+        exporter.worker_stats(&worker);
+
+        let len = influxdb.recv(&mut buf).unwrap();
+        let batch = String::from_utf8(buf[..len].to_vec()).unwrap();
+        let lines: Vec<&str> = batch.split('\n').collect();
+        assert_eq!(lines.len(), 3);
+        let tags = format!("worker_stats,worker={},rig=rig\\ 1", escape_tag(&worker.full_id()));
+        assert!(tags.contains(".rig\\ 1."));
+        for line in lines[..2].iter() {
+            // measurement,tags fields timestamp
+            assert!(line.starts_with(&format!("{} ", tags)), "{}", line);
+            let parts: Vec<&str> = line[tags.len() + 1..].split(' ').collect();
+            assert_eq!(parts.len(), 2, "{}", line);
+            assert!(parts[0].starts_with("accepted=5i,rejected=2i,stale=1i,hashrate="));
+            assert!(parts[0]["accepted=5i,rejected=2i,stale=1i,hashrate=".len()..].parse::<f64>().is_ok());
+            assert!(parts[1].parse::<u64>().unwrap() > 1_500_000_000_000_000_000);
+        }
+        let parts: Vec<&str> = lines[2].split(' ').collect();
+        assert_eq!(parts[0], "pool_shares_total");
+        assert_eq!(parts[1], "accepted=2i,stale=1i");
+        assert!(parts[2].parse::<u64>().is_ok());
+    }
+}
@@ -1,8 +1,33 @@
 pub mod config;
 pub mod logger;
+pub mod logsampler;
+pub mod metrics;
 pub mod pool;
 pub mod proto;
 pub mod server;
 pub mod consensus;
 pub mod worker;
+pub mod duplicates;
+pub mod pplns;
+pub mod db;
+pub mod api;
+pub mod admin;
+pub mod ratelimit;
+pub mod webhook;
+pub mod reload;
+pub mod sessions;
+pub mod round;
+pub mod audit;
+pub mod sharelog;
+pub mod snapshot;
+pub mod job;
+pub mod jobversions;
+pub mod hashrate;
+pub mod histogram;
+pub mod difficulty;
+pub mod sampler;
+pub mod netdiff;
+pub mod validator;
+pub mod transport;
+pub mod proxyproto;
 pub mod util;
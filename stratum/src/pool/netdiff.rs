@@ -0,0 +1,114 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network Difficulty
+//!
+//! The job difficulty upstream sends is only the minimum share difficulty
+//! it accepts.  The network difficulty comes from the candidate header in
+//! the jobs pre_pow instead: its total_difficulty is the chains cumulative
+//! difficulty including the candidate, so the difference between the totals
+//! of consecutive heights is the difficulty of the block being mined.
+//!
+//! That difficulty is scaled.  A share is a block when its unscaled
+//! difficulty times the scale for its size reaches it: graph_weight for
+//! the primary proof of work, secondary_scaling for C29.
+//!
+
+use pool::consensus::graph_weight;
+
+const SECONDARY_EDGE_BITS: u32 = 29;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NetworkDifficulty {
+    pub height: u64,
+    pub difficulty: u64, // Scaled, 0 until the totals of two consecutive heights were seen
+    pub total_difficulty: u64,
+    pub secondary_scaling: u32,
+}
+
+impl NetworkDifficulty {
+    pub fn new() -> NetworkDifficulty {
+        NetworkDifficulty {
+            height: 0,
+            difficulty: 0,
+            total_difficulty: 0,
+            secondary_scaling: 0,
+        }
+    }
+
+    /// Update from the header of a new job
+    pub fn update(&mut self, height: u64, total_difficulty: u64, secondary_scaling: u32) {
+        if height != self.height {
+            self.difficulty = if height == self.height + 1 && self.total_difficulty > 0 {
+                total_difficulty.saturating_sub(self.total_difficulty)
+            } else {
+                // A restart, a gap, or a reorg - unknown until the next height
+                0
+            };
+        }
+        self.height = height;
+        self.total_difficulty = total_difficulty;
+        self.secondary_scaling = secondary_scaling;
+    }
+
+    /// Unscaled difficulty a share of this size needs to be a block, 0 if unknown
+    pub fn unscaled(&self, edge_bits: u32) -> u64 {
+        if edge_bits > u8::max_value() as u32 || edge_bits < 24 {
+            return 0;
+        }
+        let scale = if edge_bits == SECONDARY_EDGE_BITS {
+            self.secondary_scaling as u64
+        } else {
+            graph_weight(self.height, edge_bits as u8)
+        };
+        if scale == 0 {
+            return 0;
+        }
+        self.difficulty / scale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difficulty_between_heights() {
+        let mut network = NetworkDifficulty::new();
+        network.update(100, 5_000_000, 400);
+        assert_eq!(network.difficulty, 0);
+        assert_eq!(network.unscaled(31), 0);
+        // A newer version of the same height changes nothing
+        network.update(100, 5_000_000, 400);
+        assert_eq!(network.difficulty, 0);
+        network.update(101, 5_004_000, 400);
+        assert_eq!(network.difficulty, 4000);
+        network.update(101, 5_004_000, 400);
+        assert_eq!(network.difficulty, 4000);
+        // Skipped a height, the gap holds more than one block
+        network.update(103, 5_012_000, 400);
+        assert_eq!(network.difficulty, 0);
+    }
+
+    #[test]
+    fn scaled_by_edge_bits() {
+        let mut network = NetworkDifficulty::new();
+        network.update(1, 1_000_000, 0);
+        network.update(2, 1_000_000 + 7936 * 100, 200);
+        // C31 graph_weight is (2 << 7) * 31
+        assert_eq!(network.unscaled(31), 100);
+        assert_eq!(network.unscaled(29), 7936 * 100 / 200);
+        assert_eq!(network.unscaled(32), 7936 * 100 / (2 << 8) / 32);
+        assert_eq!(network.unscaled(300), 0);
+    }
+}
@@ -12,64 +12,239 @@
 // limitations under the License.
 
 use bufstream::BufStream;
-use std::collections::HashMap;
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::mem;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Instant;
-use std::{thread, time};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::thread;
 use rand::Rng;
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Token};
 
-use failure::Error;
-use grin_util::from_hex;
-use grin_core::pow::Proof;
-use grin_core::core::BlockHeader;
-use grin_core::ser::{deserialize, ser_vec};
+use grin_core::consensus::REWARD;
+use rusqlite::Connection;
 
-use pool::config::{Config, NodeConfig, PoolConfig, WorkerConfig};
-use pool::proto::{JobTemplate, RpcError, SubmitParams, WorkerStatus};
+use pool::config::{self, Config, NodeConfig, PoolConfig, PortDifficulty, WorkerConfig};
+use pool::proto::{JobTemplate, RejectReason, RpcError, StratumProtocol, SubmitParams, WorkerStatus};
 
-use pool::server::Server;
+use pool::server::{PollInterval, Server, ServerState};
 use pool::worker::Worker;
+use pool::duplicates::Duplicates;
+use pool::pplns::PplnsWindow;
+use pool::db;
+use pool::api::{self, ApiState};
+use pool::logger::ModuleLevels;
+use pool::logsampler::LogSampler;
+use pool::admin::{self, AdminState};
+use pool::ratelimit::{IpConnections, IpRateLimiter, IpRef, JobBroadcastThrottle, PoolConnections, PoolRef};
+use pool::webhook::{self, BlockFound};
+use pool::metrics::{ConnectionCounters, InfluxDbExporter};
+use pool::reload;
+use pool::sessions::Sessions;
+use pool::round::Rounds;
+use pool::audit::{AuditEntry, AuthAuditLog, FeeEntry, ShareAuditLog};
+use pool::sharelog::{ShareEvent, ShareLogger};
+use pool::snapshot::{StatsSnapshot, WorkerSnapshot};
+use pool::job::JobIdCodec;
+use pool::jobversions::JobVersions;
+use pool::hashrate;
+use pool::histogram::DifficultyHistogram;
+use pool::difficulty::PoolDifficultyController;
+use pool::sampler::{HashrateSampler, SAMPLE_INTERVAL_SECS};
+use pool::netdiff::NetworkDifficulty;
+use pool::validator::{self, PendingShare, PowAlgorithm, ShareValidator, ValidationResult};
+use pool::transport::WorkerStream;
+use pool::proxyproto;
 use pool::consensus::Proof as MinerProof;
 use pool::consensus::PROOF_SIZE;
 
 // ----------------------------------------
 // Worker Connection Thread Function
 
-// Run in a thread. Adds new connections to the workers list
+// Resolve a configured address (an IPv4 or IPv6 literal, or a host name) and port
+fn socket_address(address: &str, port: u64) -> Result<SocketAddr, String> {
+    if port > u16::max_value() as u64 {
+        return Err(format!("Invalid port: {}", port));
+    }
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port as u16));
+    }
+    match (address, port as u16).to_socket_addrs() {
+        Ok(mut addrs) => addrs
+            .next()
+            .ok_or(format!("No address found for {}", address)),
+        Err(e) => Err(format!("Invalid address {}: {}", address, e)),
+    }
+}
+
+// Bind a worker listen port - failing to bind is fatal for the pool
+fn bind_workers(listen_address: &str, port: u64) -> Result<TcpListener, String> {
+    let address = socket_address(listen_address, port)?;
+    TcpListener::bind(address)
+        .map_err(|e| format!("Failed to bind to listen address {}: {}", address, e))
+}
+
+// Connections a listener is still reading a PROXY header or WebSocket
+// handshake from, each on its own thread
+const MAX_PENDING_HANDSHAKES: usize = 256;
+
+// What a listener needs to check and admit a new connection, from any thread
+#[derive(Clone)]
+struct Admission {
+    stratum_id: String,
+    config: Config,
+    port: u64,
+    port_difficulty: Arc<RwLock<HashMap<u64, u64>>>,
+    new_workers: Sender<Worker>,
+    pool_connections: PoolConnections,
+    banned: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    login_limiter: Arc<Mutex<IpRateLimiter>>,
+    conn_limiter: Arc<Mutex<IpRateLimiter>>,
+    ip_connections: IpConnections,
+    counters: Arc<ConnectionCounters>,
+}
+
+impl Admission {
+    // Behind a load balancer the miners own address is in the PROXY header,
+    // the connection is checked once it is known
+    fn admit_proxied(&self, stream: TcpStream, balancer_addr: SocketAddr) {
+        let worker_addr = match proxyproto::read_proxy_header(&stream) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => balancer_addr,
+            Err(e) => {
+                ConnectionCounters::count(&self.counters.dropped_error);
+                warn!(
+                    "{} - Worker Listener - Dropping connection from {}: {}",
+                    self.stratum_id, balancer_addr, e
+                );
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+        if self.allowed(&stream, worker_addr) {
+            self.add(stream, worker_addr);
+        }
+    }
+
+    // Drop connections from banned and flooding addresses
+    fn allowed(&self, stream: &TcpStream, worker_addr: SocketAddr) -> bool {
+        // XXX ALWAYS DO THIS FIRST - Check if this ip is banned and if so, drop it
+        if is_banned(&self.banned, worker_addr.ip()) {
+            ConnectionCounters::count(&self.counters.rejected_banned);
+            let _ = stream.shutdown(Shutdown::Both);
+            return false;
+        }
+        if is_connection_flood(&self.stratum_id, &self.config, &self.conn_limiter, &self.banned, worker_addr.ip()) {
+            ConnectionCounters::count(&self.counters.rejected_flood);
+            let _ = stream.shutdown(Shutdown::Both);
+            return false;
+        }
+        true
+    }
+
+    // Make a worker of an allowed connection, after the WebSocket handshake if there is one
+    fn add(&self, stream: TcpStream, worker_addr: SocketAddr) {
+        warn!(
+            "Worker Listener - New connection from ip: {}",
+            worker_addr
+        );
+        // Read for every connection, the difficulty can be changed by a config reload
+        let difficulty = match self.port_difficulty.read().unwrap().get(&self.port) {
+            Some(difficulty) => *difficulty,
+            None => {
+                // Removed, the stop signal is on its way
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+        add_worker(&self.stratum_id, &self.config, stream, worker_addr, difficulty, &self.new_workers, &self.pool_connections, &self.login_limiter, &self.ip_connections, &self.counters);
+    }
+}
+
+// Run in a thread. Hands new connections to the main loop as workers
 fn accept_workers(
     stratum_id: String,
     config: Config,
-    workers: &mut Arc<Mutex<HashMap<String, Worker>>>,
+    listener: TcpListener,
+    port: u64,
+    port_difficulty: Arc<RwLock<HashMap<u64, u64>>>,
+    new_workers: Sender<Worker>,
+    pool_connections: PoolConnections,
+    banned: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    login_limiter: Arc<Mutex<IpRateLimiter>>,
+    conn_limiter: Arc<Mutex<IpRateLimiter>>,
+    ip_connections: IpConnections,
+    counters: Arc<ConnectionCounters>,
+    stop: Receiver<()>,
 ) {
-    let address = config.workers.listen_address.clone() + ":"
-        + &config.workers.port_difficulty.port.to_string();
-    let difficulty = config.workers.port_difficulty.difficulty;
-    let listener = TcpListener::bind(address).expect("Failed to bind to listen address");
-    let banned: HashMap<SocketAddr, Instant> = HashMap::new();
-    let mut rng = rand::thread_rng();
+    let admission = Admission {
+        stratum_id: stratum_id.clone(),
+        config: config.clone(),
+        port: port,
+        port_difficulty: port_difficulty,
+        new_workers: new_workers,
+        pool_connections: pool_connections,
+        banned: banned,
+        login_limiter: login_limiter,
+        conn_limiter: conn_limiter,
+        ip_connections: ip_connections,
+        counters: counters.clone(),
+    };
+    let pending_handshakes: PoolConnections = Arc::new(AtomicUsize::new(0));
     // XXX TODO: Call the Redis api to get a list of banned IPs, refresh that list sometimes
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                // The port was removed from the config, this connection only woke us up
+                if stop.try_recv().is_ok() {
+                    warn!("{} - Worker Listener - Stopped listening on port {}", stratum_id, port);
+                    break;
+                }
                 match stream.peer_addr() {
                     Ok(worker_addr) => {
-                        // XXX ALWAYS DO THIS FIRST - Check if this ip is banned and if so, drop it
-                        if banned.contains_key(&worker_addr) {
-                            let _ = stream.shutdown(Shutdown::Both);
-                            continue;
+                        // Without a PROXY header the address is known, check it right away
+                        if !config.workers.proxy_protocol {
+                            if !admission.allowed(&stream, worker_addr) {
+                                continue;
+                            }
+                            if !config.workers.enable_websocket {
+                                admission.add(stream, worker_addr);
+                                continue;
+                            }
                         }
-                        warn!(
-                            "Worker Listener - New connection from ip: {}",
-                            worker_addr
-                        );
-                        stream
-                            .set_nonblocking(true)
-                            .expect("set_nonblocking call failed");
-                        let mut worker = Worker::new(config.clone(), BufStream::new(stream));
-                        worker.set_difficulty(difficulty);
-                        workers.lock().unwrap().insert(worker.uuid(), worker);
-                        // The new worker is now added to the workers list
+                        // The PROXY header and the WebSocket handshake can be slow to
+                        // come, read them on another thread so the connections behind
+                        // are not held up
+                        let pending = match PoolRef::acquire(&pending_handshakes, MAX_PENDING_HANDSHAKES) {
+                            Some(pending) => pending,
+                            None => {
+                                ConnectionCounters::count(&counters.dropped_error);
+                                warn!(
+                                    "{} - Worker Listener - Dropping connection from {}: {} others are still in their handshake",
+                                    stratum_id, worker_addr, MAX_PENDING_HANDSHAKES
+                                );
+                                let _ = stream.shutdown(Shutdown::Both);
+                                continue;
+                            }
+                        };
+                        let admission = admission.clone();
+                        let proxied = config.workers.proxy_protocol;
+                        let _handshake_th = thread::spawn(move || {
+                            if proxied {
+                                admission.admit_proxied(stream, worker_addr);
+                            } else {
+                                admission.add(stream, worker_addr);
+                            }
+                            drop(pending);
+                        });
                     }
                     Err(e) => {
                         warn!(
@@ -89,17 +264,306 @@ fn accept_workers(
     drop(listener);
 }
 
-fn block_header(pre_pow: String, edge_bits: u8, nonce: u64, mut proof: Vec<u64>) -> Result<BlockHeader, Error> {
-    let mut header_bytes = from_hex(pre_pow)?;
-    let mut nonce_bytes = ser_vec(&nonce)?;
-    header_bytes.append(&mut nonce_bytes);
-    let mut proof = Proof::new(proof);
-    proof.edge_bits = edge_bits;
-    let mut proof_bytes = ser_vec(&proof)?;
-    header_bytes.append(&mut proof_bytes);
-
-    let header: BlockHeader = deserialize(&mut &header_bytes[..])?;
-    Ok(header)
+// Is this ip address banned?  Expired bans are forgotten.
+fn is_banned(banned: &Arc<Mutex<HashMap<IpAddr, Instant>>>, ip: IpAddr) -> bool {
+    let mut banned = banned.lock().unwrap();
+    let expires = match banned.get(&ip) {
+        None => return false,
+        Some(expires) => expires.clone(),
+    };
+    if expires > Instant::now() {
+        return true;
+    }
+    banned.remove(&ip);
+    return false;
+}
+
+// Is this ip address opening connections too fast?  Optionally ban it.
+fn is_connection_flood(
+    stratum_id: &String,
+    config: &Config,
+    conn_limiter: &Arc<Mutex<IpRateLimiter>>,
+    banned: &Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    ip: IpAddr,
+) -> bool {
+    let max_conns = config.workers.max_conns_per_ip_per_min;
+    if max_conns == 0 || conn_limiter.lock().unwrap().check(ip) {
+        return false;
+    }
+    warn!(
+        "{} - Worker Listener - Dropping connection from ip: {} - more than {} connections per minute",
+        stratum_id, ip, max_conns
+    );
+    if config.workers.ban_connection_floods {
+        let expires = Instant::now() + Duration::from_secs(config.workers.ban_duration_secs);
+        banned.lock().unwrap().insert(ip, expires);
+    }
+    return true;
+}
+
+// Admit a new connection as a worker, or turn it away if the pool is full
+fn add_worker(
+    stratum_id: &String,
+    config: &Config,
+    stream: TcpStream,
+    worker_addr: SocketAddr,
+    difficulty: u64,
+    new_workers: &Sender<Worker>,
+    pool_connections: &PoolConnections,
+    login_limiter: &Arc<Mutex<IpRateLimiter>>,
+    ip_connections: &IpConnections,
+    counters: &ConnectionCounters,
+) {
+    let admitted = match PoolRef::acquire(pool_connections, config.workers.max_connections) {
+        None => Err(RejectReason::PoolFull),
+        Some(pool_ref) => IpRef::acquire(ip_connections, worker_addr.ip(), config.workers.max_connections_per_ip)
+            .map(|ip_ref| (pool_ref, ip_ref))
+            .ok_or(RejectReason::TooManyConnections),
+    };
+    match admitted {
+        Err(reason) => {
+            ConnectionCounters::count(&counters.rejected_full);
+            warn!(
+                "{} - Worker Listener - Rejecting connection from ip: {} - {}",
+                stratum_id, worker_addr, reason.message()
+            );
+            let mut stream = BufStream::new(stream);
+            send_rejection(&mut stream, &reason);
+            let _ = stream.get_ref().shutdown(Shutdown::Both);
+        }
+        Ok((pool_ref, ip_ref)) => {
+            // Stratum messages are small and latency sensitive, dont let Nagle hold them back
+            if let Err(e) = stream.set_nodelay(true) {
+                warn!(
+                    "{} - Worker Listener - Failed to set nodelay for ip: {} - {:?}",
+                    stratum_id, worker_addr, e
+                );
+            }
+            let stream = match WorkerStream::accept(
+                stream,
+                config.workers.enable_websocket,
+                config.workers.outbound_queue_depth,
+            ) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    ConnectionCounters::count(&counters.dropped_error);
+                    warn!(
+                        "{} - Worker Listener - Dropping ip: {} - {}",
+                        stratum_id, worker_addr, e
+                    );
+                    return;
+                }
+            };
+            ConnectionCounters::count(&counters.accepted);
+            let peer = format!("ip: {}", worker_addr);
+            hand_off_worker(stratum_id, config, stream, &peer, difficulty, new_workers, login_limiter, pool_ref, Some(ip_ref));
+        }
+    }
+}
+
+// Run in a thread. Hands connections on the unix socket to the main loop as workers.
+// They come from this host, so there is no ip address to ban or limit.
+fn accept_unix_workers(
+    stratum_id: String,
+    config: Config,
+    listener: UnixListener,
+    path: String,
+    difficulty: u64,
+    new_workers: Sender<Worker>,
+    pool_connections: PoolConnections,
+    login_limiter: Arc<Mutex<IpRateLimiter>>,
+) {
+    let peer = format!("unix socket: {}", path);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let pool_ref = match PoolRef::acquire(&pool_connections, config.workers.max_connections) {
+                    Some(pool_ref) => pool_ref,
+                    None => {
+                        let reason = RejectReason::PoolFull;
+                        warn!(
+                            "{} - Worker Listener - Rejecting connection from {} - {}",
+                            stratum_id, peer, reason.message()
+                        );
+                        let mut stream = BufStream::new(stream);
+                        send_rejection(&mut stream, &reason);
+                        let _ = stream.get_ref().shutdown(Shutdown::Both);
+                        continue;
+                    }
+                };
+                warn!("Worker Listener - New connection from {}", peer);
+                hand_off_worker(&stratum_id, &config, WorkerStream::Unix(stream), &peer, difficulty, &new_workers, &login_limiter, pool_ref, None);
+            }
+            Err(e) => {
+                warn!(
+                    "{} - Worker Listener - Error accepting connection on {}: {:?}", stratum_id, peer, e
+                );
+            }
+        }
+    }
+}
+
+// Tell a connection we are turning away why
+fn send_rejection<S: Read + Write>(stream: &mut BufStream<S>, reason: &RejectReason) {
+    let _ = StratumProtocol::new().send_error_response(
+        stream,
+        "login".to_string(),
+        reason.rpc_error(),
+        Some("0".to_string()),
+    );
+}
+
+// Make a worker of an admitted connection, from any listener, and send it
+// to the main loop to be added to the workers list
+fn hand_off_worker(
+    stratum_id: &String,
+    config: &Config,
+    stream: WorkerStream,
+    peer: &str,
+    difficulty: u64,
+    new_workers: &Sender<Worker>,
+    login_limiter: &Arc<Mutex<IpRateLimiter>>,
+    pool_ref: PoolRef,
+    ip_ref: Option<IpRef>,
+) {
+    if let Err(e) = stream.set_nonblocking(true) {
+        warn!(
+            "{} - Worker Listener - Failed to set nonblocking, dropping {} - {:?}",
+            stratum_id, peer, e
+        );
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    }
+    // Replaces the WebSocket handshake timeout, and bounds any read that does block
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(config.workers.read_timeout_secs))) {
+        warn!(
+            "{} - Worker Listener - Failed to set read timeout for {} - {:?}",
+            stratum_id, peer, e
+        );
+    }
+    let stream = BufStream::with_capacities(
+        config.workers.read_buffer_size,
+        config.workers.write_buffer_size,
+        stream,
+    );
+    let mut worker = Worker::new(config.clone(), stream);
+    worker.set_difficulty(difficulty);
+    worker.set_login_limiter(login_limiter.clone());
+    worker.set_pool_ref(pool_ref);
+    if let Some(ip_ref) = ip_ref {
+        // The address the connection was admitted for, not the load balancers
+        worker.set_ip(ip_ref.ip());
+        worker.set_ip_ref(ip_ref);
+    }
+    if new_workers.send(worker).is_err() {
+        warn!("{} - Worker Listener - The pool is gone, dropping {}", stratum_id, peer);
+    }
+}
+
+// Bind the unix socket, replacing a socket file left by an earlier run
+fn bind_unix_workers(path: &str) -> Result<UnixListener, String> {
+    if let Ok(metadata) = fs::metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("Not binding to {}, it is not a socket", path));
+        }
+        fs::remove_file(path).map_err(|e| format!("Failed to remove old socket {}: {}", path, e))?;
+    }
+    UnixListener::bind(path).map_err(|e| format!("Failed to bind to unix socket {}: {}", path, e))
+}
+
+// Outcome of the checks made before a share is validated
+enum ShareCheck {
+    RateLimited,
+    NonceOutOfRange, // Outside the nonce range sent with its job
+    Duplicate,
+    InvalidSize,
+    InvalidProofSize,
+    Stale(u64), // Blocks behind the current height
+    DeepStale(u64), // Further behind than honest miners get, or for a height never seen
+    LowDifficulty, // Below the lowest difficulty the worker could be asked for
+    UnknownJob,
+    Pending(usize), // Index of its ValidationResult
+}
+
+// A share left queued by a worker that was dropped before it was processed
+struct OrphanedShare {
+    worker_id: String, // uuid of the dropped worker
+    full_id: String,   // Credited for the share as if the worker was still connected
+    tag: Option<String>,
+    user_id: usize,
+    ip: Option<IpAddr>, // Banned if the dropped workers shares keep failing
+    invalid_shares: usize, // The workers invalid shares in the minute before it was dropped
+    difficulty: u64, // The workers difficulty when it was dropped
+    share: SubmitParams,
+}
+
+impl OrphanedShare {
+    // Take the shares a worker queued but we never processed, at most share_burst of them.
+    // They go through the workers rate limit and nonce range like live shares,
+    // returns the shares kept and how many were dropped.
+    fn take_all(worker: &mut Worker, config: &WorkerConfig) -> (Vec<OrphanedShare>, usize) {
+        let shares = match worker.get_shares() {
+            Ok(Some(shares)) => shares,
+            _ => return (vec![], 0),
+        };
+        if !worker.authenticated() {
+            // No one to credit them to
+            return (vec![], shares.len());
+        }
+        let total = shares.len();
+        let mut orphans = vec![];
+        for share in shares {
+            if orphans.len() >= config.share_burst as usize || !worker.share_rate_limiter.try_take() {
+                break;
+            }
+            if !worker.nonce_in_range(&share) {
+                worker.nonce_range_violations += 1;
+                if config.enforce_nonce_range {
+                    continue;
+                }
+            }
+            orphans.push(OrphanedShare {
+                worker_id: worker.uuid(),
+                full_id: worker.full_id(),
+                tag: worker.worker_shares.tag.clone(),
+                user_id: worker.user_id(),
+                ip: worker.ip(),
+                invalid_shares: worker.recent_invalid_shares(),
+                difficulty: worker.status.difficulty,
+                share: share,
+            });
+        }
+        let dropped = total - orphans.len();
+        (orphans, dropped)
+    }
+}
+
+// A workers stats for the block that just ended, for logstash to send to rmq.
+// Accounting depends on every one of these, they are never sampled.
+fn log_worker_shares(worker: &Worker) {
+    error!(target: "worker_shares", "{:?}", worker.worker_shares);
+}
+
+// Job heights remembered to tell lagging miners from ones sending old work
+const MAX_RECENT_HEIGHTS: usize = 16;
+
+// Finished rounds kept in memory
+const MAX_PAST_ROUNDS: usize = 100;
+
+// How often the submitted pows are saved to disk
+const DUPLICATES_SAVE_SECS: u64 = 60;
+
+// Poller token of the upstream server connection, workers count up from 1
+const SERVER_TOKEN: usize = 0;
+
+// Wait for a socket to become readable, level triggered so unread data wakes us again
+fn register_fd(poll: &Poll, fd: RawFd, token: usize) -> io::Result<()> {
+    let evented = EventedFd(&fd);
+    match poll.register(&evented, Token(token), Ready::readable(), PollOpt::level()) {
+        Ok(_) => Ok(()),
+        // The same fd number is still registered from a connection that was replaced
+        Err(_) => poll.reregister(&evented, Token(token), Ready::readable(), PollOpt::level()),
+    }
 }
 
 // ----------------------------------------
@@ -110,76 +574,402 @@ pub struct Pool {
     job: JobTemplate,
     config: Config,
     server: Server,
-    difficulty: u64,
     workers: Arc<Mutex<HashMap<String, Worker>>>,
-    duplicates: HashMap<Vec<u64>, usize>, // pow vector, worker id who first submitted it
-    job_versions: HashMap<u64, String>,   // pre_pow string, job_id version
+    new_workers: Receiver<Worker>, // Accepted by the listener threads, added to workers by the main loop
+    new_worker_sender: Sender<Worker>, // Cloned for each listener thread
+    pool_connections: PoolConnections, // Connected workers, counting those not yet in workers
+    duplicates: Duplicates, // (pow, height, job_id) submitted in the last few blocks
+    recent_heights: VecDeque<u64>, // Heights of the last few jobs, newest last - they change on reorgs too
+    job_change_time: Instant, // When the height last changed, starts the stale grace period
+    job_versions: JobVersions, // pre_pow of each job version sent at this height
+    job_id_codec: JobIdCodec, // How upstream job_ids become the job_ids workers see
+    orphaned_shares: VecDeque<OrphanedShare>, // Shares from dropped workers, processed first
+    pplns: Arc<Mutex<PplnsWindow>>, // Last N accepted shares for payouts
+    db: Arc<Mutex<Connection>>, // Share history, workers, and found blocks
+    found_block: Option<(u64, String, String)>, // height, hash, worker id of a block we submitted at this height
+    announced_blocks: HashSet<String>, // Hashes of blocks found at this height
+    port_difficulty: Arc<RwLock<HashMap<u64, u64>>>, // Listen port, starting difficulty for new workers
+    listeners: HashMap<u64, (SocketAddr, Sender<()>)>, // Configured port, where it is bound, stops its listener thread
+    config_updates: Option<Receiver<Config>>, // Reloaded config files
+    sessions: Sessions, // Stats of recently disconnected workers
+    transactions: Option<(u64, Result<String, RpcError>)>, // The nodes answer for mining.get_transactions at a height, or why there is none
+    transactions_fetch: Option<(u64, Receiver<Result<String, RpcError>>)>, // The request to the node for them, sent on its own thread
+    rounds: Arc<Mutex<Rounds>>, // Share counts between blocks found by the pool
+    audit_log: Option<Mutex<ShareAuditLog>>, // Every share decision as a line of JSON
+    auth_audit_log: AuthAuditLog, // Every login attempt, failed ones counted to ban their ip
+    share_log: Option<Mutex<ShareLogger>>, // Every share outcome for accounting, rotated by size
+    influxdb: Option<Mutex<InfluxDbExporter>>, // Worker stats for Grafana dashboards
+    banned: Arc<Mutex<HashMap<IpAddr, Instant>>>, // ip address, when the ban expires
+    login_limiter: Arc<Mutex<IpRateLimiter>>, // Login attempts per ip address
+    conn_limiter: Arc<Mutex<IpRateLimiter>>, // New connections per ip address
+    ip_connections: IpConnections, // Connected workers per ip address
+    poll: Poll, // Wakes the main loop when a socket is readable
+    events: Events,
+    ready: HashSet<usize>, // Poller tokens of the workers with messages to read
+    server_fd: Option<RawFd>, // The upstream connection registered with the poller
+    last_job_refresh: Instant, // When we last got or asked for a job template
+    job_throttle: JobBroadcastThrottle, // Spaces out broadcasts of newer jobs at the same height
+    difficulty_controller: Option<PoolDifficultyController>, // Moves the difficulty of every worker towards target_submit_rate
+    last_difficulty_adjustment: Instant,
+    validate_share: fn(&PendingShare) -> ValidationResult, // Checks a shares proof of work
+    sampler: Arc<Mutex<HashrateSampler>>, // Pool hashrate history
+    histogram: Arc<Mutex<DifficultyHistogram>>, // Accepted shares by the difficulty they had
+    network: Arc<Mutex<NetworkDifficulty>>, // Difficulty of the block being mined, from the job header
+    upstream_state: Arc<Mutex<ServerState>>, // The upstream nodes state, for the api
+    header_errors: Arc<AtomicUsize>, // Shares whose block header could not be built from their job
+    connections: Arc<ConnectionCounters>, // Worker connections accepted, rejected, and dropped
+    poll_interval: PollInterval, // How long the main loop waits for socket events
+    poll_timeout: Duration, // The next wait, the timers run at least this often
+    interval_graphs: f64, // C31 graphs accepted since the last hashrate sample
+    last_hashrate_sample: Instant,
+    last_duplicates_save: Instant,
+    last_stats_snapshot: Instant,
+    log_levels: ModuleLevels, // Changed through the api
+    log_sampler: LogSampler, // Thins out the per job and per share diagnostics
+    shutdown: Arc<AtomicBool>, // Set from the signal handler, the main loop then saves and returns
 }
 
 impl Pool {
     /// Create a new Grin Stratum Pool
-    pub fn new(config: Config) -> Pool {
+    pub fn new(config: Config, db: Arc<Mutex<Connection>>) -> Pool {
+        let (new_worker_sender, new_workers) = channel();
         Pool {
             id: "Grin Pool".to_string(),
             job: JobTemplate::new(),
             config: config.clone(),
             server: Server::new(config.clone()),
-            difficulty: 1,
             workers: Arc::new(Mutex::new(HashMap::new())),
-            duplicates: HashMap::new(),
-            job_versions: HashMap::new(),
+            new_workers: new_workers,
+            new_worker_sender: new_worker_sender,
+            pool_connections: Arc::new(AtomicUsize::new(0)),
+            recent_heights: VecDeque::new(),
+            job_change_time: Instant::now(),
+            duplicates: {
+                let mut duplicates =
+                    Duplicates::load(&config.grin_pool.duplicates_file, config.grin_pool.max_tracked_duplicates);
+                if config.grin_pool.share_duplicates {
+                    if let Err(e) = duplicates.connect_redis(&config.redis.address, config.redis.port) {
+                        warn!("{} - only this pool detects duplicate shares", e);
+                    }
+                }
+                duplicates
+            },
+            job_versions: {
+                let mut job_versions = JobVersions::new(
+                    config.grin_pool.max_job_versions,
+                    config.grin_pool.job_version_ttl_secs,
+                );
+                if config.grin_pool.persist_job_versions {
+                    match job_versions.connect_redis(&config.redis.address, config.redis.port) {
+                        Ok(count) => warn!("Reloaded {} job versions from REDIS", count),
+                        Err(e) => error!("{} - job versions are only kept in memory", e),
+                    }
+                }
+                job_versions
+            },
+            job_id_codec: JobIdCodec::from_name(&config.grin_pool.job_id_encoding).unwrap_or(JobIdCodec::HeightPrefix),
+            orphaned_shares: VecDeque::new(),
+            pplns: {
+                let mut pplns = PplnsWindow::load(&config.grin_pool.pplns_file, config.grin_pool.pplns_window);
+                pplns.set_max_age(config.grin_pool.pplns_window_secs);
+                pplns.set_fee(config.grin_pool.fee_percent, config.grin_pool.fee_address.clone());
+                Arc::new(Mutex::new(pplns))
+            },
+            db: db,
+            found_block: None,
+            announced_blocks: HashSet::new(),
+            port_difficulty: Arc::new(RwLock::new(
+                config
+                    .workers
+                    .port_difficulty
+                    .iter()
+                    .map(|p| (p.port, p.difficulty))
+                    .collect(),
+            )),
+            listeners: HashMap::new(),
+            config_updates: None,
+            sessions: Sessions::new(config.workers.reconnect_grace_secs),
+            transactions: None,
+            transactions_fetch: None,
+            rounds: Arc::new(Mutex::new(Rounds::new(MAX_PAST_ROUNDS))),
+            audit_log: match config.grin_pool.share_audit_file {
+                None => None,
+                Some(ref path) => match ShareAuditLog::open(path) {
+                    Ok(audit_log) => Some(Mutex::new(audit_log)),
+                    Err(e) => {
+                        error!("Failed to open share audit log, not auditing shares: {}", e);
+                        None
+                    }
+                },
+            },
+            auth_audit_log: AuthAuditLog::new(
+                match config.grin_pool.auth_audit_log_path {
+                    None => None,
+                    Some(ref path) => match ShareAuditLog::open(path) {
+                        Ok(auth_log) => Some(auth_log),
+                        Err(e) => {
+                            error!("Failed to open auth audit log, not auditing logins: {}", e);
+                            None
+                        }
+                    },
+                },
+                config.workers.max_auth_failures_per_hour,
+            ),
+            share_log: match config.grin_pool.share_log_file {
+                None => None,
+                Some(ref path) => match ShareLogger::open(
+                    path,
+                    config.grin_pool.share_log_max_bytes,
+                    config.grin_pool.share_log_max_files,
+                ) {
+                    Ok(share_log) => Some(Mutex::new(share_log)),
+                    Err(e) => {
+                        error!("Failed to open share log, not logging shares: {}", e);
+                        None
+                    }
+                },
+            },
+            influxdb: match config.metrics.influxdb_address {
+                None => None,
+                Some(ref address) => match InfluxDbExporter::new(address, config.metrics.influxdb_batch_size) {
+                    Ok(influxdb) => Some(Mutex::new(influxdb)),
+                    Err(e) => {
+                        error!("Failed to set up InfluxDB export, not exporting worker stats: {}", e);
+                        None
+                    }
+                },
+            },
+            banned: Arc::new(Mutex::new(HashMap::new())),
+            login_limiter: Arc::new(Mutex::new(IpRateLimiter::new(
+                config.workers.max_login_attempts_per_minute,
+            ))),
+            conn_limiter: Arc::new(Mutex::new(IpRateLimiter::new(
+                config.workers.max_conns_per_ip_per_min,
+            ))),
+            ip_connections: Arc::new(Mutex::new(HashMap::new())),
+            poll: Poll::new().expect("Failed to create the socket poller"),
+            events: Events::with_capacity(1024),
+            ready: HashSet::new(),
+            server_fd: None,
+            last_job_refresh: Instant::now(),
+            job_throttle: JobBroadcastThrottle::new(Duration::from_millis(config.grin_pool.min_job_broadcast_interval_ms)),
+            difficulty_controller: match config.workers.port_difficulty.first() {
+                Some(port) if config.grin_pool.target_submit_rate > 0.0 => {
+                    Some(PoolDifficultyController::new(config.grin_pool.target_submit_rate, port.difficulty))
+                }
+                _ => None,
+            },
+            last_difficulty_adjustment: Instant::now(),
+            validate_share: if config.grin_pool.verify_shares {
+                validator::validate_share
+            } else {
+                warn!("Share verification is off - UNSAFE unless every miner is trusted");
+                validator::validate_share_unverified
+            },
+            sampler: Arc::new(Mutex::new(HashrateSampler::new())),
+            histogram: Arc::new(Mutex::new(DifficultyHistogram::new(
+                config.grin_pool.difficulty_histogram_buckets.clone(),
+                Duration::from_secs(config.grin_pool.difficulty_histogram_window_secs),
+            ))),
+            network: Arc::new(Mutex::new(NetworkDifficulty::new())),
+            upstream_state: Arc::new(Mutex::new(ServerState::Disconnected)),
+            header_errors: Arc::new(AtomicUsize::new(0)),
+            connections: Arc::new(ConnectionCounters::default()),
+            poll_interval: PollInterval::new(
+                Duration::from_millis(config.grin_pool.server_poll_interval_ms),
+                Duration::from_millis(config.grin_pool.server_poll_max_interval_ms),
+            ),
+            poll_timeout: Duration::from_millis(config.grin_pool.server_poll_interval_ms),
+            interval_graphs: 0.0,
+            last_hashrate_sample: Instant::now(),
+            last_duplicates_save: Instant::now(),
+            last_stats_snapshot: Instant::now(),
+            log_levels: ModuleLevels::default(),
+            log_sampler: LogSampler::new(config.grin_pool.log_sample_rate),
+            shutdown: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Run the Pool
-    pub fn run(&mut self) {
-        // Start a thread to listen on port and accept new worker connections
-        let mut workers_th = self.workers.clone();
-        let id_th = self.id.clone();
-        let config_th = self.config.clone();
-        let _listener_th = thread::spawn(move || {
-            accept_workers(id_th, config_th, &mut workers_th);
-        });
+    /// The module log levels of the installed logger, for the api to change
+    pub fn set_log_levels(&mut self, log_levels: ModuleLevels) {
+        self.log_levels = log_levels;
+    }
+
+    /// Set to stop the main loop, run then saves what must survive a restart and returns
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    /// Run the Pool - returns if it can not start, or once it is shut down
+    pub fn run(&mut self) -> Result<(), String> {
+        self.config.validate().map_err(|e| format!("Invalid config: {}", e))?;
+        // Start a thread per port to listen and accept new worker connections
+        for port_difficulty in self.config.workers.port_difficulty.clone() {
+            self.start_listener(port_difficulty.port)?;
+        }
+        // And one for local proxies on the unix socket
+        if let Some(path) = self.config.workers.unix_socket_path.clone() {
+            let listener = bind_unix_workers(&path)?;
+            let difficulty = self.config.workers.unix_socket_difficulty;
+            let new_workers_th = self.new_worker_sender.clone();
+            let pool_connections_th = self.pool_connections.clone();
+            let id_th = self.id.clone();
+            let config_th = self.config.clone();
+            let login_limiter_th = self.login_limiter.clone();
+            let _listener_th = thread::spawn(move || {
+                accept_unix_workers(
+                    id_th,
+                    config_th,
+                    listener,
+                    path,
+                    difficulty,
+                    new_workers_th,
+                    pool_connections_th,
+                    login_limiter_th,
+                );
+            });
+        }
+
+        // Start the stats api in its own thread
+        if self.config.grin_pool.api_port > 0 {
+            let address = format!("0.0.0.0:{}", self.config.grin_pool.api_port);
+            let state = ApiState {
+                workers: self.workers.clone(),
+                pplns: self.pplns.clone(),
+                db: self.db.clone(),
+                sampler: self.sampler.clone(),
+                histogram: self.histogram.clone(),
+                network: self.network.clone(),
+                upstream_state: self.upstream_state.clone(),
+                header_errors: self.header_errors.clone(),
+                connections: self.connections.clone(),
+                admin_token: self.config.grin_pool.api_admin_token.expose().to_string(),
+                log_levels: self.log_levels.clone(),
+            };
+            let _api_th = thread::spawn(move || {
+                api::start(address, state);
+            });
+        }
+
+        // Start the admin control listener in its own thread
+        if self.config.grin_pool.admin_port > 0 {
+            let address = socket_address(&self.config.grin_pool.admin_address, self.config.grin_pool.admin_port)?.to_string();
+            let secret = self.config.grin_pool.admin_secret.expose().to_string();
+            let state = AdminState {
+                workers: self.workers.clone(),
+                banned: self.banned.clone(),
+            };
+            let _admin_th = thread::spawn(move || {
+                admin::start(address, secret, state);
+            });
+        }
 
-        // Set default pool difficulty
-        self.difficulty = self.config.workers.port_difficulty.difficulty;
+        // Pick up config file changes without a restart
+        if let Err(e) = self.watch_config(config::CONFIG_FILE_NAME) {
+            error!("{} - Config changes will need a restart: {}", self.id, e);
+        }
 
         // ------------
         // Main loop
         loop {
             // XXX TODO: Error checking
 
+            if self.shutdown.load(Ordering::SeqCst) {
+                self.shut_down();
+                return Ok(());
+            }
+
+            // Write out whatever the workers queued last time around
+            self.flush_workers();
+
+            // Drop an upstream that went silent, the connect below replaces it
+            if !self.server.is_healthy() {
+                self.server.drop_if_stalled();
+            }
+
             // (re)connect if server is not connected or is in error state
             match self.server.connect() {
                 Ok(_) => { } // server.connect method also logs in and requests a job
                 Err(e) => {
                     error!(
-                        "{} - Unable to connect to upstream server: {}", self.id, e
+                        "{} - Unable to connect to any upstream server, next try {}: {}",
+                        self.id,
+                        self.server.current_upstream(),
+                        e
                     );
-                    thread::sleep(time::Duration::from_secs(1));
+                    self.update_upstream_state();
+                    let backoff = self.server.next_backoff();
+                    thread::sleep(backoff);
                     continue;
                 }
             }
 
-            // check the server for messages and handle them
-            let _ = self.process_server_messages();
+            // Sleep until there is something to read, or it is time to run the timers
+            self.register_server();
+            let timeout = self.poll_timeout;
+            self.wait_for_events(timeout);
+
+            // check the server for messages and handle them, a new job means polling again right away
+            let new_job = self.process_server_messages().unwrap_or(false);
+            self.poll_timeout = self.poll_interval.next(new_job);
 
             // if the server gave us a new block
             let _ = self.accept_new_job();
 
+            // Send the newest job held back by the broadcast throttle
+            self.broadcast_pending_job();
+
+            // Pick up better templates for the current block
+            self.refresh_job();
+
+            // Keep a quiet upstream talking so a dead one is noticed
+            self.server.heartbeat();
+
+            // Add the workers the listeners accepted since the last pass
+            self.add_new_workers();
+
             // Process messages from the workers
             let _ = self.process_worker_messages();
 
+            // Answer mining.get_transactions requests
+            self.send_transactions();
+
             // Process worker shares
             let _ = self.process_shares();
 
+            // Send the shares worth submitting upstream together
+            if let Err(e) = self.server.flush_submits() {
+                error!("{} - Failed to submit shares upstream: {}", self.id, e);
+            }
+
+            // Move worker difficulties towards their share rate
+            self.retarget_workers();
+
+            // Or, without vardiff, the pool difficulty towards the submission rate
+            self.adjust_pool_difficulty();
+
             // Send jobs to needy workers
             let _ = self.send_jobs();
 
             // Delete workers in error state
             let _num_active_workers = self.clean_workers();
 
-            thread::sleep(time::Duration::from_millis(1));
+            // Apply a reloaded config file
+            self.check_config_reload();
+
+            // Record the pool hashrate once a minute
+            self.sample_hashrate();
+
+            // Forget the pows of the last generation once they can only be stale
+            if !self.in_stale_grace_period() {
+                self.duplicates.drop_previous();
+            }
+
+            // Checkpoint the submitted pows so a restart does not forget them
+            self.save_duplicates();
+
+            // Write the worker stats for tools that can not reach the api
+            self.save_stats_snapshot();
         }
     }
 
@@ -189,36 +979,138 @@ impl Pool {
 
     // Process messages from the upstream server
     // Will contain job requests, submit results, status results, etc...
-    fn process_server_messages(&mut self) -> Result<(), RpcError> {
-        match self.server.process_messages(&mut self.workers) {
-            Ok(_) => {
-                return Ok(());
+    // Returns whether the upstream sent a new job
+    fn process_server_messages(&mut self) -> Result<bool, RpcError> {
+        let result = self.server.process_messages(&mut self.workers);
+        self.update_upstream_state();
+        match result {
+            Ok(method) => {
+                return Ok(method == "job" || method == "getjobtemplate");
             }
             Err(e) => {
                 // Log an error
                 error!(
                     "{} - Error processing upstream message: {:?}", self.id, e
                 );
-                // There are also special case(s) where we want to do something for a specific
-                // error
-                if e.message.contains("Node is syncing") {
-                    thread::sleep(time::Duration::from_secs(2));
-                }
                 return Err(e);
             }
         }
     }
 
+    // Publish the upstream state, logging changes.  The server asks a
+    // syncing node for work again itself, workers stand by until then
+    fn update_upstream_state(&mut self) {
+        let state = self.server.state();
+        let previous = mem::replace(&mut *self.upstream_state.lock().unwrap(), state);
+        if state != previous {
+            warn!("{} - Upstream {} is now {:?}, was {:?}", self.id, self.server.current_upstream(), state, previous);
+        }
+    }
+
+    // The node has no work to give until it catches up
+    fn upstream_syncing(&self) -> bool {
+        self.server.state() == ServerState::Syncing
+    }
+
+    // Register the upstream connection with the poller after each (re)connect
+    fn register_server(&mut self) {
+        let fd = self.server.raw_fd();
+        if fd == self.server_fd {
+            return;
+        }
+        if let Some(fd) = fd {
+            if let Err(e) = register_fd(&self.poll, fd, SERVER_TOKEN) {
+                error!("{} - Failed to poll the upstream connection: {:?}", self.id, e);
+                return;
+            }
+        }
+        self.server_fd = fd;
+    }
+
+    // Block until a worker or the upstream server has something to read, or the timeout
+    fn wait_for_events(&mut self, timeout: Duration) {
+        // Messages already read into a buffer dont wake the poller
+        let timeout = if self.ready.is_empty() && !self.server.has_buffered_input() {
+            timeout
+        } else {
+            Duration::from_millis(0)
+        };
+        if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+            error!("{} - Failed to poll sockets: {:?}", self.id, e);
+            return;
+        }
+        for event in self.events.iter() {
+            if event.token().0 != SERVER_TOKEN {
+                self.ready.insert(event.token().0);
+            }
+        }
+    }
+
     fn process_worker_messages(&mut self) {
         let mut id_changed: Vec<String> = vec![];
         let mut w_m = self.workers.lock().unwrap();
         for (worker_uuid, worker) in w_m.iter_mut() {
+            if !worker.registered {
+                // New from the accept thread, it may already have sent something
+                match register_fd(&self.poll, worker.raw_fd(), worker.poll_token()) {
+                    Ok(_) => worker.registered = true,
+                    Err(e) => {
+                        error!("{} - Failed to poll worker {}: {:?}", self.id, worker.uuid(), e);
+                        worker.set_error();
+                        continue;
+                    }
+                }
+            } else if !self.ready.remove(&worker.poll_token()) {
+                continue; // Nothing to read
+            }
             let res = worker.process_messages();
+            if worker.has_buffered_input() {
+                self.ready.insert(worker.poll_token());
+            }
+            for attempt in worker.take_auth_attempts() {
+                if self.auth_audit_log.record(worker.ip(), &attempt) {
+                    warn!(
+                        "{} - Banning worker {} at {:?} - more than {} failed logins per hour",
+                        self.id,
+                        worker.uuid(),
+                        worker.ip(),
+                        self.config.workers.max_auth_failures_per_hour,
+                    );
+                    if let Some(ip) = worker.ip() {
+                        let expires = Instant::now() + Duration::from_secs(self.config.workers.ban_duration_secs);
+                        self.banned.lock().unwrap().insert(ip, expires);
+                    }
+                    worker.set_error();
+                }
+            }
             if worker_uuid != &*worker.uuid() {
                 // User id changed - probably because they logged in
                 id_changed.push(worker_uuid.clone());
                 debug!("id changed:  uuid {} - {:?}", worker.uuid().clone(), res );
-                worker.reset_worker_shares(self.job.height, self.difficulty);
+            }
+        }
+        for orig_id in id_changed.iter() {
+            // Two connections logged in with the same name must not share a full_id
+            let taken: HashSet<String> = w_m
+                .iter()
+                .filter(|&(uuid, worker)| uuid != orig_id && worker.authenticated())
+                .map(|(_, worker)| worker.full_id())
+                .collect();
+            let worker = match w_m.get_mut(orig_id) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            if worker.make_full_id_unique(&taken) {
+                warn!("{} - Duplicate login {}, worker renamed to {}", self.id, worker.login(), worker.full_id());
+            }
+            let difficulty = worker.status.difficulty;
+            worker.reset_worker_shares(self.job.height, difficulty);
+            // Pick up where a recently dropped connection from this miner left off
+            if self.sessions.restore(worker, self.job.height) {
+                debug!("{} - Restored stats for reconnected worker {}", self.id, worker.full_id());
+            }
+            if let Err(e) = db::upsert_worker(&self.db.lock().unwrap(), &worker.uuid(), &worker.login(), &worker.worker_shares.rigid) {
+                error!("{} - Failed to record worker login: {:?}", self.id, e);
             }
         }
         // Rehash the worker using updated id
@@ -233,19 +1125,138 @@ impl Pool {
         }
     }
 
+    fn retarget_workers(&mut self) {
+        let mut w_m = self.workers.lock().unwrap();
+        for worker in w_m.values_mut() {
+            worker.retarget_difficulty();
+        }
+    }
+
+    // Move the difficulty of every worker towards the target upstream submission rate
+    fn adjust_pool_difficulty(&mut self) {
+        let interval = Duration::from_secs(self.config.grin_pool.difficulty_adjustment_interval_secs);
+        if self.last_difficulty_adjustment.elapsed() < interval {
+            return;
+        }
+        self.last_difficulty_adjustment = Instant::now();
+        let controller = match self.difficulty_controller {
+            Some(ref mut controller) => controller,
+            None => return,
+        };
+        // The primary ports bounds, a reloaded config may have no ports left
+        let (min, max) = match self.config.workers.port_difficulty.first() {
+            Some(port) => self.config.workers.difficulty_bounds(port.port),
+            None => return,
+        };
+        let old_difficulty = controller.difficulty();
+        let rate = controller.rate();
+        let difficulty = controller.adjust_for_rate(rate, min, max);
+        if difficulty == old_difficulty {
+            return;
+        }
+        warn!(
+            "{} - Pool difficulty changed from {} to {} at {:.2} upstream submissions per second",
+            self.id, old_difficulty, difficulty, rate,
+        );
+        let mut w_m = self.workers.lock().unwrap();
+        for worker in w_m.values_mut() {
+            let previous = worker.status.difficulty;
+            worker.set_difficulty(difficulty);
+            if worker.status.difficulty != previous && worker.authenticated() {
+                worker.needs_job = true;
+            }
+        }
+    }
+
+    // Ask the node once for the workers that requested the transactions at the current height
+    fn send_transactions(&mut self) {
+        self.receive_transactions();
+        if !self.workers.lock().unwrap().values().any(|worker| worker.requested_transactions) {
+            return;
+        }
+        let height = self.job.height;
+        // Failures are kept too, the node is asked once per height
+        let transactions = match self.transactions {
+            Some((cached_height, ref transactions)) if cached_height == height => transactions.clone(),
+            _ => {
+                self.fetch_transactions(height);
+                return;
+            }
+        };
+        let mut w_m = self.workers.lock().unwrap();
+        for worker in w_m.values_mut().filter(|worker| worker.requested_transactions) {
+            let _ = match transactions {
+                Ok(ref transactions) => worker.send_transactions(height, transactions.clone()),
+                Err(ref e) => {
+                    worker.requested_transactions = false;
+                    worker.send_error_response("mining.get_transactions".to_string(), e.clone())
+                }
+            };
+        }
+    }
+
+    // Ask the node for the transactions at height on another thread, the
+    // main loop does not wait for it
+    fn fetch_transactions(&mut self, height: u64) {
+        if self.transactions_fetch.as_ref().map_or(false, |&(fetch_height, _)| fetch_height == height) {
+            return;
+        }
+        let request = self.server.transactions_request();
+        let (tx, rx) = channel();
+        let _fetch_th = thread::spawn(move || {
+            let _ = tx.send(request.send());
+        });
+        self.transactions_fetch = Some((height, rx));
+    }
+
+    // Keep the nodes answer, if it came
+    fn receive_transactions(&mut self) {
+        let received = match self.transactions_fetch {
+            Some((height, ref rx)) => match rx.try_recv() {
+                Ok(transactions) => (height, transactions),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => (height, Err(RpcError {
+                    code: -32500,
+                    message: "Node api request failed".to_string(),
+                })),
+            },
+            None => return,
+        };
+        self.transactions_fetch = None;
+        if let Err(ref e) = received.1 {
+            error!("{} - Failed to get transactions for height {}: {}", self.id, received.0, e.message);
+        }
+        self.transactions = Some(received);
+    }
+
     fn send_jobs(&mut self) {
+        let syncing = self.upstream_syncing();
         let mut w_m = self.workers.lock().unwrap();
         for (worker_uuid, worker) in w_m.iter_mut() {
-            if worker.needs_job && worker.authenticated {
-                warn!("job to: {} - needs_job: {}, requested_job: {}, authenticated: {}", worker_uuid, worker.needs_job, worker.requested_job, worker.authenticated );
+            if syncing {
+                // Miners asking for work are told to wait, the rest get a job once the node has one
+                if worker.requested_job {
+                    worker.requested_job = false;
+                    let _ = worker.send_err("getjobtemplate".to_string(), RejectReason::NodeSyncing);
+                }
+                continue;
+            }
+            if worker.needs_job && worker.authenticated() {
+                if let Some(skipped) = self.log_sampler.sample("job") {
+                    warn!(
+                        "job to: {} - needs_job: {}, requested_job: {}, authenticated: {} ({} similar skipped)",
+                        worker_uuid, worker.needs_job, worker.requested_job, worker.authenticated(), skipped,
+                    );
+                }
                 // Randomize the nonce
                 // XXX TODO (We do have the deserialized block header code so we can do this now)
-                worker.set_difficulty(self.difficulty);
                 worker.set_height(self.job.height);
-                // Print this workers worker_shares (previous block) for logstash to send to rmq
-                error!("{:?}", worker.worker_shares);
-                // Reset the workers current block stats
-                worker.reset_worker_shares(self.job.height, self.difficulty);
+                if worker.worker_shares.height != self.job.height {
+                    log_worker_shares(worker);
+                    // Reset the workers current block stats
+                    let difficulty = worker.status.difficulty;
+                    worker.reset_worker_shares(self.job.height, difficulty);
+                }
                 worker.send_job(&mut self.job.clone());
             }
         }
@@ -256,164 +1267,741 @@ impl Pool {
         if self.job.pre_pow != self.server.job.pre_pow {
             trace!("accept_new_job for height {}, job_id {}", self.server.job.height, self.server.job.job_id);
             let new_height: bool = self.job.height != self.server.job.height;
+            let reorg: bool = self.server.job.height < self.job.height;
+            if reorg {
+                error!(
+                    "{} - Upstream reorg, height went from {} back to {}",
+                    self.id, self.job.height, self.server.job.height
+                );
+            }
             let mut new_job = self.server.job.clone();
-            // Update the new jobs job_id (bminer wants this)
-            new_job.job_id = new_job.height * 1000 + new_job.job_id;
+            // Update the new jobs job_id (bminer wants it unique across heights)
+            new_job.job_id = match self.job_id_codec.encode(new_job.height, new_job.job_id) {
+                Ok(job_id) => job_id,
+                Err(e) => {
+                    error!("{} - Ignoring new job: {}", self.id, e);
+                    return;
+                }
+            };
             self.job = new_job;
+            self.last_job_refresh = Instant::now();
             // debug!("accept_new_job broadcasting: {}", self.job.pre_pow.clone());
-            // broadcast it to the workers
-            let _ = self.broadcast_job();
+            // broadcast it to the workers, unless it is one of a burst at the same height
+            // Tell miners to drop their old work only when the height changed
+            if self.job_throttle.offer(new_height) {
+                let _ = self.broadcast_job(new_height);
+            } else {
+                debug!("{} - Holding back job {} at height {}, the last was sent too recently", self.id, self.job.job_id, self.job.height);
+            }
+            if reorg {
+                // Shares already queued for the orphaned blocks are stale
+                let mut w_m = self.workers.lock().unwrap();
+                for worker in w_m.values_mut() {
+                    let stale = worker.mark_stale_on_reorg(self.job.height);
+                    if stale > 0 {
+                        warn!("{} - {} queued shares from worker {} are stale after the reorg", self.id, stale, worker.uuid());
+                    }
+                }
+            }
+            match validator::pre_pow_difficulty(&self.job.pre_pow) {
+                Ok((total_difficulty, secondary_scaling)) => {
+                    let mut network = self.network.lock().unwrap();
+                    network.update(self.job.height, total_difficulty, secondary_scaling);
+                    if new_height {
+                        warn!(
+                            "{} - New height {}, network difficulty {} (unscaled {} for C31, {} for C29)",
+                            self.id,
+                            self.job.height,
+                            network.difficulty,
+                            network.unscaled(31),
+                            network.unscaled(29)
+                        );
+                    }
+                }
+                Err(e) => error!(
+                    "{} - Failed to read the job header at height {}, shares for it will be rejected - is the node a newer version? {}",
+                    self.id, self.job.height, e
+                ),
+            }
             if new_height {
-                // clear last block duplicates map
-                self.duplicates.clear();
-                // clear the versions of the previous heights job
-                self.job_versions.clear();
+                self.job_change_time = Instant::now();
+                self.recent_heights.push_back(self.job.height);
+                while self.recent_heights.len() > MAX_RECENT_HEIGHTS {
+                    self.recent_heights.pop_front();
+                }
+                // forget the pows of blocks long gone, after the grace period for the last one
+                self.duplicates.new_height(self.job.height, self.config.grin_pool.duplicate_reset_after_blocks);
+                // clear the versions of older heights jobs, but not the
+                // versions of this height saved before a restart.  The
+                // previous heights are needed for its grace period.
+                let oldest = if self.config.grin_pool.stale_grace_period_ms > 0 {
+                    self.job.height.saturating_sub(1)
+                } else {
+                    self.job.height
+                };
+                self.job_versions.retain_heights(oldest, self.job.height);
+                self.announced_blocks.clear();
+                self.rounds.lock().unwrap().new_height(self.job.height);
+                // the chain moved on, record the block we found at the previous height
+                if let Some((height, hash, found_by)) = self.found_block.take() {
+                    if let Err(e) = db::upsert_block(&self.db.lock().unwrap(), height, &hash, &found_by) {
+                        error!("{} - Failed to record found block: {:?}", self.id, e);
+                    }
+                }
+                // checkpoint the PPLNS window once per block
+                if let Err(e) = self.pplns.lock().unwrap().save(&self.config.grin_pool.pplns_file) {
+                    error!("{} - Failed to save PPLNS window: {}", self.id, e);
+                }
             }
-            self.job_versions.insert(self.job.job_id, self.job.pre_pow.clone());
+            // Shares for any recent version of this heights job stay valid
+            let (job_id, height, pre_pow) = (self.job.job_id, self.job.height, self.job.pre_pow.clone());
+            self.job_versions.insert(job_id, height, pre_pow);
         }
     }
 
+    // Is it still soon enough after the height changed to accept shares for
+    // the previous height?  Timed when the share is checked, which is the
+    // main loop pass after it arrived.
+    fn in_stale_grace_period(&self) -> bool {
+        let grace = Duration::from_millis(self.config.grin_pool.stale_grace_period_ms);
+        self.job_change_time.elapsed() < grace
+    }
 
-    //
-    // Process shares returned by each workers
-    fn process_shares(&mut self) {
-        let mut w_m = self.workers.lock().unwrap();
-        for (worker_uuid, worker) in w_m.iter_mut() {
-            match worker.get_shares().unwrap() {
-                None => {}
-                Some(shares) => {
-                    for mut share in shares {
-                        //  Check for duplicate or add to duplicate map
-                        if self.duplicates.contains_key(&share.pow) {
-                            debug!(
-                                "{} - Rejected duplicate share from worker {} with login {}",
-                                self.id,
-                                worker.uuid(),
-                                worker.login(),
-                            );
-                            worker.status.rejected += 1;
-                            worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                            worker.send_err("submit".to_string(), "Failed to validate solution".to_string(), -32502);
-                            continue; // Dont process this share anymore
-                        } else {
-                            self.duplicates.insert(share.pow.clone(), worker.user_id());
-                        }
-                        // Check that its a valid pow size
-                        if share.edge_bits < 29 || share.edge_bits == 30 {
-                            // Invalid Size
-                            worker.status.rejected += 1;
-                            // worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                            worker.send_err("submit".to_string(), "Invalid POW size".to_string(), -32502);
-                            continue; // Dont process this share anymore
-                        }
-                        // Check solution length (proofsize check in pow verify (#2805))
-                        if share.pow.len() != PROOF_SIZE {
-                            warn!("Share has invalid PROOF_SIZE");
-                            worker.status.rejected += 1;
-                            worker.send_err("submit".to_string(), "Invalid PROOF_SIZE".to_string(), -32502);
-                            continue; // Dont process this share anymore
-                        }
-                        // Check the height to see if its stale
-                        if share.height != self.job.height {
-                            // Its stale
-                            warn!("Share is stale {} vs {}", share.height, self.job.height);
-                            worker.status.stale += 1;
-                            worker.add_shares(share.edge_bits, 0, 0, 1); // Accepted, Rejected, Stale
-                            worker.send_err("submit".to_string(), "Solution submitted too late".to_string(), -32503);
-                            continue; // Dont process this share anymore
-                        }
-                        // Check if the pre-pow matches the job we sent - avoid "constructed solutions"
-                        // A) Construct a BlockHeader from the correct version of the pre-pow and the share pow
-                        match self.job_versions.get(&share.job_id) {
-                            None => {
-                                worker.status.rejected += 1;
-                                worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                                continue // Dont process this share anymore
-                            },
-                            Some(pre_pow) => {
-                                // We need:
-                                //   a) The pre_pow as a vector
-                                //   b) the nonce
-                                //   c) the pow
-                                let bh = match block_header(pre_pow.to_string(), share.edge_bits as u8, share.nonce, share.pow.clone()) {
-                                    Ok(r) => { r },
-                                    Err(e) => { 
-                                        worker.status.rejected += 1;
-                                        worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                                        worker.send_err("submit".to_string(), "Failed to validate solution".to_string(), -32502);
-                                        continue; // Dont process this share anymore
-
-                                    },
-                                };
-                        // B) Call into grin_core::pow::verify_size()
-                                let verify_result = grin_core::pow::verify_size(&bh);
-                                if ! verify_result.is_ok() {
-                                        worker.status.rejected += 1;
-                                        worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                                        worker.send_err("submit".to_string(), "Failed to validate solution".to_string(), -32502);
-                                        continue; // Dont process this share anymore
-                                }
-                                // For debugging - remove
-                                // error!(
-                                //     "Verify Result: {}",
-                                //     verify_result.is_ok(),
-                                // );
-                            }
-                        }
-                        // We check the difficulty here
-                        let proof = MinerProof {
-                            edge_bits: share.edge_bits as u8,
-                            nonces: share.pow.clone().to_vec(),
-                        };
-                        let difficulty = proof.to_difficulty_unscaled().to_num();
-                        // warn!("Difficulty: {}", difficulty);
-                        // Check if this meets worker difficulty
-                        if difficulty < 1 {
-                            worker.status.rejected += 1;
-                            worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                            worker.send_err("submit".to_string(), "Rejected low difficulty solution".to_string(), -32502);
-                            continue; // Dont process this share anymore
-                        }
-                        if difficulty < worker.status.difficulty {
-                            worker.status.rejected += 1;
-                            worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
-                            worker.send_err("submit".to_string(), "Failed to validate solution".to_string(), -32502);
-                            continue; // Dont process this share anymore
-                        }
-                        if difficulty >= worker.status.difficulty {
-                            worker.status.accepted += 1;
-                            worker.add_shares(share.edge_bits, 1, 0, 0); // Accepted, Rejected, Stale
-                            worker.send_ok("submit".to_string());
-                        }
-                        // This is a good share, send it to grin server to be submitted
-                        // Only send high power shares - minimum difficulty is set by the upstream
-                        // grin stratum server
-                        if difficulty >= self.job.difficulty { // XXX TODO <---- this compares scaled to unscaled difficulty values - no good XXX TODO
-                            // remove the block height prefix from the job_id
-                            share.job_id = share.job_id % share.height;
-                            self.server.submit_share(&share.clone(), worker.uuid());
-                            warn!("{} - Submitted share at height {} with nonce {} with difficulty {} from worker {}",
-                                self.id,
-                                share.height,
-                                share.nonce,
-                                worker.status.difficulty,
-                                worker.uuid(),
-                            );
+    // Was a job for this height sent within the last max_benign_stale_depth
+    // heights?  Shares for those are only late, not suspicious
+    fn recently_mined(&self, height: u64) -> bool {
+        let depth = self.config.workers.max_benign_stale_depth as usize;
+        self.recent_heights
+            .iter()
+            .rev()
+            .skip(1)
+            .take(depth)
+            .any(|&recent| recent == height)
+    }
+
+    // Unscaled difficulty a share must have to be worth submitting upstream
+    fn upstream_min_difficulty(&self) -> u64 {
+        match self.config.grin_pool.upstream_min_difficulty {
+            0 => self.job.difficulty,
+            difficulty => difficulty,
+        }
+    }
+
+    // Record the pool hashrate once a sample interval has passed
+    fn sample_hashrate(&mut self) {
+        if self.last_hashrate_sample.elapsed() < Duration::from_secs(SAMPLE_INTERVAL_SECS) {
+            return;
+        }
+        self.last_hashrate_sample = Instant::now();
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => return,
+        };
+        self.sampler.lock().unwrap().push(timestamp, self.interval_graphs);
+        self.interval_graphs = 0.0;
+    }
+
+    // Ask upstream for its latest template now and then - at the same height
+    // it may have more transactions, accept_new_job adopts it if it changed
+    fn refresh_job(&mut self) {
+        let interval = self.config.grin_pool.job_refresh_interval_secs;
+        if interval == 0 || self.last_job_refresh.elapsed() < Duration::from_secs(interval) {
+            return;
+        }
+        self.last_job_refresh = Instant::now();
+        if let Err(e) = self.server.request_job() {
+            warn!("{} - Failed to request a job refresh: {}", self.id, e);
+        }
+    }
+
+    // Save the submitted pows one last time before exiting
+    fn shut_down(&mut self) {
+        warn!("{} - Shutting down", self.id);
+        if let Err(e) = self.duplicates.save(&self.config.grin_pool.duplicates_file) {
+            error!("{} - Failed to save duplicate shares: {}", self.id, e);
+        }
+    }
+
+    // Checkpoint the submitted pows, a restart then still rejects them
+    fn save_duplicates(&mut self) {
+        if self.last_duplicates_save.elapsed() < Duration::from_secs(DUPLICATES_SAVE_SECS) {
+            return;
+        }
+        self.last_duplicates_save = Instant::now();
+        if let Err(e) = self.duplicates.save(&self.config.grin_pool.duplicates_file) {
+            error!("{} - Failed to save duplicate shares: {}", self.id, e);
+        }
+    }
+
+    fn save_stats_snapshot(&mut self) {
+        let path = match self.config.grin_pool.stats_snapshot_path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let interval = Duration::from_secs(self.config.grin_pool.stats_snapshot_interval_secs);
+        if self.last_stats_snapshot.elapsed() < interval {
+            return;
+        }
+        self.last_stats_snapshot = Instant::now();
+        let workers = self
+            .workers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|worker| worker.authenticated())
+            .map(|worker| WorkerSnapshot::new(worker))
+            .collect();
+        if let Err(e) = StatsSnapshot::new(workers).write(&path, self.config.grin_pool.stats_snapshot_keep_count) {
+            error!("{} - Failed to write the stats snapshot: {}", self.id, e);
+        }
+    }
+
+    //
+    // Process shares returned by each workers
+    fn process_shares(&mut self) {
+        // Run the cheap checks under the workers lock, queueing the shares
+        // that pass them for validation.  Outcomes are kept in submission
+        // order since responses are matched to the queued request ids.
+        let mut checked: Vec<(String, SubmitParams, ShareCheck)> = Vec::new();
+        let mut validator = ShareValidator::new();
+        // Shares left behind by dropped workers go first, they were submitted earlier
+        let mut orphans: Vec<(OrphanedShare, ShareCheck)> = Vec::new();
+        while let Some(orphan) = self.orphaned_shares.pop_front() {
+            let min_difficulty = self.config.workers.min_difficulty;
+            let check = self.check_share(&orphan.share, orphan.user_id, min_difficulty, &mut validator);
+            orphans.push((orphan, check));
+        }
+        let workers = self.workers.clone();
+        {
+            let mut w_m = workers.lock().unwrap();
+            for (worker_uuid, worker) in w_m.iter_mut() {
+                match worker.get_shares().unwrap() {
+                    None => {}
+                    Some(shares) => {
+                        for share in shares {
+                            let check = if !worker.share_rate_limiter.try_take() {
+                                // Drop shares from workers flooding us before doing any work on them
+                                ShareCheck::RateLimited
+                            } else if !worker.nonce_in_range(&share) {
+                                // A miner ignoring its range, before checking the height
+                                worker.nonce_range_violations += 1;
+                                if self.config.workers.enforce_nonce_range {
+                                    ShareCheck::NonceOutOfRange
+                                } else {
+                                    if let Some(skipped) = self.log_sampler.sample("nonce out of range") {
+                                        warn!(
+                                            "{} - Worker {} submitted nonce {} outside the range sent for job {}, its latest range is {:?} ({} similar skipped)",
+                                            self.id,
+                                            worker.uuid(),
+                                            share.nonce,
+                                            share.job_id,
+                                            worker.assigned_nonce_range,
+                                            skipped,
+                                        );
+                                    }
+                                    self.check_share(&share, worker.user_id(), worker.difficulty_bounds().0, &mut validator)
+                                }
+                            } else {
+                                self.check_share(&share, worker.user_id(), worker.difficulty_bounds().0, &mut validator)
+                            };
+                            checked.push((worker_uuid.clone(), share, check));
                         }
-                        warn!("{} - Got share at height {} with nonce {} with difficulty {} from worker {}",
-                                self.id,
-                                share.height,
-                                share.nonce,
-                                worker.status.difficulty,
-                                worker.uuid(),
+                    }
+                }
+            }
+        }
+
+        // Ask the pools sharing our Redis about the new shares, without holding the lock
+        self.duplicates.claim();
+        for &mut (_, ref share, ref mut check) in checked.iter_mut() {
+            if self.duplicates.claimed_elsewhere(&share.pow, share.height, share.job_id) {
+                *check = ShareCheck::Duplicate;
+            }
+        }
+        for &mut (ref orphan, ref mut check) in orphans.iter_mut() {
+            if self.duplicates.claimed_elsewhere(&orphan.share.pow, orphan.share.height, orphan.share.job_id) {
+                *check = ShareCheck::Duplicate;
+            }
+        }
+
+        // Verify the solutions in parallel without holding the lock
+        let results = validator.validate_with(self.validate_share);
+
+        // Invalid shares of each dropped worker, on top of those it had when dropped
+        let mut orphans_invalid: HashMap<String, usize> = HashMap::new();
+        for (orphan, check) in orphans {
+            let worker_id = orphan.worker_id.clone();
+            let ip = orphan.ip;
+            let invalid_shares = orphan.invalid_shares;
+            if !self.credit_orphaned_share(orphan, check, &results) {
+                let count = orphans_invalid.entry(worker_id.clone()).or_insert(invalid_shares);
+                *count += 1;
+                self.invalid_orphaned_share(&worker_id, ip, *count);
+            }
+        }
+
+        let mut w_m = workers.lock().unwrap();
+        let mut decided: HashSet<String> = HashSet::new(); // Workers whose stats changed
+        for (worker_uuid, mut share, check) in checked {
+            let worker = match w_m.get_mut(&worker_uuid) {
+                Some(worker) => worker,
+                None => continue, // Disconnected while its shares were validated
+            };
+            decided.insert(worker_uuid);
+            let (block_hash, difficulty) = match check {
+                ShareCheck::RateLimited => {
+                    if let Some(skipped) = self.log_sampler.sample("rate limited share") {
+                        warn!(
+                            "{} - Share submission rate exceeded by worker {} ({} similar skipped)",
+                            self.id,
+                            worker.uuid(),
+                            skipped,
                         );
                     }
+                    worker.status.rejected += 1;
+                    worker.send_err("submit".to_string(), RejectReason::RateExceeded);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::NonceOutOfRange => {
+                    warn!(
+                        "{} - Worker {} submitted nonce {} outside the range sent for job {}",
+                        self.id,
+                        worker.uuid(),
+                        share.nonce,
+                        share.job_id,
+                    );
+                    // A misconfigured miner, not a late one, so not stale
+                    worker.status.rejected += 1;
+                    worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::NonceOutOfRange);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Nonce out of assigned range", 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::Duplicate => {
+                    debug!(
+                        "{} - Rejected duplicate share for job {} from worker {} with login {}",
+                        self.id,
+                        share.job_id,
+                        worker.uuid(),
+                        worker.login(),
+                    );
+                    worker.status.rejected += 1;
+                    worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::InvalidSolution);
+                    self.invalid_share(worker);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "duplicate", "Duplicate share", 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::InvalidSize => {
+                    worker.status.rejected += 1;
+                    // worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::InvalidPowSize);
+                    self.invalid_share(worker);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Invalid POW size", 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::InvalidProofSize => {
+                    if let Some(skipped) = self.log_sampler.sample("invalid proof size") {
+                        warn!("{} - Share has invalid PROOF_SIZE ({} similar skipped)", self.id, skipped);
+                    }
+                    worker.status.rejected += 1;
+                    worker.send_err("submit".to_string(), RejectReason::InvalidProofSize);
+                    self.invalid_share(worker);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Invalid PROOF_SIZE", 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::LowDifficulty => {
+                    worker.status.rejected += 1;
+                    worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::LowDifficulty);
+                    self.invalid_share(worker);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Rejected low difficulty solution", 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::Stale(depth) => {
+                    if let Some(skipped) = self.log_sampler.sample("stale share") {
+                        warn!("{} - Share is stale {} vs {} ({} similar skipped)", self.id, share.height, self.job.height, skipped);
+                    }
+                    worker.status.stale += 1;
+                    worker.add_shares(share.edge_bits, 0, 0, 1); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::TooLate);
+                    let reason = format!("Solution submitted too late - {} blocks", depth);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "stale", &reason, 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::DeepStale(depth) => {
+                    // Not lag, the miner is misconfigured or replaying old work
+                    if let Some(skipped) = self.log_sampler.sample("deep stale share") {
+                        warn!(
+                            "{} - Share from worker {} is {} blocks stale, height {} vs {} ({} similar skipped)",
+                            self.id, worker.uuid(), depth, share.height, self.job.height, skipped,
+                        );
+                    }
+                    worker.status.stale += 1;
+                    worker.status.deep_stale += 1;
+                    worker.add_shares(share.edge_bits, 0, 0, 1); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::TooLate);
+                    self.invalid_share(worker);
+                    let reason = format!("Solution submitted too late - {} blocks", depth);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "stale", &reason, 0);
+                    continue; // Dont process this share anymore
+                },
+                ShareCheck::UnknownJob => {
+                    worker.status.rejected += 1;
+                    worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                    worker.send_err("submit".to_string(), RejectReason::InvalidSolution);
+                    self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Unknown job", 0);
+                    continue // Dont process this share anymore
+                },
+                ShareCheck::Pending(index) => match results[index] {
+                    ValidationResult::Valid { ref block_hash, difficulty } => (block_hash.clone(), difficulty),
+                    ValidationResult::InvalidHeader => {
+                        // Not the miners fault, it is not counted toward a ban
+                        let header_errors = self.header_errors.fetch_add(1, Ordering::Relaxed) + 1;
+                        if let Some(skipped) = self.log_sampler.sample("header error") {
+                            error!(
+                                "{} - Failed to build the block header for a share for job {}, {} so far - if every share fails, the job header format is newer than this pool understands ({} similar skipped)",
+                                self.id, share.job_id, header_errors, skipped,
+                            );
+                        }
+                        worker.status.rejected += 1;
+                        worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                        worker.send_err("submit".to_string(), RejectReason::InvalidSolution);
+                        self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Failed to build block header", 0);
+                        continue; // Dont process this share anymore
+                    },
+                    ValidationResult::InvalidProof => {
+                        worker.status.rejected += 1;
+                        worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                        worker.send_err("submit".to_string(), RejectReason::InvalidSolution);
+                        self.invalid_share(worker);
+                        self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Failed to verify solution", 0);
+                        continue; // Dont process this share anymore
+                    },
+                },
+            };
+            // Check if this meets the difficulty required for this algorithm
+            let required = self
+                .config
+                .workers
+                .min_difficulty(share.edge_bits, worker.status.difficulty);
+            if difficulty < 1 {
+                worker.status.rejected += 1;
+                worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                worker.send_err("submit".to_string(), RejectReason::LowDifficulty);
+                self.invalid_share(worker);
+                self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Rejected low difficulty solution", difficulty);
+                continue; // Dont process this share anymore
+            }
+            if difficulty < required {
+                worker.status.rejected += 1;
+                worker.add_shares(share.edge_bits, 0, 1, 0); // Accepted, Rejected, Stale
+                worker.send_err("submit".to_string(), RejectReason::InvalidSolution);
+                self.invalid_share(worker);
+                self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "rejected", "Below required difficulty", difficulty);
+                continue; // Dont process this share anymore
+            }
+            if difficulty >= required {
+                worker.status.accepted += 1;
+                if share.height != self.job.height {
+                    // Only gets here in the stale grace period
+                    worker.status.stale_accepted += 1;
+                }
+                worker.add_shares(share.edge_bits, 1, 0, 0); // Accepted, Rejected, Stale
+                worker.send_ok("submit".to_string());
+                self.record_share(&worker.uuid(), &worker.full_id(), &worker.worker_shares.tag, &share, "accepted", "", difficulty);
+                self.duplicates.accepted(&share.pow, share.height, share.job_id);
+                // Credit the share at the difficulty the worker was asked for
+                self.pplns.lock().unwrap().add_share(worker.full_id(), share.edge_bits, required);
+                worker.hashrate.add_share(share.edge_bits, required);
+                self.histogram.lock().unwrap().add_share(share.edge_bits, difficulty);
+                worker.vardiff_share();
+                worker.share_accepted();
+                self.interval_graphs += hashrate::c31_graphs(share.edge_bits, required);
+            }
+            // This is a good share, send it to grin server to be submitted
+            // Only send high power shares - minimum difficulty is set by the upstream
+            // grin stratum server unless configured
+            if difficulty >= self.upstream_min_difficulty() {
+                worker.status.pool_accepted += 1;
+                self.submit_upstream(&mut share, worker.uuid(), worker.status.difficulty, &block_hash, difficulty);
+            }
+            if let Some(skipped) = self.log_sampler.sample("share") {
+                warn!("{} - Got share at height {} with nonce {} with difficulty {} from worker {} ({} similar skipped)",
+                        self.id,
+                        share.height,
+                        share.nonce,
+                        worker.status.difficulty,
+                        worker.uuid(),
+                        skipped,
+                );
+            }
+        }
+        if let Some(ref influxdb) = self.influxdb {
+            let mut influxdb = influxdb.lock().unwrap();
+            for worker_uuid in decided {
+                if let Some(worker) = w_m.get(&worker_uuid) {
+                    influxdb.worker_stats(worker);
+                }
+            }
+        }
+    }
+
+    // The checks every share goes through before its solution is verified,
+    // the shares that pass them are queued on the validator
+    fn check_share(
+        &mut self,
+        share: &SubmitParams,
+        user_id: usize,
+        min_difficulty: u64,
+        validator: &mut ShareValidator,
+    ) -> ShareCheck {
+        let algorithm = match PowAlgorithm::from_edge_bits(share.edge_bits) {
+            Some(algorithm) if self.config.grin_pool.is_valid_edge_bits(share.edge_bits, self.job.height) => algorithm,
+            // Fail fast, these are never worth remembering
+            _ => return ShareCheck::InvalidSize,
+        };
+        if !self.duplicates.insert_new(&share.pow, share.height, share.job_id, user_id) {
+            return ShareCheck::Duplicate;
+        }
+        if share.pow.len() != PROOF_SIZE {
+            // proofsize check in pow verify (#2805)
+            return ShareCheck::InvalidProofSize;
+        }
+        // Mined on the previous job while the new one was on its way
+        let in_grace_period = !share.stale && self.job.height.checked_sub(1) == Some(share.height) && self.in_stale_grace_period();
+        if (share.stale || share.height != self.job.height) && !in_grace_period {
+            let depth = self.job.height.saturating_sub(share.height);
+            if share.stale || self.recently_mined(share.height) {
+                return ShareCheck::Stale(depth);
+            }
+            return ShareCheck::DeepStale(depth);
+        }
+        // The difficulty comes from the proof alone, no need to build the header to reject it
+        let proof = MinerProof {
+            edge_bits: share.edge_bits as u8,
+            nonces: share.pow.clone(),
+        };
+        if proof.to_difficulty_unscaled().to_num() < min_difficulty {
+            return ShareCheck::LowDifficulty;
+        }
+        // Check the pow against the version of the pre-pow we sent
+        // - avoid "constructed solutions"
+        match self.job_versions.get(share.job_id) {
+            None => ShareCheck::UnknownJob,
+            Some(pre_pow) => ShareCheck::Pending(validator.push(PendingShare {
+                pre_pow: pre_pow.to_string(),
+                algorithm: algorithm,
+                edge_bits: share.edge_bits,
+                nonce: share.nonce,
+                pow: share.pow.clone(),
+            })),
+        }
+    }
+
+    // Credit a good share from a dropped worker, there is no one to answer.
+    // Returns false if the share counts toward a ban, as it would have live.
+    fn credit_orphaned_share(&mut self, orphan: OrphanedShare, check: ShareCheck, results: &[ValidationResult]) -> bool {
+        let mut share = orphan.share;
+        let (block_hash, difficulty) = match check {
+            ShareCheck::Pending(index) => match results[index] {
+                ValidationResult::Valid { ref block_hash, difficulty } => (block_hash.clone(), difficulty),
+                ValidationResult::InvalidHeader => {
+                    // Not the miners fault
+                    self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "rejected", "Failed to build block header", 0);
+                    return true;
+                }
+                ValidationResult::InvalidProof => {
+                    self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "rejected", "Failed to verify solution", 0);
+                    return false;
                 }
+            },
+            ShareCheck::Stale(depth) => {
+                let reason = format!("Solution submitted too late - {} blocks", depth);
+                self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "stale", &reason, 0);
+                return true;
+            }
+            ShareCheck::DeepStale(depth) => {
+                let reason = format!("Solution submitted too late - {} blocks", depth);
+                self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "stale", &reason, 0);
+                return false;
+            }
+            ShareCheck::Duplicate => {
+                self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "duplicate", "Duplicate share", 0);
+                return false;
+            }
+            ShareCheck::UnknownJob => {
+                self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "rejected", "Unknown job", 0);
+                return true;
+            }
+            _ => {
+                self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "rejected", "Invalid share", 0);
+                return false;
+            }
+        };
+        let required = self.config.workers.min_difficulty(share.edge_bits, orphan.difficulty);
+        if difficulty < 1 || difficulty < required {
+            self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "rejected", "Below required difficulty", difficulty);
+            return false;
+        }
+        self.record_share(&orphan.worker_id, &orphan.full_id, &orphan.tag, &share, "accepted", "", difficulty);
+        self.duplicates.accepted(&share.pow, share.height, share.job_id);
+        self.pplns.lock().unwrap().add_share(orphan.full_id.clone(), share.edge_bits, required);
+        self.interval_graphs += hashrate::c31_graphs(share.edge_bits, required);
+        if difficulty >= self.upstream_min_difficulty() {
+            self.submit_upstream(&mut share, orphan.worker_id.clone(), orphan.difficulty, &block_hash, difficulty);
+        }
+        warn!(
+            "{} - Credited share at height {} with nonce {} from dropped worker {}",
+            self.id, share.height, share.nonce, orphan.full_id,
+        );
+        true
+    }
+
+    // Send a good share to the grin server, it may be a block
+    fn submit_upstream(
+        &mut self,
+        share: &mut SubmitParams,
+        worker_id: String,
+        worker_difficulty: u64,
+        block_hash: &String,
+        difficulty: u64,
+    ) {
+        // back to the upstream job_id
+        share.job_id = self.job_id_codec.decode(share.job_id, share.height);
+        let submitted = self.server.submit_share(&share.clone(), worker_id.clone());
+        if submitted.is_ok() {
+            if let Some(ref mut controller) = self.difficulty_controller {
+                controller.record_submission();
+            }
+        }
+        // The unscaled difficulty a share of this size needs to be a block,
+        // 0 until the first job header was read
+        let block_difficulty = self.network.lock().unwrap().unscaled(share.edge_bits);
+        // Announce each winning share once, even if it is submitted again
+        if submitted.is_ok()
+            && block_difficulty > 0
+            && difficulty >= block_difficulty
+            && self.announced_blocks.insert(block_hash.clone())
+        {
+            if self.found_block.is_none() {
+                self.found_block = Some((share.height, block_hash.clone(), worker_id.clone()));
+            }
+            self.rounds.lock().unwrap().finalize(block_hash.clone(), share.height);
+            self.record_fee(share.height, &block_hash);
+            let event = BlockFound {
+                height: share.height,
+                hash: block_hash.clone(),
+                worker: worker_id.clone(),
+                nonce: share.nonce,
+                difficulty: difficulty,
+            };
+            webhook::block_found(&self.id, &self.config.grin_pool.block_found_webhook_url, event);
+        }
+        warn!("{} - Submitted share at height {} with nonce {} with difficulty {} from worker {}",
+            self.id,
+            share.height,
+            share.nonce,
+            worker_difficulty,
+            worker_id,
+        );
+    }
+
+    // Count an invalid share against the worker, ban it if it submits too many
+    fn invalid_share(&self, worker: &mut Worker) {
+        let max_invalid = self.config.workers.max_invalid_per_minute;
+        if max_invalid == 0 || worker.add_invalid_share() <= max_invalid {
+            return;
+        }
+        warn!(
+            "{} - Banning worker {} at {:?} - more than {} invalid shares per minute",
+            self.id,
+            worker.uuid(),
+            worker.ip(),
+            max_invalid,
+        );
+        if let Some(ip) = worker.ip() {
+            let expires = Instant::now() + Duration::from_secs(self.config.workers.ban_duration_secs);
+            self.banned.lock().unwrap().insert(ip, expires);
+        }
+        worker.set_error();
+    }
+
+    // Count an invalid share from a dropped worker like invalid_share would have,
+    // invalid_shares is how many it has submitted in the last minute
+    fn invalid_orphaned_share(&self, worker_id: &str, ip: Option<IpAddr>, invalid_shares: usize) {
+        let max_invalid = self.config.workers.max_invalid_per_minute;
+        if max_invalid == 0 || invalid_shares <= max_invalid {
+            return;
+        }
+        warn!(
+            "{} - Banning dropped worker {} at {:?} - more than {} invalid shares per minute",
+            self.id,
+            worker_id,
+            ip,
+            max_invalid,
+        );
+        if let Some(ip) = ip {
+            let expires = Instant::now() + Duration::from_secs(self.config.workers.ban_duration_secs);
+            self.banned.lock().unwrap().insert(ip, expires);
+        }
+    }
+
+    // Record the pool fee from the payouts for a block we found in the audit log
+    fn record_fee(&self, height: u64, hash: &str) {
+        let fee_address = &self.config.grin_pool.fee_address;
+        if self.config.grin_pool.fee_percent <= 0.0 {
+            return;
+        }
+        if let Some(ref audit_log) = self.audit_log {
+            let fee = self.pplns.lock().unwrap().payout_snapshot(REWARD).fee;
+            let entry = FeeEntry::new(height, hash.to_string(), fee_address.clone(), fee, REWARD);
+            if let Err(e) = audit_log.lock().unwrap().write(&entry) {
+                error!("{} - Failed to write fee to audit log: {}", self.id, e);
             }
         }
     }
 
-    fn broadcast_job(&mut self) -> Result<(), String> {
+    // Record a share submission in the database, the current round, and the audit log.
+    // difficulty is 0 if the share was rejected before its difficulty was known
+    fn record_share(&self, worker_id: &str, full_id: &str, tag: &Option<String>, share: &SubmitParams, result: &str, reason: &str, difficulty: u64) {
+        self.rounds.lock().unwrap().add_share(result == "accepted");
+        if let Some(ref influxdb) = self.influxdb {
+            influxdb.lock().unwrap().count_share(result);
+        }
+        if let Some(ref audit_log) = self.audit_log {
+            let entry = AuditEntry::new(worker_id.to_string(), share, difficulty, result, reason);
+            if let Err(e) = audit_log.lock().unwrap().write(&entry) {
+                error!("{} - Failed to write share audit log: {}", self.id, e);
+            }
+        }
+        if let Some(ref share_log) = self.share_log {
+            let event = ShareEvent::new(full_id.to_string(), tag.clone(), share, difficulty, result);
+            if let Err(e) = share_log.lock().unwrap().write(&event) {
+                error!("{} - Failed to write share log: {}", self.id, e);
+            }
+        }
+        // The first submission of the pow is already in the table
+        if result == "duplicate" {
+            return;
+        }
+        // Only well formed proofs can be hashed
+        let pow_hash = if share.pow.len() == PROOF_SIZE && share.edge_bits < 64 {
+            let proof = MinerProof {
+                edge_bits: share.edge_bits as u8,
+                nonces: share.pow.clone(),
+            };
+            proof.hash().to_hex()
+        } else {
+            String::new()
+        };
+        let db = self.db.lock().unwrap();
+        if let Err(e) = db::insert_share(&db, worker_id, share.height, share.nonce, share.edge_bits, &pow_hash, result) {
+            error!("{} - Failed to record share: {:?}", self.id, e);
+        }
+    }
+
+    fn broadcast_job(&mut self, clean_jobs: bool) -> Result<(), String> {
         let mut w_m = self.workers.lock().unwrap();
         debug!(
             "{} - broadcasting a job to {} workers",
@@ -423,30 +2011,83 @@ impl Pool {
         // XXX TODO: To do this I need to deserialize the block header
         // XXX TODO: need to randomize the nonce (just in case a miner forgets)
         // XXX TODO: need to set a unique timestamp and record it in the worker struct
+        let mut job = self.job.clone();
+        job.clean_jobs = clean_jobs;
         for (worker_uuid, worker) in w_m.iter_mut() {
-            if worker.authenticated {
-                worker.set_difficulty(self.config.workers.port_difficulty.difficulty);
+            if worker.authenticated() {
                 worker.set_height(self.job.height);
-                // Print this workers block_status for logstash to send to rmq
-                error!("{:?}", worker.worker_shares);
-                worker.send_job(&mut self.job.clone());
-                worker.reset_worker_shares(self.job.height, self.difficulty);
+                worker.send_job(&mut job.clone());
+                // A refreshed job at the same height keeps the current block stats
+                if worker.worker_shares.height != self.job.height {
+                    log_worker_shares(worker);
+                    let difficulty = worker.status.difficulty;
+                    worker.reset_worker_shares(self.job.height, difficulty);
+                }
             }
         }
         return Ok(());
     }
 
+    // Add the workers handed over by the listener threads to the workers list
+    fn add_new_workers(&mut self) {
+        let mut w_m = self.workers.lock().unwrap();
+        while let Ok(mut worker) = self.new_workers.try_recv() {
+            if let Some(ref controller) = self.difficulty_controller {
+                worker.set_difficulty(controller.difficulty());
+            }
+            w_m.insert(worker.uuid(), worker);
+        }
+    }
+
+    // Broadcast the job held back by the throttle once it is time
+    fn broadcast_pending_job(&mut self) {
+        if !self.upstream_syncing() && self.job_throttle.take_pending() {
+            let _ = self.broadcast_job(false);
+        }
+    }
+
+    // Write queued messages to every worker without blocking
+    fn flush_workers(&mut self) {
+        let mut w_m = self.workers.lock().unwrap();
+        for (_, worker) in w_m.iter_mut() {
+            let _ = worker.flush_outbound();
+        }
+    }
+
     // Purge dead/sick workers - remove all workers marked in error state
+    // and workers that stayed silent after being probed with a ping
     fn clean_workers(&mut self) -> usize {
         let mut dead_workers: Vec<String> = vec![];
         let mut w_m = self.workers.lock().unwrap();
+        // Ping quiet workers, those that stay quiet are put in error and dropped below
+        if self.config.workers.keepalive_interval_secs > 0 {
+            let interval = Duration::from_secs(self.config.workers.keepalive_interval_secs);
+            let timeout = Duration::from_secs(self.config.workers.keepalive_timeout_secs);
+            for worker in w_m.values_mut() {
+                worker.check_keepalive(interval, timeout);
+            }
+        }
         for (worker_uuid, worker) in w_m.iter_mut() {
             if worker.error() == true {
+                if worker.closed() {
+                    ConnectionCounters::count(&self.connections.disconnected);
+                } else {
+                    ConnectionCounters::count(&self.connections.dropped_error);
+                }
                 warn!(
                     "{} - Dropping worker: {}",
                     self.id,
                     worker.uuid(),
                 );
+                self.sessions.save(worker);
+                // Last chance to deliver the error that put it in this state
+                let _ = worker.flush_outbound();
+                // The work was done, dont lose the credit for it with the connection
+                let (orphans, dropped) = OrphanedShare::take_all(worker, &self.config.workers);
+                if dropped > 0 {
+                    warn!("{} - Dropped {} queued shares from worker {}", self.id, dropped, worker.uuid());
+                }
+                self.orphaned_shares.extend(orphans);
                 dead_workers.push(worker_uuid.clone());
             }
         }
@@ -454,7 +2095,1641 @@ impl Pool {
         for worker_uuid in dead_workers {
             let _ = w_m.remove(&worker_uuid);
         }
+        self.sessions.purge();
+        // Probe idle workers, drop them if the probe got no reply by the next pass
+        if self.config.workers.idle_timeout_secs > 0 {
+            let idle_timeout = Duration::from_secs(self.config.workers.idle_timeout_secs);
+            let mut idle_workers: Vec<String> = vec![];
+            for (worker_uuid, worker) in w_m.iter_mut() {
+                if worker.is_idle(idle_timeout) {
+                    if worker.ping_pending() {
+                        warn!(
+                            "{} - Dropping idle worker: {} - idle for {} seconds",
+                            self.id,
+                            worker.uuid(),
+                            worker.status.idle_seconds(),
+                        );
+                        ConnectionCounters::count(&self.connections.dropped_idle);
+                        self.sessions.save(worker);
+                        let (orphans, dropped) = OrphanedShare::take_all(worker, &self.config.workers);
+                        if dropped > 0 {
+                            warn!("{} - Dropped {} queued shares from worker {}", self.id, dropped, worker.uuid());
+                        }
+                        self.orphaned_shares.extend(orphans);
+                        idle_workers.push(worker_uuid.clone());
+                    } else {
+                        let _ = worker.send_ping();
+                    }
+                }
+            }
+            for worker_uuid in idle_workers {
+                let _ = w_m.remove(&worker_uuid);
+            }
+        }
+        // Forget poller wakeups for workers that are gone
+        if !self.ready.is_empty() {
+            let live: HashSet<usize> = w_m.values().map(|worker| worker.poll_token()).collect();
+            self.ready.retain(|token| live.contains(token));
+        }
         return w_m.len();
     }
 
+    /// Shares it took in the current round over the shares expected
+    /// at the primary ports difficulty, below 1.0 is lucky
+    pub fn current_round_luck(&self) -> f64 {
+        let share_difficulty = self.config.workers.port_difficulty.first().map_or(0, |port| port.difficulty);
+        let network_difficulty = self.network.lock().unwrap().unscaled(31);
+        self.rounds
+            .lock()
+            .unwrap()
+            .current
+            .luck(network_difficulty, share_difficulty)
+    }
+
+    /// Estimated C31 graphs per second of each worker, and of the whole pool
+    pub fn graphs_per_second(&self) -> (HashMap<String, f64>, f64) {
+        hashrate::pool_graphs_per_second(&self.workers.lock().unwrap())
+    }
+
+    /// Start watching a config file for changes
+    pub fn watch_config(&mut self, path: &str) -> Result<(), String> {
+        let (tx, rx) = channel();
+        reload::watch_config(path, tx)?;
+        self.config_updates = Some(rx);
+        Ok(())
+    }
+
+    // Apply the newest reloaded config, if there is one
+    fn check_config_reload(&mut self) {
+        let mut new_config = None;
+        if let Some(ref config_updates) = self.config_updates {
+            while let Ok(config) = config_updates.try_recv() {
+                new_config = Some(config);
+            }
+        }
+        if let Some(config) = new_config {
+            self.apply_config(config);
+        }
+    }
+
+    // Bind a worker listen port and accept connections on it in a new thread
+    fn start_listener(&mut self, port: u64) -> Result<(), String> {
+        let listener = bind_workers(&self.config.workers.listen_address, port)?;
+        let address = listener
+            .local_addr()
+            .map_err(|e| format!("Failed to get the address of port {}: {}", port, e))?;
+        let (stop, stop_th) = channel();
+        self.listeners.insert(port, (address, stop));
+        let port_difficulty_th = self.port_difficulty.clone();
+        let new_workers_th = self.new_worker_sender.clone();
+        let pool_connections_th = self.pool_connections.clone();
+        let id_th = self.id.clone();
+        let config_th = self.config.clone();
+        let banned_th = self.banned.clone();
+        let login_limiter_th = self.login_limiter.clone();
+        let conn_limiter_th = self.conn_limiter.clone();
+        let ip_connections_th = self.ip_connections.clone();
+        let connections_th = self.connections.clone();
+        let _listener_th = thread::spawn(move || {
+            accept_workers(
+                id_th,
+                config_th,
+                listener,
+                port,
+                port_difficulty_th,
+                new_workers_th,
+                pool_connections_th,
+                banned_th,
+                login_limiter_th,
+                conn_limiter_th,
+                ip_connections_th,
+                connections_th,
+                stop_th,
+            );
+        });
+        Ok(())
+    }
+
+    // Stop accepting connections on a port removed from the config, returns
+    // the port it was bound to.  The listener thread is blocked in accept, so
+    // it is woken with a connection once the stop signal is sent.
+    fn stop_listener(&mut self, port: u64) -> Option<u64> {
+        let (mut address, stop) = self.listeners.remove(&port)?;
+        let _ = stop.send(());
+        if address.ip().is_unspecified() {
+            let loopback: IpAddr = if address.is_ipv4() { "127.0.0.1" } else { "::1" }.parse().unwrap();
+            address.set_ip(loopback);
+        }
+        if let Err(e) = TcpStream::connect(address) {
+            warn!("{} - Failed to wake the listener on port {}: {}", self.id, port, e);
+        }
+        Some(address.port() as u64)
+    }
+
+    // Tell the workers connected to a removed port why they are being
+    // dropped, clean_workers removes them after sending it
+    fn drain_port(&mut self, bound_port: u64) -> usize {
+        let mut drained = 0;
+        let mut w_m = self.workers.lock().unwrap();
+        for worker in w_m.values_mut() {
+            if worker.port() == bound_port {
+                let _ = worker.send_disconnect(RejectReason::PortDecommissioned);
+                worker.set_error();
+                drained += 1;
+            }
+        }
+        drained
+    }
+
+    // Only values that can change without starting a new listener are applied
+    fn apply_config(&mut self, new_config: Config) {
+        let ports: Vec<u64> = self.config.workers.port_difficulty.iter().map(|p| p.port).collect();
+        let new_ports: Vec<u64> = new_config.workers.port_difficulty.iter().map(|p| p.port).collect();
+        let added: Vec<u64> = new_ports.iter().filter(|p| !ports.contains(p)).cloned().collect();
+        if !added.is_empty() {
+            warn!(
+                "{} - Config reload - Ignoring new listen ports {:?}, they need a restart",
+                self.id, added
+            );
+        }
+        // Removed ports stop listening, and their workers are disconnected
+        for port in ports.iter().filter(|p| !new_ports.contains(p)) {
+            let bound_port = self.stop_listener(*port).unwrap_or(*port);
+            self.port_difficulty.write().unwrap().remove(port);
+            self.config.workers.port_difficulty.retain(|pd| pd.port != *port);
+            let drained = self.drain_port(bound_port);
+            warn!(
+                "{} - Config reload - Removed listen port {}, disconnecting its {} workers",
+                self.id, port, drained
+            );
+        }
+        {
+            let mut port_difficulty = self.port_difficulty.write().unwrap();
+            for new_pd in new_config.workers.port_difficulty.iter() {
+                if let Some(difficulty) = port_difficulty.get_mut(&new_pd.port) {
+                    *difficulty = new_pd.difficulty;
+                }
+            }
+            for pd in self.config.workers.port_difficulty.iter_mut() {
+                pd.difficulty = port_difficulty[&pd.port];
+            }
+        }
+        self.config.workers.idle_timeout_secs = new_config.workers.idle_timeout_secs;
+        self.config.workers.edge_bits_difficulty = new_config.workers.edge_bits_difficulty;
+        self.config.grin_pool.block_found_webhook_url = new_config.grin_pool.block_found_webhook_url;
+        self.config.grin_pool.log_sample_rate = new_config.grin_pool.log_sample_rate;
+        self.log_sampler.set_rate(new_config.grin_pool.log_sample_rate);
+        warn!("{} - Config reloaded", self.id);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use serde_json;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::{env, fs, process};
+    use pool::config::AlgorithmPolicy;
+    use pool::proto::{JobId, LoginParams};
+    use pool::worker::ConnectionState;
+    use mockito::{self, mock, Matcher};
+    use toml;
+    use tungstenite;
+
+    pub const TEST_CONFIG: &'static str = r#"
+            [grin_pool]
+            log_dir = "/tmp"
+
+            [workers]
+            listen_address = "127.0.0.1"
+            port_difficulty = [0, 1]
+            idle_timeout_secs = 1
+
+            [redis]
+            address = "127.0.0.1"
+            port = 6379
+
+            [grin_node]
+            address = "127.0.0.1"
+            api_port = 13413
+            stratum_port = 13416
+            login = "GrinPool"
+            password = ""
+        "#;
+
+    pub fn test_config() -> Config {
+        toml::from_str(TEST_CONFIG).unwrap()
+    }
+
+    fn test_pool() -> Pool {
+        Pool::new(test_config(), Arc::new(Mutex::new(db::open_in_memory().unwrap())))
+    }
+
+    // A worker connected over loopback, along with the miners end of the socket
+    pub fn test_worker(config: &Config) -> (Worker, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let miner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        (Worker::new(config.clone(), BufStream::new(WorkerStream::Tcp(stream))), miner)
+    }
+
+    // Accept workers on listener in the background as a pool port would,
+    // the workers it lets in arrive on the receiver
+    fn spawn_listener(config: Config, listener: TcpListener, banned: Arc<Mutex<HashMap<IpAddr, Instant>>>) -> Receiver<Worker> {
+        let (new_workers, arrived) = channel();
+        let port = listener.local_addr().unwrap().port() as u64;
+        thread::spawn(move || {
+            let port_difficulty = Arc::new(RwLock::new(vec![(port, 1)].into_iter().collect()));
+            let login_limiter = Arc::new(Mutex::new(IpRateLimiter::new(5)));
+            let conn_limiter = Arc::new(Mutex::new(IpRateLimiter::new(config.workers.max_conns_per_ip_per_min)));
+            accept_workers(
+                "test".to_string(),
+                config,
+                listener,
+                port,
+                port_difficulty,
+                new_workers,
+                Arc::new(AtomicUsize::new(0)),
+                banned,
+                login_limiter,
+                conn_limiter,
+                Arc::new(Mutex::new(HashMap::new())),
+                Arc::new(ConnectionCounters::default()),
+                channel().1,
+            );
+        });
+        arrived
+    }
+
+    // The params of a submit request
+    pub fn share_json(height: u64, job_id: u64, nonce: u64, edge_bits: u32, pow: &[u64]) -> String {
+        format!(
+            "{{\"height\":{},\"job_id\":{},\"nonce\":{},\"edge_bits\":{},\"pow\":{:?}}}",
+            height, job_id, nonce, edge_bits, pow
+        )
+    }
+
+    // Send a stratum request from the miner to the pool
+    fn miner_send(miner: &mut TcpStream, id: u64, method: &str, params: &str) {
+        let msg = format!(
+            "{{\"id\":\"{}\",\"jsonrpc\":\"2.0\",\"method\":\"{}\",\"params\":{}}}\n",
+            id, method, params
+        );
+        miner.write_all(msg.as_bytes()).unwrap();
+        miner.flush().unwrap();
+    }
+
+    // Read the next message the pool sent to the miner
+    fn miner_read(reader: &mut BufReader<TcpStream>) -> Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn submit_before_login_not_authorized() {
+        let config = test_config();
+        let (mut worker, mut miner) = test_worker(&config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        let share = r#"{"height":1,"job_id":0,"nonce":1,"edge_bits":31,"pow":[]}"#;
+
+        miner_send(&mut miner, 1, "submit", share);
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        worker.flush_outbound().unwrap();
+        let response = miner_read(&mut reader);
+        assert_eq!(response["id"], "1");
+        assert_eq!(response["error"]["code"], -32500);
+        assert_eq!(response["error"]["message"], "Not authorized");
+        assert!(worker.get_shares().unwrap().is_none());
+        assert_eq!(worker.state, ConnectionState::Connected);
+
+        // Asking for a job first is fine, it is sent once logged in
+        miner_send(&mut miner, 2, "getjobtemplate", "null");
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        assert!(worker.requested_job);
+
+        worker.state = ConnectionState::Authorized;
+        miner_send(&mut miner, 3, "submit", share);
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        assert_eq!(worker.get_shares().unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn transactions_fetched_once_per_height() {
+        let node_api = mock("POST", "/v2/foreign")
+            .match_body(Matcher::Regex("get_unconfirmed_transactions".to_string()))
+            .with_body(r#"{"id":"MWGrinPool","jsonrpc":"2.0","result":{"Ok":[{"src":"Broadcast","tx":{"offset":"00"}}]}}"#)
+            .expect(1)
+            .create();
+        let mut config = test_config();
+        config.grin_node.api_port = mockito::server_url().rsplit(':').next().unwrap().parse().unwrap();
+        let mut pool = Pool::new(config, Arc::new(Mutex::new(db::open_in_memory().unwrap())));
+        pool.job.height = 5;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        worker.state = ConnectionState::Authorized;
+        worker.set_height(5);
+
+        // The second request is answered from the workers cache
+        for id in 1..3 {
+            miner_send(&mut miner, id, "mining.get_transactions", "[]");
+            thread::sleep(Duration::from_millis(100));
+            worker.process_messages().unwrap();
+            assert_eq!(worker.requested_transactions, id == 1);
+            worker = answer_transactions(&mut pool, worker);
+            let response = miner_read(&mut reader);
+            assert_eq!(response["id"], id.to_string());
+            assert_eq!(response["method"], "mining.get_transactions");
+            assert_eq!(response["result"][0]["src"], "Broadcast");
+            assert_eq!(response["result"][0]["tx"]["offset"], "00");
+        }
+        assert!(!worker.requested_transactions);
+        node_api.assert();
+    }
+
+    #[test]
+    fn transactions_failure_kept_for_the_height() {
+        let node_api = mock("POST", "/v2/foreign")
+            .match_body(Matcher::Regex("get_unconfirmed_transactions".to_string()))
+            .with_status(500)
+            .expect(1)
+            .create();
+        let mut config = test_config();
+        config.grin_node.api_port = mockito::server_url().rsplit(':').next().unwrap().parse().unwrap();
+        let mut pool = Pool::new(config, Arc::new(Mutex::new(db::open_in_memory().unwrap())));
+        pool.job.height = 5;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        worker.state = ConnectionState::Authorized;
+        worker.set_height(5);
+
+        // Both requests fail, the node is not asked again
+        for id in 1..3 {
+            miner_send(&mut miner, id, "mining.get_transactions", "[]");
+            thread::sleep(Duration::from_millis(100));
+            worker.process_messages().unwrap();
+            assert!(worker.requested_transactions);
+            worker = answer_transactions(&mut pool, worker);
+            let response = miner_read(&mut reader);
+            assert_eq!(response["id"], id.to_string());
+            assert_eq!(response["error"]["code"], -32500);
+        }
+        node_api.assert();
+    }
+
+    // Run send_transactions until the node answered the worker, the
+    // request is sent on another thread
+    fn answer_transactions(pool: &mut Pool, worker: Worker) -> Worker {
+        let uuid = worker.uuid();
+        pool.workers.lock().unwrap().insert(uuid.clone(), worker);
+        let start = Instant::now();
+        loop {
+            pool.send_transactions();
+            if !pool.workers.lock().unwrap()[&uuid].requested_transactions || start.elapsed() > Duration::from_secs(5) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        pool.flush_workers();
+        pool.workers.lock().unwrap().remove(&uuid).unwrap()
+    }
+
+    #[test]
+    fn stats_snapshot_written() {
+        let path = env::temp_dir().join(format!("grin-pool-stats-{}.json", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut pool = test_pool();
+        pool.config.grin_pool.stats_snapshot_path = Some(path.clone());
+        pool.config.grin_pool.stats_snapshot_interval_secs = 1;
+        let (mut worker, _miner) = test_worker(&pool.config);
+        worker.state = ConnectionState::Authorized;
+        worker.status.accepted = 3;
+        worker.set_difficulty(16);
+        let full_id = worker.full_id();
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        // Not until the interval has passed
+        pool.save_stats_snapshot();
+        assert!(!Path::new(&path).exists());
+        thread::sleep(Duration::from_millis(1100));
+        pool.save_stats_snapshot();
+
+        let snapshot: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        assert!(snapshot["timestamp"].as_u64().unwrap() > 1_500_000_000);
+        assert_eq!(snapshot["workers"].as_array().unwrap().len(), 1);
+        assert_eq!(snapshot["workers"][0]["id"], Value::from(full_id));
+        assert_eq!(snapshot["workers"][0]["accepted"], 3);
+        assert_eq!(snapshot["workers"][0]["difficulty"], 16);
+        assert!(snapshot["workers"][0]["hashrate_gps"].is_f64());
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn subscribe_authorize_notify() {
+        let mut pool = test_pool();
+        pool.job.height = 5;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+
+        miner_send(&mut miner, 1, "mining.subscribe", r#"["testminer/1.0"]"#);
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        worker.flush_outbound().unwrap();
+        let subscribed = miner_read(&mut reader);
+        assert_eq!(subscribed["id"], "1");
+        assert_eq!(subscribed["result"][0][0][0], "job");
+        assert_eq!(subscribed["result"][0][0][1], Value::from(worker.connection_id.clone()));
+        assert_eq!(subscribed["result"][2], 8);
+        assert_eq!(worker.state, ConnectionState::Subscribed);
+
+        // Not authorized yet, no jobs
+        let uuid = worker.uuid();
+        pool.workers.lock().unwrap().insert(uuid.clone(), worker);
+        pool.send_jobs();
+        pool.flush_workers();
+        let mut worker = pool.workers.lock().unwrap().remove(&uuid).unwrap();
+
+        // The user is known, as if redis had it
+        worker.user_id = 7;
+        miner_send(&mut miner, 2, "mining.authorize", r#"["alice.rig1", "x"]"#);
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        worker.flush_outbound().unwrap();
+        let authorized = miner_read(&mut reader);
+        assert_eq!(authorized["id"], "2");
+        assert_eq!(authorized["result"], true);
+        assert_eq!(worker.state, ConnectionState::Authorized);
+
+        // Then it is sent a job without asking for one
+        pool.workers.lock().unwrap().insert(uuid, worker);
+        pool.send_jobs();
+        pool.flush_workers();
+        let job = miner_read(&mut reader);
+        assert_eq!(job["method"], "job");
+        assert_eq!(job["params"]["height"], 5);
+    }
+
+    #[test]
+    fn failed_logins_banned() {
+        let mut pool = test_pool();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut miners = vec![];
+        for attempt in 1..7 {
+            let (worker, mut miner) = test_worker(&pool.config);
+            pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+            // No account name
+            miner_send(&mut miner, 1, "mining.authorize", r#"[".rig1", "x"]"#);
+            miners.push(miner);
+            thread::sleep(Duration::from_millis(100));
+            pool.process_worker_messages();
+            assert_eq!(is_banned(&pool.banned, ip), attempt == 6, "after {} failed logins", attempt);
+        }
+    }
+
+    #[test]
+    fn duplicate_share_rejected() {
+        let mut pool = test_pool();
+        let (worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        // The first copy is stale (wrong height) but still gets remembered
+        let share = share_json(1, JobId::new(1, 0).encode().unwrap(), 7, 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        miner_send(&mut miner, 2, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..2 {
+            pool.process_worker_messages();
+            pool.process_shares();
+        }
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32502);
+    }
+
+    #[test]
+    fn nonce_outside_assigned_range_rejected() {
+        let mut config = test_config();
+        config.workers.nonce_range_bits = 8;
+        config.workers.enforce_nonce_range = true;
+        let mut pool = Pool::new(config, Arc::new(Mutex::new(db::open_in_memory().unwrap())));
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        let mut job = pool.job.clone();
+        job.job_id = JobId::new(job.height, 0).encode().unwrap();
+        worker.send_job(&mut job).unwrap();
+        let start = job.nonce_start.unwrap();
+        let uuid = worker.uuid();
+        pool.workers.lock().unwrap().insert(uuid.clone(), worker);
+
+        // One past the end of the range
+        let share = share_json(job.height, job.job_id, start.wrapping_add(256), 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        pool.flush_workers();
+        let response = loop {
+            let message = miner_read(&mut reader);
+            if message["method"] != "job" {
+                break message;
+            }
+        };
+        assert_eq!(response["error"]["code"], -32502);
+        assert_eq!(response["error"]["message"], "Nonce out of assigned range");
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&uuid].status.rejected, 1);
+        assert_eq!(w_m[&uuid].status.stale, 0);
+        assert_eq!(w_m[&uuid].nonce_range_violations, 1);
+    }
+
+    #[test]
+    fn nonce_outside_assigned_range_counted() {
+        let mut config = test_config();
+        config.workers.nonce_range_bits = 8;
+        let mut pool = Pool::new(config, Arc::new(Mutex::new(db::open_in_memory().unwrap())));
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        let mut job = pool.job.clone();
+        job.job_id = JobId::new(job.height, 0).encode().unwrap();
+        worker.send_job(&mut job).unwrap();
+        let start = job.nonce_start.unwrap();
+        let uuid = worker.uuid();
+        pool.workers.lock().unwrap().insert(uuid.clone(), worker);
+
+        // Counted, then checked like any other share
+        let share = share_json(job.height, job.job_id, start.wrapping_add(256), 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        pool.flush_workers();
+        let response = loop {
+            let message = miner_read(&mut reader);
+            if message["method"] != "job" {
+                break message;
+            }
+        };
+        assert_ne!(response["error"]["message"], "Nonce out of assigned range");
+        assert_eq!(pool.workers.lock().unwrap()[&uuid].nonce_range_violations, 1);
+    }
+
+    #[test]
+    fn excess_connections_rejected() {
+        let mut config = test_config();
+        config.workers.max_connections = 2;
+        let (new_workers, arrived) = channel();
+        let pool_connections: PoolConnections = Arc::new(AtomicUsize::new(0));
+        let login_limiter = Arc::new(Mutex::new(IpRateLimiter::new(5)));
+        let ip_connections: IpConnections = Arc::new(Mutex::new(HashMap::new()));
+        let counters = ConnectionCounters::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut miners = vec![];
+        for _ in 0..3 {
+            let miner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (stream, worker_addr) = listener.accept().unwrap();
+            add_worker(&"test".to_string(), &config, stream, worker_addr, 1, &new_workers, &pool_connections, &login_limiter, &ip_connections, &counters);
+            miners.push(miner);
+        }
+        // Still full while the main loop has not picked them up
+        let workers: Vec<Worker> = arrived.try_iter().collect();
+        assert_eq!(workers.len(), 2);
+        assert_eq!(pool_connections.load(Ordering::SeqCst), 2);
+        assert_eq!(counters.counts().accepted, 2);
+        assert_eq!(counters.counts().rejected_full, 1);
+
+        // The third miner is told the pool is full and then disconnected
+        let mut reader = BufReader::new(miners.pop().unwrap());
+        let response = miner_read(&mut reader);
+        assert_eq!(response["error"]["code"], -32000);
+        assert_eq!(response["error"]["message"], "Pool full");
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn ip_admitted_after_disconnect() {
+        let mut config = test_config();
+        config.workers.max_connections_per_ip = 1;
+        let (new_workers, arrived) = channel();
+        let pool_connections: PoolConnections = Arc::new(AtomicUsize::new(0));
+        let login_limiter = Arc::new(Mutex::new(IpRateLimiter::new(5)));
+        let ip_connections: IpConnections = Arc::new(Mutex::new(HashMap::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let connect = || {
+            let miner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (stream, worker_addr) = listener.accept().unwrap();
+            add_worker(&"test".to_string(), &config, stream, worker_addr, 1, &new_workers, &pool_connections, &login_limiter, &ip_connections, &ConnectionCounters::default());
+            miner
+        };
+
+        let _first = connect();
+        let second = connect();
+        let workers: Vec<Worker> = arrived.try_iter().collect();
+        assert_eq!(workers.len(), 1);
+        assert_eq!(ip_connections.lock().unwrap()[&ip], 1);
+        let response = miner_read(&mut BufReader::new(second));
+        assert_eq!(response["error"]["message"], "Too many connections from your address");
+
+        // Dropping the worker releases its address, and its place in the pool
+        drop(workers);
+        assert!(ip_connections.lock().unwrap().get(&ip).is_none());
+        assert_eq!(pool_connections.load(Ordering::SeqCst), 0);
+        let _third = connect();
+        assert_eq!(arrived.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn same_pow_other_job_not_duplicate() {
+        let mut pool = test_pool();
+        let (worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        // Both get past the duplicate check to the stale check
+        for (id, version) in [(1, 0), (2, 1)].iter() {
+            let share = share_json(1, JobId::new(1, *version).encode().unwrap(), 7, 31, &vec![1u64; PROOF_SIZE]);
+            miner_send(&mut miner, *id, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..2 {
+            pool.process_worker_messages();
+            pool.process_shares();
+        }
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+    }
+
+    #[test]
+    fn disallowed_edge_bits_rejected() {
+        let mut pool = test_pool();
+        pool.config.grin_pool.allowed_edge_bits = vec![29, 31];
+        pool.config.grin_pool.allowed_edge_bits_after_height.insert(31, 10);
+        let (worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        // Rejected before the duplicate check, both times
+        let share = share_json(1, JobId::new(1, 0).encode().unwrap(), 7, 32, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        miner_send(&mut miner, 2, "submit", &share);
+        // Not allowed until after height 10
+        miner_send(&mut miner, 3, "submit", &share.replace("\"edge_bits\":32", "\"edge_bits\":31"));
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..3 {
+            pool.process_worker_messages();
+            pool.process_shares();
+        }
+        pool.flush_workers();
+        for _ in 0..3 {
+            let response = miner_read(&mut reader);
+            assert_eq!(response["error"]["code"], -32502);
+            assert_eq!(response["error"]["message"], "Invalid POW size");
+        }
+
+        // Past the height C31 is allowed, the share gets as far as the stale check
+        pool.job.height = 11;
+        miner_send(&mut miner, 4, "submit", &share.replace("\"edge_bits\":32", "\"edge_bits\":31"));
+        pool.wait_for_events(Duration::from_secs(5));
+        pool.process_worker_messages();
+        pool.process_shares();
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+    }
+
+    #[test]
+    fn only_ready_workers_read() {
+        let mut pool = test_pool();
+        let (quiet, _quiet_miner) = test_worker(&pool.config);
+        let (busy, mut busy_miner) = test_worker(&pool.config);
+        busy_miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(busy_miner.try_clone().unwrap());
+        let busy_token = busy.poll_token();
+        pool.workers.lock().unwrap().insert(quiet.uuid(), quiet);
+        pool.workers.lock().unwrap().insert(busy.uuid(), busy);
+        // New workers are registered with the poller
+        pool.process_worker_messages();
+
+        // Nothing to read, the wait runs to its timeout
+        let start = Instant::now();
+        pool.wait_for_events(Duration::from_millis(200));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert!(pool.ready.is_empty());
+
+        // Two messages in one packet wake the poller once, both get read
+        busy_miner
+            .write_all(b"{\"id\":\"1\",\"jsonrpc\":\"2.0\",\"method\":\"status\",\"params\":null}\n{\"id\":\"2\",\"jsonrpc\":\"2.0\",\"method\":\"status\",\"params\":null}\n")
+            .unwrap();
+        let start = Instant::now();
+        pool.wait_for_events(Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(pool.ready.iter().cloned().collect::<Vec<usize>>(), vec![busy_token]);
+        pool.process_worker_messages();
+        // The second message is still buffered, so the next wait does not block
+        assert!(pool.ready.contains(&busy_token));
+        pool.wait_for_events(Duration::from_secs(5));
+        pool.process_worker_messages();
+        assert!(pool.ready.is_empty());
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["id"], "1");
+        assert_eq!(miner_read(&mut reader)["id"], "2");
+    }
+
+    // Valid, at a difficulty of the shares nonce
+    fn nonce_difficulty(share: &PendingShare) -> ValidationResult {
+        ValidationResult::Valid {
+            block_hash: format!("{:064x}", share.nonce),
+            difficulty: share.nonce,
+        }
+    }
+
+    #[test]
+    fn header_errors_counted_not_banned() {
+        let mut pool = test_pool();
+        pool.validate_share = |_| ValidationResult::InvalidHeader;
+        pool.config.workers.max_invalid_per_minute = 1;
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        for id in 1..4u64 {
+            let share = share_json(1, job_id, id, 31, &vec![id; PROOF_SIZE]);
+            miner_send(&mut miner, id, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        assert_eq!(pool.header_errors.load(Ordering::Relaxed), 3);
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&worker_id].status.rejected, 3);
+        assert!(!w_m[&worker_id].error());
+        assert!(!is_banned(&pool.banned, "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn algorithm_policy_enforced() {
+        let policies = [
+            (AlgorithmPolicy::Any, 2, 0),
+            (AlgorithmPolicy::CuckatooOnly, 1, 1),
+            (AlgorithmPolicy::CuckarooOnly, 1, 1),
+        ];
+        for &(policy, accepted, rejected) in policies.iter() {
+            let mut pool = test_pool();
+            pool.validate_share = nonce_difficulty;
+            pool.config.grin_pool.algorithm_policy = policy;
+            pool.job.height = 1;
+            let job_id = JobId::new(1, 0).encode().unwrap();
+            pool.job_versions.insert(job_id, 1, "00".to_string());
+            let (mut worker, mut miner) = test_worker(&pool.config);
+            worker.set_difficulty(1);
+            let worker_id = worker.uuid();
+            pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+            // A Cuckaroo and a Cuckatoo share
+            for &(id, edge_bits) in [(1u64, 29), (2, 31)].iter() {
+                let share = share_json(1, job_id, 5, edge_bits, &vec![id; PROOF_SIZE]);
+                miner_send(&mut miner, id, "submit", &share);
+            }
+            thread::sleep(Duration::from_millis(100));
+            pool.process_worker_messages();
+            pool.process_shares();
+            let w_m = pool.workers.lock().unwrap();
+            assert_eq!(w_m[&worker_id].status.accepted, accepted, "{:?}", policy);
+            assert_eq!(w_m[&worker_id].status.rejected, rejected, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn low_shares_accepted_not_submitted() {
+        let mut pool = test_pool();
+        pool.validate_share = nonce_difficulty;
+        pool.config.grin_pool.upstream_min_difficulty = 10;
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        for (id, nonce) in [(1, 5), (2, 20)].iter() {
+            let share = share_json(1, job_id, *nonce as u64, 31, &vec![*nonce as u64; PROOF_SIZE]);
+            miner_send(&mut miner, *id, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..2 {
+            pool.process_worker_messages();
+            pool.process_shares();
+        }
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&worker_id].status.accepted, 2);
+        assert_eq!(w_m[&worker_id].status.pool_accepted, 1);
+    }
+
+    #[test]
+    fn parallel_validation_matches_serial() {
+        let mut pool = test_pool();
+        pool.validate_share = nonce_difficulty;
+        pool.config.grin_pool.upstream_min_difficulty = 1_000_000;
+        pool.config.workers.share_burst = 100;
+        pool.config.workers.max_invalid_per_minute = 0;
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+
+        // 100 shares in one pass, from 4 workers, some below their difficulty
+        let mut miners = Vec::new();
+        let mut expected = Vec::new();
+        for w in 0..4u64 {
+            let (mut worker, mut miner) = test_worker(&pool.config);
+            worker.set_difficulty(10 + w * 5);
+            let nonces: Vec<u64> = (w * 25..w * 25 + 25).collect();
+            for nonce in nonces.iter() {
+                let share = share_json(1, job_id, *nonce, 31, &vec![*nonce; PROOF_SIZE]);
+                miner_send(&mut miner, *nonce, "submit", &share);
+            }
+            // What checking them one at a time on this thread decides
+            let required = worker.status.difficulty;
+            let accepted = nonces
+                .iter()
+                .map(|nonce| {
+                    nonce_difficulty(&PendingShare {
+                        pre_pow: "00".to_string(),
+                        algorithm: PowAlgorithm::Cuckatoo,
+                        edge_bits: 31,
+                        nonce: *nonce,
+                        pow: vec![*nonce; PROOF_SIZE],
+                    })
+                })
+                .filter(|result| match *result {
+                    ValidationResult::Valid { difficulty, .. } => difficulty >= 1 && difficulty >= required,
+                    _ => false,
+                })
+                .count() as u64;
+            expected.push((worker.uuid(), accepted, 25 - accepted));
+            pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+            miners.push(miner);
+        }
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+
+        let w_m = pool.workers.lock().unwrap();
+        for (worker_id, accepted, rejected) in expected {
+            assert_eq!((w_m[&worker_id].status.accepted, w_m[&worker_id].status.rejected), (accepted, rejected));
+        }
+    }
+
+    #[test]
+    fn dropped_workers_queued_shares_credited() {
+        let mut pool = test_pool();
+        pool.validate_share = nonce_difficulty;
+        pool.config.grin_pool.upstream_min_difficulty = 1000;
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        worker.user_id = 7;
+        worker.state = ConnectionState::Authorized;
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        let full_id = worker.full_id();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        for (id, nonce) in [(1, 5), (2, 6)].iter() {
+            let share = share_json(1, job_id, *nonce as u64, 31, &vec![*nonce as u64; PROOF_SIZE]);
+            miner_send(&mut miner, *id, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+
+        // The connection fails with both shares still queued
+        pool.workers.lock().unwrap().get_mut(&worker_id).unwrap().set_error();
+        assert_eq!(pool.clean_workers(), 0);
+        assert_eq!(pool.orphaned_shares.len(), 2);
+
+        pool.process_shares();
+        assert!(pool.orphaned_shares.is_empty());
+        let pplns = pool.pplns.lock().unwrap();
+        assert_eq!(pplns.len(), 2);
+        assert!(pplns.contributions().contains_key(&full_id));
+    }
+
+    #[test]
+    fn dropped_workers_queued_shares_limited() {
+        let mut pool = test_pool();
+        pool.validate_share = |_| ValidationResult::InvalidProof;
+        pool.config.workers.share_burst = 3;
+        pool.config.workers.max_invalid_per_minute = 1;
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        worker.user_id = 7;
+        worker.state = ConnectionState::Authorized;
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        for id in 1..6u64 {
+            let share = share_json(1, job_id, id, 31, &vec![id; PROOF_SIZE]);
+            miner_send(&mut miner, id, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+
+        // No more than the burst are kept, as if they were submitted live
+        pool.workers.lock().unwrap().get_mut(&worker_id).unwrap().set_error();
+        pool.clean_workers();
+        assert_eq!(pool.orphaned_shares.len(), 3);
+
+        // Their invalid solutions still get the address banned
+        pool.process_shares();
+        assert_eq!(pool.pplns.lock().unwrap().len(), 0);
+        assert!(is_banned(&pool.banned, "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn low_difficulty_rejected_unverified() {
+        let mut pool = test_pool();
+        pool.validate_share = |_| panic!("Verified a share below the minimum difficulty");
+        pool.config.workers.min_difficulty = u64::max_value();
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        let worker_id = worker.uuid();
+        worker.set_difficulty(1);
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        let share = share_json(1, job_id, 5, 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        pool.flush_workers();
+        let response = miner_read(&mut reader);
+        assert_eq!(response["error"]["code"], -32502);
+        assert_eq!(response["error"]["message"], "Rejected low difficulty solution");
+        assert_eq!(pool.workers.lock().unwrap()[&worker_id].status.rejected, 1);
+    }
+
+    #[test]
+    fn job_versions_capped() {
+        let mut pool = test_pool();
+        for version in 0..1001 {
+            pool.job_versions.insert(JobId::new(1, version).encode().unwrap(), 1, format!("{:x}", version));
+        }
+        assert_eq!(pool.job_versions.len(), 1000);
+        // The oldest went first
+        assert!(pool.job_versions.get(JobId::new(1, 0).encode().unwrap()).is_none());
+        assert!(pool.job_versions.get(JobId::new(1, 1000).encode().unwrap()).is_some());
+    }
+
+    #[test]
+    fn expired_job_version_rejected() {
+        let mut pool = test_pool();
+        pool.validate_share = nonce_difficulty;
+        pool.job_versions = JobVersions::new(pool.config.grin_pool.max_job_versions, 1);
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        thread::sleep(Duration::from_millis(1100));
+        let share = share_json(1, job_id, 5, 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32502);
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&worker_id].status.accepted, 0);
+        assert_eq!(w_m[&worker_id].status.rejected, 1);
+    }
+
+    #[test]
+    fn share_flood_throttled() {
+        let mut pool = test_pool();
+        pool.config.workers.share_burst = 2;
+        pool.config.workers.max_shares_per_sec = 1;
+        let (worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        for id in 1..4 {
+            let share = share_json(1, JobId::new(1, 0).encode().unwrap(), id, 31, &vec![id; PROOF_SIZE]);
+            miner_send(&mut miner, id, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..3 {
+            pool.process_worker_messages();
+            pool.process_shares();
+        }
+        pool.flush_workers();
+        // The burst gets as far as the stale check, the rest is throttled
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32004);
+        assert_eq!(pool.workers.lock().unwrap()[&worker_id].status.rejected, 1);
+    }
+
+    #[test]
+    fn second_bind_is_an_error() {
+        let listener = bind_workers("127.0.0.1", 0).unwrap();
+        let port = listener.local_addr().unwrap().port() as u64;
+        let result = bind_workers("127.0.0.1", port);
+        assert!(result.unwrap_err().contains("Failed to bind"));
+    }
+
+    #[test]
+    fn idle_worker_removed() {
+        let mut pool = test_pool();
+        let (worker, _miner) = test_worker(&pool.config);
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+        assert_eq!(pool.clean_workers(), 1);
+
+        // The miner goes silent past the idle timeout
+        thread::sleep(Duration::from_millis(1100));
+        // First pass sends a ping and grants a grace period
+        assert_eq!(pool.clean_workers(), 1);
+        // No pong arrived, so the worker is dropped
+        assert_eq!(pool.clean_workers(), 0);
+        assert_eq!(pool.connections.counts().dropped_idle, 1);
+    }
+
+    #[test]
+    fn connection_ends_counted() {
+        let mut pool = test_pool();
+        let (closing, miner) = test_worker(&pool.config);
+        let (failing, _miner) = test_worker(&pool.config);
+        let failing_id = failing.uuid();
+        pool.workers.lock().unwrap().insert(closing.uuid(), closing);
+        pool.workers.lock().unwrap().insert(failing.uuid(), failing);
+        drop(miner);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.workers.lock().unwrap().get_mut(&failing_id).unwrap().set_error();
+
+        assert_eq!(pool.clean_workers(), 0);
+        let counts = pool.connections.counts();
+        assert_eq!(counts.disconnected, 1);
+        assert_eq!(counts.dropped_error, 1);
+        assert_eq!(counts.dropped_idle, 0);
+    }
+
+    #[test]
+    fn config_reloaded() {
+        let dir = env::temp_dir().join(format!("grin-pool-reload-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("grin-pool.toml");
+        fs::write(&path, TEST_CONFIG).unwrap();
+        let mut pool = test_pool();
+        pool.watch_config(path.to_str().unwrap()).unwrap();
+
+        let updated = TEST_CONFIG
+            .replace("log_dir = \"/tmp\"", "log_dir = \"/tmp\"\nblock_found_webhook_url = \"http://localhost/block\"")
+            .replace("port_difficulty = [0, 1]", "port_difficulty = [0, 16]\nedge_bits_difficulty = { 29 = 4 }")
+            .replace("idle_timeout_secs = 1", "idle_timeout_secs = 60");
+        fs::write(&path, updated).unwrap();
+        let start = Instant::now();
+        while pool.config.workers.idle_timeout_secs != 60 && start.elapsed() < Duration::from_millis(500) {
+            pool.check_config_reload();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.config.workers.idle_timeout_secs, 60);
+        assert_eq!(pool.config.workers.edge_bits_difficulty[&29], 4);
+        assert_eq!(pool.config.grin_pool.block_found_webhook_url, Some("http://localhost/block".to_string()));
+        assert_eq!(pool.port_difficulty.read().unwrap()[&0], 16);
+
+        // New ports need a restart, removed ones are closed right away
+        let moved = TEST_CONFIG.replace("port_difficulty = [0, 1]", "port_difficulty = [4444, 1]");
+        pool.apply_config(toml::from_str(&moved).unwrap());
+        assert!(pool.config.workers.port_difficulty.is_empty());
+        assert!(pool.port_difficulty.read().unwrap().is_empty());
+        // Without a port to bound it the pool difficulty is left alone
+        pool.difficulty_controller = Some(PoolDifficultyController::new(1.0, 16));
+        pool.last_difficulty_adjustment = Instant::now() - Duration::from_secs(3600);
+        pool.adjust_pool_difficulty();
+        assert_eq!(pool.difficulty_controller.as_ref().unwrap().difficulty(), 16);
+        assert_eq!(pool.current_round_luck(), 0.0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn removed_port_drained() {
+        let mut pool = test_pool();
+        pool.start_listener(0).unwrap();
+        let port = pool.listeners[&0].0.port();
+        let miner = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (other, _other_miner) = test_worker(&pool.config);
+        let other_id = other.uuid();
+        pool.workers.lock().unwrap().insert(other_id.clone(), other);
+        let start = Instant::now();
+        while pool.workers.lock().unwrap().len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.workers.lock().unwrap().len(), 2);
+
+        let moved = TEST_CONFIG.replace("port_difficulty = [0, 1]", "port_difficulty = [4444, 1]");
+        pool.apply_config(toml::from_str(&moved).unwrap());
+        pool.clean_workers();
+        {
+            let w_m = pool.workers.lock().unwrap();
+            assert_eq!(w_m.len(), 1);
+            assert!(w_m.contains_key(&other_id));
+            assert!(w_m.values().all(|w| w.port() != port as u64));
+        }
+        let disconnect = miner_read(&mut BufReader::new(miner));
+        assert_eq!(disconnect["method"], "disconnect");
+        assert_eq!(disconnect["error"]["code"], -32600);
+        assert_eq!(disconnect["error"]["message"], "Port decommissioned");
+
+        // The listener is gone too
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_ok() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        assert!(pool.listeners.is_empty());
+    }
+
+    #[test]
+    fn reconnect_with_session_token() {
+        let mut pool = test_pool();
+        let (mut first, _miner1) = test_worker(&pool.config);
+        first.user_id = 7;
+        first.state = ConnectionState::Authorized;
+        let token = first.start_session();
+        first.status.accepted = 5;
+        first.add_shares(31, 5, 0, 0);
+        let first_id = first.uuid();
+        pool.workers.lock().unwrap().insert(first_id.clone(), first);
+
+        // The connection drops
+        pool.workers.lock().unwrap().get_mut(&first_id).unwrap().disconnect();
+        assert_eq!(pool.clean_workers(), 0);
+
+        // The miner reconnects and logs in with its token
+        let (mut second, _miner2) = test_worker(&pool.config);
+        let unauthenticated_id = second.uuid();
+        second.set_login(LoginParams {
+            login: "user".to_string(),
+            pass: "".to_string(),
+            agent: "test".to_string(),
+            session_token: Some(token),
+        });
+        second.user_id = 7;
+        second.state = ConnectionState::Authorized;
+        let second_id = second.uuid();
+        pool.workers.lock().unwrap().insert(unauthenticated_id, second);
+        pool.process_worker_messages();
+
+        let w_m = pool.workers.lock().unwrap();
+        let second = &w_m[&second_id];
+        assert_eq!(second.status.accepted, 5);
+        assert_eq!(second.worker_shares.shares[&31].accepted, 5);
+    }
+
+    #[test]
+    fn clean_jobs_only_on_new_height() {
+        let mut pool = test_pool();
+        let (mut worker, miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner);
+        worker.state = ConnectionState::Authorized;
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        pool.broadcast_job(true).unwrap();
+        pool.broadcast_job(false).unwrap();
+        pool.flush_workers();
+        let job = miner_read(&mut reader);
+        assert_eq!(job["method"], "job");
+        assert_eq!(job["params"]["clean_jobs"], true);
+        assert_eq!(miner_read(&mut reader)["params"]["clean_jobs"], false);
+    }
+
+    #[test]
+    fn job_bursts_throttled() {
+        let mut pool = test_pool();
+        pool.job_throttle = JobBroadcastThrottle::new(Duration::from_millis(500));
+        let (mut worker, miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut reader = BufReader::new(miner);
+        worker.state = ConnectionState::Authorized;
+        pool.workers.lock().unwrap().insert(worker.uuid(), worker);
+
+        // A new height goes out at once, then five more templates for it in a burst
+        pool.server.job.height = 5;
+        for version in 0..6 {
+            pool.server.job.job_id = version;
+            pool.server.job.pre_pow = format!("{:02x}", version);
+            pool.accept_new_job();
+        }
+        pool.broadcast_pending_job();
+        thread::sleep(Duration::from_millis(500));
+        pool.broadcast_pending_job();
+        pool.broadcast_pending_job();
+        pool.flush_workers();
+
+        let first = miner_read(&mut reader);
+        assert_eq!(first["params"]["pre_pow"], "00");
+        assert_eq!(first["params"]["clean_jobs"], true);
+        // Only the newest of the burst is sent
+        let second = miner_read(&mut reader);
+        assert_eq!(second["params"]["pre_pow"], "05");
+        assert_eq!(second["params"]["clean_jobs"], false);
+        let mut line = String::new();
+        assert!(reader.read_line(&mut line).is_err());
+
+        // A new height is never held back
+        pool.server.job.height = 6;
+        pool.server.job.pre_pow = "06".to_string();
+        pool.accept_new_job();
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["params"]["pre_pow"], "06");
+    }
+
+    #[test]
+    fn same_height_refresh_keeps_stats() {
+        let mut pool = test_pool();
+        let pplns_file = env::temp_dir().join(format!("grin-pool-refresh-{}.bin", process::id()));
+        pool.config.grin_pool.pplns_file = pplns_file.to_str().unwrap().to_string();
+        let (mut worker, _miner) = test_worker(&pool.config);
+        worker.state = ConnectionState::Authorized;
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        pool.server.job.height = 5;
+        pool.server.job.job_id = 0;
+        pool.server.job.pre_pow = "aa".to_string();
+        pool.accept_new_job();
+        pool.workers.lock().unwrap().get_mut(&worker_id).unwrap().add_shares(31, 3, 0, 0);
+
+        // A better template for the same block
+        pool.server.job.job_id = 1;
+        pool.server.job.pre_pow = "bb".to_string();
+        pool.accept_new_job();
+        assert_eq!(pool.job_versions.len(), 2);
+        assert_eq!(pool.job_versions.get(JobId::new(5, 0).encode().unwrap()).unwrap(), "aa");
+        assert_eq!(pool.job_versions.get(JobId::new(5, 1).encode().unwrap()).unwrap(), "bb");
+        assert_eq!(pool.workers.lock().unwrap()[&worker_id].worker_shares.shares[&31].accepted, 3);
+
+        // The next block starts over, keeping the last blocks versions for its grace period
+        pool.server.job.height = 6;
+        pool.server.job.job_id = 0;
+        pool.server.job.pre_pow = "cc".to_string();
+        pool.accept_new_job();
+        assert_eq!(pool.job_versions.len(), 3);
+        pool.server.job.height = 7;
+        pool.server.job.pre_pow = "dd".to_string();
+        pool.accept_new_job();
+        assert_eq!(pool.job_versions.len(), 2);
+        assert!(pool.workers.lock().unwrap()[&worker_id].worker_shares.shares.is_empty());
+        let _ = fs::remove_file(&pplns_file);
+    }
+
+    #[test]
+    fn reorg_makes_queued_shares_stale() {
+        let mut pool = test_pool();
+        let pplns_file = env::temp_dir().join(format!("grin-pool-reorg-{}.bin", process::id()));
+        pool.config.grin_pool.pplns_file = pplns_file.to_str().unwrap().to_string();
+        pool.validate_share = nonce_difficulty;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+
+        pool.server.job.height = 5;
+        pool.server.job.job_id = 0;
+        pool.server.job.pre_pow = "aa".to_string();
+        pool.accept_new_job();
+
+        // A share for height 5 is read, but not processed before the reorg
+        let share = share_json(5, JobId::new(5, 0).encode().unwrap(), 7, 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+
+        pool.server.job.height = 4;
+        pool.server.job.pre_pow = "bb".to_string();
+        pool.accept_new_job();
+        assert_eq!(pool.job.height, 4);
+        // The new chain reaches height 5 again before the share is processed
+        pool.server.job.height = 5;
+        pool.server.job.pre_pow = "cc".to_string();
+        pool.accept_new_job();
+        pool.process_shares();
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&worker_id].status.stale, 1);
+        assert_eq!(w_m[&worker_id].status.accepted, 0);
+        let _ = fs::remove_file(&pplns_file);
+    }
+
+    #[test]
+    fn previous_height_accepted_in_grace_period() {
+        let mut pool = test_pool();
+        pool.config.grin_pool.stale_grace_period_ms = 500;
+        pool.config.grin_pool.upstream_min_difficulty = 1;
+        pool.validate_share = nonce_difficulty;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+        for height in 5..7 {
+            pool.server.job.height = height;
+            pool.server.job.pre_pow = format!("{:02x}", height);
+            pool.accept_new_job();
+        }
+
+        // Mined on the job for height 5, arriving 200ms and then 1000ms after height 6 was sent
+        for (id, delay) in [(1u64, 200u64), (2, 1000)].iter() {
+            pool.job_change_time = Instant::now() - Duration::from_millis(*delay);
+            let share = share_json(5, JobId::new(5, 0).encode().unwrap(), *id, 31, &vec![*id; PROOF_SIZE]);
+            miner_send(&mut miner, *id, "submit", &share);
+            thread::sleep(Duration::from_millis(100));
+            pool.process_worker_messages();
+            pool.process_shares();
+        }
+        pool.flush_workers();
+        assert_eq!(miner_read(&mut reader)["result"], "ok");
+        assert_eq!(miner_read(&mut reader)["error"]["code"], -32503);
+        let w_m = pool.workers.lock().unwrap();
+        let status = &w_m[&worker_id].status;
+        assert_eq!((status.accepted, status.stale_accepted, status.stale), (1, 1, 1));
+        // Forwarded upstream like any other good share
+        assert_eq!(status.pool_accepted, 1);
+    }
+
+    #[test]
+    fn share_for_the_highest_height_is_stale() {
+        let mut pool = test_pool();
+        pool.config.grin_pool.stale_grace_period_ms = 500;
+        pool.validate_share = nonce_difficulty;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+        pool.server.job.height = 5;
+        pool.server.job.pre_pow = "05".to_string();
+        pool.accept_new_job();
+
+        // In the grace period, where the height before the current one is checked
+        pool.job_change_time = Instant::now();
+        let share = share_json(u64::max_value(), 0, 1, 31, &vec![1u64; PROOF_SIZE]);
+        miner_send(&mut miner, 1, "submit", &share);
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&worker_id].status.stale, 1);
+        assert_eq!(w_m[&worker_id].status.accepted, 0);
+    }
+
+    #[test]
+    fn deep_stale_shares_count_as_invalid() {
+        let mut pool = test_pool();
+        pool.config.workers.max_benign_stale_depth = 2;
+        pool.config.workers.max_invalid_per_minute = 1;
+        pool.config.grin_pool.stale_grace_period_ms = 0;
+        pool.validate_share = nonce_difficulty;
+        let (mut worker, mut miner) = test_worker(&pool.config);
+        worker.set_difficulty(1);
+        let worker_id = worker.uuid();
+        pool.workers.lock().unwrap().insert(worker_id.clone(), worker);
+        for height in 5..9 {
+            pool.server.job.height = height;
+            pool.server.job.pre_pow = format!("{:02x}", height);
+            pool.accept_new_job();
+        }
+
+        // Heights 7 and 6 are only late, 5 and one never seen are not
+        for (id, height) in [7u64, 6, 5, 2].iter().enumerate() {
+            let share = share_json(*height, JobId::new(*height, 0).encode().unwrap(), 7, 31, &vec![1u64; PROOF_SIZE]);
+            miner_send(&mut miner, id as u64, "submit", &share);
+        }
+        thread::sleep(Duration::from_millis(100));
+        pool.process_worker_messages();
+        pool.process_shares();
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m[&worker_id].status.stale, 4);
+        assert_eq!(w_m[&worker_id].status.deep_stale, 2);
+        // The second deeply stale share is one invalid share too many
+        assert!(w_m[&worker_id].error());
+        assert!(is_banned(&pool.banned, "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_listener_and_ban() {
+        assert_eq!(socket_address("::1", 3333).unwrap().to_string(), "[::1]:3333");
+        assert_eq!(socket_address("127.0.0.1", 3333).unwrap().to_string(), "127.0.0.1:3333");
+        // Nothing more to check without an IPv6 loopback
+        let listener = match bind_workers("::1", 0) {
+            Ok(listener) => listener,
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        // Banned by ip address, whatever the source port
+        let banned: Arc<Mutex<HashMap<IpAddr, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+        let ip: IpAddr = "::1".parse().unwrap();
+        banned.lock().unwrap().insert(ip, Instant::now() + Duration::from_secs(60));
+        assert!(is_banned(&banned, ip));
+
+        let arrived = spawn_listener(test_config(), listener, banned);
+        let miner = TcpStream::connect(("::1", port)).unwrap();
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut line = String::new();
+        assert_eq!(BufReader::new(miner).read_line(&mut line).unwrap(), 0);
+        assert!(arrived.try_recv().is_err());
+    }
+
+    #[test]
+    fn connection_flood_dropped_and_banned() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let banned: Arc<Mutex<HashMap<IpAddr, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut config = test_config();
+        config.workers.max_conns_per_ip_per_min = 2;
+        config.workers.ban_connection_floods = true;
+        let arrived = spawn_listener(config, listener, banned.clone());
+        let _miners: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap()).collect();
+        let flood = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        flood.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut line = String::new();
+        assert_eq!(BufReader::new(flood).read_line(&mut line).unwrap(), 0);
+        assert_eq!(arrived.try_iter().count(), 2);
+        assert!(is_banned(&banned, "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn proxy_header_gives_worker_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let banned: Arc<Mutex<HashMap<IpAddr, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+        banned.lock().unwrap().insert("203.0.113.9".parse().unwrap(), Instant::now() + Duration::from_secs(60));
+        let mut config = test_config();
+        config.workers.proxy_protocol = true;
+        let arrived = spawn_listener(config, listener, banned);
+        let connect = |header: &str| {
+            let mut miner = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            miner.write_all(header.as_bytes()).unwrap();
+            miner
+        };
+
+        // A connection slow to send its header does not hold up the next
+        let _silent = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        // The worker has the address from the header, not the balancers
+        let _miner = connect("PROXY TCP4 203.0.113.7 127.0.0.1 50000 3333\r\n");
+        let worker = arrived.recv_timeout(Duration::from_millis(500)).unwrap();
+        assert_eq!(worker.ip(), Some("203.0.113.7".parse().unwrap()));
+
+        // Bans apply to the address in the header, and a connection without one is refused
+        for header in ["PROXY TCP4 203.0.113.9 127.0.0.1 50000 3333\r\n", "{\"id\":\"1\"}\n"].iter() {
+            let miner = connect(header);
+            let mut line = String::new();
+            // Closed with unread bytes the connection may be reset rather than ended
+            assert_eq!(BufReader::new(miner).read_line(&mut line).unwrap_or(0), 0);
+        }
+        assert!(arrived.try_recv().is_err());
+    }
+
+    #[test]
+    fn unix_socket_worker() {
+        let path = env::temp_dir().join(format!("grin-pool-test-{}.sock", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let listener = bind_unix_workers(&path).unwrap();
+        let (new_workers, arrived) = channel();
+        let path_th = path.clone();
+        let config = test_config();
+        thread::spawn(move || {
+            let login_limiter = Arc::new(Mutex::new(IpRateLimiter::new(5)));
+            let pool_connections = Arc::new(AtomicUsize::new(0));
+            accept_unix_workers("test".to_string(), config, listener, path_th, 3, new_workers, pool_connections, login_limiter);
+        });
+        let mut miner = UnixStream::connect(&path).unwrap();
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        miner
+            .write_all(b"{\"id\":\"1\",\"jsonrpc\":\"2.0\",\"method\":\"keepalive\",\"params\":null}\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        {
+            let mut worker = arrived.recv_timeout(Duration::from_secs(5)).unwrap();
+            // Nothing to ban or limit by
+            assert!(worker.ip().is_none());
+            assert_eq!(worker.status.difficulty, 3);
+            worker.process_messages().unwrap();
+            worker.flush_outbound().unwrap();
+        }
+        let mut line = String::new();
+        BufReader::new(miner).read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["result"], "ok");
+
+        // A socket left behind is replaced, anything else is not touched
+        assert!(bind_unix_workers(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, "not a socket").unwrap();
+        assert!(bind_unix_workers(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn websocket_worker() {
+        let mut pool = test_pool();
+        pool.config.workers.enable_websocket = true;
+        pool.validate_share = nonce_difficulty;
+        pool.job.height = 1;
+        let job_id = JobId::new(1, 0).encode().unwrap();
+        pool.job_versions.insert(job_id, 1, "00".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let arrived = spawn_listener(pool.config.clone(), listener, Arc::new(Mutex::new(HashMap::new())));
+        // A client slow to start its handshake does not hold up the next
+        let _silent = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut ws, _) = tungstenite::client(format!("ws://127.0.0.1:{}/", port).as_str(), stream).unwrap();
+        let worker = arrived.recv_timeout(Duration::from_secs(1)).unwrap();
+        pool.new_worker_sender.send(worker).unwrap();
+        pool.add_new_workers();
+        let read = |ws: &mut tungstenite::WebSocket<TcpStream>| -> Value {
+            match ws.read_message().unwrap() {
+                tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("Unexpected message {:?}", other),
+            }
+        };
+
+        // The user is known, as if redis had it
+        for worker in pool.workers.lock().unwrap().values_mut() {
+            assert!(worker.is_websocket());
+            worker.user_id = 7;
+        }
+        let login = r#"{"id":"1","jsonrpc":"2.0","method":"login","params":{"login":"alice","pass":"x","agent":"test"}}"#;
+        ws.write_message(tungstenite::Message::Text(login.to_string())).unwrap();
+        pool.wait_for_events(Duration::from_secs(5));
+        pool.process_worker_messages();
+        pool.flush_workers();
+        let logged_in = read(&mut ws);
+        assert_eq!(logged_in["id"], "1");
+        assert_eq!(logged_in["result"], "ok");
+
+        let share = format!(
+            "{{\"id\":\"2\",\"jsonrpc\":\"2.0\",\"method\":\"submit\",\"params\":{}}}",
+            share_json(1, job_id, 5, 31, &vec![5u64; PROOF_SIZE])
+        );
+        ws.write_message(tungstenite::Message::Text(share)).unwrap();
+        pool.wait_for_events(Duration::from_secs(5));
+        pool.process_worker_messages();
+        pool.process_shares();
+        pool.flush_workers();
+        let response = read(&mut ws);
+        assert_eq!(response["id"], "2");
+        assert_eq!(response["result"], "ok");
+        let w_m = pool.workers.lock().unwrap();
+        assert_eq!(w_m.values().next().unwrap().status.accepted, 1);
+    }
 }
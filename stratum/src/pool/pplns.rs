@@ -0,0 +1,342 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PPLNS Share Window
+//!
+//! Pay-Per-Last-N-Shares accounting: the last N accepted shares across
+//! blocks, optionally also limited to the last T seconds, used to split a
+//! block reward between workers.
+//!
+//! Shares are weighted by their difficulty and by their size: each extra
+//! edge bit doubles the work to find a share, so it doubles its weight.
+//!
+
+use bincode;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ShareEntry {
+    pub worker_id: String,
+    pub edge_bits: u32,
+    pub timestamp: u64, // Unix time the share was accepted
+    pub difficulty: u64,
+}
+
+/// Reward weight of a share of this size at difficulty 1, a C24 share weighs 1
+pub fn edge_bits_weight(edge_bits: u32) -> f64 {
+    2f64.powi(edge_bits as i32 - 24)
+}
+
+// Weight of a share in the window, in the same proportion as
+// difficulty * edge_bits_weight but kept integer so payouts are exact
+fn share_weight(share: &ShareEntry) -> u128 {
+    (share.difficulty as u128) << min(share.edge_bits, 63)
+}
+
+/// Payouts for a block reward split over the window
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PayoutSnapshot {
+    pub reward: u64,
+    pub fee_percent: f64,
+    pub fee_address: String,
+    pub fee: u64, // Owed to the fee address, 0 without a fee
+    pub payouts: HashMap<String, u64>, // Worker id, paid after the fee
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PplnsWindow {
+    max_shares: usize,
+    #[serde(skip)]
+    max_age_secs: u64, // Drop shares older than this, 0 keeps them until evicted by count
+    #[serde(skip)]
+    fee_percent: f64, // Taken from every payout
+    #[serde(skip)]
+    fee_address: String, // Paid the fees
+    shares: VecDeque<ShareEntry>, // Oldest share first
+}
+
+impl PplnsWindow {
+    /// Create an empty window holding the last max_shares shares
+    pub fn new(max_shares: usize) -> PplnsWindow {
+        PplnsWindow {
+            max_shares: max_shares,
+            max_age_secs: 0,
+            fee_percent: 0.0,
+            fee_address: String::new(),
+            shares: VecDeque::with_capacity(max_shares),
+        }
+    }
+
+    /// Load a saved window, or start an empty one if there is none
+    pub fn load(path: &str, max_shares: usize) -> PplnsWindow {
+        let mut window = match File::open(path) {
+            Ok(file) => match bincode::deserialize_from(BufReader::new(file)) {
+                Ok(window) => window,
+                Err(e) => {
+                    error!("Failed to read PPLNS window from {}: {:?}", path, e);
+                    PplnsWindow::new(max_shares)
+                }
+            },
+            Err(e) => {
+                warn!("No saved PPLNS window at {}: {}", path, e);
+                PplnsWindow::new(max_shares)
+            }
+        };
+        // The configured window size wins over the saved one
+        window.max_shares = max_shares;
+        window.trim();
+        window
+    }
+
+    /// Also drop shares older than max_age_secs, 0 disables
+    pub fn set_max_age(&mut self, max_age_secs: u64) {
+        self.max_age_secs = max_age_secs;
+        self.trim();
+    }
+
+    /// Take fee_percent of every payout for fee_address
+    pub fn set_fee(&mut self, fee_percent: f64, fee_address: String) {
+        self.fee_percent = fee_percent;
+        self.fee_address = fee_address;
+    }
+
+    /// Save the window so it survives a restart
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        bincode::serialize_into(BufWriter::new(file), self).map_err(|e| e.to_string())
+    }
+
+    /// Add an accepted share, evicting the oldest once the window is full
+    pub fn add_share(&mut self, worker_id: String, edge_bits: u32, difficulty: u64) {
+        self.push(ShareEntry {
+            worker_id: worker_id,
+            edge_bits: edge_bits,
+            timestamp: now(),
+            difficulty: difficulty,
+        });
+    }
+
+    /// Add a share entry, evicting the oldest once the window is full
+    pub fn push(&mut self, share: ShareEntry) {
+        self.shares.push_back(share);
+        self.trim();
+    }
+
+    /// Number of shares in the window
+    pub fn len(&self) -> usize {
+        self.shares.len()
+    }
+
+    /// Number and total difficulty of the shares accepted at or after timestamp
+    pub fn shares_since(&self, timestamp: u64) -> (u64, u64) {
+        let mut count = 0;
+        let mut difficulty = 0;
+        for share in self.shares.iter().rev() {
+            if share.timestamp < timestamp {
+                break;
+            }
+            count += 1;
+            difficulty += share.difficulty;
+        }
+        (count, difficulty)
+    }
+
+    /// Each workers fraction of the total share weight in the window
+    pub fn contributions(&self) -> HashMap<String, f64> {
+        let mut weights: HashMap<String, u128> = HashMap::new();
+        let mut total_weight: u128 = 0;
+        for share in self.shares.iter() {
+            *weights.entry(share.worker_id.clone()).or_insert(0) += share_weight(share);
+            total_weight += share_weight(share);
+        }
+        weights
+            .into_iter()
+            .map(|(worker_id, weight)| (worker_id, weight as f64 / total_weight as f64))
+            .collect()
+    }
+
+    /// Split total_reward between workers in proportion to the weight of
+    /// their shares in the window.  Rounding dust is not paid out.  With a
+    /// fee the fee address is included, paid what was taken from everyone.
+    pub fn compute_payouts(&self, total_reward: u64) -> HashMap<String, u64> {
+        let snapshot = self.payout_snapshot(total_reward);
+        let mut payouts = snapshot.payouts;
+        if snapshot.fee_percent > 0.0 {
+            *payouts.entry(snapshot.fee_address).or_insert(0) += snapshot.fee;
+        }
+        payouts
+    }
+
+    /// What a block found now would pay each worker, with the fee owed to
+    /// the fee address kept apart
+    pub fn payout_snapshot(&self, total_reward: u64) -> PayoutSnapshot {
+        let mut weights: HashMap<String, u128> = HashMap::new();
+        let mut total_weight: u128 = 0;
+        for share in self.shares.iter() {
+            *weights.entry(share.worker_id.clone()).or_insert(0) += share_weight(share);
+            total_weight += share_weight(share);
+        }
+        let mut snapshot = PayoutSnapshot {
+            reward: total_reward,
+            fee_percent: self.fee_percent,
+            fee_address: self.fee_address.clone(),
+            fee: 0,
+            payouts: HashMap::new(),
+        };
+        if total_weight == 0 {
+            return snapshot;
+        }
+        for (worker_id, weight) in weights {
+            let reward = ((total_reward as u128) * weight / total_weight) as u64;
+            let worker_fee = (reward as f64 * self.fee_percent / 100.0) as u64;
+            snapshot.fee += worker_fee;
+            snapshot.payouts.insert(worker_id, reward - worker_fee);
+        }
+        snapshot
+    }
+
+    fn trim(&mut self) {
+        while self.shares.len() > self.max_shares {
+            self.shares.pop_front();
+        }
+        if self.max_age_secs > 0 {
+            let oldest = now().saturating_sub(self.max_age_secs);
+            while self.shares.front().map_or(false, |share| share.timestamp < oldest) {
+                self.shares.pop_front();
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payouts_weighted_by_difficulty() {
+        let mut window = PplnsWindow::new(10);
+        window.add_share("a".to_string(), 29, 1);
+        window.add_share("a".to_string(), 29, 2);
+        window.add_share("b".to_string(), 29, 6);
+        let payouts = window.compute_payouts(900);
+        assert_eq!(payouts["a"], 300);
+        assert_eq!(payouts["b"], 600);
+        // Dust from rounding down is not paid out
+        let payouts = window.compute_payouts(10);
+        assert_eq!(payouts["a"], 3);
+        assert_eq!(payouts["b"], 6);
+        assert!(PplnsWindow::new(10).compute_payouts(900).is_empty());
+    }
+
+    #[test]
+    fn payouts_weighted_by_edge_bits() {
+        assert_eq!(edge_bits_weight(24), 1.0);
+        assert_eq!(edge_bits_weight(31), 128.0 * edge_bits_weight(24));
+        let mut window = PplnsWindow::new(10);
+        window.add_share("a".to_string(), 29, 1);
+        window.add_share("b".to_string(), 31, 1);
+        let payouts = window.compute_payouts(1000);
+        assert_eq!(payouts["a"], 200);
+        assert_eq!(payouts["b"], 800);
+        // A C24 share at difficulty 128 weighs the same as a C31 share at 1
+        let mut window = PplnsWindow::new(10);
+        window.add_share("a".to_string(), 24, 128);
+        window.add_share("b".to_string(), 31, 1);
+        assert_eq!(window.contributions()["a"], 0.5);
+    }
+
+    #[test]
+    fn window_evicts_at_n_shares() {
+        let mut window = PplnsWindow::new(3);
+        window.add_share("a".to_string(), 29, 1);
+        window.add_share("b".to_string(), 29, 1);
+        window.add_share("b".to_string(), 29, 1);
+        assert_eq!(window.len(), 3);
+        assert_eq!(window.compute_payouts(3)["a"], 1);
+        // The 4th share pushes out the oldest, "a" is no longer paid
+        window.add_share("b".to_string(), 29, 1);
+        assert_eq!(window.len(), 3);
+        let payouts = window.compute_payouts(3);
+        assert!(!payouts.contains_key("a"));
+        assert_eq!(payouts["b"], 3);
+    }
+
+    #[test]
+    fn window_evicts_old_shares() {
+        let mut window = PplnsWindow::new(10);
+        window.set_max_age(60);
+        window.push(ShareEntry {
+            worker_id: "a".to_string(),
+            edge_bits: 29,
+            timestamp: now() - 120,
+            difficulty: 1,
+        });
+        window.add_share("b".to_string(), 31, 1);
+        window.add_share("b".to_string(), 31, 2);
+        window.add_share("c".to_string(), 31, 1);
+        assert_eq!(window.len(), 3);
+        let contributions = window.contributions();
+        assert!(!contributions.contains_key("a"));
+        assert_eq!(contributions["b"], 0.75);
+        assert_eq!(contributions["c"], 0.25);
+        assert!(PplnsWindow::new(10).contributions().is_empty());
+    }
+
+    #[test]
+    fn fee_deducted_from_payouts() {
+        let mut window = PplnsWindow::new(10);
+        window.add_share("a".to_string(), 29, 1);
+        window.add_share("b".to_string(), 29, 2);
+        // No fee, no fee entry
+        let payouts = window.compute_payouts(900);
+        assert_eq!(payouts.len(), 2);
+        assert_eq!(payouts["a"], 300);
+
+        window.set_fee(1.0, "pool".to_string());
+        let payouts = window.compute_payouts(900);
+        assert_eq!(payouts["a"], 297);
+        assert_eq!(payouts["b"], 594);
+        assert_eq!(payouts["pool"], 9);
+
+        window.set_fee(99.9, "pool".to_string());
+        let payouts = window.compute_payouts(1000);
+        assert_eq!(payouts.values().sum::<u64>(), 999);
+        assert!(payouts["pool"] >= 997);
+    }
+
+    #[test]
+    fn fee_kept_apart_in_snapshot() {
+        let mut window = PplnsWindow::new(10);
+        assert_eq!(window.payout_snapshot(900).fee, 0);
+        window.add_share("a".to_string(), 29, 1);
+        window.add_share("pool".to_string(), 29, 2);
+        window.set_fee(1.0, "pool".to_string());
+        let snapshot = window.payout_snapshot(900);
+        assert_eq!(snapshot.fee, 9);
+        assert_eq!(snapshot.payouts["a"], 297);
+        // Mining to the fee address does not hide the fee
+        assert_eq!(snapshot.payouts["pool"], 594);
+        assert_eq!(window.compute_payouts(900)["pool"], 603);
+    }
+}
@@ -20,8 +20,8 @@ use bufstream::BufStream;
 use serde_json;
 use serde_json::Value;
 use std::io::BufRead;
-use std::io::{ErrorKind, Write};
-use std::net::TcpStream;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Instant;
 
 // ----------------------------------------
 // RPC Messages
@@ -44,6 +44,8 @@ pub struct RpcResponse {
     pub method: String,
     pub result: Option<Value>,
     pub error: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>, // Only in login responses to workers
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -52,11 +54,88 @@ pub struct RpcError {
     pub message: String,
 }
 
+/// Why a request from a worker was refused.  Miner software matches on the
+/// codes and messages, so they must not change.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    PoolFull,
+    TooManyConnections,
+    TooManyLoginAttempts,
+    MissingLoginParams,
+    InvalidLoginParams,
+    LoginFailed(String),
+    NotAuthorized,
+    InvalidDifficulty,
+    RateExceeded,
+    InvalidSolution,
+    InvalidPowSize,
+    InvalidProofSize,
+    LowDifficulty,
+    NonceOutOfRange,
+    TooLate,
+    NodeSyncing,
+    PortDecommissioned,
+}
+
+impl RejectReason {
+    pub fn code(&self) -> i32 {
+        match *self {
+            RejectReason::PoolFull | RejectReason::TooManyConnections => -32000,
+            RejectReason::TooManyLoginAttempts => -32001,
+            RejectReason::RateExceeded => -32004,
+            RejectReason::MissingLoginParams
+            | RejectReason::InvalidLoginParams
+            | RejectReason::LoginFailed(_)
+            | RejectReason::NotAuthorized
+            | RejectReason::InvalidDifficulty => -32500,
+            RejectReason::InvalidSolution
+            | RejectReason::InvalidPowSize
+            | RejectReason::InvalidProofSize
+            | RejectReason::LowDifficulty
+            | RejectReason::NonceOutOfRange => -32502,
+            RejectReason::TooLate => -32503,
+            RejectReason::NodeSyncing => -32701,
+            RejectReason::PortDecommissioned => -32600,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            RejectReason::PoolFull => "Pool full".to_string(),
+            RejectReason::TooManyConnections => "Too many connections from your address".to_string(),
+            RejectReason::TooManyLoginAttempts => "Too many login attempts".to_string(),
+            RejectReason::MissingLoginParams => "Missing Login request parameters".to_string(),
+            RejectReason::InvalidLoginParams => "Invalid Login request parameters".to_string(),
+            RejectReason::LoginFailed(ref message) => message.clone(),
+            RejectReason::NotAuthorized => "Not authorized".to_string(),
+            RejectReason::InvalidDifficulty => "Invalid difficulty".to_string(),
+            RejectReason::RateExceeded => "Share submission rate exceeded".to_string(),
+            RejectReason::InvalidSolution => "Failed to validate solution".to_string(),
+            RejectReason::InvalidPowSize => "Invalid POW size".to_string(),
+            RejectReason::InvalidProofSize => "Invalid PROOF_SIZE".to_string(),
+            RejectReason::LowDifficulty => "Rejected low difficulty solution".to_string(),
+            RejectReason::NonceOutOfRange => "Nonce out of assigned range".to_string(),
+            RejectReason::TooLate => "Solution submitted too late".to_string(),
+            RejectReason::NodeSyncing => "Node is syncing - Please wait".to_string(),
+            RejectReason::PortDecommissioned => "Port decommissioned".to_string(),
+        }
+    }
+
+    pub fn rpc_error(&self) -> RpcError {
+        RpcError {
+            code: self.code(),
+            message: self.message(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoginParams {
     pub login: String,
     pub pass: String,
     pub agent: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>, // From an earlier login response, to resume that session
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -66,6 +145,61 @@ pub struct SubmitParams {
     pub nonce: u64,
     pub edge_bits: u32,
     pub pow: Vec<u64>,
+    #[serde(skip)]
+    pub stale: bool, // Its block was reorged away while it waited to be processed
+}
+
+impl SubmitParams {
+    /// Mark the share stale if it is for a block above the height the
+    /// chain reorged back to, returns true if it was marked
+    pub fn mark_stale_on_reorg(&mut self, current_height: u64) -> bool {
+        if self.height > current_height {
+            self.stale = true;
+        }
+        return self.stale;
+    }
+}
+
+// Low bits of a worker job_id hold the job version, the rest the height
+pub const JOB_VERSION_BITS: u32 = 24;
+const MAX_JOB_HEIGHT: u64 = (1 << (64 - JOB_VERSION_BITS)) - 1;
+pub const MAX_JOB_VERSION: u64 = (1 << JOB_VERSION_BITS) - 1;
+
+/// The job_id workers see: the block height and the upstream job version
+/// at that height packed into one number (bminer wants job_ids unique
+/// across heights)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId {
+    pub height: u64,
+    pub version: u64,
+}
+
+impl JobId {
+    pub fn new(height: u64, version: u64) -> JobId {
+        JobId {
+            height: height,
+            version: version,
+        }
+    }
+
+    /// Pack into a worker job_id, fails if either part does not fit
+    pub fn encode(&self) -> Result<u64, String> {
+        if self.height > MAX_JOB_HEIGHT {
+            return Err(format!("Job height {} does not fit in a job_id", self.height));
+        }
+        if self.version > MAX_JOB_VERSION {
+            return Err(format!("Job version {} does not fit in a job_id", self.version));
+        }
+        Ok((self.height << JOB_VERSION_BITS) | self.version)
+    }
+
+    /// Unpack a worker job_id, the exact reverse of encode
+    pub fn decode(job_id: u64) -> JobId {
+        JobId {
+            height: job_id >> JOB_VERSION_BITS,
+            version: job_id & MAX_JOB_VERSION,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -74,6 +208,10 @@ pub struct JobTemplate {
     pub job_id: u64,
     pub difficulty: u64,
     pub pre_pow: String,
+    #[serde(default)]
+    pub clean_jobs: bool, // Set when the height changed, miners should drop work on older jobs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce_start: Option<u64>, // Where this worker should start its nonce search, if ranges are assigned
 }
 
 impl JobTemplate {
@@ -83,6 +221,8 @@ impl JobTemplate {
             job_id: 0,
             difficulty: 0,
             pre_pow: "".to_string(),
+            clean_jobs: false,
+            nonce_start: None,
         }
     }
 }
@@ -93,8 +233,19 @@ pub struct WorkerStatus {
     pub height: u64,
     pub difficulty: u64,
     pub accepted: u64,
+    #[serde(default)]
+    pub pool_accepted: u64, // Accepted shares that also met the upstream minimum and were submitted
+    #[serde(default)]
+    pub weighted_accepted: f64, // Accepted shares weighted by edge_bits, a C24 share weighs 1
     pub rejected: u64,
     pub stale: u64,
+    #[serde(default)]
+    pub deep_stale: u64, // Stale shares for heights older than max_benign_stale_depth, also counted in stale
+    #[serde(default)]
+    pub stale_accepted: u64, // Shares for the previous height accepted in the grace period, also counted in accepted
+    pub dropped_messages: u64, // Outbound messages dropped because the worker was not reading
+    #[serde(skip)]
+    last_seen: Option<Instant>,
 }
 
 impl WorkerStatus {
@@ -104,8 +255,27 @@ impl WorkerStatus {
             height: 0,
             difficulty: 0,
             accepted: 0,
+            pool_accepted: 0,
+            weighted_accepted: 0.0,
             rejected: 0,
             stale: 0,
+            deep_stale: 0,
+            stale_accepted: 0,
+            dropped_messages: 0,
+            last_seen: Some(Instant::now()),
+        }
+    }
+
+    /// Record the time a message was last received from the worker
+    pub fn set_last_seen(&mut self, when: Instant) {
+        self.last_seen = Some(when);
+    }
+
+    /// Seconds since a message was last received from the worker
+    pub fn idle_seconds(&self) -> u64 {
+        match self.last_seen {
+            Some(when) => when.elapsed().as_secs(),
+            None => 0,
         }
     }
 }
@@ -126,9 +296,9 @@ impl StratumProtocol {
     }
 
     /// Read a message from the stream
-    fn read_message(
+    fn read_message<S: Read + Write>(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<S>,
         buffer: &mut String,
     ) -> Result<Option<String>, String> {
         // Read and return a single message or None or Err
@@ -139,7 +309,7 @@ impl StratumProtocol {
                 buffer.clear();
                 return Ok(Some(res));
             }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
                 // Not an error, just no messages ready
                 return Ok(None);
             }
@@ -152,10 +322,10 @@ impl StratumProtocol {
     }
 
     /// Write a message to the stream and flush
-    pub fn write_message(
+    pub fn write_message<S: Read + Write>(
         &mut self,
         message_in: String,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<S>,
     ) -> Result<(), String> {
         let mut message = message_in.clone();
         if !message.ends_with("\n") {
@@ -178,9 +348,9 @@ impl StratumProtocol {
     }
 
     /// Get a message from the upstream
-    pub fn get_message(
+    pub fn get_message<S: Read + Write>(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<S>,
         buffer: &mut String,
     ) -> Result<Option<String>, String> {
         return self.read_message(stream, buffer);
@@ -188,13 +358,24 @@ impl StratumProtocol {
 
     /// Send a Request
     // params is the method parameters in serde_json string
-    pub fn send_request(
+    pub fn send_request<S: Read + Write>(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<S>,
         method: String,
         params: Option<Value>,
         connection_id: Option<String>,
     ) -> Result<(), String> {
+        let req_str = self.request(method, params, connection_id);
+        return self.write_message(req_str, stream);
+    }
+
+    /// Build a Request message
+    pub fn request(
+        &self,
+        method: String,
+        params: Option<Value>,
+        connection_id: Option<String>,
+    ) -> String {
         let request_id = match connection_id {
             None => "0".to_string(),
             Some(id) => id,
@@ -211,23 +392,30 @@ impl StratumProtocol {
             self.id,
             req_str
         );
-        return self.write_message(req_str, stream);
+        return req_str;
     }
 
     /// Send a Response
-    pub fn send_response(
+    pub fn send_response<S: Read + Write>(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<S>,
         method: String,
         result: Value,
         id: Option<String>,
     ) -> Result<(), String> {
+        let res_str = self.response(method, result, id);
+        return self.write_message(res_str, stream);
+    }
+
+    /// Build a Response message
+    pub fn response(&self, method: String, result: Value, id: Option<String>) -> String {
         let res = RpcResponse {
             id: id.clone().unwrap(),
             jsonrpc: "2.0".to_string(),
             method: method,
             result: Some(result),
             error: None,
+            session_token: None,
         };
         let res_str = serde_json::to_string(&res).unwrap();
         trace!(
@@ -235,29 +423,106 @@ impl StratumProtocol {
             self.id,
             res_str
         );
-        return self.write_message(res_str, stream);
+        return res_str;
+    }
+
+    /// Build a login OK Response carrying the workers session token
+    pub fn login_response(&self, method: String, session_token: String, id: Option<String>) -> String {
+        let res = RpcResponse {
+            id: id.clone().unwrap(),
+            jsonrpc: "2.0".to_string(),
+            method: method,
+            result: Some(Value::from("ok")),
+            error: None,
+            session_token: Some(session_token),
+        };
+        let res_str = serde_json::to_string(&res).unwrap();
+        trace!(
+            "{} - Responding: {}",
+            self.id,
+            res_str
+        );
+        return res_str;
     }
 
     /// Send an Error Response
-    pub fn send_error_response(
+    pub fn send_error_response<S: Read + Write>(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<S>,
         method: String,
         error: RpcError,
         id: Option<String>,
     ) -> Result<(), String> {
+        let res_str = self.error_response(method, error, id);
+        return self.write_message(res_str, stream);
+    }
+
+    /// Build an Error Response message
+    pub fn error_response(&self, method: String, error: RpcError, id: Option<String>) -> String {
         let res = RpcResponse {
             id: id.clone().unwrap(),
             jsonrpc: "2.0".to_string(),
             method: method,
             result: None,
             error: Some(serde_json::to_value(error).unwrap()),
+            session_token: None,
         };
         let res_str = serde_json::to_string(&res).unwrap();
         trace!(
             "{} - Responding with Error: {}",
             self.id,
             res_str);
-        return self.write_message(res_str, stream);
+        return res_str;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_id_round_trip() {
+        let cases = [
+            (0, 0),
+            (1, 0),
+            (1, 999),
+            (1, 1000),
+            (250000, 12345),
+            (MAX_JOB_HEIGHT, MAX_JOB_VERSION),
+        ];
+        for &(height, version) in cases.iter() {
+            let job_id = JobId::new(height, version);
+            assert_eq!(JobId::decode(job_id.encode().unwrap()), job_id);
+        }
+        // No collisions between heights, even with many versions
+        assert!(JobId::new(1, 1000).encode().unwrap() != JobId::new(2, 0).encode().unwrap());
+    }
+
+    #[test]
+    fn job_id_overflow() {
+        assert!(JobId::new(1, MAX_JOB_VERSION + 1).encode().is_err());
+        assert!(JobId::new(MAX_JOB_HEIGHT + 1, 0).encode().is_err());
+    }
+
+    #[test]
+    fn reject_reasons_on_the_wire() {
+        // Miners depend on these exact codes and messages
+        let cases = [
+            (RejectReason::InvalidSolution, -32502, "Failed to validate solution"),
+            (RejectReason::LowDifficulty, -32502, "Rejected low difficulty solution"),
+            (RejectReason::NonceOutOfRange, -32502, "Nonce out of assigned range"),
+            (RejectReason::TooLate, -32503, "Solution submitted too late"),
+            (RejectReason::NodeSyncing, -32701, "Node is syncing - Please wait"),
+            (RejectReason::RateExceeded, -32004, "Share submission rate exceeded"),
+            (RejectReason::PoolFull, -32000, "Pool full"),
+            (RejectReason::LoginFailed("Bad login".to_string()), -32500, "Bad login"),
+            (RejectReason::NotAuthorized, -32500, "Not authorized"),
+            (RejectReason::PortDecommissioned, -32600, "Port decommissioned"),
+        ];
+        for &(ref reason, code, message) in cases.iter() {
+            let e = reason.rpc_error();
+            assert_eq!(e.code, code);
+            assert_eq!(e.message, message);
+        }
     }
 }
@@ -0,0 +1,212 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PROXY Protocol
+//!
+//! Behind a TCP load balancer every connection comes from the balancers
+//! address, so bans and per ip limits would all land on it.  A balancer
+//! speaking the PROXY protocol starts each connection with a header giving
+//! the miners own address, as a line of text in version 1 like
+//! `PROXY TCP4 192.0.2.1 198.51.100.1 56324 3333`, or in binary in
+//! version 2.  The header is read off the connection before the worker sees
+//! it.  With the PROXY protocol on, a connection without a valid header is
+//! refused - it did not come through the balancer, and would be trusted
+//! with whatever address it connected from.
+//!
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+// The balancer sends the header as soon as it connects
+const HEADER_TIMEOUT_MS: u64 = 1000;
+// Longest version 1 header, with its \r\n
+const MAX_V1_LEN: usize = 107;
+const V1_PREFIX: &'static [u8] = b"PROXY ";
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+/// Read the PROXY header from the start of a new connection.  Returns the
+/// miners address, or None when the header says the connection is the
+/// balancers own, like a health check
+pub fn read_proxy_header(stream: &TcpStream) -> Result<Option<SocketAddr>, String> {
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(HEADER_TIMEOUT_MS)));
+    let mut reader = stream;
+    read_header(&mut reader)
+}
+
+// Reads no further than the header, the rest is the workers
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>, String> {
+    let mut prefix = [0u8; 6];
+    read_exact(reader, &mut prefix)?;
+    if prefix[..] == V1_PREFIX[..] {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= MAX_V1_LEN {
+                return Err("PROXY header is too long".to_string());
+            }
+            let mut byte = [0u8; 1];
+            read_exact(reader, &mut byte)?;
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8(line).map_err(|_| "PROXY header is not text".to_string())?;
+        parse_v1(&line[..line.len() - 2])
+    } else if prefix[..] == V2_SIGNATURE[..6] {
+        let mut header = [0u8; 10];
+        read_exact(reader, &mut header)?;
+        if header[..6] != V2_SIGNATURE[6..] {
+            return Err("PROXY header has a bad signature".to_string());
+        }
+        let len = ((header[8] as usize) << 8) | header[9] as usize;
+        let mut addresses = vec![0u8; len];
+        read_exact(reader, &mut addresses)?;
+        parse_v2(header[6], header[7], &addresses)
+    } else {
+        Err("Connection did not start with a PROXY header".to_string())
+    }
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), String> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| format!("Failed to read the PROXY header: {}", e))
+}
+
+// PROXY TCP4|TCP6 source destination source_port destination_port, or PROXY UNKNOWN
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, String> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.get(1) == Some(&"UNKNOWN") {
+        return Ok(None);
+    }
+    if parts.len() != 6 {
+        return Err(format!("PROXY header {:?} is malformed", line));
+    }
+    let addresses = (parts[2].parse::<IpAddr>(), parts[3].parse::<IpAddr>());
+    let ip = match (parts[1], addresses) {
+        ("TCP4", (Ok(IpAddr::V4(source)), Ok(IpAddr::V4(_)))) => IpAddr::V4(source),
+        ("TCP6", (Ok(IpAddr::V6(source)), Ok(IpAddr::V6(_)))) => IpAddr::V6(source),
+        _ => return Err(format!("PROXY header {:?} has bad addresses", line)),
+    };
+    match (parts[4].parse::<u16>(), parts[5].parse::<u16>()) {
+        (Ok(port), Ok(_)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(format!("PROXY header {:?} has bad ports", line)),
+    }
+}
+
+// The version and command, the address family and protocol, then the addresses
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if version_command >> 4 != 2 {
+        return Err(format!("PROXY header version {} is not supported", version_command >> 4));
+    }
+    match version_command & 0x0F {
+        0 => return Ok(None), // LOCAL, from the balancer itself
+        1 => {}
+        command => return Err(format!("PROXY header has an unknown command {}", command)),
+    }
+    let port = |at: usize| ((addresses[at] as u16) << 8) | addresses[at + 1] as u16;
+    match family >> 4 {
+        0 => Ok(None), // Unknown, use the connections address
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32))))
+        }
+        _ => Err(format!("PROXY header family {:#x} with {} address bytes is not supported", family, addresses.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(header: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let mut reader = header;
+        let result = read_header(&mut reader);
+        (result, reader.to_vec())
+    }
+
+    #[test]
+    fn v1_headers() {
+        let login = b"{\"method\":\"login\"}\n";
+        let mut header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 3333\r\n".to_vec();
+        header.extend_from_slice(login);
+        let (result, rest) = read(&header);
+        assert_eq!(result, Ok(Some("192.0.2.1:56324".parse().unwrap())));
+        // The workers first message is left to read
+        assert_eq!(rest, login.to_vec());
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 3333\r\n");
+        assert_eq!(result, Ok(Some("[2001:db8::1]:4000".parse().unwrap())));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").0, Ok(None));
+
+        for bad in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            &b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 3333\r\n"[..],
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 3333\r\n"[..],
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 3333"[..],
+            &b"{\"method\":\"login\"}\n"[..],
+        ]
+            .iter()
+        {
+            assert!(read(bad).0.is_err(), "{:?}", String::from_utf8_lossy(bad));
+        }
+        let mut long = b"PROXY ".to_vec();
+        long.extend_from_slice(&[b'1'; 200]);
+        assert_eq!(read(&long).0, Err("PROXY header is too long".to_string()));
+    }
+
+    #[test]
+    fn v2_headers() {
+        let v2 = |command: u8, family: u8, addresses: &[u8]| {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(0x20 | command);
+            header.push(family);
+            header.push((addresses.len() >> 8) as u8);
+            header.push(addresses.len() as u8);
+            header.extend_from_slice(addresses);
+            header
+        };
+        let mut header = v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x0D, 0x05]);
+        header.extend_from_slice(b"{}\n");
+        let (result, rest) = read(&header);
+        assert_eq!(result, Ok(Some("192.0.2.1:56324".parse().unwrap())));
+        assert_eq!(rest, b"{}\n".to_vec());
+
+        let mut addresses = vec![0x20, 0x01, 0x0d, 0xb8];
+        addresses.extend_from_slice(&[0; 11]);
+        addresses.push(1);
+        addresses.extend_from_slice(&[0; 16]);
+        addresses.extend_from_slice(&[0x0F, 0xA0, 0x0D, 0x05]);
+        // Extra TLVs after the addresses are skipped
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let (result, rest) = read(&v2(1, 0x21, &addresses));
+        assert_eq!(result, Ok(Some("[2001:db8::1]:4000".parse().unwrap())));
+        assert!(rest.is_empty());
+
+        // A health check from the balancer
+        assert_eq!(read(&v2(0, 0x00, &[])).0, Ok(None));
+
+        // Too few address bytes, a unix socket, and version 3
+        assert!(read(&v2(1, 0x11, &[192, 0, 2, 1])).0.is_err());
+        assert!(read(&v2(1, 0x31, &[0; 216])).0.is_err());
+        let mut header = v2(1, 0x11, &[0; 12]);
+        header[12] = 0x31;
+        assert!(read(&header).0.is_err());
+        // Cut short
+        let header = v2(1, 0x11, &[0; 12]);
+        assert!(read(&header[..20]).0.is_err());
+    }
+}
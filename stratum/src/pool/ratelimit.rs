@@ -0,0 +1,300 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rate Limiting
+//!
+//! Counts login attempts and new connections per ip address in fixed 60
+//! second windows so a single address can not brute force worker
+//! credentials or flood the listeners with connections, and a token bucket
+//! to cap how fast a single worker can submit shares.
+//!
+//! Also counts the workers connected from each ip address and to the whole
+//! pool.  A worker holds an IpRef for its address and a PoolRef, and the
+//! counts go down when it is dropped.
+//!
+//! And spaces out job broadcasts, so an upstream sending several templates
+//! for the same block in a burst does not bury the miners in jobs.
+//!
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const WINDOW_SECS: u64 = 60;
+// Expired windows are purged once this many addresses are tracked
+const PURGE_THRESHOLD: usize = 10000;
+
+pub struct IpRateLimiter {
+    max_attempts: u32,
+    window: Duration,
+    attempts: HashMap<IpAddr, (u32, Instant)>, // attempt count, window start
+}
+
+impl IpRateLimiter {
+    /// Allow max_attempts per ip address per minute
+    pub fn new(max_attempts: u32) -> IpRateLimiter {
+        IpRateLimiter {
+            max_attempts: max_attempts,
+            window: Duration::from_secs(WINDOW_SECS),
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Record an attempt from ip, returns false if it is over the limit
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        if self.attempts.len() >= PURGE_THRESHOLD {
+            self.purge();
+        }
+        let now = Instant::now();
+        let window = self.window;
+        let entry = self.attempts.entry(ip).or_insert((0, now));
+        if now.duration_since(entry.1) >= window {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        return entry.0 <= self.max_attempts;
+    }
+
+    /// Forget addresses whose window has ended
+    pub fn purge(&mut self) {
+        let now = Instant::now();
+        let window = self.window;
+        self.attempts
+            .retain(|_, &mut (_, start)| now.duration_since(start) < window);
+    }
+}
+
+/// A token bucket: holds up to capacity tokens, refilled continuously
+/// Lets a job at the same height be broadcast at most once per min_interval,
+/// the newest job held back is sent once the interval has passed
+pub struct JobBroadcastThrottle {
+    min_interval: Duration,
+    last_broadcast: Option<Instant>,
+    pending: bool, // A job was held back and has not been sent yet
+}
+
+impl JobBroadcastThrottle {
+    pub fn new(min_interval: Duration) -> JobBroadcastThrottle {
+        JobBroadcastThrottle {
+            min_interval: min_interval,
+            last_broadcast: None,
+            pending: false,
+        }
+    }
+
+    /// A new job was accepted, returns true if it should be broadcast now.
+    /// A new height is always broadcast right away.
+    pub fn offer(&mut self, new_height: bool) -> bool {
+        if new_height || self.ready() {
+            self.broadcast();
+            return true;
+        }
+        self.pending = true;
+        return false;
+    }
+
+    /// Returns true if a held back job should be broadcast now
+    pub fn take_pending(&mut self) -> bool {
+        if self.pending && self.ready() {
+            self.broadcast();
+            return true;
+        }
+        return false;
+    }
+
+    fn ready(&self) -> bool {
+        match self.last_broadcast {
+            Some(last) => last.elapsed() >= self.min_interval,
+            None => true,
+        }
+    }
+
+    fn broadcast(&mut self) {
+        self.last_broadcast = Some(Instant::now());
+        self.pending = false;
+    }
+}
+
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(capacity: u32, refill_per_sec: u32) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            refill_per_sec: refill_per_sec as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        return false;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed_secs * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Workers connected from each ip address
+pub type IpConnections = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// One connection from an ip address, counted for as long as it is held
+pub struct IpRef(IpConnections, IpAddr);
+
+impl IpRef {
+    /// Count a connection from this address, unless it already has max
+    pub fn acquire(connections: &IpConnections, ip: IpAddr, max: usize) -> Option<IpRef> {
+        let mut counts = connections.lock().unwrap();
+        let count = counts.get(&ip).cloned().unwrap_or(0);
+        if count >= max {
+            return None;
+        }
+        counts.insert(ip, count + 1);
+        Some(IpRef(connections.clone(), ip))
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.1
+    }
+}
+
+/// Workers connected to the pool, counting those not yet picked up by the main loop
+pub type PoolConnections = Arc<AtomicUsize>;
+
+/// One connection to the pool, counted for as long as it is held
+pub struct PoolRef(PoolConnections);
+
+impl PoolRef {
+    /// Count a connection, unless the pool already has max
+    pub fn acquire(connections: &PoolConnections, max: usize) -> Option<PoolRef> {
+        if connections.fetch_add(1, Ordering::SeqCst) >= max {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(PoolRef(connections.clone()))
+    }
+}
+
+impl Drop for PoolRef {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for IpRef {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.0.lock() {
+            let remaining = match counts.get_mut(&self.1) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                }
+                None => return,
+            };
+            if remaining == 0 {
+                counts.remove(&self.1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sixth_login_rejected() {
+        let mut limiter = IpRateLimiter::new(5);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..5 {
+            assert!(limiter.check(ip));
+        }
+        assert!(!limiter.check(ip));
+        // Other addresses are counted separately
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.check(other));
+    }
+
+    #[test]
+    fn share_burst_over_capacity() {
+        let mut bucket = TokenBucket::new(100, 10);
+        let rejected = (0..101).filter(|_| !bucket.try_take()).count();
+        assert_eq!(rejected, 1);
+        // Still inside the refill window for the next token
+        assert!(!bucket.try_take());
+        assert!(!bucket.try_take());
+        ::std::thread::sleep(Duration::from_millis(150));
+        assert!(bucket.try_take());
+    }
+
+    #[test]
+    fn window_resets() {
+        let mut limiter = IpRateLimiter::new(1);
+        limiter.window = Duration::from_millis(50);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limiter.check(ip));
+        assert!(!limiter.check(ip));
+        ::std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(ip));
+        limiter.purge();
+        assert_eq!(limiter.attempts.len(), 1);
+    }
+
+    #[test]
+    fn ip_connections_released_on_drop() {
+        let connections: IpConnections = Arc::new(Mutex::new(HashMap::new()));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = IpRef::acquire(&connections, ip, 2).unwrap();
+        let second = IpRef::acquire(&connections, ip, 2).unwrap();
+        assert!(IpRef::acquire(&connections, ip, 2).is_none());
+        assert_eq!(connections.lock().unwrap()[&ip], 2);
+        drop(first);
+        assert_eq!(connections.lock().unwrap()[&ip], 1);
+        let third = IpRef::acquire(&connections, ip, 2).unwrap();
+        drop(second);
+        drop(third);
+        assert!(connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pool_connections_released_on_drop() {
+        let connections: PoolConnections = Arc::new(AtomicUsize::new(0));
+        let first = PoolRef::acquire(&connections, 2).unwrap();
+        let _second = PoolRef::acquire(&connections, 2).unwrap();
+        assert!(PoolRef::acquire(&connections, 2).is_none());
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        drop(first);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert!(PoolRef::acquire(&connections, 2).is_some());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}
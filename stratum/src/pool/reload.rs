@@ -0,0 +1,73 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Config Hot Reload
+//!
+//! Watches the config file and hands every new, valid version of it to the
+//! pool main loop.  The directory is watched rather than the file so editors
+//! that save by replacing the file are still noticed.
+//!
+
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+
+use pool::config::{load_config, Config};
+
+const DEBOUNCE_MILLIS: u64 = 100;
+
+/// Watch a config file from a new thread, sending each valid reload on updates
+pub fn watch_config(path: &str, updates: Sender<Config>) -> Result<(), String> {
+    let file = PathBuf::from(path);
+    let file_name = match file.file_name() {
+        Some(name) => name.to_os_string(),
+        None => return Err(format!("Invalid config file path: {}", path)),
+    };
+    let dir = match file.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (tx, rx) = channel();
+    let mut config_watcher = watcher(tx, Duration::from_millis(DEBOUNCE_MILLIS))
+        .map_err(|e| format!("Failed to create config watcher: {}", e))?;
+    config_watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("Failed to watch {}: {}", dir.display(), e))?;
+    let path = path.to_string();
+    thread::spawn(move || {
+        // The watcher stops when dropped, keep it for the life of the thread
+        let _config_watcher = config_watcher;
+        for event in rx.iter() {
+            let changed = match event {
+                DebouncedEvent::Create(p) | DebouncedEvent::Write(p) | DebouncedEvent::Rename(_, p) => p,
+                _ => continue,
+            };
+            if changed.file_name() != Some(file_name.as_os_str()) {
+                continue;
+            }
+            match load_config(&path) {
+                Ok(config) => {
+                    if updates.send(config).is_err() {
+                        return; // The pool is gone
+                    }
+                }
+                Err(e) => {
+                    error!("Config reload - Keeping the running config: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,139 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool Rounds
+//!
+//! A round is the work between two blocks found by the pool.  It is ended
+//! when the pool finds a block and the next one starts at the following
+//! height.
+//!
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct Round {
+    pub start_height: u64,
+    pub start_time: Instant,
+    pub shares_accepted: u64,
+    pub shares_rejected: u64,     // Including stale shares
+    pub lucky_hash: Option<String>, // Hash of the block that ended the round
+}
+
+impl Round {
+    pub fn new(start_height: u64) -> Round {
+        Round {
+            start_height: start_height,
+            start_time: Instant::now(),
+            shares_accepted: 0,
+            shares_rejected: 0,
+            lucky_hash: None,
+        }
+    }
+
+    /// Accepted shares over the shares expected to find a block,
+    /// below 1.0 the pool was lucky
+    pub fn luck(&self, network_difficulty: u64, share_difficulty: u64) -> f64 {
+        if network_difficulty == 0 || share_difficulty == 0 {
+            return 0.0;
+        }
+        let expected_shares = network_difficulty as f64 / share_difficulty as f64;
+        return self.shares_accepted as f64 / expected_shares;
+    }
+}
+
+/// The current round and the most recent finished ones
+pub struct Rounds {
+    pub current: Round,
+    pub past: VecDeque<Round>, // Oldest first
+    max_past: usize,
+}
+
+impl Rounds {
+    pub fn new(max_past: usize) -> Rounds {
+        Rounds {
+            current: Round::new(0),
+            past: VecDeque::new(),
+            max_past: max_past,
+        }
+    }
+
+    /// The chain moved to height - a round with no shares yet starts here
+    pub fn new_height(&mut self, height: u64) {
+        if self.current.shares_accepted == 0 && self.current.shares_rejected == 0 {
+            self.current = Round::new(height);
+        }
+    }
+
+    /// Count a share in the current round
+    pub fn add_share(&mut self, accepted: bool) {
+        if accepted {
+            self.current.shares_accepted += 1;
+        } else {
+            self.current.shares_rejected += 1;
+        }
+    }
+
+    /// The pool found a block at height, end the round
+    pub fn finalize(&mut self, hash: String, height: u64) {
+        let mut round = ::std::mem::replace(&mut self.current, Round::new(height + 1));
+        round.lucky_hash = Some(hash);
+        self.past.push_back(round);
+        while self.past.len() > self.max_past {
+            self.past.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_transitions() {
+        let mut rounds = Rounds::new(2);
+        rounds.new_height(100);
+        assert_eq!(rounds.current.start_height, 100);
+        rounds.add_share(true);
+        rounds.add_share(false);
+        // Heights without a pool block stay in the same round
+        rounds.new_height(101);
+        assert_eq!(rounds.current.start_height, 100);
+
+        rounds.finalize("aaaa".to_string(), 101);
+        assert_eq!(rounds.past.len(), 1);
+        assert_eq!(rounds.past[0].start_height, 100);
+        assert_eq!(rounds.past[0].shares_accepted, 1);
+        assert_eq!(rounds.past[0].shares_rejected, 1);
+        assert_eq!(rounds.past[0].lucky_hash, Some("aaaa".to_string()));
+        assert_eq!(rounds.current.start_height, 102);
+        assert_eq!(rounds.current.shares_accepted, 0);
+
+        // Only the most recent rounds are kept
+        rounds.finalize("bbbb".to_string(), 102);
+        rounds.finalize("cccc".to_string(), 103);
+        assert_eq!(rounds.past.len(), 2);
+        assert_eq!(rounds.past[0].lucky_hash, Some("bbbb".to_string()));
+    }
+
+    #[test]
+    fn luck() {
+        let mut round = Round::new(100);
+        round.shares_accepted = 50;
+        // 100 shares expected at this difficulty, found in half
+        assert_eq!(round.luck(1000, 10), 0.5);
+        round.shares_accepted = 200;
+        assert_eq!(round.luck(1000, 10), 2.0);
+        assert_eq!(round.luck(0, 10), 0.0);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool Hashrate History
+//!
+//! Once a minute the pool records the C31 graphs its accepted shares stand
+//! for, keeping the last 24 hours.  Averages over any window up to that are
+//! taken from the samples: a sample counts for the part of its minute that
+//! falls inside the window, and a window reaching back before the first
+//! sample is averaged over the time actually sampled.
+//!
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+pub const SAMPLE_INTERVAL_SECS: u64 = 60;
+const MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+pub struct HashrateSampler {
+    interval: u64,
+    samples: VecDeque<(u64, f64)>, // Unix time the interval ended, graphs accepted in it
+}
+
+impl HashrateSampler {
+    pub fn new() -> HashrateSampler {
+        HashrateSampler {
+            interval: SAMPLE_INTERVAL_SECS,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record the graphs accepted in the interval ending at timestamp
+    pub fn push(&mut self, timestamp: u64, graphs: f64) {
+        self.samples.push_back((timestamp, graphs));
+        while let Some(&(oldest, _)) = self.samples.front() {
+            if oldest + MAX_AGE_SECS > timestamp {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Average graphs per second over the window ending at the last sample
+    pub fn hashrate_gps(&self, window: Duration) -> f64 {
+        let end = match self.samples.back() {
+            Some(&(timestamp, _)) => timestamp,
+            None => return 0.0,
+        };
+        let start = end.saturating_sub(window.as_secs());
+        let mut graphs = 0.0;
+        let mut covered = 0;
+        for &(timestamp, sample) in self.samples.iter() {
+            let from = timestamp.saturating_sub(self.interval).max(start);
+            if timestamp <= from {
+                continue;
+            }
+            let overlap = timestamp - from;
+            graphs += sample * overlap as f64 / self.interval as f64;
+            covered += overlap;
+        }
+        if covered == 0 {
+            return 0.0;
+        }
+        graphs / covered as f64
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windowed_averages() {
+        let mut sampler = HashrateSampler::new();
+        assert_eq!(sampler.hashrate_gps(Duration::from_secs(60)), 0.0);
+        // 14 minutes at 10 graphs per second, then one at 70
+        for minute in 1..15 {
+            sampler.push(minute * 60, 600.0);
+        }
+        sampler.push(15 * 60, 4200.0);
+        assert_eq!(sampler.hashrate_gps(Duration::from_secs(60)), 70.0);
+        assert_eq!(sampler.hashrate_gps(Duration::from_secs(15 * 60)), 14.0);
+        // Only 15 minutes were sampled
+        assert_eq!(sampler.hashrate_gps(Duration::from_secs(24 * 60 * 60)), 14.0);
+        // Half of the older minute is inside a 90 second window
+        assert_eq!(sampler.hashrate_gps(Duration::from_secs(90)), (4200.0 + 300.0) / 90.0);
+    }
+
+    #[test]
+    fn day_old_samples_dropped() {
+        let mut sampler = HashrateSampler::new();
+        for minute in 0..(24 * 60 + 10) {
+            sampler.push(minute * 60, 60.0);
+        }
+        assert_eq!(sampler.len(), 24 * 60);
+        assert_eq!(sampler.hashrate_gps(Duration::from_secs(24 * 60 * 60)), 1.0);
+    }
+}
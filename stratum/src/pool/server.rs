@@ -19,10 +19,17 @@
 use bufstream::BufStream;
 use serde_json;
 use serde_json::Value;
+use std::io::{BufRead, ErrorKind, Read};
 use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex, RwLock};
 use std::{thread, time};
+use std::cmp::min;
+use std::mem;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use rand::{self, Rng};
+use reqwest;
 
 
 use pool::config::{Config, NodeConfig, PoolConfig, WorkerConfig};
@@ -30,9 +37,141 @@ use pool::proto::{JobTemplate, LoginParams, RpcError, StratumProtocol, SubmitPar
 use pool::proto::{RpcRequest, RpcResponse};
 use pool::worker::Worker;
 
+// Consecutive "Node is syncing" errors before failing over to the next node
+const SYNCING_FAILOVER_ERRORS: u32 = 5;
+
+// The error a syncing node answers a job request with
+const NODE_SYNCING_CODE: i32 = -32701;
+
+// Longest the main loop waits on the nodes http api
+const NODE_API_TIMEOUT_SECS: u64 = 2;
+
+// Read the next line from the node.  A line that arrives over several reads
+// is kept in pending until its newline does, Ok(None) until then.  An empty
+// line means the node closed the connection.
+fn read_line<R: BufRead>(stream: &mut R, pending: &mut Vec<u8>, max_bytes: usize) -> Result<Option<String>, String> {
+    // Never buffer more than one byte past the limit
+    let limit = (max_bytes + 1).saturating_sub(pending.len()) as u64;
+    match stream.by_ref().take(limit).read_until(b'\n', pending) {
+        Ok(0) if pending.is_empty() => return Ok(Some(String::new())),
+        Ok(_) if !pending.ends_with(b"\n") && pending.len() > max_bytes => {
+            pending.clear();
+            return Err(format!("Message longer than {} bytes", max_bytes));
+        }
+        Ok(_) => {} // A whole line, or the end of the stream
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            // The rest has not arrived yet
+            return Ok(None);
+        }
+        Err(e) => {
+            pending.clear();
+            return Err(e.to_string());
+        }
+    }
+    String::from_utf8(mem::replace(pending, Vec::new()))
+        .map(Some)
+        .map_err(|e| format!("Invalid UTF-8: {}", e))
+}
+
+// A syncing node answers with its own error code, some only say so in the message
+fn is_syncing_error(e: &RpcError) -> bool {
+    e.code == NODE_SYNCING_CODE || e.message.starts_with("Node is syncing")
+}
+
+// ----------------------------------------
+// Reconnect backoff - doubles the wait after every failed attempt
+
+pub struct ExponentialBackoff {
+    initial: Duration,
+    multiplier: u32,
+    max: Duration,
+    current: Duration,
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, multiplier: u32, max: Duration) -> ExponentialBackoff {
+        ExponentialBackoff {
+            initial: initial,
+            multiplier: multiplier,
+            max: max,
+            current: initial,
+        }
+    }
+
+    /// The interval to wait now, advancing to the next one
+    pub fn next_interval(&mut self) -> Duration {
+        let interval = self.current;
+        self.current = min(self.current * self.multiplier, self.max);
+        return interval;
+    }
+
+    /// The interval to wait now +/- 20%, so restarting pools dont retry in lockstep
+    pub fn next_interval_with_jitter(&mut self) -> Duration {
+        let interval = self.next_interval();
+        let jitter: f64 = rand::thread_rng().gen_range(-0.2, 0.2);
+        let millis = (interval.as_secs() * 1000 + interval.subsec_millis() as u64) as f64;
+        return Duration::from_millis((millis * (1.0 + jitter)) as u64);
+    }
+
+    /// Start over from the initial interval
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+// ----------------------------------------
+// Main loop poll timeout - none right after a new job so it reaches the
+// workers quickly, then the base interval, doubling after every
+// IDLE_POLLS_PER_DOUBLING polls without a new job up to the max
+
+const IDLE_POLLS_PER_DOUBLING: u32 = 10;
+
+pub struct PollInterval {
+    backoff: ExponentialBackoff,
+    current: Duration,
+    idle_polls: u32, // Polls without a new job since the interval last changed
+}
+
+impl PollInterval {
+    pub fn new(interval: Duration, max: Duration) -> PollInterval {
+        let mut backoff = ExponentialBackoff::new(interval, 2, max);
+        PollInterval {
+            current: backoff.next_interval(),
+            backoff: backoff,
+            idle_polls: 0,
+        }
+    }
+
+    /// How long the next poll may wait, given whether the last one got a new job
+    pub fn next(&mut self, new_job: bool) -> Duration {
+        if new_job {
+            self.backoff.reset();
+            self.current = self.backoff.next_interval();
+            self.idle_polls = 0;
+            return Duration::from_millis(0);
+        }
+        self.idle_polls += 1;
+        if self.idle_polls >= IDLE_POLLS_PER_DOUBLING {
+            self.idle_polls = 0;
+            self.current = self.backoff.next_interval();
+        }
+        return self.current;
+    }
+}
+
 // ----------------------------------------
 // Server Object - our connection to a stratum server - a grin node
 
+/// What the pool knows of its upstream node
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    Connected,
+    Syncing, // Connected, but the node has no work until it catches up
+    Disconnected,
+    Error, // The connection failed, the next connect replaces it
+}
+
 pub struct Server {
     id: String,
     config: Config,
@@ -41,12 +180,21 @@ pub struct Server {
     error: bool,
     pub job: JobTemplate,
     status: WorkerStatus,
-    buffer: String,
+    pending_buf: Vec<u8>, // The start of a message from the node still waiting for the rest
+    upstream_index: usize, // Which of the configured nodes we use
+    syncing_errors: u32,   // Consecutive "Node is syncing" errors from the current node
+    state: ServerState,
+    job_retry_at: Option<Instant>, // When to ask a syncing node for a job again
+    backoff: ExponentialBackoff, // How long to wait before trying to connect again
+    last_message: Instant,  // When we last heard anything from the node
+    last_keepalive: Instant, // When we last probed a quiet node
+    pending_submits: Vec<String>, // Share submissions waiting for flush_submits
 }
 
 impl Server {
     /// Creates a new Stratum Server Connection.
     pub fn new(cfg: Config) -> Server {
+        let backoff_max = Duration::from_secs(cfg.grin_node.reconnect_backoff_max_secs);
         Server {
             id: "MWGrinPool".to_string(),
             config: cfg,
@@ -55,19 +203,148 @@ impl Server {
             error: false,
             job: JobTemplate::new(),
             status: WorkerStatus::new("MWGrinPool".to_string()),
-            buffer: String::with_capacity(4096),
+            pending_buf: Vec::with_capacity(4096),
+            upstream_index: 0,
+            syncing_errors: 0,
+            state: ServerState::Disconnected,
+            job_retry_at: None,
+            backoff: ExponentialBackoff::new(Duration::from_secs(1), 2, backoff_max),
+            last_message: Instant::now(),
+            last_keepalive: Instant::now(),
+            pending_submits: Vec::new(),
         }
     }
 
-    /// Connect to an upstream Grin Stratum Server
+    /// Connected, and heard from within the upstream timeout
+    pub fn is_healthy(&self) -> bool {
+        if self.error || self.stream.is_none() {
+            return false;
+        }
+        let timeout = self.config.grin_node.upstream_timeout_secs;
+        return timeout == 0 || self.last_message.elapsed() < Duration::from_secs(timeout);
+    }
+
+    /// Give up on a connection the node stopped talking on, the next connect replaces it
+    pub fn drop_if_stalled(&mut self) {
+        if self.error || self.stream.is_none() {
+            return;
+        }
+        error!(
+            "{} - Upstream {} stalled - nothing received for {} seconds, reconnecting",
+            self.id,
+            self.current_upstream(),
+            self.last_message.elapsed().as_secs(),
+        );
+        self.error = true;
+        self.state = ServerState::Error;
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    /// Probe the node once it has been quiet for half the upstream timeout
+    pub fn heartbeat(&mut self) {
+        if self.job_retry_at.map_or(false, |at| Instant::now() >= at) {
+            self.job_retry_at = None;
+            if let Err(e) = self.request_job() {
+                warn!("{} - Failed to ask the syncing node for a job: {}", self.id, e);
+            }
+        }
+        let timeout = self.config.grin_node.upstream_timeout_secs;
+        if timeout == 0 || self.error {
+            return;
+        }
+        let quiet = Duration::from_secs(timeout) / 2;
+        if self.last_message.elapsed() < quiet || self.last_keepalive.elapsed() < quiet {
+            return;
+        }
+        self.last_keepalive = Instant::now();
+        if let Err(e) = self.send_keepalive() {
+            warn!("{} - Failed to send keepalive upstream: {}", self.id, e);
+        }
+    }
+
+    /// The socket to wait on for upstream messages, if connected
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.stream.as_ref().map(|stream| stream.get_ref().as_raw_fd())
+    }
+
+    /// Are there more upstream messages read but not processed yet?
+    pub fn has_buffered_input(&mut self) -> bool {
+        match self.stream {
+            Some(ref mut stream) => match stream.fill_buf() {
+                Ok(buf) => !buf.is_empty(),
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Back to the shortest reconnect wait
+    pub fn reset_backoff(&mut self) {
+        self.backoff.reset();
+    }
+
+    /// How long to wait after a failed connect, or a syncing node, before trying again
+    pub fn next_backoff(&mut self) -> Duration {
+        return self.backoff.next_interval_with_jitter();
+    }
+
+    /// The upstream stratum server we are using (or will try next)
+    pub fn current_upstream(&self) -> String {
+        let addresses = self.config.grin_node.addresses();
+        return addresses[self.upstream_index % addresses.len()].clone() + ":"
+            + &self.config.grin_node.stratum_port.to_string();
+    }
+
+    /// Connect to an upstream Grin Stratum Server, trying each configured node in turn
     /// Request Login and Job Request
     pub fn connect(&mut self) -> Result<(), String> {
         // Only connect if we are not already connected
         if !self.error && self.stream.is_some() {
             return Ok(());
         }
-        let grin_stratum_url = self.config.grin_node.address.clone() + ":"
-            + &self.config.grin_node.stratum_port.to_string();
+        // A connection that was up and then failed moves on to the next node
+        if self.stream.is_some() {
+            self.fail_over();
+        }
+        let num_upstreams = self.config.grin_node.addresses().len();
+        let mut result = Ok(());
+        for _ in 0..num_upstreams {
+            result = self.connect_current();
+            match result {
+                Ok(_) => {
+                    self.reset_backoff();
+                    return Ok(());
+                }
+                Err(ref e) => {
+                    self.state = ServerState::Error;
+                    error!(
+                        "{} - Failed to connect to upstream stratum server at {}: {}",
+                        self.id,
+                        self.current_upstream(),
+                        e
+                    );
+                }
+            }
+            self.fail_over();
+        }
+        return result;
+    }
+
+    // Drop the current connection and move to the next node in priority order
+    fn fail_over(&mut self) {
+        self.stream = None;
+        self.syncing_errors = 0;
+        self.job_retry_at = None;
+        self.state = ServerState::Disconnected;
+        self.upstream_index = (self.upstream_index + 1) % self.config.grin_node.addresses().len();
+    }
+
+    // Connect to the current upstream node
+    fn connect_current(&mut self) -> Result<(), String> {
+        let grin_stratum_url = self.current_upstream();
         warn!(
             "{} - Connecting to upstream stratum server at {}",
             self.id,
@@ -78,7 +355,10 @@ impl Server {
                 let _ = conn.set_nonblocking(true)
                     .expect("set_nonblocking call failed");
                 self.stream = Some(BufStream::new(conn));
+                self.pending_buf.clear();
                 self.error = false;
+                self.last_message = Instant::now();
+                self.last_keepalive = Instant::now();
             }
             Err(e) => {
                 self.error = true;
@@ -101,6 +381,7 @@ impl Server {
                 return Err(e.to_string());
             }
         };
+        self.state = ServerState::Connected;
         return Ok(());
     }
 
@@ -128,8 +409,9 @@ impl Server {
             Some(ref mut stream) => {
                 let login_params = LoginParams {
                     login: self.config.grin_node.login.clone().to_string(),
-                    pass: self.config.grin_node.password.clone().to_string(),
+                    pass: self.config.grin_node.password.expose().to_string(),
                     agent: self.id.clone(),
+                    session_token: None,
                 };
                 let params_value = serde_json::to_value(login_params).unwrap();
                 trace!("{} - Requesting Login", self.id);
@@ -145,7 +427,7 @@ impl Server {
     }
 
     /// Request a new job template from the upstream Grin Stratum server
-    fn request_job(&mut self) -> Result<(), String> {
+    pub fn request_job(&mut self) -> Result<(), String> {
         match self.stream {
             Some(ref mut stream) => {
                 trace!("{} - Requesting Job Template", self.id);
@@ -169,6 +451,16 @@ impl Server {
         match self.stream {
             Some(ref mut stream) => {
                 let params_value = serde_json::to_value(solution).unwrap();
+                if self.config.grin_node.batch_submits {
+                    trace!("{} - Queueing a share for submission", self.id);
+                    let request = self.protocol.request(
+                        "submit".to_string(),
+                        Some(params_value),
+                        Some(self.id.clone()),
+                    );
+                    self.pending_submits.push(request);
+                    return Ok(());
+                }
                 trace!("{} - Submitting a share", self.id);
                 return self.protocol.send_request(
                     stream,
@@ -181,24 +473,67 @@ impl Server {
         }
     }
 
-    /// Send Keepalive
-    // Not currently used
-//    pub fn send_keepalive(&mut self) -> Result<(), String> {
-//        match self.stream {
-//            Some(ref mut stream) => {
-//                trace!("{} - Sending Keepalive", self.id);
-//                return self.protocol.send_request(
-//                    stream,
-//                    "keepalive".to_string(),
-//                    None,
-//                    Some(self.id.clone()),
-//                );
-//            }
-//            None => Err("No upstream connection".to_string()),
-//        }
-//    }
+    /// Send the queued share submissions in a single write
+    // The node answers each one in order, process_message counts the results as before
+    pub fn flush_submits(&mut self) -> Result<usize, String> {
+        if self.pending_submits.is_empty() {
+            return Ok(0);
+        }
+        let count = self.pending_submits.len();
+        let batch = self.pending_submits.join("\n");
+        self.pending_submits.clear();
+        match self.stream {
+            Some(ref mut stream) => {
+                trace!("{} - Submitting {} shares", self.id, count);
+                match self.protocol.write_message(batch, stream) {
+                    Ok(_) => Ok(count),
+                    Err(e) => {
+                        self.error = true;
+                        Err(e)
+                    }
+                }
+            }
+            None => Err("No upstream connection".to_string()),
+        }
+    }
 
+    /// Send Keepalive
+    pub fn send_keepalive(&mut self) -> Result<(), String> {
+        match self.stream {
+            Some(ref mut stream) => {
+                trace!("{} - Sending Keepalive", self.id);
+                return self.protocol.send_request(
+                    stream,
+                    "keepalive".to_string(),
+                    None,
+                    Some(self.id.clone()),
+                );
+            }
+            None => Err("No upstream connection".to_string()),
+        }
+    }
 
+    /// A request to the current node for the transactions waiting to go
+    /// into the next block, to send on a thread of its own
+    // The stratum server has no such method, so this goes to the nodes
+    // foreign api and blocks until it answers or times out
+    pub fn transactions_request(&self) -> TransactionsRequest {
+        let addresses = self.config.grin_node.addresses();
+        let url = format!(
+            "http://{}:{}/v2/foreign",
+            addresses[self.upstream_index % addresses.len()],
+            self.config.grin_node.api_port
+        );
+        let request = self.protocol.request(
+            "get_unconfirmed_transactions".to_string(),
+            Some(Value::Array(vec![])),
+            Some(self.id.clone()),
+        );
+        TransactionsRequest {
+            url: url,
+            request: request,
+        }
+    }
 
     /// Process Messages from the upstream stratum server
     // Method to handle responses from the upstream stratum server
@@ -207,7 +542,37 @@ impl Server {
         workers: &mut Arc<Mutex<HashMap<String, Worker>>>,
     ) -> Result<String, RpcError> {
         // XXX TODO: With some reasonable rate limiting (like N message per pass)
-        return self.process_message(workers);
+        let result = self.process_message(workers);
+        match result {
+            Ok(ref method) if method == "job" || method == "getjobtemplate" => {
+                self.syncing_errors = 0;
+                self.job_retry_at = None;
+                self.state = ServerState::Connected;
+                self.reset_backoff();
+            }
+            Err(ref e) if is_syncing_error(e) => {
+                self.syncing_errors += 1;
+                self.state = ServerState::Syncing;
+                if self.syncing_errors >= SYNCING_FAILOVER_ERRORS
+                    && self.config.grin_node.addresses().len() > 1
+                {
+                    warn!(
+                        "{} - Upstream {} is still syncing, failing over",
+                        self.id,
+                        self.current_upstream()
+                    );
+                    self.error = true;
+                } else {
+                    // Keep asking until the node is done syncing, without holding up the workers
+                    self.job_retry_at = Some(Instant::now() + self.next_backoff());
+                }
+            }
+            _ => {}
+        }
+        if self.error {
+            self.state = ServerState::Error;
+        }
+        return result;
     }
     pub fn process_message(
         &mut self,
@@ -218,10 +583,25 @@ impl Server {
         // XXX TODO: Complete adding RPC error results (especially still syncing error)
         match self.stream {
             Some(ref mut stream) => {
-                match self.protocol.get_message(stream, &mut self.buffer) {
+                match read_line(stream, &mut self.pending_buf, self.config.grin_node.max_message_bytes) {
                     Ok(rpc_msg) => {
                         match rpc_msg {
+                            Some(ref message) if message.is_empty() => {
+                                // End of stream - the node closed the connection
+                                warn!(
+                                    "{} - Upstream {} closed the connection",
+                                    self.id,
+                                    self.current_upstream(),
+                                );
+                                self.error = true;
+                                let e = RpcError {
+                                    code: -32500,
+                                    message: "Upstream closed the connection".to_string(),
+                                };
+                                return Err(e);
+                            }
                             Some(message) => {
+                                self.last_message = Instant::now();
                                 trace!(
                                     "{} - Got Message from upstream Server: {:?}",
                                     self.id,
@@ -337,12 +717,15 @@ impl Server {
                                                 let result: Value = match res.result {
                                                     Some(r) => r,
                                                     None => {
-                                                        let err_msg = format!("Error result: {}", res.error.unwrap());
-                                                        let err = RpcError {
-                                                            code: -32600,
-                                                            message: err_msg,
-                                                        };
-                                                        return Err(err);
+                                                        // Keep the nodes error code, a syncing node has its own
+                                                        let error = res.error.unwrap_or(Value::Null);
+                                                        return Err(match serde_json::from_value::<RpcError>(error.clone()) {
+                                                            Ok(e) => e,
+                                                            Err(_) => RpcError {
+                                                                code: -32600,
+                                                                message: format!("Error result: {}", error),
+                                                            },
+                                                        });
                                                     }
                                                 };
                                                 let job: JobTemplate = match serde_json::from_value(result) {
@@ -372,6 +755,10 @@ impl Server {
                                                 );
                                                 return Ok(res.method.clone());
                                             }
+                                            "keepalive" => {
+                                                trace!("{} - Upstream server is alive", self.id);
+                                                return Ok(res.method.clone());
+                                            }
                                             "submit" => {
                                                 // XXX TODO: Error checking
                                                 // Debug print this method
@@ -445,6 +832,7 @@ impl Server {
                         }
                     }
                     Err(e) => {
+                        error!("{} - Failed to read from upstream: {}", self.id, e);
                         self.error = true;
                         let e = RpcError {
                             code: -32600,
@@ -466,3 +854,250 @@ impl Server {
         //return Ok("unknown".to_string());
     }
 }
+
+/// A get_unconfirmed_transactions request for a nodes foreign api
+pub struct TransactionsRequest {
+    url: String,
+    request: String,
+}
+
+impl TransactionsRequest {
+    /// Send the request, returns the JSON result as the node sent it
+    pub fn send(self) -> Result<String, RpcError> {
+        trace!("Requesting transactions from {}", self.url);
+        let node_error = |message: String| RpcError {
+            code: -32500,
+            message: message,
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(NODE_API_TIMEOUT_SECS))
+            .build()
+            .map_err(|e| node_error(e.to_string()))?;
+        let mut response = client
+            .post(self.url.as_str())
+            .header("content-type", "application/json")
+            .body(self.request)
+            .send()
+            .map_err(|e| node_error(format!("Node api request failed: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(node_error(format!("Node api returned {}", response.status())));
+        }
+        let v: Value = response
+            .json()
+            .map_err(|e| node_error(format!("Invalid response from node api: {}", e)))?;
+        if !v["error"].is_null() {
+            return match serde_json::from_value(v["error"].clone()) {
+                Ok(e) => Err(e),
+                Err(_) => Err(node_error(format!("Error result: {}", v["error"]))),
+            };
+        }
+        // The result is {"Ok": [...]} or {"Err": ...}
+        match v["result"].get("Ok") {
+            Some(transactions) => Ok(transactions.to_string()),
+            None => Err(node_error(format!("Error result: {}", v["result"]))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pool::pool::tests::test_config;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    // A server connected to a node that accepted the connection and does nothing else
+    fn connected_server(timeout_secs: u64) -> (Server, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = test_config();
+        config.grin_node.stratum_port = listener.local_addr().unwrap().port() as u64;
+        config.grin_node.upstream_timeout_secs = timeout_secs;
+        let mut server = Server::new(config);
+        server.connect().unwrap();
+        let (node, _) = listener.accept().unwrap();
+        (server, node)
+    }
+
+    #[test]
+    fn silent_upstream_is_unhealthy() {
+        let (mut server, _node) = connected_server(1);
+        assert!(server.is_healthy());
+        thread::sleep(Duration::from_millis(1100));
+        assert!(!server.is_healthy());
+        server.drop_if_stalled();
+        assert!(server.error);
+    }
+
+    #[test]
+    fn closed_upstream_detected() {
+        let (mut server, node) = connected_server(120);
+        node.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut workers = Arc::new(Mutex::new(HashMap::new()));
+        let e = server.process_messages(&mut workers).unwrap_err();
+        assert_eq!(e.message, "Upstream closed the connection");
+        assert!(!server.is_healthy());
+    }
+
+    #[test]
+    fn syncing_node_tracked() {
+        let (mut server, mut node) = connected_server(120);
+        assert_eq!(server.state(), ServerState::Connected);
+        let mut workers = Arc::new(Mutex::new(HashMap::new()));
+        node.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = std::io::BufReader::new(node.try_clone().unwrap());
+        let mut line = String::new();
+        for _ in 0..2 {
+            reader.read_line(&mut line).unwrap(); // login, getjobtemplate
+        }
+
+        // Known by its error code, and asked again later rather than straight away
+        node.write_all(
+            b"{\"id\":\"MWGrinPool\",\"jsonrpc\":\"2.0\",\"method\":\"getjobtemplate\",\"error\":{\"code\":-32701,\"message\":\"Please wait\"}}\n",
+        ).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.process_messages(&mut workers).unwrap_err().code, -32701);
+        assert_eq!(server.state(), ServerState::Syncing);
+        assert!(server.job_retry_at.is_some());
+        server.job_retry_at = Some(Instant::now());
+        server.heartbeat();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.contains("getjobtemplate"));
+        assert!(server.job_retry_at.is_none());
+
+        // Synced once there is a job
+        node.write_all(
+            b"{\"id\":\"Stratum\",\"jsonrpc\":\"2.0\",\"method\":\"job\",\"params\":{\"height\":7,\"job_id\":0,\"difficulty\":1,\"pre_pow\":\"00\"}}\n",
+        ).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        server.process_messages(&mut workers).unwrap();
+        assert_eq!(server.state(), ServerState::Connected);
+
+        node.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(server.process_messages(&mut workers).is_err());
+        assert_eq!(server.state(), ServerState::Error);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_cap() {
+        let mut backoff = ExponentialBackoff::new(Duration::from_secs(1), 2, Duration::from_secs(60));
+        let intervals: Vec<u64> = (0..9).map(|_| backoff.next_interval().as_secs()).collect();
+        assert_eq!(intervals, vec![1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        backoff.reset();
+        assert_eq!(backoff.next_interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_jitter_within_bounds() {
+        let mut backoff = ExponentialBackoff::new(Duration::from_secs(60), 2, Duration::from_secs(60));
+        for _ in 0..100 {
+            let interval = backoff.next_interval_with_jitter();
+            assert!(interval >= Duration::from_secs(48));
+            assert!(interval <= Duration::from_secs(72));
+        }
+    }
+
+    #[test]
+    fn poll_interval_adapts_to_jobs() {
+        let (mut server, mut node) = connected_server(120);
+        let mut workers = Arc::new(Mutex::new(HashMap::new()));
+        let mut interval = PollInterval::new(Duration::from_millis(50), Duration::from_millis(500));
+        let mut timeouts = vec![];
+        for _ in 0..40 {
+            timeouts.push(interval.next(false).subsec_millis());
+        }
+        assert_eq!((timeouts[0], timeouts[8], timeouts[9]), (50, 50, 100));
+        assert_eq!(timeouts[19], 200);
+        assert_eq!(timeouts[29], 400);
+        assert_eq!(timeouts[39], 500);
+
+        // The node sends a job, poll again straight away then start over
+        node.write_all(
+            b"{\"id\":\"Stratum\",\"jsonrpc\":\"2.0\",\"method\":\"job\",\"params\":{\"height\":7,\"job_id\":0,\"difficulty\":1,\"pre_pow\":\"00\"}}\n",
+        ).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let method = server.process_messages(&mut workers).unwrap();
+        assert_eq!(interval.next(method == "job"), Duration::from_millis(0));
+        assert_eq!(server.job.height, 7);
+        assert_eq!(interval.next(false), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn submits_batched_until_flushed() {
+        let (mut server, node) = connected_server(120);
+        node.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut reader = std::io::BufReader::new(node);
+        let mut line = String::new();
+        for method in &["login", "getjobtemplate"] {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.contains(method));
+        }
+        let share = |nonce| SubmitParams {
+            height: 7,
+            job_id: 0,
+            nonce: nonce,
+            edge_bits: 29,
+            pow: vec![],
+            stale: false,
+        };
+        server.submit_share(&share(1), "1".to_string()).unwrap();
+        server.submit_share(&share(2), "2".to_string()).unwrap();
+        line.clear();
+        assert!(reader.read_line(&mut line).is_err());
+        assert_eq!(server.flush_submits(), Ok(2));
+        for nonce in &["\"nonce\":1", "\"nonce\":2"] {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.contains("\"method\":\"submit\""));
+            assert!(line.contains(nonce));
+        }
+        assert_eq!(server.flush_submits(), Ok(0));
+
+        // Without batching the share goes out right away
+        server.config.grin_node.batch_submits = false;
+        server.submit_share(&share(3), "3".to_string()).unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.contains("\"nonce\":3"));
+        assert_eq!(server.flush_submits(), Ok(0));
+    }
+
+    #[test]
+    fn job_split_over_reads() {
+        let (mut server, mut node) = connected_server(120);
+        let mut workers = Arc::new(Mutex::new(HashMap::new()));
+        let job = format!(
+            "{{\"id\":\"Stratum\",\"jsonrpc\":\"2.0\",\"method\":\"job\",\"params\":{{\"height\":9,\"job_id\":1,\"difficulty\":1,\"pre_pow\":\"{}\"}}}}\n",
+            "00".repeat(2000)
+        );
+        let (first, rest) = job.split_at(1500);
+        node.write_all(first.as_bytes()).unwrap();
+        node.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.process_messages(&mut workers).unwrap(), "None");
+        assert!(server.is_healthy());
+
+        node.write_all(rest.as_bytes()).unwrap();
+        node.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.process_messages(&mut workers).unwrap(), "job");
+        assert_eq!(server.job.height, 9);
+        assert_eq!(server.job.pre_pow.len(), 4000);
+    }
+
+    #[test]
+    fn oversized_message_dropped() {
+        let (mut server, mut node) = connected_server(120);
+        server.config.grin_node.max_message_bytes = 1000;
+        let mut workers = Arc::new(Mutex::new(HashMap::new()));
+        node.write_all("x".repeat(1500).as_bytes()).unwrap();
+        node.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(server.process_messages(&mut workers).is_err());
+        assert!(!server.is_healthy());
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reconnect Grace Period
+//!
+//! When a logged in worker disconnects its stats are kept for a short time,
+//! keyed by full_id.  If the same miner logs in again before they expire
+//! the new connection carries on from the old totals instead of zero.
+//!
+//! A miner that logs in again with the session token it was given resumes
+//! that exact session: its status and share counts are restored as they
+//! were instead of being added to the new connection's.
+//!
+//! Only ended sessions are cached.  If a miner opens a second connection
+//! while the first is still up, the two count separately; whichever drops
+//! first is cached and handed to the next login with that full_id, so no
+//! share is ever counted twice.
+//!
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use pool::proto::WorkerStatus;
+use pool::worker::{Worker, WorkerShares};
+
+pub struct Sessions {
+    grace: Duration,
+    expired: HashMap<String, (WorkerStatus, WorkerShares, Option<String>, Instant)>, // full_id, stats, session token, when the connection ended
+}
+
+impl Sessions {
+    /// Keep ended sessions for grace_secs, 0 disables
+    pub fn new(grace_secs: u64) -> Sessions {
+        Sessions {
+            grace: Duration::from_secs(grace_secs),
+            expired: HashMap::new(),
+        }
+    }
+
+    /// Remember a disconnecting worker's stats
+    pub fn save(&mut self, worker: &Worker) {
+        if !worker.authenticated() || self.grace == Duration::from_secs(0) {
+            return;
+        }
+        self.purge();
+        self.expired.insert(
+            worker.full_id(),
+            (
+                worker.status.clone(),
+                worker.worker_shares.clone(),
+                worker.session_token.clone(),
+                Instant::now(),
+            ),
+        );
+    }
+
+    /// Bring a recently ended session into a newly logged in worker,
+    /// share counts only carry over if they are for the current height
+    pub fn restore(&mut self, worker: &mut Worker, height: u64) -> bool {
+        let (status, worker_shares, token, ended) = match self.expired.remove(&worker.full_id()) {
+            Some(session) => session,
+            None => return false,
+        };
+        if ended.elapsed() > self.grace {
+            return false;
+        }
+        let resumed = token.is_some() && token == worker.login_session_token();
+        if resumed {
+            // The same session: carry on exactly where it left off
+            worker.status = status;
+            worker.status.id = worker.uuid();
+            worker.status.set_last_seen(Instant::now());
+            let difficulty = worker.status.difficulty;
+            worker.set_difficulty(difficulty);
+            if worker_shares.height == height {
+                let (id, tag) = (worker.worker_shares.id.clone(), worker.worker_shares.tag.clone());
+                worker.worker_shares = worker_shares;
+                worker.worker_shares.id = id;
+                worker.worker_shares.tag = tag;
+            }
+        } else {
+            worker.status.accepted += status.accepted;
+            worker.status.pool_accepted += status.pool_accepted;
+            worker.status.rejected += status.rejected;
+            worker.status.stale += status.stale;
+            worker.status.deep_stale += status.deep_stale;
+            worker.status.stale_accepted += status.stale_accepted;
+            if worker_shares.height == height {
+                for shares in worker_shares.shares.values() {
+                    worker.add_shares(shares.edge_bits, shares.accepted, shares.rejected, shares.stale);
+                }
+            }
+        }
+        return true;
+    }
+
+    /// Number of cached sessions
+    pub fn len(&self) -> usize {
+        return self.expired.len();
+    }
+
+    /// Forget sessions past the grace period
+    pub fn purge(&mut self) {
+        let grace = self.grace;
+        self.expired.retain(|_, session| session.3.elapsed() <= grace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pool::pool::tests::{test_config, test_worker};
+    use pool::proto::LoginParams;
+    use pool::worker::ConnectionState;
+
+    fn logged_in(worker: &mut Worker) {
+        worker.user_id = 7;
+        worker.state = ConnectionState::Authorized;
+        worker.worker_shares.rigid = "rig1".to_string();
+        worker.reset_worker_shares(100, 1);
+    }
+
+    #[test]
+    fn reconnect_keeps_stats() {
+        let config = test_config();
+        let mut sessions = Sessions::new(60);
+        let (mut first, _miner1) = test_worker(&config);
+        logged_in(&mut first);
+        first.status.accepted = 5;
+        first.status.rejected = 1;
+        first.add_shares(31, 5, 1, 0);
+        sessions.save(&first);
+        assert_eq!(sessions.len(), 1);
+
+        let (mut second, _miner2) = test_worker(&config);
+        logged_in(&mut second);
+        assert!(sessions.restore(&mut second, 100));
+        assert_eq!(second.status.accepted, 5);
+        assert_eq!(second.status.rejected, 1);
+        assert_eq!(second.worker_shares.shares[&31].accepted, 5);
+        // Restored once only
+        assert_eq!(sessions.len(), 0);
+
+        // Share counts from an older height are not carried over
+        sessions.save(&second);
+        let (mut third, _miner3) = test_worker(&config);
+        logged_in(&mut third);
+        third.reset_worker_shares(101, 1);
+        assert!(sessions.restore(&mut third, 101));
+        assert_eq!(third.status.accepted, 5);
+        assert!(third.worker_shares.shares.is_empty());
+    }
+
+    #[test]
+    fn overlapping_sessions_count_separately() {
+        let config = test_config();
+        let mut sessions = Sessions::new(60);
+        let (mut first, _miner1) = test_worker(&config);
+        logged_in(&mut first);
+        first.status.accepted = 5;
+
+        // A second connection logs in while the first is still up
+        let (mut second, _miner2) = test_worker(&config);
+        logged_in(&mut second);
+        assert!(!sessions.restore(&mut second, 100));
+        second.status.accepted = 2;
+
+        // The first drops, the next login picks up only its totals
+        sessions.save(&first);
+        let (mut third, _miner3) = test_worker(&config);
+        logged_in(&mut third);
+        assert!(sessions.restore(&mut third, 100));
+        assert_eq!(third.status.accepted, 5);
+        assert_eq!(second.status.accepted, 2);
+    }
+
+    #[test]
+    fn expired_session_not_restored() {
+        let config = test_config();
+        let mut sessions = Sessions::new(60);
+        sessions.grace = Duration::from_millis(10);
+        let (mut first, _miner1) = test_worker(&config);
+        logged_in(&mut first);
+        first.status.accepted = 5;
+        sessions.save(&first);
+        ::std::thread::sleep(Duration::from_millis(20));
+        let (mut second, _miner2) = test_worker(&config);
+        logged_in(&mut second);
+        assert!(!sessions.restore(&mut second, 100));
+        assert_eq!(second.status.accepted, 0);
+    }
+
+    #[test]
+    fn session_token_resumes_session() {
+        let config = test_config();
+        let mut sessions = Sessions::new(60);
+        let (mut first, _miner1) = test_worker(&config);
+        logged_in(&mut first);
+        let token = first.start_session();
+        first.set_difficulty(32);
+        first.status.accepted = 5;
+        first.add_shares(31, 5, 0, 0);
+        sessions.save(&first);
+
+        let (mut second, _miner2) = test_worker(&config);
+        logged_in(&mut second);
+        second.set_login(LoginParams {
+            login: "user".to_string(),
+            pass: "".to_string(),
+            agent: "test".to_string(),
+            session_token: Some(token),
+        });
+        second.status.accepted = 1;
+        assert!(sessions.restore(&mut second, 100));
+        // Restored as it was rather than added to
+        assert_eq!(second.status.accepted, 5);
+        assert_eq!(second.status.difficulty, 32);
+        assert_eq!(second.status.id, second.uuid());
+        assert_eq!(second.worker_shares.shares[&31].accepted, 5);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Share Event Log
+//!
+//! One JSON line per share outcome, keyed by the workers full_id, for
+//! accounting to consume.  Unlike the pool log it does not depend on the
+//! log level.  The file is rotated by size: when it would grow past
+//! max_bytes it is renamed to path.1, path.1 to path.2 and so on, keeping
+//! at most max_files old files.
+//!
+
+use serde_json;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pool::proto::SubmitParams;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShareEvent {
+    pub ts: u64, // Milliseconds since the unix epoch
+    pub full_id: String,
+    pub tag: Option<String>, // From the workers login, null if it sent none
+    pub height: u64,
+    pub job_id: u64,
+    pub edge_bits: u32,
+    pub difficulty: u64, // 0 if rejected before the difficulty was computed
+    pub outcome: String, // accepted, rejected, stale, or duplicate
+}
+
+impl ShareEvent {
+    pub fn new(full_id: String, tag: Option<String>, share: &SubmitParams, difficulty: u64, outcome: &str) -> ShareEvent {
+        let ts = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+            Err(_) => 0,
+        };
+        ShareEvent {
+            ts: ts,
+            full_id: full_id,
+            tag: tag,
+            height: share.height,
+            job_id: share.job_id,
+            edge_bits: share.edge_bits,
+            difficulty: difficulty,
+            outcome: outcome.to_string(),
+        }
+    }
+}
+
+pub struct ShareLogger {
+    path: String,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64, // Bytes in the current file
+}
+
+impl ShareLogger {
+    /// Open the share log for appending, creating it if needed
+    pub fn open(path: &str, max_bytes: u64, max_files: usize) -> Result<ShareLogger, String> {
+        let (file, size) = open_append(path)?;
+        Ok(ShareLogger {
+            path: path.to_string(),
+            max_bytes: max_bytes,
+            max_files: max_files,
+            file: file,
+            size: size,
+        })
+    }
+
+    /// Append an event, rotating first if it would not fit.  Flushed before returning.
+    pub fn write(&mut self, event: &ShareEvent) -> Result<(), String> {
+        let mut line = serde_json::to_string(event).map_err(|e| e.to_string())?;
+        line += "\n";
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        self.size += line.len() as u64;
+        self.file.flush().map_err(|e| e.to_string())
+    }
+
+    fn rotate(&mut self) -> Result<(), String> {
+        if self.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(self.rotated(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))
+                .map_err(|e| format!("Failed to rotate {}: {}", self.path, e))?;
+        }
+        let (file, size) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> String {
+        format!("{}.{}", self.path, n)
+    }
+}
+
+fn open_append(path: &str) -> Result<(File, u64), String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok((file, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader};
+    use std::path::Path;
+    use std::{env, process};
+
+    fn read_events(path: &str) -> Vec<Value> {
+        BufReader::new(File::open(path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rotated_by_size() {
+        let path = env::temp_dir().join(format!("grin-pool-sharelog-{}.log", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        for n in 0..4 {
+            let _ = fs::remove_file(if n == 0 { path.clone() } else { format!("{}.{}", path, n) });
+        }
+        let share = SubmitParams {
+            height: 100,
+            job_id: 7,
+            nonce: 42,
+            edge_bits: 31,
+            pow: vec![],
+            stale: false,
+        };
+        let event = ShareEvent::new("7.rig1.0".to_string(), Some("eu".to_string()), &share, 20, "accepted");
+        let line_len = serde_json::to_string(&event).unwrap().len() as u64 + 1;
+
+        // Room for three events per file, two old files kept
+        let mut logger = ShareLogger::open(&path, line_len * 3, 2).unwrap();
+        for _ in 0..10 {
+            logger.write(&event).unwrap();
+        }
+        assert_eq!(read_events(&path).len(), 1);
+        assert_eq!(read_events(&format!("{}.1", path)).len(), 3);
+        assert_eq!(read_events(&format!("{}.2", path)).len(), 3);
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+
+        let events = read_events(&path);
+        assert_eq!(events[0]["full_id"], "7.rig1.0");
+        assert_eq!(events[0]["tag"], "eu");
+        assert_eq!(events[0]["height"], 100);
+        assert_eq!(events[0]["job_id"], 7);
+        assert_eq!(events[0]["difficulty"], 20);
+        assert_eq!(events[0]["outcome"], "accepted");
+        assert!(events[0]["ts"].as_u64().unwrap() > 0);
+
+        // Reopening picks up the size of the current file
+        let mut logger = ShareLogger::open(&path, line_len * 3, 2).unwrap();
+        logger.write(&event).unwrap();
+        logger.write(&event).unwrap();
+        logger.write(&event).unwrap();
+        assert_eq!(read_events(&path).len(), 1);
+        assert_eq!(read_events(&format!("{}.1", path)).len(), 3);
+
+        for n in 0..3 {
+            let _ = fs::remove_file(if n == 0 { path.clone() } else { format!("{}.{}", path, n) });
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker Stats Snapshots
+//!
+//! The stats of every connected worker written to a JSON file now and then,
+//! for payment processors and dashboards that can not reach the api.  The
+//! snapshot is written to path.tmp and renamed over path, so readers never
+//! see half a file.  The previous snapshot becomes path.1, path.1 becomes
+//! path.2 and so on, keeping at most keep_count old ones.
+//!
+
+use serde_json;
+use std::fs::{self, File};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pool::worker::Worker;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkerSnapshot {
+    pub id: String, // The workers full_id
+    pub login: String,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub stale: u64,
+    pub hashrate_gps: f64, // Estimated C31 graphs per second
+    pub difficulty: u64,
+    #[serde(default)]
+    pub last_accepted_share: Option<u64>, // Unix time a share was last accepted, None if none was
+    #[serde(default)]
+    pub silent: bool, // No share accepted in many times the expected share interval
+}
+
+impl WorkerSnapshot {
+    pub fn new(worker: &Worker) -> WorkerSnapshot {
+        let now = unix_time();
+        WorkerSnapshot {
+            id: worker.full_id(),
+            login: worker.login(),
+            accepted: worker.status.accepted,
+            rejected: worker.status.rejected,
+            stale: worker.status.stale,
+            hashrate_gps: worker.hashrate.c31_graphs_per_second(),
+            difficulty: worker.status.difficulty,
+            last_accepted_share: worker.last_accepted_share_age().map(|age| now.saturating_sub(age)),
+            silent: worker.is_silent(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    pub timestamp: u64, // Seconds since the unix epoch
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+impl StatsSnapshot {
+    pub fn new(workers: Vec<WorkerSnapshot>) -> StatsSnapshot {
+        StatsSnapshot {
+            timestamp: unix_time(),
+            workers: workers,
+        }
+    }
+
+    /// Replace the snapshot at path with this one, keeping keep_count old ones
+    pub fn write(&self, path: &str, keep_count: usize) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", path);
+        let json = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        let written = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(&json).and_then(|_| file.sync_all()))
+            .map_err(|e| format!("Failed to write {}: {}", tmp_path, e));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        if keep_count > 0 {
+            let _ = fs::remove_file(format!("{}.{}", path, keep_count));
+            for n in (1..keep_count).rev() {
+                let _ = fs::rename(format!("{}.{}", path, n), format!("{}.{}", path, n + 1));
+            }
+            let _ = fs::rename(path, format!("{}.1", path));
+        }
+        fs::rename(&tmp_path, path).map_err(|e| format!("Failed to rename {} to {}: {}", tmp_path, path, e))
+    }
+}
+
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::{env, process};
+
+    #[test]
+    fn old_snapshots_rotated() {
+        let path = env::temp_dir().join(format!("grin-pool-snapshot-{}.json", process::id()));
+        let path = path.to_str().unwrap();
+        for accepted in 0..4 {
+            let worker = WorkerSnapshot {
+                id: "7-abc".to_string(),
+                login: "alice".to_string(),
+                accepted: accepted,
+                rejected: 0,
+                stale: 0,
+                hashrate_gps: 1.5,
+                difficulty: 8,
+                last_accepted_share: None,
+                silent: false,
+            };
+            StatsSnapshot::new(vec![worker]).write(path, 2).unwrap();
+        }
+        let read = |path: &str| -> StatsSnapshot { serde_json::from_slice(&fs::read(path).unwrap()).unwrap() };
+        assert_eq!(read(path).workers[0].accepted, 3);
+        assert_eq!(read(&format!("{}.1", path)).workers[0].accepted, 2);
+        assert_eq!(read(&format!("{}.2", path)).workers[0].accepted, 1);
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        for file in [path.to_string(), format!("{}.1", path), format!("{}.2", path)].iter() {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
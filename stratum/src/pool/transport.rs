@@ -0,0 +1,251 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker Transport
+//!
+//! Workers connect with raw TCP or, when enabled, with a WebSocket on the
+//! same port.  Either way the worker reads and writes newline terminated
+//! JSON-RPC messages: each WebSocket text message is one JSON-RPC message.
+//! Proxies on the same host can also connect over a Unix domain socket,
+//! those connections have no ip address.
+//!
+
+use std::cmp::min;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::server::accept_with_config;
+use tungstenite::{Error as WsError, Message, WebSocket};
+
+// How long to wait for a new connection to show what it is
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+pub enum WorkerStream {
+    Tcp(TcpStream),
+    WebSocket(Box<WsStream>),
+    Unix(UnixStream),
+}
+
+impl WorkerStream {
+    /// Complete a WebSocket handshake if the miner asked for one,
+    /// otherwise use the connection as it is
+    pub fn accept(stream: TcpStream, enable_websocket: bool, queue_depth: usize) -> Result<WorkerStream, String> {
+        if !enable_websocket || !is_websocket_upgrade(&stream) {
+            return Ok(WorkerStream::Tcp(stream));
+        }
+        let config = WebSocketConfig {
+            max_send_queue: Some(queue_depth),
+            ..WebSocketConfig::default()
+        };
+        match accept_with_config(stream, Some(config)) {
+            Ok(ws) => Ok(WorkerStream::WebSocket(Box::new(WsStream::new(ws)))),
+            Err(e) => Err(format!("WebSocket handshake failed: {}", e)),
+        }
+    }
+
+    // The TCP connection under this stream, None for a Unix socket
+    fn tcp(&self) -> Option<&TcpStream> {
+        match *self {
+            WorkerStream::Tcp(ref stream) => Some(stream),
+            WorkerStream::WebSocket(ref ws) => Some(ws.ws.get_ref()),
+            WorkerStream::Unix(_) => None,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.tcp() {
+            Some(stream) => stream.peer_addr(),
+            None => Err(io::Error::new(ErrorKind::Other, "Unix socket has no ip address")),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.tcp() {
+            Some(stream) => stream.local_addr(),
+            None => Err(io::Error::new(ErrorKind::Other, "Unix socket has no ip address")),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            WorkerStream::Tcp(ref stream) => stream.set_nonblocking(nonblocking),
+            WorkerStream::WebSocket(ref ws) => ws.ws.get_ref().set_nonblocking(nonblocking),
+            WorkerStream::Unix(ref stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            WorkerStream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            WorkerStream::WebSocket(ref ws) => ws.ws.get_ref().set_read_timeout(timeout),
+            WorkerStream::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            WorkerStream::Tcp(ref stream) => stream.shutdown(how),
+            WorkerStream::WebSocket(ref ws) => ws.ws.get_ref().shutdown(how),
+            WorkerStream::Unix(ref stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        match *self {
+            WorkerStream::Tcp(_) | WorkerStream::Unix(_) => false,
+            WorkerStream::WebSocket(_) => true,
+        }
+    }
+}
+
+impl AsRawFd for WorkerStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            WorkerStream::Tcp(ref stream) => stream.as_raw_fd(),
+            WorkerStream::WebSocket(ref ws) => ws.ws.get_ref().as_raw_fd(),
+            WorkerStream::Unix(ref stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for WorkerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            WorkerStream::Tcp(ref mut stream) => stream.read(buf),
+            WorkerStream::WebSocket(ref mut ws) => ws.read(buf),
+            WorkerStream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for WorkerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            WorkerStream::Tcp(ref mut stream) => stream.write(buf),
+            WorkerStream::WebSocket(ref mut ws) => ws.write(buf),
+            WorkerStream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            WorkerStream::Tcp(ref mut stream) => stream.flush(),
+            WorkerStream::WebSocket(ref mut ws) => ws.flush(),
+            WorkerStream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+// Does the connection start with an HTTP request to upgrade to a WebSocket?
+fn is_websocket_upgrade(stream: &TcpStream) -> bool {
+    // Raw TCP miners send their login straight away, so this is not long
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)));
+    let mut buf = [0u8; 2048];
+    let n = match stream.peek(&mut buf) {
+        Ok(n) => n,
+        Err(_) => return false,
+    };
+    let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+    request.starts_with("get ") && request.lines().any(|line| {
+        let mut header = line.splitn(2, ':');
+        header.next().map(|name| name.trim()) == Some("upgrade")
+            && header.next().map(|value| value.contains("websocket")).unwrap_or(false)
+    })
+}
+
+fn io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::new(ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// A WebSocket read and written as a stream of newline terminated messages
+pub struct WsStream {
+    ws: WebSocket<TcpStream>,
+    incoming: Vec<u8>, // Received messages not read yet
+    outgoing: Vec<u8>, // Written bytes not sent as a message yet
+}
+
+impl WsStream {
+    fn new(ws: WebSocket<TcpStream>) -> WsStream {
+        WsStream {
+            ws: ws,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    // Send each complete line as a text message, false if the send queue is full
+    fn send_lines(&mut self) -> io::Result<bool> {
+        while let Some(end) = self.outgoing.iter().position(|b| *b == b'\n') {
+            let text = String::from_utf8_lossy(&self.outgoing[..end]).into_owned();
+            match self.ws.write_message(Message::Text(text)) {
+                Ok(_) => {}
+                // Queued, it goes out with a later write or flush
+                Err(WsError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(WsError::SendQueueFull(_)) => return Ok(false),
+                Err(e) => return Err(io_error(e)),
+            }
+            self.outgoing.drain(..end + 1);
+        }
+        Ok(true)
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.incoming.is_empty() {
+            let data = match self.ws.read_message() {
+                Ok(Message::Text(text)) => text.into_bytes(),
+                Ok(Message::Binary(data)) => data,
+                // Pings and close frames are answered by tungstenite
+                Ok(_) => continue,
+                Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => return Ok(0),
+                Err(e) => return Err(io_error(e)),
+            };
+            if data.is_empty() {
+                continue;
+            }
+            self.incoming.extend(data);
+            if self.incoming.last() != Some(&b'\n') {
+                self.incoming.push(b'\n');
+            }
+        }
+        let n = min(buf.len(), self.incoming.len());
+        buf[..n].copy_from_slice(&self.incoming[..n]);
+        self.incoming.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Messages that did not fit the send queue go first
+        if !self.send_lines()? {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "WebSocket send queue is full"));
+        }
+        self.outgoing.extend_from_slice(buf);
+        self.send_lines()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_lines()?;
+        self.ws.write_pending().map_err(io_error)
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Share Validation
+//!
+//! Rebuilding the block header and verifying the cuckoo cycle is the most
+//! expensive part of handling a share.  Shares that pass the cheap checks
+//! are collected here and verified in parallel on the rayon thread pool,
+//! without holding the workers lock.
+//!
+
+use failure::Error;
+use rayon::prelude::*;
+use std::mem;
+
+use grin_core::core::hash::Hashed;
+use grin_core::core::BlockHeader;
+use grin_core::pow::{new_cuckaroo_ctx, new_cuckatoo_ctx, PoWContext, Proof};
+use grin_core::ser::{deserialize, ser_vec};
+use grin_util::from_hex;
+
+use pool::consensus::Proof as MinerProof;
+use pool::consensus::PROOF_SIZE;
+
+/// The cuckoo cycle variant a proof was found with, told apart by its edge_bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowAlgorithm {
+    Cuckatoo, // Primary, 31 edge_bits and up, for ASICs
+    Cuckaroo, // Secondary, 29 edge_bits, for GPUs
+}
+
+impl PowAlgorithm {
+    pub fn from_edge_bits(edge_bits: u32) -> Option<PowAlgorithm> {
+        match edge_bits {
+            29 => Some(PowAlgorithm::Cuckaroo),
+            31..=63 => Some(PowAlgorithm::Cuckatoo),
+            _ => None,
+        }
+    }
+}
+
+/// Everything needed to validate a share away from its worker
+#[derive(Debug, Clone)]
+pub struct PendingShare {
+    pub pre_pow: String,
+    pub algorithm: PowAlgorithm,
+    pub edge_bits: u32,
+    pub nonce: u64,
+    pub pow: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationResult {
+    Valid { block_hash: String, difficulty: u64 },
+    InvalidHeader, // Failed to build block header
+    InvalidProof,  // Failed to verify solution
+}
+
+pub struct ShareValidator {
+    pending: Vec<PendingShare>,
+}
+
+impl ShareValidator {
+    pub fn new() -> ShareValidator {
+        ShareValidator { pending: Vec::new() }
+    }
+
+    /// Queue a share, returns the index of its result
+    pub fn push(&mut self, share: PendingShare) -> usize {
+        self.pending.push(share);
+        return self.pending.len() - 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Validate the queued shares in parallel, results are in queue order
+    pub fn validate(&mut self) -> Vec<ValidationResult> {
+        self.validate_with(validate_share)
+    }
+
+    /// Validate the queued shares one at a time on this thread
+    pub fn validate_serial(&mut self) -> Vec<ValidationResult> {
+        self.validate_serial_with(validate_share)
+    }
+
+    /// Validate the queued shares in parallel with another check
+    pub fn validate_with<F>(&mut self, f: F) -> Vec<ValidationResult>
+    where
+        F: Fn(&PendingShare) -> ValidationResult + Sync,
+    {
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        pending.par_iter().map(|share| f(share)).collect()
+    }
+
+    fn validate_serial_with<F>(&mut self, f: F) -> Vec<ValidationResult>
+    where
+        F: Fn(&PendingShare) -> ValidationResult,
+    {
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        pending.iter().map(|share| f(share)).collect()
+    }
+}
+
+/// Check the share pow against the job pre_pow and compute its difficulty
+pub fn validate_share(share: &PendingShare) -> ValidationResult {
+    check_share(share, true)
+}
+
+/// Like validate_share, but the cuckoo cycle is not verified.  UNSAFE for
+/// a production pool: any 42 nonces pass, and since a shares difficulty is
+/// a hash of its nonces, a miner can grind made up proofs to any difficulty.
+/// Only for testnets and miners that are trusted.
+pub fn validate_share_unverified(share: &PendingShare) -> ValidationResult {
+    check_share(share, false)
+}
+
+fn check_share(share: &PendingShare, verify: bool) -> ValidationResult {
+    // A) Construct a BlockHeader from the pre-pow and the share pow
+    let bh = match block_header(
+        share.pre_pow.clone(),
+        share.edge_bits as u8,
+        share.nonce,
+        share.pow.clone(),
+    ) {
+        Ok(bh) => bh,
+        Err(_) => return ValidationResult::InvalidHeader,
+    };
+    // B) Verify the cycle with the verifier of its algorithm
+    if verify && verify_share(share.algorithm, &bh).is_err() {
+        return ValidationResult::InvalidProof;
+    }
+    let proof = MinerProof {
+        edge_bits: share.edge_bits as u8,
+        nonces: share.pow.clone(),
+    };
+    ValidationResult::Valid {
+        block_hash: bh.hash().to_hex(),
+        difficulty: proof.to_difficulty_unscaled().to_num(),
+    }
+}
+
+/// Verify the proof in a header with the verifier of its algorithm
+// What grin_core::pow::verify_size does, without leaving the choice of
+// algorithm to the chain type grin_core was set up for
+pub fn verify_share(algorithm: PowAlgorithm, header: &BlockHeader) -> Result<(), Error> {
+    let edge_bits = header.pow.edge_bits();
+    let proof_size = header.pow.proof.nonces.len();
+    let mut ctx: Box<PoWContext<u64>> = match algorithm {
+        PowAlgorithm::Cuckaroo => new_cuckaroo_ctx(edge_bits, proof_size)?,
+        PowAlgorithm::Cuckatoo => new_cuckatoo_ctx(edge_bits, proof_size, 1)?,
+    };
+    ctx.set_header_nonce(header.pre_pow(), None, false)?;
+    ctx.verify(&header.pow.proof)?;
+    Ok(())
+}
+
+/// Total difficulty and secondary scaling of a jobs candidate header
+pub fn pre_pow_difficulty(pre_pow: &str) -> Result<(u64, u32), Error> {
+    // Any well formed proof will do, only the pre_pow part is read
+    let header = block_header(pre_pow.to_string(), 31, 0, vec![0; PROOF_SIZE])?;
+    Ok((header.pow.total_difficulty.to_num(), header.pow.secondary_scaling))
+}
+
+/// The header nonce of a share from a miner given an extranonce1 - it
+/// fills the top extranonce1_bytes of the nonce, the miners extranonce2
+/// the rest.  A full nonce that already starts with extranonce1 is unchanged.
+pub fn extranonce_nonce(extranonce1: u64, extranonce1_bytes: usize, extranonce2: u64) -> u64 {
+    if extranonce1_bytes == 0 {
+        return extranonce2;
+    }
+    let extranonce2_bits = 64 - 8 * extranonce1_bytes as u32;
+    (extranonce1 << extranonce2_bits) | (extranonce2 & ((1u64 << extranonce2_bits) - 1))
+}
+
+pub fn block_header(pre_pow: String, edge_bits: u8, nonce: u64, proof: Vec<u64>) -> Result<BlockHeader, Error> {
+    let mut header_bytes = from_hex(pre_pow)?;
+    let mut nonce_bytes = ser_vec(&nonce)?;
+    header_bytes.append(&mut nonce_bytes);
+    let mut proof = Proof::new(proof);
+    proof.edge_bits = edge_bits;
+    let mut proof_bytes = ser_vec(&proof)?;
+    header_bytes.append(&mut proof_bytes);
+
+    let header: BlockHeader = deserialize(&mut &header_bytes[..])?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grin_util::to_hex;
+    use rayon;
+    use std::time::Instant;
+
+    // Stand in for cycle verification, cpu bound like the real thing
+    fn busy_validate(share: &PendingShare) -> ValidationResult {
+        let mut x = share.nonce;
+        for _ in 0..200_000 {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        }
+        ValidationResult::Valid {
+            block_hash: format!("{:016x}", x),
+            difficulty: 1,
+        }
+    }
+
+    fn fill(validator: &mut ShareValidator, count: u64) {
+        for nonce in 0..count {
+            validator.push(PendingShare {
+                pre_pow: String::new(),
+                algorithm: PowAlgorithm::Cuckaroo,
+                edge_bits: 29,
+                nonce: nonce,
+                pow: vec![],
+            });
+        }
+    }
+
+    #[test]
+    fn parallel_faster_than_serial() {
+        let mut validator = ShareValidator::new();
+        fill(&mut validator, 1000);
+        let start = Instant::now();
+        let serial = validator.validate_serial_with(busy_validate);
+        let serial_time = start.elapsed();
+        assert_eq!(validator.len(), 0);
+
+        fill(&mut validator, 1000);
+        let start = Instant::now();
+        let parallel = validator.validate_with(busy_validate);
+        let parallel_time = start.elapsed();
+
+        // Same results, in the same order
+        assert_eq!(serial, parallel);
+        if rayon::current_num_threads() > 1 {
+            assert!(
+                parallel_time < serial_time,
+                "parallel {:?} vs serial {:?}",
+                parallel_time,
+                serial_time
+            );
+        }
+    }
+
+    #[test]
+    fn bad_pre_pow_rejected() {
+        let mut validator = ShareValidator::new();
+        let index = validator.push(PendingShare {
+            pre_pow: "not hex".to_string(),
+            algorithm: PowAlgorithm::Cuckaroo,
+            edge_bits: 29,
+            nonce: 1,
+            pow: vec![0; 42],
+        });
+        assert_eq!(index, 0);
+        assert_eq!(validator.validate(), vec![ValidationResult::InvalidHeader]);
+    }
+
+    #[test]
+    fn algorithm_from_edge_bits() {
+        assert_eq!(PowAlgorithm::from_edge_bits(29), Some(PowAlgorithm::Cuckaroo));
+        assert_eq!(PowAlgorithm::from_edge_bits(31), Some(PowAlgorithm::Cuckatoo));
+        assert_eq!(PowAlgorithm::from_edge_bits(33), Some(PowAlgorithm::Cuckatoo));
+        assert_eq!(PowAlgorithm::from_edge_bits(30), None);
+        assert_eq!(PowAlgorithm::from_edge_bits(64), None);
+    }
+
+    #[test]
+    fn unverified_shares_still_priced() {
+        // A candidate header without its nonce and proof, as a job sends it
+        let header = BlockHeader::default();
+        let mut bytes = ser_vec(&header).unwrap();
+        let proof_len = ser_vec(&header.pow.proof).unwrap().len();
+        bytes.truncate(bytes.len() - proof_len - 8);
+        let share = PendingShare {
+            pre_pow: to_hex(bytes),
+            algorithm: PowAlgorithm::Cuckaroo,
+            edge_bits: 29,
+            nonce: 1,
+            pow: (0..PROOF_SIZE as u64).collect(),
+        };
+        assert_eq!(validate_share(&share), ValidationResult::InvalidProof);
+        let proof = MinerProof {
+            edge_bits: 29,
+            nonces: share.pow.clone(),
+        };
+        match validate_share_unverified(&share) {
+            ValidationResult::Valid { difficulty, .. } => {
+                assert_eq!(difficulty, proof.to_difficulty_unscaled().to_num())
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Block Found Events
+//!
+//! Logs every block the pool finds and tells an operator supplied url
+//! about it.
+//!
+
+use reqwest;
+use std::fmt;
+use std::thread;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockFound {
+    pub height: u64,
+    pub hash: String,
+    pub worker: String,
+    pub nonce: u64,
+    pub difficulty: u64,
+}
+
+impl fmt::Display for BlockFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BLOCK FOUND height={} hash={} worker={} nonce={} difficulty={}",
+            self.height, self.hash, self.worker, self.nonce, self.difficulty
+        )
+    }
+}
+
+/// Log a found block and fire the webhook if there is one
+pub fn block_found(pool_id: &str, url: &Option<String>, event: BlockFound) {
+    warn!("{} - {}", pool_id, event);
+    if let Some(ref url) = *url {
+        notify_block_found(url.clone(), event);
+    }
+}
+
+/// POST the event to url from a new thread so the main loop never waits on it
+pub fn notify_block_found(url: String, event: BlockFound) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let client = reqwest::Client::new();
+        match client.post(url.as_str()).json(&event).send() {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    warn!("Block found webhook {} returned {}", url, response.status());
+                }
+            }
+            Err(e) => {
+                error!("Block found webhook {} failed: {}", url, e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{self, mock, Matcher};
+    use serde_json;
+
+    #[test]
+    fn posts_block_found() {
+        let event = BlockFound {
+            height: 100,
+            hash: "0a1b2c".to_string(),
+            worker: "7-abc".to_string(),
+            nonce: 42,
+            difficulty: 4000,
+        };
+        let hook = mock("POST", "/block")
+            .match_header("content-type", "application/json")
+            .match_body(Matcher::JsonString(serde_json::to_string(&event).unwrap()))
+            .with_status(200)
+            .create();
+        let url = format!("{}/block", mockito::server_url());
+        notify_block_found(url, event).join().unwrap();
+        hook.assert();
+    }
+
+    #[test]
+    fn block_found_log_line() {
+        let event = BlockFound {
+            height: 100,
+            hash: "0a1b2c".to_string(),
+            worker: "7-abc".to_string(),
+            nonce: 42,
+            difficulty: 4000,
+        };
+        assert_eq!(
+            event.to_string(),
+            "BLOCK FOUND height=100 hash=0a1b2c worker=7-abc nonce=42 difficulty=4000"
+        );
+    }
+}
@@ -19,19 +19,95 @@
 use bufstream::BufStream;
 use serde_json;
 use serde_json::Value;
-use std::net::TcpStream;
+use std::net::{IpAddr, Shutdown};
 use reqwest;
-use std::collections::HashMap;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, ErrorKind, Write};
 use redis::{Client, Commands, Connection, RedisResult};
 use std::iter;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{thread, time};
+use std::time::{Duration, Instant};
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
 use queues::*;
 
+use pool::audit::AuthEntry;
 use pool::config::{Config, NodeConfig, PoolConfig, WorkerConfig};
-use pool::proto::{RpcRequest, RpcError};
+use pool::proto::{RejectReason, RpcRequest, RpcError};
+use pool::ratelimit::{IpRateLimiter, IpRef, PoolRef, TokenBucket};
+use pool::hashrate::HashrateEstimator;
+use pool::pplns::edge_bits_weight;
+use pool::transport::WorkerStream;
 use pool::proto::{JobTemplate, LoginParams, StratumProtocol, SubmitParams, WorkerStatus};
+use pool::validator::extranonce_nonce;
+
+// Identifies a workers socket to the poller, 0 is the upstream server
+static NEXT_POLL_TOKEN: AtomicUsize = AtomicUsize::new(1);
+// Handed out in turn as extranonce1, unique until it wraps at extranonce1_bytes
+static NEXT_EXTRANONCE1: AtomicUsize = AtomicUsize::new(0);
+// Most vardiff multiplies or divides the difficulty by in one retarget
+const VARDIFF_MAX_STEP: f64 = 4.0;
+// A worker is silent after this many vardiff share intervals without an accepted share
+const SILENT_SHARE_INTERVALS: u64 = 10;
+
+/// Split a login into lowercase account, rig and worker names.
+/// The rig and worker names are optional, "default" and "0" if not given.
+pub fn parse_login(login: &str, delimiter: &str, max_len: usize) -> Result<(String, String, String), String> {
+    let parts: Vec<String> = login
+        .split(delimiter)
+        .map(|part| part.trim().to_lowercase())
+        .collect();
+    if parts.len() > 3 {
+        return Err("Invalid Username Format".to_string());
+    }
+    if parts.iter().any(|part| part.len() > max_len) {
+        return Err(format!("Invalid Username Format - names are limited to {} characters", max_len));
+    }
+    if parts[0].is_empty() {
+        return Err("Invalid Username Format - missing username".to_string());
+    }
+    if parts.len() >= 2 && parts[1].is_empty() {
+        return Err("Invalid Username Format - empty rig name".to_string());
+    }
+    if parts.len() == 3 && parts[2].is_empty() {
+        return Err("Invalid Username Format - empty worker name".to_string());
+    }
+    let rigid = parts.get(1).cloned().unwrap_or("default".to_string());
+    let workerid = parts.get(2).cloned().unwrap_or("0".to_string());
+    Ok((parts[0].clone(), rigid, workerid))
+}
+
+/// Longest rig id kept
+const MAX_RIG_ID_LEN: usize = 64;
+
+/// Keep only the letters, digits, hyphens and underscores of a rig id,
+/// at most 64 of them - it ends up in ids, file names and the database
+pub fn sanitize_rig_id(raw: &str) -> String {
+    raw.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(MAX_RIG_ID_LEN)
+        .collect()
+}
+
+/// Split the free-form tag off the end of a login, it is kept as sent.
+/// No tag if the delimiter is empty, missing, or nothing follows it.
+pub fn split_login_tag<'a>(login: &'a str, tag_delimiter: &str) -> (&'a str, Option<String>) {
+    if tag_delimiter.is_empty() {
+        return (login, None);
+    }
+    match login.find(tag_delimiter) {
+        None => (login, None),
+        Some(i) => {
+            let tag = login[i + tag_delimiter.len()..].trim();
+            (&login[..i], if tag.is_empty() { None } else { Some(tag.to_string()) })
+        }
+    }
+}
 
 // ----------------------------------------
 // Worker Object - a connected stratum client - a miner
@@ -62,9 +138,13 @@ pub struct WorkerShares {
     pub rigid: String,  // User assigned or "default"
     pub workerid: String, // User assigned or "0"
     pub agent: String,  // Miner identifier
+    #[serde(default)]
+    pub tag: Option<String>, // Free-form part of the login after login_tag_delimiter
     pub height: u64,
     pub difficulty: u64,
     pub shares: HashMap<u32, Shares>,
+    #[serde(default)]
+    pub weighted_shares: f64, // Accepted shares weighted by edge_bits, a C24 share weighs 1
 }
 
 impl WorkerShares {
@@ -74,42 +154,95 @@ impl WorkerShares {
             rigid: "default".to_string(),
             workerid: "0".to_string(),
             agent: "unknown".to_string(),
+            tag: None,
             height: 0,
             difficulty: 0,
             shares: HashMap::new(),
+            weighted_shares: 0.0,
         }
     }
 }
 
+/// Where a connection is in the stratum handshake.  Grin miners (grin-miner,
+/// gminer, lolMiner, bminer) just send login.  Miners and proxies written
+/// for generic stratum (nicehash style proxies, some firmware) first send
+/// mining.subscribe and then mining.authorize.  Either way a worker only
+/// gets jobs and may submit shares once it is authorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Subscribed,
+    Authorized,
+}
 
 pub struct Worker {
     pub user_id: usize,   // the pool user_id or 0 if we dont know yet
     pub connection_id: String,  // The random per-connection id used to match proxied stratum messages
     login: Option<LoginParams>,  // The stratum login parameters sent by the miner
-    stream: BufStream<TcpStream>,  // Connection with the mier process
+    stream: BufStream<WorkerStream>,  // Connection with the mier process
     config: Config, // Values from the config.toml file
     protocol: StratumProtocol,  // Structures, codes, methods for stratum protocol
     error: bool, // Is this worker connection in error state?
-    pub authenticated: bool, // Has the miner already successfully logged in?
+    closed: bool, // Did the miner close the connection?
+    pub state: ConnectionState, // Where the miner is in the login handshake
+    subscribe_agent: Option<String>, // Miner identifier sent with mining.subscribe, used at mining.authorize
     pub status: WorkerStatus,        // Runing totals - reported with stratum status message
     pub worker_shares: WorkerShares, // Share Counts for current block
     shares: Vec<SubmitParams>, // shares submitted by the miner that need to be processed by the pool
     request_ids: Queue<String>,     // Queue of request message ID's
     pub needs_job: bool, // Does this miner need a job for any reason
     pub requested_job: bool, // The miner sent a job request
+    pub requested_transactions: bool, // The miner sent a mining.get_transactions request the pool has not answered yet
+    transactions: Option<(u64, String)>, // Height and the last transactions sent for mining.get_transactions
     redis: Option<redis::Connection>, // Login/UserID are cached here
     pub buffer: String, // Read-Buffer for stream
+    last_message_received: Instant, // When we last heard anything from the miner
+    last_ping: Option<Instant>, // When we last sent a ping to a quiet worker
+    last_pong: Option<Instant>, // When the miner last answered a ping
+    ip: Option<IpAddr>, // The miners address
+    invalid_shares: VecDeque<Instant>, // When invalid shares were submitted in the last minute
+    login_limiter: Option<Arc<Mutex<IpRateLimiter>>>, // Shared per-ip login attempt counter
+    ip_ref: Option<IpRef>, // Counts this connection against its ip address until the worker is dropped
+    pool_ref: Option<PoolRef>, // Counts this connection against max_connections until the worker is dropped
+    pub share_rate_limiter: TokenBucket, // Caps how fast shares are accepted for processing
+    pub hashrate: HashrateEstimator, // Recently accepted shares
+    outbound: VecDeque<String>, // Messages waiting to be written to the miner
+    outbound_partial: Vec<u8>, // Unwritten bytes of the message currently being written
+    suggested_difficulty: Option<u64>, // Lowest difficulty the miner asked for
+    job_difficulty: Option<u64>, // Difficulty of the last job sent, the miner is told when it changes
+    pub session_token: Option<String>, // Handed out on login so a reconnect can resume this session
+    poll_token: usize, // This workers socket in the poller
+    pub registered: bool, // Has the socket been registered with the poller yet?
+    port: u64, // The pool port the miner connected to
+    vardiff_shares: u64, // Shares accepted since the last retarget
+    vardiff_since: Instant, // When the last retarget was
+    consecutive_timeouts: u32, // Reads in a row that found the socket ready but no complete message
+    pub assigned_nonce_range: Option<(u64, u64)>, // Start and end of the nonce range sent with the latest job
+    job_nonces: HashMap<u64, (u64, (u64, u64))>, // Height and nonce range sent with each job at the current height
+    pub nonce_range_violations: u64, // Shares with a nonce outside the range sent for their job
+    extranonce1: Option<(u64, usize)>, // Extranonce1 given at subscribe and its size in bytes
+    connected_at: Instant, // When the miner connected
+    last_accepted_share: Option<Instant>, // When a share from this worker was last accepted
+    auth_attempts: Vec<AuthEntry>, // Login attempts the pool has not audited yet
 }
 
 impl Worker {
     /// Creates a new Stratum Worker.
-    pub fn new(config: Config, stream: BufStream<TcpStream>) -> Worker {
+    pub fn new(config: Config, stream: BufStream<WorkerStream>) -> Worker {
         let mut rng = thread_rng();
         let connection_id: String = iter::repeat(())
             .map(|()| rng.sample(Alphanumeric))
             .take(16)
             .collect();
         let uuid = format!("{}-{}", 0, connection_id.clone());
+        let ip = match stream.get_ref().peer_addr() {
+            Ok(addr) => Some(addr.ip()),
+            Err(_) => None,
+        };
+        let port = match stream.get_ref().local_addr() {
+            Ok(addr) => addr.port() as u64,
+            Err(_) => 0,
+        };
         Worker {
             user_id: 0, // We dont know until the user logs in
             connection_id: connection_id,
@@ -118,15 +251,50 @@ impl Worker {
             stream: stream,
             protocol: StratumProtocol::new(),
             error: false,
-            authenticated: false,
+            closed: false,
+            state: ConnectionState::Connected,
             status: WorkerStatus::new(uuid.clone()),
             worker_shares: WorkerShares::new(uuid.clone()),
             shares: Vec::new(),
             request_ids: queue![],
             needs_job: false,
             requested_job: false,
+            requested_transactions: false,
+            transactions: None,
             redis: None,
             buffer: String::with_capacity(4096),
+            last_message_received: Instant::now(),
+            last_ping: None,
+            last_pong: None,
+            subscribe_agent: None,
+            ip: ip,
+            invalid_shares: VecDeque::new(),
+            login_limiter: None,
+            ip_ref: None,
+            pool_ref: None,
+            share_rate_limiter: TokenBucket::new(
+                config.workers.share_burst,
+                config.workers.max_shares_per_sec,
+            ),
+            hashrate: HashrateEstimator::new(),
+            outbound: VecDeque::new(),
+            outbound_partial: Vec::new(),
+            suggested_difficulty: None,
+            job_difficulty: None,
+            session_token: None,
+            poll_token: NEXT_POLL_TOKEN.fetch_add(1, Ordering::Relaxed),
+            registered: false,
+            port: port,
+            vardiff_shares: 0,
+            vardiff_since: Instant::now(),
+            consecutive_timeouts: 0,
+            assigned_nonce_range: None,
+            job_nonces: HashMap::new(),
+            nonce_range_violations: 0,
+            extranonce1: None,
+            connected_at: Instant::now(),
+            last_accepted_share: None,
+            auth_attempts: Vec::new(),
         }
     }
 
@@ -135,6 +303,115 @@ impl Worker {
         return self.error;
     }
 
+    /// Did the miner close the connection, rather than the pool drop it?
+    pub fn closed(&self) -> bool {
+        return self.closed;
+    }
+
+    /// Put the worker in error state so it gets dropped
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    /// Close the connection and put the worker in error state so it gets dropped
+    pub fn disconnect(&mut self) {
+        let _ = self.stream.get_ref().shutdown(Shutdown::Both);
+        self.error = true;
+    }
+
+    /// Count an invalid share, returns how many were submitted in the last minute
+    pub fn add_invalid_share(&mut self) -> usize {
+        let now = Instant::now();
+        self.invalid_shares.push_back(now);
+        while let Some(first) = self.invalid_shares.front().cloned() {
+            if now.duration_since(first) <= Duration::from_secs(60) {
+                break;
+            }
+            self.invalid_shares.pop_front();
+        }
+        return self.invalid_shares.len();
+    }
+
+    /// How many invalid shares were submitted in the last minute
+    pub fn recent_invalid_shares(&self) -> usize {
+        self.invalid_shares
+            .iter()
+            .filter(|when| when.elapsed() <= Duration::from_secs(60))
+            .count()
+    }
+
+    /// Has the miner already successfully logged in?
+    pub fn authenticated(&self) -> bool {
+        self.state == ConnectionState::Authorized
+    }
+
+    /// Has the worker been silent for longer than timeout?
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        return self.last_message_received.elapsed() > timeout;
+    }
+
+    /// Has a ping been sent without anything heard from the miner since?
+    pub fn ping_pending(&self) -> bool {
+        match self.last_ping {
+            Some(ping) => self.last_message_received <= ping,
+            None => false,
+        }
+    }
+
+    /// Ping the worker once it has been quiet for interval, and put it in
+    /// error if nothing follows the ping within timeout - a half-open
+    /// connection is otherwise only noticed when a write to it fails.
+    /// Workers that are submitting shares are never quiet, so never pinged.
+    pub fn check_keepalive(&mut self, interval: Duration, timeout: Duration) {
+        if self.ping_pending() {
+            let ping = self.last_ping.unwrap();
+            if ping.elapsed() >= timeout {
+                let answered = match self.last_pong {
+                    Some(pong) => format!("last answered one {} seconds ago", pong.elapsed().as_secs()),
+                    None => "never answered one".to_string(),
+                };
+                warn!(
+                    "Worker {} - No reply to a ping in {} seconds, {}, dropping it",
+                    self.uuid(),
+                    timeout.as_secs(),
+                    answered
+                );
+                self.set_error();
+            }
+        } else if self.last_message_received.elapsed() >= interval {
+            let _ = self.send_ping();
+        }
+    }
+
+    /// get the ip address the worker connected from
+    pub fn ip(&self) -> Option<IpAddr> {
+        return self.ip;
+    }
+
+    /// The token for this workers socket in the poller
+    pub fn poll_token(&self) -> usize {
+        return self.poll_token;
+    }
+
+    /// The socket to wait on for messages from the miner
+    pub fn raw_fd(&self) -> RawFd {
+        return self.stream.get_ref().as_raw_fd();
+    }
+
+    /// Are there more messages read from the socket but not processed yet?
+    /// The poller only wakes us for data still in the socket.
+    pub fn has_buffered_input(&mut self) -> bool {
+        match self.stream.fill_buf() {
+            Ok(buf) => !buf.is_empty(),
+            Err(_) => false,
+        }
+    }
+
+    /// Is the worker connected with a WebSocket?
+    pub fn is_websocket(&self) -> bool {
+        return self.stream.get_ref().is_websocket();
+    }
+
     /// get the workers pool user_id
     pub fn user_id(&self) -> usize {
         return self.user_id;
@@ -146,6 +423,26 @@ impl Worker {
         return uuid
     }
 
+    /// get the full_id:  user_id + rig id + worker id, the same for every connection a miner makes
+    pub fn full_id(&self) -> String {
+        format!("{}.{}.{}", self.user_id, self.worker_shares.rigid, self.worker_shares.workerid)
+    }
+
+    /// Add a numbered suffix to the worker name until full_id is not one
+    /// already taken, returns true if it had to be changed
+    pub fn make_full_id_unique(&mut self, taken: &HashSet<String>) -> bool {
+        if !taken.contains(&self.full_id()) {
+            return false;
+        }
+        let workerid = self.worker_shares.workerid.clone();
+        let mut suffix = 2;
+        while taken.contains(&self.full_id()) {
+            self.worker_shares.workerid = format!("{}-{}", workerid, suffix);
+            suffix += 1;
+        }
+        return true;
+    }
+
     /// Get worker login
     pub fn login(&self) -> String {
         match self.login {
@@ -157,9 +454,163 @@ impl Worker {
         }
     }
 
-    /// Set job difficulty
+    /// The session token the miner sent with its login, if any
+    pub fn login_session_token(&self) -> Option<String> {
+        match self.login {
+            None => None,
+            Some(ref login) => login.session_token.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_login(&mut self, login: LoginParams) {
+        self.login = Some(login);
+    }
+
+    /// Start a new session, returns its token
+    pub fn start_session(&mut self) -> String {
+        // A random (version 4) UUID
+        let mut bytes: [u8; 16] = thread_rng().gen();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let token = format!(
+            "{}-{}-{}-{}-{}",
+            hex[0..4].concat(),
+            hex[4..6].concat(),
+            hex[6..8].concat(),
+            hex[8..10].concat(),
+            hex[10..16].concat()
+        );
+        self.session_token = Some(token.clone());
+        return token;
+    }
+
+    /// Set job difficulty, never below the min_difficulty of this workers
+    /// port or what the miner suggested
     pub fn set_difficulty(&mut self, new_difficulty: u64) {
-        self.status.difficulty = new_difficulty;
+        let floor = max(self.difficulty_bounds().0, self.suggested_difficulty.unwrap_or(0));
+        self.status.difficulty = max(new_difficulty, floor);
+    }
+
+    /// The pool port the miner connected to, 0 on the unix socket
+    pub fn port(&self) -> u64 {
+        self.port
+    }
+
+    /// Lowest and highest difficulty for this worker, from the port it connected to
+    pub fn difficulty_bounds(&self) -> (u64, u64) {
+        self.config.workers.difficulty_bounds(self.port)
+    }
+
+    /// The difficulty that would have had this worker find a share every
+    /// vardiff_target_share_secs, given it found shares over elapsed.  It
+    /// moves at most VARDIFF_MAX_STEP times at once and stays in bounds.
+    pub fn compute_target_difficulty(&self, shares: u64, elapsed: Duration) -> u64 {
+        let current = max(self.status.difficulty, 1) as f64;
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let ideal = if elapsed_secs > 0.0 {
+            current * shares as f64 * self.config.workers.vardiff_target_share_secs as f64 / elapsed_secs
+        } else {
+            current
+        };
+        let target = ideal.max(current / VARDIFF_MAX_STEP).min(current * VARDIFF_MAX_STEP);
+        let (floor, ceiling) = self.difficulty_bounds();
+        if target <= floor as f64 {
+            floor
+        } else if target >= ceiling as f64 {
+            ceiling
+        } else {
+            target as u64
+        }
+    }
+
+    /// Count an accepted share towards the next vardiff retarget
+    pub fn vardiff_share(&mut self) {
+        self.vardiff_shares += 1;
+    }
+
+    /// Record that a share from this worker was accepted just now
+    pub fn share_accepted(&mut self) {
+        self.last_accepted_share = Some(Instant::now());
+    }
+
+    /// Seconds since a share from this worker was last accepted, None if none was
+    pub fn last_accepted_share_age(&self) -> Option<u64> {
+        self.last_accepted_share.map(|when| when.elapsed().as_secs())
+    }
+
+    /// Has this worker gone SILENT_SHARE_INTERVALS times the vardiff share
+    /// interval without an accepted share, or since it connected if it never
+    /// had one?  Never with vardiff off, there is no interval to expect.
+    pub fn is_silent(&self) -> bool {
+        let interval = self.config.workers.vardiff_target_share_secs;
+        if interval == 0 || !self.authenticated() {
+            return false;
+        }
+        let since = self.last_accepted_share.unwrap_or(self.connected_at);
+        since.elapsed() > Duration::from_secs(interval * SILENT_SHARE_INTERVALS)
+    }
+
+    /// Retarget the difficulty once vardiff_retarget_secs have passed,
+    /// the miner is sent a new job if it changed
+    pub fn retarget_difficulty(&mut self) {
+        if self.config.workers.vardiff_target_share_secs == 0 || !self.authenticated() {
+            return;
+        }
+        let elapsed = self.vardiff_since.elapsed();
+        if elapsed < Duration::from_secs(self.config.workers.vardiff_retarget_secs) {
+            return;
+        }
+        let difficulty = self.compute_target_difficulty(self.vardiff_shares, elapsed);
+        self.vardiff_shares = 0;
+        self.vardiff_since = Instant::now();
+        let current = self.status.difficulty;
+        self.set_difficulty(difficulty);
+        if self.status.difficulty != current {
+            debug!("Worker {} - vardiff from {} to {}", self.uuid(), current, self.status.difficulty);
+            self.needs_job = true;
+        }
+    }
+
+    /// The miner asked for at least this difficulty, clamped to the pools range
+    pub fn suggest_difficulty(&mut self, difficulty: f64) {
+        let min = self.config.workers.min_share_difficulty;
+        let max = self.config.workers.max_share_difficulty;
+        let suggested = if difficulty.is_nan() || difficulty <= min as f64 {
+            min
+        } else if difficulty >= max as f64 {
+            max
+        } else {
+            difficulty as u64
+        };
+        self.suggested_difficulty = Some(suggested);
+        let current = self.status.difficulty;
+        self.set_difficulty(current);
+        // Give the miner work at the new difficulty
+        if self.status.difficulty != current && self.authenticated() {
+            self.needs_job = true;
+        }
+    }
+
+    /// Set the shared login rate limiter
+    pub fn set_login_limiter(&mut self, login_limiter: Arc<Mutex<IpRateLimiter>>) {
+        self.login_limiter = Some(login_limiter);
+    }
+
+    /// The miners own address, when the connection came through a load balancer
+    pub fn set_ip(&mut self, ip: IpAddr) {
+        self.ip = Some(ip);
+    }
+
+    /// Count this connection against its ip address for as long as the worker lives
+    pub fn set_ip_ref(&mut self, ip_ref: IpRef) {
+        self.ip_ref = Some(ip_ref);
+    }
+
+    /// Count this connection against the pools max_connections for as long as the worker lives
+    pub fn set_pool_ref(&mut self, pool_ref: PoolRef) {
+        self.pool_ref = Some(pool_ref);
     }
 
     /// Set job height
@@ -173,10 +624,28 @@ impl Worker {
         self.worker_shares.height = height;
         self.worker_shares.difficulty = difficulty;
         self.worker_shares.shares = HashMap::new();
+        self.worker_shares.weighted_shares = 0.0;
     }
     
+    /// Zero the share counts an operator sees, staying on the current
+    /// height and difficulty
+    pub fn reset_stats(&mut self) {
+        let (height, difficulty) = (self.worker_shares.height, self.status.difficulty);
+        self.reset_worker_shares(height, difficulty);
+        self.status.accepted = 0;
+        self.status.pool_accepted = 0;
+        self.status.weighted_accepted = 0.0;
+        self.status.rejected = 0;
+        self.status.stale = 0;
+        self.status.deep_stale = 0;
+        self.status.stale_accepted = 0;
+    }
+
     /// Add a share to the worker_shares
     pub fn add_shares(&mut self, size: u32, accepted: u64, rejected: u64, stale: u64) {
+        let weighted = accepted as f64 * edge_bits_weight(size);
+        self.worker_shares.weighted_shares += weighted;
+        self.status.weighted_accepted += weighted;
         if self.worker_shares.shares.contains_key(&size) {
             match self.worker_shares.shares.get_mut(&size) {
                 Some(mut shares) => {
@@ -217,12 +686,9 @@ impl Worker {
             result.clone(),
             req_id.clone(),
         );
-        return self.protocol.send_response(
-                &mut self.stream,
-                method,
-                result,
-                Some(req_id),
-            );
+        let message = self.protocol.response(method, result, Some(req_id));
+        self.queue_message(message);
+        return Ok(());
     }
 
     /// Send an ERROR response
@@ -245,12 +711,57 @@ impl Worker {
             e.clone(),
             req_id.clone(),
         );
-        return self.protocol.send_error_response(
-            &mut self.stream,
-            method,
-            e,
-            Some(req_id),
-        );
+        let message = self.protocol.error_response(method, e, Some(req_id));
+        self.queue_message(message);
+        return Ok(());
+    }
+
+    // Queue a message for flush_outbound, the oldest queued message is
+    // dropped if the worker is not reading fast enough
+    fn queue_message(&mut self, message: String) {
+        self.outbound.push_back(message);
+        while self.outbound.len() > self.config.workers.outbound_queue_depth {
+            self.outbound.pop_front();
+            self.status.dropped_messages += 1;
+        }
+    }
+
+    /// Write queued messages until the socket would block, returns how many were completely written
+    pub fn flush_outbound(&mut self) -> Result<usize, String> {
+        let mut sent = 0;
+        loop {
+            if self.outbound_partial.is_empty() {
+                match self.outbound.pop_front() {
+                    None => return Ok(sent),
+                    Some(mut message) => {
+                        if !message.ends_with("\n") {
+                            message += "\n";
+                        }
+                        self.outbound_partial = message.into_bytes();
+                    }
+                }
+            }
+            // Straight to the socket - everything to the miner goes through this queue
+            match self.stream.get_mut().write(&self.outbound_partial) {
+                Ok(0) => {
+                    self.error = true;
+                    return Err("Connection closed".to_string());
+                }
+                Ok(n) => {
+                    self.outbound_partial.drain(..n);
+                    if self.outbound_partial.is_empty() {
+                        sent += 1;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(sent),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.error = true;
+                    error!("{} - Failed to write to worker: {}", self.uuid(), e);
+                    return Err(format!("{}", e));
+                }
+            }
+        }
     }
 
 
@@ -259,36 +770,83 @@ impl Worker {
     /// Send a job to the worker
     pub fn send_job(&mut self, job: &mut JobTemplate) -> Result<(), String> {
         trace!("Worker {} - Sending a job downstream: requested = {}", self.uuid(), self.requested_job);
-        // Set the difficulty
+        // Set the difficulty, some miners only pick up a change from set_difficulty
+        if self.job_difficulty.map_or(false, |sent| sent != self.status.difficulty) {
+            self.send_set_difficulty();
+        }
+        self.job_difficulty = Some(self.status.difficulty);
         job.difficulty = self.status.difficulty;
+        job.nonce_start = self.pick_nonce_range(job);
         let requested = self.requested_job;
         self.needs_job = false;
         self.requested_job = false;
         let job_value = serde_json::to_value(job.clone()).unwrap();
-        let result;
         if requested {
-            result = self.send_response(
+            return self.send_response(
                 "getjobtemplate".to_string(),
                 job_value,
             );
-        } else {
-            result = self.protocol.send_request(
-                &mut self.stream,
-                "job".to_string(),
-                Some(job_value.clone()),
-                Some("Stratum".to_string()),    // XXX UGLY
-            );
         }
-        match result {
-            Ok(r) => { return Ok(r); }
-            Err(e) => {
-                self.error = true;
-                error!("{} - Failed to send job: {}", self.uuid(), e);
-                return Err(format!("{}", e));
-            }
+        let message = self.protocol.request(
+            "job".to_string(),
+            Some(job_value.clone()),
+            Some("Stratum".to_string()),    // XXX UGLY
+        );
+        self.queue_message(message);
+        return Ok(());
+    }
+
+    // Pick a random range of nonces for this worker to search on this job
+    fn pick_nonce_range(&mut self, job: &JobTemplate) -> Option<u64> {
+        let bits = self.config.workers.nonce_range_bits;
+        if bits == 0 || bits >= 64 {
+            self.assigned_nonce_range = None;
+            // Without a range a miner with an extranonce1 starts at the beginning of its part
+            return self.extranonce1.map(|_| self.header_nonce(0));
+        }
+        let height = job.height;
+        self.job_nonces.retain(|_, &mut (job_height, _)| job_height == height);
+        // Aligned to the range size so the range never wraps, inside the extranonce1 if there is one.
+        // The end of the last range is 0, one past u64::max_value().
+        let start = self.header_nonce(thread_rng().gen::<u64>() & !((1u64 << bits) - 1));
+        let end = start.wrapping_add(1u64 << bits);
+        self.assign_nonce_range(start, end);
+        self.job_nonces.insert(job.job_id, (height, (start, end)));
+        Some(start)
+    }
+
+    /// Ask the worker to search the nonces from start up to, not including, end
+    pub fn assign_nonce_range(&mut self, start: u64, end: u64) {
+        self.assigned_nonce_range = Some((start, end));
+    }
+
+    /// The nonce in the header of a share the miner submitted with this
+    /// nonce, miners with an extranonce1 may send only their extranonce2
+    pub fn header_nonce(&self, nonce: u64) -> u64 {
+        match self.extranonce1 {
+            Some((extranonce1, bytes)) => extranonce_nonce(extranonce1, bytes, nonce),
+            None => nonce,
+        }
+    }
+
+    /// Could this share have come from the nonce range sent with its job?
+    /// Shares for jobs sent without a range always could.
+    pub fn nonce_in_range(&self, share: &SubmitParams) -> bool {
+        match self.job_nonces.get(&share.job_id) {
+            Some(&(_, (start, end))) => share.nonce.wrapping_sub(start) < end.wrapping_sub(start),
+            None => true,
         }
     }
 
+    /// Answer a mining.get_transactions request with the transactions the
+    /// node has for height, they are kept for more requests at that height
+    pub fn send_transactions(&mut self, height: u64, transactions: String) -> Result<(), String> {
+        self.requested_transactions = false;
+        self.transactions = Some((height, transactions.clone()));
+        let result = serde_json::from_str(&transactions).unwrap_or(Value::String(transactions));
+        return self.send_response("mining.get_transactions".to_string(), result);
+    }
+
     /// Send worker mining status
     pub fn send_status(&mut self, status: WorkerStatus) -> Result<(), String> {
         trace!("Worker {} - Sending worker status", self.uuid());
@@ -299,6 +857,23 @@ impl Worker {
         );
     }
 
+    /// Send a mining.set_difficulty notification with the current difficulty
+    fn send_set_difficulty(&mut self) {
+        trace!("Worker {} - Sending difficulty {}", self.uuid(), self.status.difficulty);
+        let params = serde_json::to_value(vec![self.status.difficulty]).unwrap();
+        let message = self.protocol.request("mining.set_difficulty".to_string(), Some(params), None);
+        self.queue_message(message);
+    }
+
+    /// Send a ping notification to probe an idle worker
+    pub fn send_ping(&mut self) -> Result<(), String> {
+        trace!("Worker {} - Sending ping", self.uuid());
+        self.last_ping = Some(Instant::now());
+        let message = self.protocol.request("ping".to_string(), None, None);
+        self.queue_message(message);
+        return Ok(());
+    }
+
     /// Send OK Response
     pub fn send_ok(&mut self, method: String) -> Result<(), String> {
         trace!("Worker {} - sending OK Response", self.uuid());
@@ -308,19 +883,111 @@ impl Worker {
         );
     }
 
+    /// Send the login OK Response with the new session token
+    pub fn send_login_ok(&mut self, method: String, session_token: String) -> Result<(), String> {
+        trace!("Worker {} - sending login OK Response", self.uuid());
+        let req_id = match self.request_ids.remove() {
+            Ok(id) => id,
+            Err(e) => {
+                error!("EMPTY request_ids ERROR");
+                "0".to_string()
+            },
+        };
+        let message = self.protocol.login_response(method, session_token, Some(req_id));
+        self.queue_message(message);
+        return Ok(());
+    }
+
+    /// The mining.subscribe result, laid out as in other stratum protocols:
+    /// [[[notification, subscription id]], extranonce1, extranonce2 size].
+    /// Grin headers have no extranonce, so an extranonce1 is the top bytes
+    /// of the 8 byte nonce.  Without one the miner searches the whole nonce.
+    fn subscription(&mut self) -> Value {
+        let bytes = self.config.workers.extranonce1_bytes;
+        if bytes > 0 && self.extranonce1.is_none() {
+            let mask = (1u64 << (8 * bytes)) - 1;
+            let extranonce1 = NEXT_EXTRANONCE1.fetch_add(1, Ordering::Relaxed) as u64 & mask;
+            self.extranonce1 = Some((extranonce1, bytes));
+        }
+        let (extranonce1, extranonce2_size) = match self.extranonce1 {
+            Some((extranonce1, bytes)) => (format!("{:0width$x}", extranonce1, width = bytes * 2), 8 - bytes),
+            None => (String::new(), 8),
+        };
+        let subscriptions = vec![
+            vec!["job".to_string(), self.connection_id.clone()],
+            vec!["mining.suggest_difficulty".to_string(), self.connection_id.clone()],
+        ];
+        serde_json::to_value((subscriptions, extranonce1, extranonce2_size)).unwrap()
+    }
+
+    // Log in with the params of a login or mining.authorize request and
+    // answer it, login answers with a session token
+    fn accept_login(&mut self, method: String, login_params: LoginParams) -> Result<(), String> {
+        let allowed = match (&self.login_limiter, self.ip) {
+            (&Some(ref limiter), Some(ip)) => limiter.lock().unwrap().check(ip),
+            _ => true,
+        };
+        if !allowed {
+            self.error = true;
+            warn!("Worker {} - Too many login attempts from {:?}", self.uuid(), self.ip);
+            let reason = RejectReason::TooManyLoginAttempts;
+            self.auth_attempts.push(AuthEntry::new(self.ip, &login_params.login, "", "failure", &reason.message()));
+            return self.send_err(method, reason);
+        }
+        let login = login_params.login.clone();
+        if let Err(e) = self.do_login(login_params) {
+            self.auth_attempts.push(AuthEntry::new(self.ip, &login, &self.worker_shares.rigid, "failure", &e));
+            return self.send_err(method, RejectReason::LoginFailed(e));
+        }
+        self.auth_attempts.push(AuthEntry::new(self.ip, &login, &self.worker_shares.rigid, "success", ""));
+        // We accepted the login, send ok result
+        self.state = ConnectionState::Authorized;
+        // Keep the difficulty this workers port started it with
+        let difficulty = self.status.difficulty;
+        self.status = WorkerStatus::new(self.uuid());
+        self.status.difficulty = difficulty;
+        let session_token = self.start_session();
+        if method == "mining.authorize" {
+            // Subscribed miners wait to be sent a job
+            self.needs_job = true;
+            return self.send_response(method, Value::Bool(true));
+        }
+        self.needs_job = false; // not until requested
+        return self.send_login_ok(method, session_token);
+    }
+
+    /// The login attempts made since this was last called, for the auth audit log
+    pub fn take_auth_attempts(&mut self) -> Vec<AuthEntry> {
+        mem::replace(&mut self.auth_attempts, Vec::new())
+    }
+
     /// Send Err Response
-    pub fn send_err(&mut self, method: String, message: String, code: i32) -> Result<(), String> {
+    pub fn send_err(&mut self, method: String, reason: RejectReason) -> Result<(), String> {
         trace!("Worker {} - sending Err Response", self.uuid());
-        let e = RpcError {
-            code: code,
-            message: message.to_string(),
-        };
         return self.send_error_response(
             method.to_string(),
-            e,
+            reason.rpc_error(),
         );
     }
 
+    /// Tell the miner why the pool is closing its connection - not the
+    /// response to any request, so it does not use up a request id
+    pub fn send_disconnect(&mut self, reason: RejectReason) -> Result<(), String> {
+        trace!("Worker {} - sending disconnect", self.uuid());
+        let message = self.protocol.error_response("disconnect".to_string(), reason.rpc_error(), Some("0".to_string()));
+        self.queue_message(message);
+        return Ok(());
+    }
+
+    /// Mark the shares waiting to be processed that a reorg made stale,
+    /// returns how many there are
+    pub fn mark_stale_on_reorg(&mut self, current_height: u64) -> usize {
+        self.shares
+            .iter_mut()
+            .filter(|share| share.mark_stale_on_reorg(current_height))
+            .count()
+    }
+
     /// Return any pending shares from this worker
     pub fn get_shares(&mut self) -> Result<Option<Vec<SubmitParams>>, String> {
         if self.shares.len() > 0 {
@@ -389,28 +1056,41 @@ impl Worker {
         }
         // END TEMPORARY
 
-        // Separate the username/RigID/WorkerID if provided
-        let mut username_split: Vec<&str> = login_params.login.split('.').collect();
-        if username_split.len() > 3 {
-            // TEMPORARY
-            if temp_id != 0 {
-                self.user_id = temp_id;
-                trace!("User LEGACY login found in cache: {}", self.user_id.clone());
-                // We accepted the login
-                return Ok(());
+        // Separate the username/RigID/WorkerID and tag if provided
+        let (login, tag) = split_login_tag(&login_params.login, &self.config.workers.login_tag_delimiter);
+        let username = match parse_login(
+            login,
+            &self.config.workers.login_delimiter,
+            self.config.workers.max_login_part_len,
+        ) {
+            Ok((username, rigid, workerid)) => {
+                let mut clean_rigid = sanitize_rig_id(&rigid);
+                if clean_rigid != rigid {
+                    warn!("Worker {} sent an invalid rig id {:?}, using {:?}", self.uuid(), rigid, clean_rigid);
+                }
+                if clean_rigid.is_empty() {
+                    clean_rigid = "default".to_string();
+                }
+                self.worker_shares.rigid = clean_rigid;
+                self.worker_shares.workerid = workerid;
+                let max_len = self.config.workers.max_login_part_len;
+                self.worker_shares.tag = tag.map(|tag| tag.chars().take(max_len).collect());
+                username
             }
-            // END TEMPORARY
-            self.error = true;
-            debug!("Worker {} failed to log in - Invalid username format: {}", self.user_id, login_params.login.clone());
-            return Err("Invalid Username Format".to_string());
-        }
-        let mut username = username_split[0].to_string();
-        if username_split.len() >= 2 {
-            self.worker_shares.rigid = username_split[1].to_string();
-        } 
-        if username_split.len() >= 3 {
-            self.worker_shares.workerid = username_split[2].to_string();
-        }
+            Err(e) => {
+                // TEMPORARY
+                if temp_id != 0 {
+                    self.user_id = temp_id;
+                    trace!("User LEGACY login found in cache: {}", self.user_id.clone());
+                    // We accepted the login
+                    return Ok(());
+                }
+                // END TEMPORARY
+                self.error = true;
+                debug!("Worker {} failed to log in - {}: {}", self.user_id, e, login_params.login.clone());
+                return Err(e);
+            }
+        };
         debug!("DEBUG: have username={}, rigid={}, workerid={}", username.clone(), self.worker_shares.rigid.clone(), self.worker_shares.workerid.clone());
 
         // Set the agent string in WorkerShares
@@ -495,7 +1175,18 @@ impl Worker {
             Ok(rpc_msg) => {
                 match rpc_msg {
                     Some(message) => {
+                        // Nothing read and no error is the end of the stream
+                        if message.is_empty() {
+                            debug!("Worker {} - Connection closed by the miner", self.uuid());
+                            self.closed = true;
+                            self.error = true;
+                            return Err("Connection closed".to_string());
+                        }
                         trace!("Worker {} - Got Message: {:?}", self.uuid(), message);
+                        // Any message at all proves the connection is alive
+                        self.consecutive_timeouts = 0;
+                        self.last_message_received = Instant::now();
+                        self.status.set_last_seen(self.last_message_received);
                         // let v: Value = serde_json::from_str(&message).unwrap();
                         let req: RpcRequest = match serde_json::from_str(&message) {
                             Ok(r) => r,
@@ -512,6 +1203,12 @@ impl Worker {
                             self.uuid(),
                             req.method
                         );
+                        // Replies to our idle probe are not requests, dont queue an id for them
+                        if req.method == "pong" || req.method == "ping" {
+                            trace!("Worker {} - Got pong", self.uuid());
+                            self.last_pong = Some(self.last_message_received);
+                            return Ok(());
+                        }
                         // Add this request id to the queue
                         self.request_ids.add(req.id.clone());
                         match req.method.as_str() {
@@ -520,6 +1217,7 @@ impl Worker {
                                 if self.user_id != 0 {
                                     // dont log in again, just say ok
                                     debug!("User already logged in: {}", self.user_id.clone());
+                                    self.state = ConnectionState::Authorized;
                                     self.send_ok(req.method);
                                     return Ok(());
                                 }
@@ -530,8 +1228,7 @@ impl Worker {
                                         debug!("Worker {} - Missing Login request parameters", self.uuid());
                                         return self.send_err(
                                             "login".to_string(),
-                                            "Missing Login request parameters".to_string(),
-                                            -32500,
+                                            RejectReason::MissingLoginParams,
                                         );
                                         // XXX TODO: Invalid request
                                         //return Err("Invalid Login request".to_string());
@@ -544,30 +1241,53 @@ impl Worker {
                                         debug!("Worker {} - Invalid Login request parameters", self.uuid());
                                         return self.send_err(
                                             "login".to_string(),
-                                            "Invalid Login request parameters".to_string(),
-                                            -32500,
+                                            RejectReason::InvalidLoginParams,
                                         );
                                         // XXX TODO: Invalid request
                                         //return Err(e.to_string());
                                     }
                                 };
-                                // Call do_login()
-                                match self.do_login(login_params) {
-                                    Ok(_) => {
-                                        // We accepted the login, send ok result
-					                    self.authenticated = true;
-                                        self.needs_job = false; // not until requested
-                                        self.status = WorkerStatus::new(self.uuid());
-                                        self.send_ok(req.method);
-                                    },
-                                    Err(e) => {
-                                        return self.send_err(
-                                            "login".to_string(),
-                                            e,
-                                            -32500,
-                                        );
+                                return self.accept_login(req.method, login_params);
+                            }
+                            "mining.subscribe" => {
+                                debug!("Worker {} - Accepting subscribe request", self.uuid());
+                                // Sent as [agent, ...] by most miners
+                                self.subscribe_agent = match req.params {
+                                    Some(Value::Array(ref params)) if !params.is_empty() => {
+                                        params[0].as_str().map(|agent| agent.to_string())
                                     }
+                                    _ => None,
+                                };
+                                if self.state == ConnectionState::Connected {
+                                    self.state = ConnectionState::Subscribed;
                                 }
+                                let subscription = self.subscription();
+                                self.send_response(req.method, subscription);
+                            }
+                            "mining.authorize" => {
+                                debug!("Worker {} - Accepting authorize request", self.uuid());
+                                if self.user_id != 0 {
+                                    debug!("User already logged in: {}", self.user_id.clone());
+                                    self.state = ConnectionState::Authorized;
+                                    self.needs_job = true;
+                                    self.send_response(req.method, Value::Bool(true));
+                                    return Ok(());
+                                }
+                                // Sent as [login, password]
+                                let login_params = match req.params {
+                                    Some(Value::Array(ref params)) if !params.is_empty() && params[0].is_string() => LoginParams {
+                                        login: params[0].as_str().unwrap_or("").to_string(),
+                                        pass: params.get(1).and_then(|pass| pass.as_str()).unwrap_or("").to_string(),
+                                        agent: self.subscribe_agent.clone().unwrap_or("unknown".to_string()),
+                                        session_token: None,
+                                    },
+                                    _ => {
+                                        self.error = true;
+                                        debug!("Worker {} - Invalid authorize request parameters", self.uuid());
+                                        return self.send_err(req.method, RejectReason::InvalidLoginParams);
+                                    }
+                                };
+                                return self.accept_login(req.method, login_params);
                             }
                             "getjobtemplate" => {
                                 trace!("Worker {} - Accepting request for job", self.uuid());
@@ -575,19 +1295,63 @@ impl Worker {
                                 self.requested_job = true;
                             }
                             "submit" => {
+                                if !self.authenticated() {
+                                    debug!("Worker {} - Share submitted before login", self.uuid());
+                                    return self.send_err(req.method, RejectReason::NotAuthorized);
+                                }
                                 trace!("Worker {} - Accepting share", self.uuid());
-                                match serde_json::from_value(req.params.unwrap()) {
-                                    Result::Ok(share) => {
+                                match serde_json::from_value::<SubmitParams>(req.params.unwrap()) {
+                                    Result::Ok(mut share) => {
+                                        // Validated and submitted upstream with the nonce that is in the header
+                                        share.nonce = self.header_nonce(share.nonce);
                            			    self.shares.push(share);
                                     },
                                     Result::Err(err) => { }
                                 };
                             }
+                            "mining.get_transactions" => {
+                                if !self.authenticated() {
+                                    return self.send_err(req.method, RejectReason::NotAuthorized);
+                                }
+                                trace!("Worker {} - Accepting request for transactions", self.uuid());
+                                // Answer from the last response at this height, the pool asks the node otherwise
+                                let cached = match self.transactions {
+                                    Some((height, ref transactions)) if height == self.status.height => {
+                                        Some(transactions.clone())
+                                    }
+                                    _ => None,
+                                };
+                                match cached {
+                                    Some(transactions) => {
+                                        let height = self.status.height;
+                                        return self.send_transactions(height, transactions);
+                                    }
+                                    None => self.requested_transactions = true,
+                                }
+                            }
                             "status" => {
                                 trace!("Worker {} - Accepting status request", self.uuid());
                                 let status = self.status.clone();
                                 self.send_status(status);
                             }
+                            "mining.suggest_difficulty" => {
+                                trace!("Worker {} - Accepting difficulty suggestion", self.uuid());
+                                // Sent either as [difficulty] or as a bare number
+                                let difficulty = match req.params {
+                                    Some(Value::Array(ref params)) if !params.is_empty() => params[0].as_f64(),
+                                    Some(ref param) => param.as_f64(),
+                                    None => None,
+                                };
+                                match difficulty {
+                                    Some(difficulty) => {
+                                        self.suggest_difficulty(difficulty);
+                                        self.send_ok(req.method);
+                                    }
+                                    None => {
+                                        self.send_err(req.method, RejectReason::InvalidDifficulty);
+                                    }
+                                }
+                            }
                             "keepalive" => {
                                 trace!("Worker {} - Accepting keepalive request", self.uuid());
                                 self.send_ok(req.method);
@@ -603,7 +1367,22 @@ impl Worker {
                             }
                         };
                     }
-                    None => {} // Not an error, just no messages for us right now
+                    None => {
+                        // Only read when the socket was ready, so the read gave up part way
+                        // through a message.  A few in a row happen with slow links, more
+                        // means the miner is stuck or trickling bytes to hold the socket.
+                        self.consecutive_timeouts += 1;
+                        let max_timeouts = self.config.workers.max_consecutive_timeouts;
+                        if max_timeouts > 0 && self.consecutive_timeouts >= max_timeouts {
+                            warn!(
+                                "Worker {} - Dropping after {} reads timed out in a row",
+                                self.uuid(),
+                                self.consecutive_timeouts
+                            );
+                            self.error = true;
+                            return Err("Read timed out".to_string());
+                        }
+                    }
                 }
             }
             Err(e) => {
@@ -619,3 +1398,427 @@ impl Worker {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pool::pool::tests::{share_json, test_config, test_worker};
+    use pool::config::PortDifficulty;
+    use pool::proto::JobId;
+
+    #[test]
+    fn slow_worker_queue_is_bounded() {
+        let config = test_config();
+        // The miner never reads, so the socket fills up
+        let (mut worker, _miner) = test_worker(&config);
+        let big = "x".repeat(64 * 1024);
+        for _ in 0..1000 {
+            worker.send_err("submit".to_string(), RejectReason::LoginFailed(big.clone())).unwrap();
+            worker.flush_outbound().unwrap();
+            assert!(worker.outbound.len() <= 16);
+        }
+        assert!(!worker.error());
+        assert!(worker.status.dropped_messages > 0);
+        assert_eq!(worker.outbound.len(), 16);
+    }
+
+    #[test]
+    fn vardiff_stays_in_bounds() {
+        let mut config = test_config();
+        config.workers.min_difficulty = 4;
+        config.workers.max_difficulty = 1000;
+        config.workers.vardiff_target_share_secs = 10;
+        let (mut worker, _miner) = test_worker(&config);
+        worker.set_difficulty(16);
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let shares: u64 = rng.gen_range(0, 500);
+            let elapsed = Duration::from_secs(rng.gen_range(1, 120));
+            let difficulty = worker.compute_target_difficulty(shares, elapsed);
+            assert!(difficulty >= 4 && difficulty <= 1000, "vardiff set {}", difficulty);
+            worker.set_difficulty(difficulty);
+        }
+        // Pinned at the ceiling by a flood of shares, then the floor by none
+        for _ in 0..10 {
+            let difficulty = worker.compute_target_difficulty(1000000, Duration::from_secs(1));
+            worker.set_difficulty(difficulty);
+        }
+        assert_eq!(worker.status.difficulty, 1000);
+        for _ in 0..10 {
+            let difficulty = worker.compute_target_difficulty(0, Duration::from_secs(60));
+            worker.set_difficulty(difficulty);
+        }
+        assert_eq!(worker.status.difficulty, 4);
+
+        // The port the worker connected to has its own floor
+        worker.config.workers.port_difficulty.push(PortDifficulty {
+            port: worker.port,
+            difficulty: 64,
+            min_difficulty: Some(32),
+            max_difficulty: None,
+        });
+        assert_eq!(worker.difficulty_bounds(), (32, 1000));
+        assert_eq!(worker.compute_target_difficulty(0, Duration::from_secs(60)), 32);
+    }
+
+    #[test]
+    fn silent_worker_flagged() {
+        let mut config = test_config();
+        config.workers.vardiff_target_share_secs = 2;
+        let (mut worker, _miner) = test_worker(&config);
+        worker.state = ConnectionState::Authorized;
+        assert!(!worker.is_silent());
+        assert_eq!(worker.last_accepted_share_age(), None);
+
+        // Never had a share in ten intervals since connecting
+        worker.connected_at = Instant::now() - Duration::from_secs(21);
+        assert!(worker.is_silent());
+        worker.share_accepted();
+        assert!(!worker.is_silent());
+        assert_eq!(worker.last_accepted_share_age(), Some(0));
+        worker.last_accepted_share = Some(Instant::now() - Duration::from_secs(25));
+        assert!(worker.is_silent());
+        assert_eq!(worker.last_accepted_share_age(), Some(25));
+
+        // Without vardiff there is no share interval to expect
+        worker.config.workers.vardiff_target_share_secs = 0;
+        assert!(!worker.is_silent());
+    }
+
+    #[test]
+    fn min_difficulty_never_violated() {
+        let mut config = test_config();
+        config.workers.min_difficulty = 16;
+        config.workers.min_share_difficulty = 1;
+        config.workers.vardiff_target_share_secs = 10;
+        let (mut worker, _miner) = test_worker(&config);
+        worker.config.workers.port_difficulty.push(PortDifficulty {
+            port: worker.port,
+            difficulty: 64,
+            min_difficulty: Some(32),
+            max_difficulty: None,
+        });
+        worker.set_difficulty(1);
+        assert_eq!(worker.status.difficulty, 32);
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            match rng.gen_range(0, 3) {
+                0 => worker.set_difficulty(rng.gen_range(0, 100)),
+                1 => worker.suggest_difficulty(rng.gen_range(0.0, 100.0)),
+                _ => {
+                    let difficulty = worker.compute_target_difficulty(rng.gen_range(0, 50), Duration::from_secs(60));
+                    worker.set_difficulty(difficulty);
+                }
+            }
+            assert!(worker.status.difficulty >= 32, "difficulty set to {}", worker.status.difficulty);
+        }
+    }
+
+    #[test]
+    fn dropped_after_consecutive_timeouts() {
+        let mut config = test_config();
+        config.workers.max_consecutive_timeouts = 3;
+        let (mut worker, mut miner) = test_worker(&config);
+
+        // The miner trickles a message without ever finishing it
+        miner.write_all(b"{\"id\":\"1\",\"jsonrpc\":\"2.0\",").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        worker.process_messages().unwrap();
+        assert!(!worker.error());
+
+        // A complete message starts the count over
+        miner.write_all(b"\"method\":\"keepalive\",\"params\":null}\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        assert_eq!(worker.consecutive_timeouts, 0);
+
+        worker.process_messages().unwrap();
+        worker.process_messages().unwrap();
+        assert!(!worker.error());
+        assert!(worker.process_messages().is_err());
+        assert!(worker.error());
+    }
+
+    #[test]
+    fn quiet_worker_pinged_then_dropped() {
+        use std::io::{BufRead, BufReader};
+        let config = test_config();
+        let (mut worker, mut miner) = test_worker(&config);
+        let (interval, timeout) = (Duration::from_millis(50), Duration::from_millis(100));
+        // Not quiet for long enough yet
+        worker.check_keepalive(interval, timeout);
+        assert!(!worker.ping_pending());
+
+        thread::sleep(interval);
+        worker.check_keepalive(interval, timeout);
+        assert!(worker.ping_pending());
+        worker.flush_outbound().unwrap();
+        let mut line = String::new();
+        BufReader::new(miner.try_clone().unwrap()).read_line(&mut line).unwrap();
+        assert!(line.contains("\"method\":\"ping\""));
+
+        // The miner answers, the connection is alive
+        miner.write_all(b"{\"id\":\"0\",\"jsonrpc\":\"2.0\",\"method\":\"pong\",\"params\":null}\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        worker.process_messages().unwrap();
+        assert!(!worker.ping_pending());
+        assert!(worker.last_pong.is_some());
+        worker.check_keepalive(interval, timeout);
+        assert!(!worker.error());
+
+        // Quiet again, and this time nothing answers
+        thread::sleep(interval);
+        worker.check_keepalive(interval, timeout);
+        assert!(worker.ping_pending());
+        worker.check_keepalive(interval, timeout);
+        assert!(!worker.error());
+        thread::sleep(timeout);
+        worker.check_keepalive(interval, timeout);
+        assert!(worker.error());
+    }
+
+    #[test]
+    fn shares_weighted_by_edge_bits() {
+        let config = test_config();
+        let (mut worker, _miner) = test_worker(&config);
+        worker.add_shares(24, 1, 0, 0);
+        assert_eq!(worker.worker_shares.weighted_shares, 1.0);
+        worker.add_shares(31, 1, 2, 3);
+        assert_eq!(worker.worker_shares.weighted_shares, 129.0);
+        assert_eq!(worker.status.weighted_accepted, 129.0);
+        // Rejected and stale shares earn nothing
+        worker.add_shares(31, 0, 1, 1);
+        assert_eq!(worker.status.weighted_accepted, 129.0);
+        worker.reset_worker_shares(2, 1);
+        assert_eq!(worker.worker_shares.weighted_shares, 0.0);
+    }
+
+    #[test]
+    fn nonce_outside_sent_range() {
+        let mut config = test_config();
+        config.workers.nonce_range_bits = 8;
+        let (mut worker, _miner) = test_worker(&config);
+        let mut job = JobTemplate::new();
+        job.height = 5;
+        job.job_id = JobId::new(5, 0).encode().unwrap();
+        worker.send_job(&mut job).unwrap();
+        let start = job.nonce_start.unwrap();
+        assert_eq!(start % 256, 0);
+        assert_eq!(worker.assigned_nonce_range, Some((start, start.wrapping_add(256))));
+
+        let share = |job_id: u64, nonce: u64| SubmitParams {
+            height: 5,
+            job_id: job_id,
+            nonce: nonce,
+            edge_bits: 31,
+            pow: vec![],
+            stale: false,
+        };
+        assert!(worker.nonce_in_range(&share(job.job_id, start)));
+        assert!(worker.nonce_in_range(&share(job.job_id, start + 255)));
+        assert!(!worker.nonce_in_range(&share(job.job_id, start + 256)));
+        assert!(!worker.nonce_in_range(&share(job.job_id, start.wrapping_sub(1))));
+        // No range was sent for this job
+        assert!(worker.nonce_in_range(&share(JobId::new(5, 1).encode().unwrap(), start + 256)));
+
+        // Ranges for older heights are forgotten
+        let old_job_id = job.job_id;
+        job.height = 6;
+        job.job_id = JobId::new(6, 0).encode().unwrap();
+        worker.send_job(&mut job).unwrap();
+        assert!(worker.nonce_in_range(&share(old_job_id, start + 256)));
+        assert_eq!(worker.job_nonces.len(), 1);
+    }
+
+    #[test]
+    fn extranonce_round_trip() {
+        use grin_core::core::BlockHeader;
+        use grin_core::ser::ser_vec;
+        use grin_util::to_hex;
+        use pool::consensus::PROOF_SIZE;
+        use pool::validator::block_header;
+        use std::io::BufReader;
+
+        let mut config = test_config();
+        config.workers.extranonce1_bytes = 2;
+        let (mut worker, mut miner) = test_worker(&config);
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner.try_clone().unwrap());
+        miner
+            .write_all(b"{\"id\":\"1\",\"jsonrpc\":\"2.0\",\"method\":\"mining.subscribe\",\"params\":[\"testminer/1.0\"]}\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        worker.flush_outbound().unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let subscribed: Value = serde_json::from_str(&line).unwrap();
+        let extranonce1_hex = subscribed["result"][1].as_str().unwrap();
+        assert_eq!(extranonce1_hex.len(), 4);
+        assert_eq!(subscribed["result"][2], 6);
+        let extranonce1 = u64::from_str_radix(extranonce1_hex, 16).unwrap();
+
+        // Jobs start at the beginning of the workers part of the nonce
+        let mut job = JobTemplate::new();
+        job.height = 5;
+        job.job_id = JobId::new(5, 0).encode().unwrap();
+        worker.send_job(&mut job).unwrap();
+        assert_eq!(job.nonce_start, Some(extranonce1 << 48));
+
+        // The miner sends only its extranonce2
+        worker.state = ConnectionState::Authorized;
+        let submit = format!(
+            "{{\"id\":\"2\",\"jsonrpc\":\"2.0\",\"method\":\"submit\",\"params\":{}}}\n",
+            share_json(5, job.job_id, 0x1234, 31, &vec![])
+        );
+        miner.write_all(submit.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        let share = worker.get_shares().unwrap().unwrap().remove(0);
+        assert_eq!(share.nonce, (extranonce1 << 48) | 0x1234);
+        // Sending the whole nonce works the same
+        assert_eq!(worker.header_nonce(share.nonce), share.nonce);
+
+        // The header is rebuilt with the extranonce1 leading the nonce bytes
+        let header = BlockHeader::default();
+        let mut bytes = ser_vec(&header).unwrap();
+        let proof_len = ser_vec(&header.pow.proof).unwrap().len();
+        bytes.truncate(bytes.len() - proof_len - 8);
+        let rebuilt = block_header(to_hex(bytes), 31, share.nonce, vec![0; PROOF_SIZE]).unwrap();
+        assert_eq!(rebuilt.pow.nonce, share.nonce);
+        let nonce_bytes = ser_vec(&rebuilt.pow.nonce).unwrap();
+        assert_eq!(to_hex(nonce_bytes[..2].to_vec()), extranonce1_hex);
+    }
+
+    #[test]
+    fn suggested_difficulty_is_a_floor() {
+        let mut config = test_config();
+        config.workers.min_share_difficulty = 4;
+        config.workers.max_share_difficulty = 500;
+        let (mut worker, mut miner) = test_worker(&config);
+        worker.set_difficulty(8);
+
+        miner
+            .write_all(b"{\"id\":\"1\",\"jsonrpc\":\"2.0\",\"method\":\"mining.suggest_difficulty\",\"params\":[64]}\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        worker.process_messages().unwrap();
+        assert_eq!(worker.status.difficulty, 64);
+        worker.set_difficulty(16);
+        assert_eq!(worker.status.difficulty, 64);
+        worker.set_difficulty(128);
+        assert_eq!(worker.status.difficulty, 128);
+
+        // Out of range suggestions are clamped
+        worker.suggest_difficulty(100000.0);
+        worker.set_difficulty(1);
+        assert_eq!(worker.status.difficulty, 500);
+        worker.suggest_difficulty(-5.0);
+        worker.set_difficulty(1);
+        assert_eq!(worker.status.difficulty, 4);
+    }
+
+    #[test]
+    fn difficulty_change_notified() {
+        use std::io::BufReader;
+        use std::net::TcpStream;
+        let (mut worker, miner) = test_worker(&test_config());
+        miner.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(miner);
+        let read_method = |reader: &mut BufReader<TcpStream>| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let message: Value = serde_json::from_str(&line).unwrap();
+            (message["method"].as_str().unwrap().to_string(), message["params"].clone())
+        };
+        let mut job = JobTemplate::new();
+        worker.set_difficulty(8);
+        worker.send_job(&mut job).unwrap();
+        worker.send_job(&mut job).unwrap();
+        worker.set_difficulty(8);
+        worker.send_job(&mut job).unwrap();
+        // Two changes between jobs are one notification
+        worker.set_difficulty(16);
+        worker.set_difficulty(32);
+        worker.send_job(&mut job).unwrap();
+        worker.send_job(&mut job).unwrap();
+        worker.flush_outbound().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(read_method(&mut reader).0, "job");
+        }
+        let (method, params) = read_method(&mut reader);
+        assert_eq!(method, "mining.set_difficulty");
+        assert_eq!(params, Value::from(vec![32]));
+        let (method, params) = read_method(&mut reader);
+        assert_eq!(method, "job");
+        assert_eq!(params["difficulty"], 32);
+        assert_eq!(read_method(&mut reader).0, "job");
+    }
+
+    fn login_parts(account: &str, rigid: &str, workerid: &str) -> Result<(String, String, String), String> {
+        Ok((account.to_string(), rigid.to_string(), workerid.to_string()))
+    }
+
+    #[test]
+    fn rig_id_sanitized() {
+        assert_eq!(sanitize_rig_id(""), "");
+        let valid = "Rig_1-abcXYZ0123456789";
+        assert_eq!(sanitize_rig_id(valid), valid);
+        assert_eq!(sanitize_rig_id("rig1'; DROP TABLE workers; --"), "rig1DROPTABLEworkers--");
+        assert_eq!(sanitize_rig_id("../../etc/passwd"), "etcpasswd");
+        assert_eq!(sanitize_rig_id("rïg-ünï_çødé"), "rg-n_d");
+        assert_eq!(sanitize_rig_id("矿机1"), "1");
+        let long = "a".repeat(200);
+        assert_eq!(sanitize_rig_id(&long), "a".repeat(64));
+    }
+
+    #[test]
+    fn login_tag() {
+        assert_eq!(split_login_tag("alice.rig1.gpu0#Farm EU", "#"), ("alice.rig1.gpu0", Some("Farm EU".to_string())));
+        assert_eq!(split_login_tag("alice#", "#"), ("alice", None));
+        assert_eq!(split_login_tag("alice.rig1", "#"), ("alice.rig1", None));
+        assert_eq!(split_login_tag("alice#eu", ""), ("alice#eu", None));
+        // The tag is not part of the worker names
+        let (login, tag) = split_login_tag("Alice.Rig1#eu-1", "#");
+        assert_eq!(parse_login(login, ".", 64), login_parts("alice", "rig1", "0"));
+        assert_eq!(tag, Some("eu-1".to_string()));
+    }
+
+    #[test]
+    fn login_format() {
+        // Missing delimiter, the rig and worker names default
+        assert_eq!(parse_login("Alice", ".", 64), login_parts("alice", "default", "0"));
+        assert_eq!(parse_login("alice.Rig1.GPU0", ".", 64), login_parts("alice", "rig1", "gpu0"));
+        assert_eq!(parse_login("alice/rig1", "/", 64), login_parts("alice", "rig1", "0"));
+        // Empty rig or worker names
+        assert!(parse_login("alice.", ".", 64).is_err());
+        assert!(parse_login("alice..gpu0", ".", 64).is_err());
+        assert!(parse_login("alice.rig1.", ".", 64).is_err());
+        // Missing username, too many parts, oversized names
+        assert!(parse_login("", ".", 64).is_err());
+        assert!(parse_login(".rig1", ".", 64).is_err());
+        assert!(parse_login("alice.rig1.gpu0.x", ".", 64).is_err());
+        assert!(parse_login(&"a".repeat(65), ".", 64).is_err());
+    }
+
+    #[test]
+    fn duplicate_login_renamed() {
+        let config = test_config();
+        let mut taken = HashSet::new();
+        let mut workers = vec![];
+        for _ in 0..3 {
+            let (mut worker, miner) = test_worker(&config);
+            worker.user_id = 7;
+            worker.worker_shares.rigid = "rig1".to_string();
+            worker.make_full_id_unique(&taken);
+            taken.insert(worker.full_id());
+            workers.push((worker, miner));
+        }
+        assert_eq!(workers[0].0.full_id(), "7.rig1.0");
+        assert_eq!(workers[1].0.full_id(), "7.rig1.0-2");
+        assert_eq!(workers[2].0.full_id(), "7.rig1.0-3");
+        assert!(!workers[0].0.make_full_id_unique(&HashSet::new()));
+    }
+}